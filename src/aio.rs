@@ -0,0 +1,89 @@
+//! An async API over `SharedDB`, behind the `tokio` feature.
+
+use std::{num::NonZeroU32, panic};
+
+use crate::{db::DB, error::DbError, row::RowVal, shared::SharedDB};
+
+/// An async handle to a database. Every call runs on tokio's blocking thread
+/// pool, so neither file IO nor waiting for the lock stalls the runtime.
+/// Clones share the same database.
+#[derive(Debug, Clone)]
+pub struct Db(SharedDB);
+
+/// Runs `f` on the blocking thread pool, passing on any panic.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(e) => panic::resume_unwind(e.into_panic()),
+    }
+}
+
+impl Db {
+    pub fn new(db: DB) -> Self {
+        Self(SharedDB::new(db))
+    }
+
+    /// Opens a database written by an earlier run, as `DB::open` does.
+    pub async fn open(file_name: &str) -> Result<Option<Self>, DbError> {
+        let file_name = file_name.to_string();
+        let db = blocking(move || DB::open(&file_name)).await?;
+        Ok(db.map(Self::new))
+    }
+
+    /// The handle the calls go through, for blocking code that shares the
+    /// database.
+    pub fn shared(&self) -> &SharedDB {
+        &self.0
+    }
+
+    pub async fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        let db = self.0.clone();
+        blocking(move || db.get(id)).await
+    }
+
+    pub async fn insert(&self, id: NonZeroU32, val: Vec<RowVal>) -> Result<(), DbError> {
+        let db = self.0.clone();
+        blocking(move || db.insert(id, &val)).await
+    }
+
+    pub async fn remove(&self, id: NonZeroU32) -> Result<Option<Vec<RowVal>>, DbError> {
+        let db = self.0.clone();
+        blocking(move || db.remove(id)).await
+    }
+
+    pub async fn sync(&self) -> bool {
+        let db = self.0.clone();
+        blocking(move || db.sync()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::row::RowType;
+
+    use super::*;
+
+    #[test]
+    fn get_insert_remove() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let file_name = "tests/aio";
+        let one = NonZeroU32::new(1).unwrap();
+        runtime.block_on(async {
+            let db = Db::new(DB::new(file_name, &[RowType::Id, RowType::U32]).unwrap());
+            db.insert(one, vec![RowVal::U32(1)]).await.unwrap();
+            assert_eq!(db.get(one).await, Some(vec![RowVal::U32(1)]));
+            assert_eq!(
+                db.insert(one, vec![RowVal::Bool(true)]).await,
+                Err(DbError::SchemaMismatch)
+            );
+            assert!(db.sync().await);
+        });
+        runtime.block_on(async {
+            let db = Db::open(file_name).await.unwrap().unwrap();
+            assert_eq!(db.remove(one).await, Ok(Some(vec![RowVal::U32(1)])));
+            assert_eq!(db.get(one).await, None);
+        });
+    }
+}
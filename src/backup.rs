@@ -0,0 +1,237 @@
+//! Online backups, taken while writes carry on. `DB::backup` pins the `.db`
+//! file the way a `Snapshot` does, so a sync replaces the file rather than
+//! writing over the pages being copied, and keeps the WAL's records up to an
+//! LSN. `Backup::write_to` copies them after, without holding the database,
+//! to a directory laid out like a database's, with a `BACKUP` file naming the
+//! LSN and the CRC32 of every file copied. `restore` checks them and copies
+//! the backup back to a database of its own.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use crate::{db::DB, manifest};
+
+/// A backup of every write up to an LSN, ready to be copied.
+#[derive(Debug)]
+pub struct Backup {
+    /// The LSN of the last write the backup holds.
+    pub lsn: u64,
+    pub(crate) epoch: u64,
+    /// The `.db` file as of the last sync.
+    pub(crate) db_file: File,
+    /// The WAL's frames since the last sync, up to `lsn`.
+    pub(crate) wal: Vec<u8>,
+    /// Every live run of the LSM engine, with its number and level.
+    pub(crate) runs: Vec<(u64, u32, File)>,
+    /// Keeps the `.db` file from being written in place, see `DB::snapshots`.
+    pub(crate) _pinned: Arc<()>,
+}
+
+impl Backup {
+    /// Copies the backup to the directory `path`, which can't hold a
+    /// database already. The manifest is written last, so a backup cut
+    /// short doesn't open as a database.
+    pub fn write_to(&self, path: &str) -> io::Result<()> {
+        if manifest::read(path).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{path} already holds a database"),
+            ));
+        }
+        fs::create_dir_all(path)?;
+        let mut list = format!("lsn {}\n", self.lsn);
+        let mut add = |name: String, from: &mut dyn Read| -> io::Result<()> {
+            let checksum = copy(from, &format!("{path}/{name}"))?;
+            list.push_str(&format!("file {name} {checksum:08x}\n"));
+            Ok(())
+        };
+
+        let mut db_file = &self.db_file;
+        db_file.seek(SeekFrom::Start(0))?;
+        add(format!("{}.db", self.epoch), &mut db_file)?;
+        add(format!("{}.wal.0001", self.epoch), &mut &self.wal[..])?;
+        let mut runs = String::new();
+        for (number, level, file) in &self.runs {
+            let mut file = file;
+            file.seek(SeekFrom::Start(0))?;
+            add(format!("run.{number}"), &mut file)?;
+            runs.push_str(&format!("run {number} {level}\n"));
+        }
+        if !runs.is_empty() {
+            add("RUNS".to_string(), &mut runs.as_bytes())?;
+        }
+
+        fs::write(manifest::backup_file(path), list)?;
+        File::open(manifest::backup_file(path))?.sync_all()?;
+        manifest::write(path, self.epoch)
+    }
+}
+
+/// Copies `from` to `to`, returning the CRC32 of what was copied.
+fn copy(from: &mut dyn Read, to: &str) -> io::Result<u32> {
+    let mut file = File::create(to)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let len = from.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        file.write_all(&buf[..len])?;
+    }
+    file.sync_all()?;
+    Ok(hasher.finalize())
+}
+
+/// Restores the backup in `backup_dir` to a new database at `dest`, which
+/// can't hold one already. Every file is checked against the CRC32 the
+/// backup lists for it as it's copied, and the WAL is replayed and synced
+/// to the pages, so the database opens like any other. If a file doesn't
+/// match, nothing restored is left behind.
+pub fn restore(backup_dir: &str, dest: &str) -> io::Result<()> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let list = fs::read_to_string(manifest::backup_file(backup_dir))
+        .map_err(|e| invalid(format!("{backup_dir} isn't a backup: {e}")))?;
+    let epoch = manifest::read(backup_dir)
+        .ok_or_else(|| invalid(format!("{backup_dir} is a backup cut short")))?;
+    if manifest::read(dest).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{dest} already holds a database"),
+        ));
+    }
+    fs::create_dir_all(dest)?;
+
+    let mut copied = vec![];
+    let restore_file = |line: &str| -> io::Result<()> {
+        let Some((name, checksum)) = line
+            .strip_prefix("file ")
+            .and_then(|file| file.split_once(' '))
+        else {
+            return Ok(());
+        };
+        let to = format!("{dest}/{name}");
+        copied.push(to.clone());
+        let actual = copy(&mut File::open(format!("{backup_dir}/{name}"))?, &to)?;
+        if u32::from_str_radix(checksum, 16).ok() != Some(actual) {
+            return Err(invalid(format!("{name} doesn't match its checksum")));
+        }
+        Ok(())
+    };
+    let restored = list.lines().try_for_each(restore_file);
+    if let Err(e) = restored {
+        for path in copied {
+            let _ = fs::remove_file(path);
+        }
+        return Err(e);
+    }
+    manifest::write(dest, epoch)?;
+
+    // opening replays the WAL
+    let mut db = DB::open(dest).map_err(io::Error::other)?.unwrap();
+    db.sync();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use crate::{
+        db::DB,
+        row::{RowType, RowVal},
+    };
+
+    use super::*;
+
+    #[test]
+    fn online_backup() {
+        let file_name = "tests/online_backup";
+        let path = "tests/online_backup_copy";
+        let _ = fs::remove_dir_all(file_name);
+        let _ = fs::remove_dir_all(path);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::new(file_name, &[RowType::Id, RowType::U32]).unwrap();
+        for i in 1..=50 {
+            db.insert(id(i), &[RowVal::U32(i)]).unwrap();
+        }
+        db.sync();
+        let synced = db.wal.lsn;
+        for i in 51..=60 {
+            db.insert(id(i), &[RowVal::U32(i)]).unwrap();
+        }
+        assert!(db.backup(synced - 1).is_err());
+        let backup = db.backup(synced + 5).unwrap();
+
+        // writes carry on, and a sync doesn't touch the pinned file
+        db.remove(id(1));
+        db.insert(id(2), &[RowVal::U32(200)]).unwrap();
+        db.sync();
+        backup.write_to(path).unwrap();
+        assert_eq!(backup.lsn, synced + 5);
+        assert!(fs::read_to_string(manifest::backup_file(path))
+            .unwrap()
+            .starts_with(&format!("lsn {}\n", synced + 5)));
+        assert_eq!(
+            backup.write_to(path).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        let copy = DB::open(path).unwrap().unwrap();
+        let rows = copy.scan(..);
+        assert_eq!(rows.len(), 55);
+        assert_eq!(rows[0], (id(1), vec![RowVal::U32(1)]));
+        assert_eq!(copy.get(id(2)), Some(vec![RowVal::U32(2)]));
+        assert_eq!(copy.get(id(56)), None);
+        assert_eq!(db.get(id(2)), Some(vec![RowVal::U32(200)]));
+    }
+
+    #[test]
+    fn restore_backup() {
+        let file_name = "tests/restore_backup";
+        let path = "tests/restore_backup_copy";
+        let dest = "tests/restore_backup_restored";
+        for dir in [file_name, path, dest] {
+            let _ = fs::remove_dir_all(dir);
+        }
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::new(file_name, &[RowType::Id, RowType::U32]).unwrap();
+        for i in 1..=20 {
+            db.insert(id(i), &[RowVal::U32(i)]).unwrap();
+        }
+        db.sync();
+        db.remove(id(3));
+        db.insert(id(21), &[RowVal::U32(21)]).unwrap();
+        let lsn = db.backup_to(path).unwrap();
+        drop(db);
+
+        // a flipped bit in the WAL is caught, and leaves nothing behind
+        let wal = format!("{path}/1.wal.0001");
+        let mut bytes = fs::read(&wal).unwrap();
+        bytes[20] ^= 1;
+        fs::write(&wal, &bytes).unwrap();
+        let err = DB::restore_backup(path, dest).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(DB::open(dest).unwrap().is_none());
+        bytes[20] ^= 1;
+        fs::write(&wal, &bytes).unwrap();
+
+        DB::restore_backup(path, dest).unwrap();
+        assert_eq!(
+            DB::restore_backup(path, dest).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        let db = DB::open(dest).unwrap().unwrap();
+        // the WAL was replayed and synced
+        assert!(db.wal.records.is_empty());
+        assert_eq!(db.wal.lsn, lsn);
+        assert_eq!(db.get(id(3)), None);
+        assert_eq!(db.get(id(21)), Some(vec![RowVal::U32(21)]));
+        assert_eq!(db.scan(..).len(), 20);
+        assert!(DB::restore_backup(file_name, "tests/restore_backup_other").is_err());
+    }
+}
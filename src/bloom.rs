@@ -0,0 +1,102 @@
+//! Bloom filters over the ids on a leaf, so looking up an id that isn't on
+//! a page can usually tell so from the page's bytes without decoding it. A
+//! filter goes in the free space at the end of its page: the filter's bits
+//! followed by their length in bytes. A full page ends in rows instead, so
+//! whether a page has a filter is marked in its header, `PageHeader::bloom`,
+//! and only a page marked so is looked up here.
+
+use std::num::NonZeroU32;
+
+/// How many bits of filter each id gets, which with `HASHES` hashes has
+/// about 1 in 100 ids that aren't on the page let through.
+pub const BITS_PER_ID: usize = 10;
+
+/// Fewer bits than this per id let too many through to be worth the space.
+const MIN_BITS_PER_ID: usize = 8;
+
+/// How many bits each id sets.
+const HASHES: u64 = 7;
+
+/// The size of the length at the end of a page.
+const LEN_SIZE: usize = 2;
+
+/// The bits an id sets, out of `len`, by double hashing.
+fn bits(id: NonZeroU32, len: usize) -> impl Iterator<Item = usize> {
+    let h1 = mix(id.get() as u64);
+    let h2 = mix(h1) | 1;
+    (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len as u64) as usize)
+}
+
+/// The finalizer of SplitMix64, which spreads nearby ids far apart.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Fills out a page with a filter over `ids`, padding it to `page_size`.
+/// The filter is made smaller to fit in what's left of the page, and left
+/// out if it would have to be too small to be of use. Returns whether it was
+/// added.
+pub fn append(page: &mut Vec<u8>, ids: &[NonZeroU32], page_size: usize) -> bool {
+    let free = page_size - page.len();
+    let len = (ids.len() * BITS_PER_ID)
+        .div_ceil(8)
+        .min(free.saturating_sub(LEN_SIZE))
+        .min(u16::MAX as usize);
+    if ids.is_empty() || len * 8 < ids.len() * MIN_BITS_PER_ID {
+        page.resize(page_size, 0);
+        return false;
+    }
+    let mut filter = vec![0; len];
+    for &id in ids {
+        for bit in bits(id, len * 8) {
+            filter[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    page.resize(page_size - len - LEN_SIZE, 0);
+    page.extend(filter);
+    page.extend((len as u16).to_le_bytes());
+    true
+}
+
+/// Whether `id` might be on the page `page` holds the bytes of, which has to
+/// be one `append` added a filter to. `false` means it certainly isn't.
+pub fn may_contain(page: &[u8], id: NonZeroU32) -> bool {
+    let Some(filter) = filter(page) else {
+        return true;
+    };
+    bits(id, filter.len() * 8).all(|bit| filter[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+/// The filter at the end of a page, if it has one.
+fn filter(page: &[u8]) -> Option<&[u8]> {
+    let end = page.len().checked_sub(LEN_SIZE)?;
+    let len = u16::from_le_bytes(page[end..].try_into().unwrap()) as usize;
+    let start = end.checked_sub(len).filter(|_| len > 0)?;
+    Some(&page[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_lookups() {
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let ids: Vec<_> = (1..=200).map(|i| id(2 * i)).collect();
+        let mut page = vec![1; 100];
+        assert!(append(&mut page, &ids, 4096));
+        assert_eq!(page.len(), 4096);
+        assert!(ids.iter().all(|&id| may_contain(&page, id)));
+        let passed = (0..200)
+            .filter(|i| may_contain(&page, id(2 * i + 1)))
+            .count();
+        assert!(passed < 10, "{passed} of 200 missing ids let through");
+
+        // a page too full for a filter gets none
+        let mut full = vec![1; 4000];
+        assert!(!append(&mut full, &ids, 4096));
+        assert_eq!(full.len(), 4096);
+    }
+}
@@ -0,0 +1,293 @@
+//! The B+tree over the pages of a `.db` file. The pages holding rows are its
+//! leaves, each pointing at the next one in key order. Above them, interior
+//! pages hold the largest id under each child and where the child is in the
+//! file, up to a root named in the file header. Finding a row reads one page
+//! per level instead of every page header, so a mapped database looks up a
+//! row in O(log n) page reads however little of it is in memory.
+//!
+//! An interior page starts with the header of an empty leaf, so anything
+//! reading the pages in file order, as `deserialize` does, passes over it.
+
+use std::{iter, num::NonZeroU32};
+
+use crate::{
+    header::HEADER_SIZE,
+    page::{PageHeader, PAGE_SIZE},
+    utils::bytes_to_u32,
+};
+
+/// How many children an interior page holds. Each takes its largest id and
+/// its index, after the page header and the number of children.
+pub const FANOUT: usize = (PAGE_SIZE - PageHeader::size() - 4) / 8;
+
+/// Where the tree starts, from the file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Root {
+    pub index: u32,
+    /// How many levels of interior pages are above the leaves, 0 if the
+    /// root is a leaf itself.
+    pub height: u32,
+}
+
+impl Root {
+    pub(crate) fn from_bytes(bytes: &[u8; 8]) -> Option<Self> {
+        let index = bytes_to_u32(&bytes[0..4]).checked_sub(1)?;
+        Some(Self {
+            index,
+            height: bytes_to_u32(&bytes[4..8]),
+        })
+    }
+}
+
+/// A page as its parent sees it: the largest id under it, its index in the
+/// file, and the highest LSN of the pages under it.
+pub type Child = (NonZeroU32, u32, u64);
+
+/// An interior page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// The highest LSN of any page under this one.
+    pub lsn: u64,
+    /// The largest id under each child and its index, in key order.
+    pub children: Vec<(NonZeroU32, u32)>,
+}
+
+impl Node {
+    pub fn to_page_bytes(&self) -> Vec<u8> {
+        let header = PageHeader {
+            end: None,
+            start: None,
+            count: 0,
+            version: 0,
+            lsn: self.lsn,
+            next: None,
+            bloom: false,
+        };
+        let mut res = header.to_bytes();
+        res.extend((self.children.len() as u32).to_le_bytes());
+        for (end, i) in &self.children {
+            res.extend(end.get().to_le_bytes());
+            res.extend(i.to_le_bytes());
+        }
+        res.resize(PAGE_SIZE, 0);
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let header = PageHeader::from_bytes(bytes[..PageHeader::size()].try_into().unwrap());
+        let bytes = &bytes[PageHeader::size()..];
+        let len = (bytes_to_u32(&bytes[0..4]) as usize).min(FANOUT);
+        let children = bytes[4..4 + len * 8]
+            .chunks(8)
+            .filter_map(|child| {
+                let end = NonZeroU32::new(bytes_to_u32(&child[0..4]))?;
+                Some((end, bytes_to_u32(&child[4..8])))
+            })
+            .collect();
+        Self {
+            lsn: header.lsn,
+            children,
+        }
+    }
+
+    /// The index of the child `id` falls under: the first one whose largest
+    /// id is at least `id`. `None` if `id` is past all of them.
+    pub fn child(&self, id: NonZeroU32) -> Option<u32> {
+        let i = self.children.partition_point(|(end, _)| *end < id);
+        self.children.get(i).map(|(_, i)| *i)
+    }
+}
+
+/// Builds the interior pages over `leaves`, which are in key order, putting
+/// each at the index `slot` hands out. Returns the root along with them.
+pub fn build(
+    leaves: Vec<Child>,
+    mut slot: impl FnMut() -> u32,
+) -> (Option<Root>, Vec<(u32, Node)>) {
+    let mut nodes = vec![];
+    let mut level = leaves;
+    let mut height = 0;
+    while level.len() > 1 {
+        level = level
+            .chunks(FANOUT)
+            .map(|children| {
+                let node = Node {
+                    lsn: children.iter().map(|&(_, _, lsn)| lsn).max().unwrap(),
+                    children: children.iter().map(|&(end, i, _)| (end, i)).collect(),
+                };
+                let index = slot();
+                let child = (children.last().unwrap().0, index, node.lsn);
+                nodes.push((index, node));
+                child
+            })
+            .collect();
+        height += 1;
+    }
+    let root = level.first().map(|&(_, index, _)| Root { index, height });
+    (root, nodes)
+}
+
+/// How many interior pages `build` puts over `leaves` leaves.
+pub fn node_count(mut leaves: usize) -> usize {
+    let mut count = 0;
+    while leaves > 1 {
+        leaves = leaves.div_ceil(FANOUT);
+        count += leaves;
+    }
+    count
+}
+
+/// Reads the tree out of a whole `.db` file, header included.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Tree<'a> {
+    bytes: &'a [u8],
+    root: Option<Root>,
+}
+
+impl<'a> Tree<'a> {
+    pub(crate) fn new(bytes: &'a [u8], root: Option<Root>) -> Self {
+        Self { bytes, root }
+    }
+
+    fn page(&self, i: usize) -> &'a [u8] {
+        let start = HEADER_SIZE + i * PAGE_SIZE;
+        &self.bytes[start..start + PAGE_SIZE]
+    }
+
+    pub(crate) fn header(&self, i: usize) -> PageHeader {
+        PageHeader::from_bytes(self.page(i)[..PageHeader::size()].try_into().unwrap())
+    }
+
+    /// The highest LSN of any page, which the root records.
+    pub(crate) fn lsn(&self) -> u64 {
+        self.root
+            .map_or(0, |root| self.header(root.index as usize).lsn)
+    }
+
+    /// The index of the leaf `id` falls under: the first one that ends at or
+    /// after it. If `id` is past every leaf, that's `None`, or the last leaf
+    /// if `or_last` is set.
+    pub(crate) fn leaf(&self, id: NonZeroU32, or_last: bool) -> Option<usize> {
+        let root = self.root?;
+        let mut i = root.index;
+        for _ in 0..root.height {
+            let node = Node::from_bytes(self.page(i as usize));
+            i = match node.child(id) {
+                Some(child) => child,
+                None if or_last => node.children.last()?.1,
+                None => return None,
+            };
+        }
+        if !or_last && self.header(i as usize).end.is_none_or(|end| end < id) {
+            return None;
+        }
+        Some(i as usize)
+    }
+
+    /// The header and index of every leaf with rows on it, in key order,
+    /// from the one `id` falls under on, following each leaf to the next.
+    pub(crate) fn leaves_from(
+        &self,
+        id: NonZeroU32,
+    ) -> impl Iterator<Item = (PageHeader, usize)> + 'a {
+        let tree = *self;
+        let first = self.leaf(id, false).map(|i| (self.header(i), i));
+        iter::successors(first, move |(header, _)| {
+            header.next.map(|i| (tree.header(i as usize), i as usize))
+        })
+        .filter(|(header, _)| header.count > 0)
+    }
+
+    pub(crate) fn leaves(&self) -> impl Iterator<Item = (PageHeader, usize)> + 'a {
+        self.leaves_from(NonZeroU32::MIN)
+    }
+
+    /// The indexes of every interior page, and every leaf as its parent sees
+    /// it, in key order. A leaf is given its parent's LSN, which is at least
+    /// its own.
+    pub(crate) fn walk(&self) -> (Vec<u32>, Vec<Child>) {
+        let Some(root) = self.root else {
+            return (vec![], vec![]);
+        };
+        let header = self.header(root.index as usize);
+        let mut nodes = vec![];
+        let mut level = vec![(
+            header.end.unwrap_or(NonZeroU32::MAX),
+            root.index,
+            header.lsn,
+        )];
+        for _ in 0..root.height {
+            nodes.extend(level.iter().map(|&(_, i, _)| i));
+            level = level
+                .iter()
+                .flat_map(|&(_, i, _)| {
+                    let node = Node::from_bytes(self.page(i as usize));
+                    let lsn = node.lsn;
+                    node.children
+                        .into_iter()
+                        .map(move |(end, child)| (end, child, lsn))
+                })
+                .collect();
+        }
+        (nodes, level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::page::Page;
+    use crate::row::{RowType, RowVal};
+
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let leaves = FANOUT * 2 + 1;
+        let mut bytes = vec![0; HEADER_SIZE];
+        let mut children = vec![];
+        for i in 0..leaves {
+            // two rows per leaf, at 2i + 1 and 2i + 2
+            let rows: Vec<_> = (1..=2)
+                .map(|j| vec![RowVal::Id(id(2 * i as u32 + j))])
+                .collect();
+            let mut page = Page::new(&rows, &[RowType::Id]);
+            page.header.lsn = i as u64;
+            let next = (i + 1 < leaves).then_some(i as u32 + 1);
+            bytes.extend(page.to_leaf_bytes(next));
+            children.push((page.header.end.unwrap(), i as u32, page.header.lsn));
+        }
+        let mut next = leaves as u32;
+        let (root, nodes) = build(children.clone(), || {
+            next += 1;
+            next - 1
+        });
+        assert_eq!(nodes.len(), node_count(leaves));
+        assert_eq!(root.unwrap().height, 2);
+        for (_, node) in &nodes {
+            bytes.extend(node.to_page_bytes());
+        }
+
+        let tree = Tree::new(&bytes, root);
+        assert_eq!(tree.lsn(), leaves as u64 - 1);
+        assert_eq!(tree.leaf(id(1), false), Some(0));
+        assert_eq!(tree.leaf(id(2 * FANOUT as u32 + 1), false), Some(FANOUT));
+        assert_eq!(tree.leaf(id(2 * leaves as u32 + 1), false), None);
+        assert_eq!(tree.leaf(id(2 * leaves as u32 + 1), true), Some(leaves - 1));
+
+        let from: Vec<_> = tree.leaves_from(id(4)).map(|(_, i)| i).collect();
+        assert_eq!(from, (1..leaves).collect::<Vec<_>>());
+        let (interior, walked) = tree.walk();
+        assert_eq!(interior.len(), nodes.len());
+        assert_eq!(
+            walked
+                .iter()
+                .map(|&(end, i, _)| (end, i))
+                .collect::<Vec<_>>(),
+            children
+                .iter()
+                .map(|&(end, i, _)| (end, i))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! The checksums that catch torn and corrupted WAL frames and double-write
+//! buffers. Each database picks one, recorded in its file header, and every
+//! frame it writes is checked with that one when read back.
+
+/// A checksum algorithm. Every one is stored in 32 bits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Checksum {
+    /// CRC32 (IEEE), which databases used before the algorithm could be
+    /// picked.
+    #[default]
+    Crc32,
+    /// CRC32 with the Castagnoli polynomial. With the `simd` feature it uses
+    /// the SSE4.2 `crc32` instruction on CPUs that have it.
+    Crc32c,
+    /// XXH64 with a seed of 0, cut down to its low 32 bits.
+    XxHash64,
+}
+
+impl Checksum {
+    pub fn hash(self, bytes: &[u8]) -> u32 {
+        match self {
+            Checksum::Crc32 => crc32fast::hash(bytes),
+            Checksum::Crc32c => crc32c(bytes),
+            Checksum::XxHash64 => xxh64(bytes) as u32,
+        }
+    }
+
+    /// The number the algorithm is stored as on disk.
+    pub fn id(self) -> u8 {
+        match self {
+            Checksum::Crc32 => 0,
+            Checksum::Crc32c => 1,
+            Checksum::XxHash64 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Checksum::Crc32),
+            1 => Some(Checksum::Crc32c),
+            2 => Some(Checksum::XxHash64),
+            _ => None,
+        }
+    }
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("sse4.2") {
+        // safe since the CPU was just checked for SSE4.2
+        return unsafe { crc32c_sse42(bytes) };
+    }
+    crc32c_software(bytes)
+}
+
+fn crc32c_software(bytes: &[u8]) -> u32 {
+    let mut crc = !0;
+    for &byte in bytes {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Works through 8 bytes per instruction, then the rest one at a time.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(bytes: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    let mut words = bytes.chunks_exact(8);
+    let mut crc = !0u64;
+    for word in &mut words {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    !crc
+}
+
+const PRIME64_1: u64 = 0x9E37_79B1_85EB_CA87;
+const PRIME64_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const PRIME64_3: u64 = 0x1656_67B1_9E37_79F9;
+const PRIME64_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const PRIME64_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[0..8].try_into().unwrap())
+}
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh64_round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// XXH64 with a seed of 0, following the reference implementation.
+fn xxh64(bytes: &[u8]) -> u64 {
+    let mut stripes = bytes.chunks_exact(32);
+    let mut h = if bytes.len() >= 32 {
+        let mut acc = [
+            PRIME64_1.wrapping_add(PRIME64_2),
+            PRIME64_2,
+            0,
+            0u64.wrapping_sub(PRIME64_1),
+        ];
+        for stripe in &mut stripes {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = xxh64_round(*acc, read_u64(&stripe[i * 8..]));
+            }
+        }
+        let mut h = acc[0]
+            .rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18));
+        for acc in acc {
+            h = xxh64_merge_round(h, acc);
+        }
+        h
+    } else {
+        PRIME64_5
+    };
+    h = h.wrapping_add(bytes.len() as u64);
+
+    let mut words = stripes.remainder().chunks_exact(8);
+    for word in &mut words {
+        h ^= xxh64_round(0, read_u64(word));
+        h = h
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+    }
+    let mut rest = words.remainder();
+    if rest.len() >= 4 {
+        let word = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as u64;
+        h ^= word.wrapping_mul(PRIME64_1);
+        h = h
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        rest = &rest[4..];
+    }
+    for &byte in rest {
+        h ^= (byte as u64).wrapping_mul(PRIME64_5);
+        h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck_macros::quickcheck;
+
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(Checksum::Crc32.hash(b"123456789"), 0xCBF4_3926);
+        assert_eq!(Checksum::Crc32c.hash(b"123456789"), 0xE306_9283);
+        assert_eq!(Checksum::Crc32c.hash(&[0; 32]), 0x8A91_36AA);
+        assert_eq!(Checksum::Crc32c.hash(&[0xFF; 32]), 0x62A8_AB43);
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+        assert_eq!(xxh64(b"abc"), 0x44BC_2CF5_AD77_0999);
+        assert_eq!(
+            xxh64(b"Nobody inspects the spammish repetition"),
+            0xFBCE_A83C_8A37_8BF1
+        );
+        assert_eq!(Checksum::XxHash64.hash(b"abc"), 0xAD77_0999);
+
+        for checksum in [Checksum::Crc32, Checksum::Crc32c, Checksum::XxHash64] {
+            assert_eq!(Checksum::from_id(checksum.id()), Some(checksum));
+        }
+        assert_eq!(Checksum::from_id(3), None);
+    }
+
+    #[quickcheck]
+    fn fuzz_crc32c_matches_software(bytes: Vec<u8>) -> bool {
+        crc32c(&bytes) == crc32c_software(&bytes)
+    }
+}
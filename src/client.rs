@@ -0,0 +1,355 @@
+//! A client for `db serve`, which sends the REPL's commands one per line and
+//! reads back each response, ending at an empty line.
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    num::NonZeroU32,
+    thread,
+    time::Duration,
+};
+
+use crate::{copy, row::RowVal};
+
+#[derive(Debug)]
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Connection {
+    fn open(addrs: &[SocketAddr]) -> io::Result<Self> {
+        let stream = TcpStream::connect(addrs)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends every command before reading any responses, so they take one
+    /// round trip between them.
+    fn send(&mut self, commands: &[&str]) -> io::Result<Vec<Vec<String>>> {
+        for command in commands {
+            writeln!(self.writer, "{command}")?;
+        }
+        self.writer.flush()?;
+        commands.iter().map(|_| self.response()).collect()
+    }
+
+    /// Sends a `copy` message of `rows`, with the idempotency key `key` if
+    /// there is one, and reads its response.
+    fn copy(&mut self, key: Option<u64>, rows: usize, body: &[u8]) -> io::Result<Vec<String>> {
+        if let Some(key) = key {
+            write!(self.writer, "request {key} ")?;
+        }
+        writeln!(self.writer, "copy {rows} {}", body.len())?;
+        self.writer.write_all(body)?;
+        self.writer.flush()?;
+        self.response()
+    }
+
+    fn response(&mut self) -> io::Result<Vec<String>> {
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                return Ok(lines);
+            }
+            lines.push(line.to_string());
+        }
+    }
+}
+
+/// A connection to a `db serve` server, which reconnects when it's dropped.
+///
+/// Each connection is its own session on the server, so a reconnect loses
+/// any open transaction, variables and undo history. Commands that failed
+/// are sent again on the new connection, though they may have run the first
+/// time. Gets are safe to run twice, but a write run again could undo a
+/// later one. `insert_once` and `copy_once` send a key with the write,
+/// which the server only applies once.
+#[derive(Debug)]
+pub struct Client {
+    addrs: Vec<SocketAddr>,
+    conn: Option<Connection>,
+    /// How many times a command is retried on a new connection before its
+    /// error is returned.
+    pub retries: usize,
+    /// How long to wait before the first retry, doubling after each one.
+    pub retry_delay: Duration,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let conn = Connection::open(&addrs)?;
+        Ok(Self {
+            addrs,
+            conn: Some(conn),
+            retries: 3,
+            retry_delay: Duration::from_millis(100),
+        })
+    }
+
+    /// Runs one command, returning the lines it printed.
+    pub fn execute(&mut self, command: &str) -> io::Result<Vec<String>> {
+        Ok(self.pipeline(&[command])?.remove(0))
+    }
+
+    /// Runs every command in order, sending them all before waiting on any
+    /// response, and returns the lines each one printed.
+    pub fn pipeline(&mut self, commands: &[&str]) -> io::Result<Vec<Vec<String>>> {
+        if let Some(command) = commands.iter().find(|command| command.contains('\n')) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("command {command:?} spans more than one line"),
+            ));
+        }
+        self.with_retries(|conn| conn.send(commands))
+    }
+
+    /// Runs `f` on the connection, reconnecting and running it again if it
+    /// fails, up to `retries` times.
+    fn with_retries<T>(
+        &mut self,
+        mut f: impl FnMut(&mut Connection) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let result = match &mut self.conn {
+                Some(conn) => f(conn),
+                None => Connection::open(&self.addrs).and_then(|conn| f(self.conn.insert(conn))),
+            };
+            match result {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt == self.retries => {
+                    self.conn = None;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.conn = None;
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    pub fn get(&mut self, id: u32) -> io::Result<Option<Vec<RowVal>>> {
+        let lines = self.execute(&format!("get {id}"))?;
+        row(&lines, &format!("{id}: "), id)
+    }
+
+    pub fn insert(&mut self, id: u32, vals: &[RowVal]) -> io::Result<()> {
+        nothing(self.execute(&insert_command(id, vals))?)
+    }
+
+    /// Inserts a row unless a write with the idempotency key `key` was
+    /// applied already, see `DB::apply_once`. Retrying it with the same key
+    /// after an error can't apply it twice.
+    pub fn insert_once(&mut self, key: u64, id: u32, vals: &[RowVal]) -> io::Result<()> {
+        let command = format!("request {key} {}", insert_command(id, vals));
+        nothing(self.execute(&command)?)
+    }
+
+    /// Deletes a row, returning it if it was there.
+    pub fn delete(&mut self, id: u32) -> io::Result<Option<Vec<RowVal>>> {
+        let lines = self.execute(&format!("delete {id}"))?;
+        row(&lines, &format!("Removing {id}: "), id)
+    }
+
+    /// Inserts every row as one batch with a binary `copy` message, which
+    /// the server checks against the table's schema. If any row is rejected,
+    /// none are inserted.
+    pub fn copy(&mut self, rows: &[(NonZeroU32, Vec<RowVal>)]) -> io::Result<()> {
+        self.copy_with(None, rows)
+    }
+
+    /// Like `copy`, but the rows are only inserted if no write with the
+    /// idempotency key `key` was applied already.
+    pub fn copy_once(&mut self, key: u64, rows: &[(NonZeroU32, Vec<RowVal>)]) -> io::Result<()> {
+        self.copy_with(Some(key), rows)
+    }
+
+    fn copy_with(
+        &mut self,
+        key: Option<u64>,
+        rows: &[(NonZeroU32, Vec<RowVal>)],
+    ) -> io::Result<()> {
+        let body = copy::encode(rows);
+        if body.len() > copy::MAX_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes of rows is more than one copy takes", body.len()),
+            ));
+        }
+        nothing(self.with_retries(|conn| conn.copy(key, rows.len(), &body))?)
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        nothing(self.execute("sync")?)
+    }
+}
+
+fn insert_command(id: u32, vals: &[RowVal]) -> String {
+    let mut command = format!("insert {id}");
+    for val in vals {
+        command.push_str(&format!(", {val}"));
+    }
+    command
+}
+
+/// Anything a command that prints nothing on success printed is an error.
+fn nothing(lines: Vec<String>) -> io::Result<()> {
+    if lines.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::other(lines.join("\n")))
+    }
+}
+
+/// Reads the row printed after `prefix`, or `None` if the key wasn't found.
+fn row(lines: &[String], prefix: &str, id: u32) -> io::Result<Option<Vec<RowVal>>> {
+    let vals = match lines {
+        [line] if *line == format!("Key {id} not found.") => return Ok(None),
+        [line] => line
+            .strip_prefix(prefix)
+            .and_then(|vals| vals.strip_prefix('['))
+            .and_then(|vals| vals.strip_suffix(']')),
+        _ => None,
+    };
+    let vals = vals.ok_or_else(|| io::Error::other(lines.join("\n")))?;
+    vals.split(", ")
+        .filter(|val| !val.is_empty())
+        .map(parse_val)
+        .collect::<Option<_>>()
+        .map(Some)
+        .ok_or_else(|| io::Error::other(format!("could not parse row {vals}")))
+}
+
+/// Parses a value the way the REPL prints it.
+fn parse_val(val: &str) -> Option<RowVal> {
+    if let Some(bytes) = val.strip_prefix('"') {
+        return Some(RowVal::Bytes(bytes.strip_suffix('"')?.into()));
+    }
+    match val {
+        "true" => Some(RowVal::Bool(true)),
+        "false" => Some(RowVal::Bool(false)),
+        _ => val.parse().ok().map(RowVal::U32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::row::RowType;
+
+    use super::*;
+
+    /// Answers every line with the next response in `responses`, hanging up
+    /// after `per_connection` of them.
+    fn server(responses: Vec<&'static str>, per_connection: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut responses = responses.into_iter();
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut out = stream.try_clone().unwrap();
+                let lines = BufReader::new(stream).lines().take(per_connection);
+                for (_, response) in lines.zip(&mut responses) {
+                    writeln!(out, "{response}").unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn typed_commands() {
+        let addr = server(
+            vec![
+                "",
+                "1: [\"a b\", 3, true]\n",
+                "Key 2 not found.\n",
+                "Removing 1: [\"a b\", 3, true]\n",
+                "Table rejects value, rejecting insert.\n",
+                "",
+                "line 1\nline 2\n",
+            ],
+            usize::MAX,
+        );
+        let mut client = Client::connect(addr).unwrap();
+        let row = vec![
+            RowVal::Bytes(b"a b".to_vec()),
+            RowVal::U32(3),
+            RowVal::Bool(true),
+        ];
+        client.insert(1, &row).unwrap();
+        assert_eq!(client.get(1).unwrap(), Some(row.clone()));
+        assert_eq!(client.get(2).unwrap(), None);
+        assert_eq!(client.delete(1).unwrap(), Some(row));
+        assert!(client.insert(1, &[]).is_err());
+        assert_eq!(
+            client.pipeline(&["sync", "show"]).unwrap(),
+            vec![vec![], vec!["line 1".to_string(), "line 2".to_string()]]
+        );
+        assert!(client.execute("get 1\nget 2").is_err());
+    }
+
+    #[test]
+    fn reconnects() {
+        let addr = server(vec!["1\n", "2\n", "3\n"], 1);
+        let mut client = Client::connect(addr).unwrap();
+        client.retry_delay = Duration::from_millis(1);
+        for expected in ["1", "2", "3"] {
+            assert_eq!(client.execute("show").unwrap(), vec![expected]);
+        }
+        client.retries = 0;
+        assert!(client.execute("show").is_err());
+    }
+
+    #[test]
+    fn copy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut out = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut copies = vec![];
+            for _ in 0..2 {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let (key, args) = header.trim().split_once("copy ").unwrap();
+                let args: Vec<usize> = args.split(' ').map(|arg| arg.parse().unwrap()).collect();
+                let mut body = vec![0; args[1]];
+                io::Read::read_exact(&mut reader, &mut body).unwrap();
+                writeln!(out).unwrap();
+                let rows = copy::decode(&body, args[0], &[RowType::Id, RowType::U32]).unwrap();
+                copies.push((key.to_string(), rows));
+            }
+            copies
+        });
+        let mut client = Client::connect(addr).unwrap();
+        let rows: Vec<_> = (1..=100)
+            .map(|i| (NonZeroU32::new(i).unwrap(), vec![RowVal::U32(i * 2)]))
+            .collect();
+        client.copy(&rows).unwrap();
+        client.copy_once(9, &rows).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                (String::new(), rows.clone()),
+                ("request 9 ".to_string(), rows)
+            ]
+        );
+    }
+}
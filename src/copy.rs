@@ -0,0 +1,122 @@
+//! The binary bulk-insert message `db serve` takes, for loading many rows
+//! without a round trip or a parse each, like Postgres's `COPY`. A client
+//! sends a line `copy $rows $bytes`, then `$bytes` bytes holding `$rows`
+//! rows, each a little-endian u32 length and then the row's id and values
+//! encoded as they're stored on pages. The server checks every row against
+//! the table's schema and inserts them all as one WAL batch, or none of them,
+//! answering like any other command.
+
+use std::num::NonZeroU32;
+
+use crate::row::{values_to_bytes, RowType, RowVal};
+
+/// The most bytes one message can hold, so a bad length can't make the
+/// server allocate without bound.
+pub const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// The body of a message holding `rows`.
+pub fn encode(rows: &[(NonZeroU32, Vec<RowVal>)]) -> Vec<u8> {
+    let mut res = vec![];
+    for (id, vals) in rows {
+        let row = [&[RowVal::Id(*id)], &vals[..]].concat();
+        let bytes = values_to_bytes(&row);
+        res.extend((bytes.len() as u32).to_le_bytes());
+        res.extend(bytes);
+    }
+    res
+}
+
+/// The `count` rows of a message's body, read as rows of `schema`, or why
+/// they can't be.
+pub fn decode(
+    bytes: &[u8],
+    count: usize,
+    schema: &[RowType],
+) -> Result<Vec<(NonZeroU32, Vec<RowVal>)>, String> {
+    let mut rows = vec![];
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let len = rest
+            .get(..4)
+            .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+            .ok_or_else(|| format!("Row {} is cut off", rows.len() + 1))?;
+        let row = rest
+            .get(4..4 + len)
+            .ok_or_else(|| format!("Row {} is cut off", rows.len() + 1))?;
+        let row = decode_row(row, schema)
+            .ok_or_else(|| format!("Row {} doesn't fit the table", rows.len() + 1))?;
+        rows.push(row);
+        rest = &rest[4 + len..];
+    }
+    if rows.len() != count {
+        return Err(format!("Expected {count} rows, not {}", rows.len()));
+    }
+    Ok(rows)
+}
+
+/// A row encoded as every value of `schema`, with nothing left over.
+fn decode_row(mut bytes: &[u8], schema: &[RowType]) -> Option<(NonZeroU32, Vec<RowVal>)> {
+    let mut vals = vec![];
+    for &row_type in schema {
+        let len = match row_type {
+            RowType::Id | RowType::U32 | RowType::Ttl => 4,
+            RowType::Bool => 1,
+            RowType::Bytes => {
+                let len = bytes.get(..2)?.try_into().unwrap();
+                2 + u16::from_le_bytes(len) as usize
+            }
+        };
+        let val = bytes.get(..len)?;
+        vals.push(match row_type {
+            RowType::Id => RowVal::Id(NonZeroU32::new(u32::from_le_bytes(
+                val.try_into().unwrap(),
+            ))?),
+            RowType::Bool if val[0] > 1 => return None,
+            row_type => RowVal::from_bytes(val, row_type),
+        });
+        bytes = &bytes[len..];
+    }
+    if !bytes.is_empty() {
+        return None;
+    }
+    match vals.first() {
+        Some(&RowVal::Id(id)) => Some((id, vals.split_off(1))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let schema = &[RowType::Id, RowType::Bytes, RowType::U32, RowType::Bool];
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let rows: Vec<_> = (1..=3)
+            .map(|i| {
+                let name = RowVal::Bytes(format!("row {i}").into());
+                (id(i), vec![name, RowVal::U32(i), RowVal::Bool(i % 2 == 0)])
+            })
+            .collect();
+        let bytes = encode(&rows);
+        assert_eq!(decode(&bytes, 3, schema), Ok(rows.clone()));
+
+        assert_eq!(
+            decode(&bytes, 4, schema),
+            Err("Expected 4 rows, not 3".to_string())
+        );
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1], 3, schema),
+            Err("Row 3 is cut off".to_string())
+        );
+        // rows of another table don't decode as this one's
+        assert_eq!(
+            decode(&bytes, 3, &[RowType::Id, RowType::Bytes, RowType::U32]),
+            Err("Row 1 doesn't fit the table".to_string())
+        );
+        let zero = encode(&[(id(1), vec![RowVal::U32(0)])]);
+        let zero = [&zero[..4], &[0; 4], &zero[8..]].concat();
+        assert!(decode(&zero, 1, &[RowType::Id, RowType::U32]).is_err());
+    }
+}
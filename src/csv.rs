@@ -0,0 +1,298 @@
+//! Importing CSV files. The first record is a header naming the columns its
+//! fields go to, in any order; columns it leaves out get their defaults.
+//! Fields are read as the type of their column, and a row that can't be, or
+//! that the table rejects, is reported by its line and skipped, while the
+//! rest are inserted a batch at a time.
+
+use std::{
+    io::{self, BufRead},
+    num::NonZeroU32,
+};
+
+use crate::{
+    db::DB,
+    dump::column_name,
+    row::{RowType, RowVal},
+    schema::type_name,
+};
+
+/// How many rows are inserted with each `bulk_insert`.
+pub const BATCH_ROWS: usize = 1000;
+
+/// What an import did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CsvImport {
+    /// How many rows were inserted.
+    pub inserted: u64,
+    /// The line each rejected row starts on, and why it was rejected.
+    pub rejected: Vec<(u64, String)>,
+}
+
+/// Reads records from a CSV file as RFC 4180 writes them: fields separated
+/// by commas, and quoted if they hold commas, quotes (doubled) or newlines.
+pub struct Reader<R> {
+    reader: R,
+    /// The number of the last line read.
+    pub line: u64,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, line: 0 }
+    }
+
+    /// The next record's fields, or `None` at the end of the file. A quoted
+    /// field runs on over as many lines as it takes to close.
+    pub fn record(&mut self) -> io::Result<Option<Vec<String>>> {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut buf = String::new();
+        loop {
+            buf.clear();
+            if self.reader.read_line(&mut buf)? == 0 {
+                if in_quotes {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unterminated quote on line {}", self.line),
+                    ));
+                }
+                return Ok(None);
+            }
+            self.line += 1;
+            let mut chars = buf.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if in_quotes && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' => in_quotes = !in_quotes,
+                    ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+                    '\r' | '\n' if !in_quotes => {}
+                    c => field.push(c),
+                }
+            }
+            if !in_quotes {
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+        }
+    }
+}
+
+/// Imports the CSV file read from `reader` into `db`, see the module docs.
+/// Fails if the header doesn't name the id, names a column the table doesn't
+/// have, or leaves out one without a default.
+pub fn import(db: &mut DB, reader: impl BufRead) -> io::Result<CsvImport> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let mut reader = Reader::new(reader);
+    let header = reader
+        .record()?
+        .ok_or_else(|| invalid("the file is empty".to_string()))?;
+    let schema = &db.schema;
+    let names: Vec<_> = (0..schema.schema.len())
+        .map(|i| column_name(schema, i))
+        .collect();
+    // the field each column is read from
+    let mut fields = vec![None; names.len()];
+    for (i, name) in header.iter().enumerate() {
+        let column = names
+            .iter()
+            .position(|n| n == name.trim())
+            .ok_or_else(|| invalid(format!("there's no column {}", name.trim())))?;
+        fields[column] = Some(i);
+    }
+    if fields[0].is_none() {
+        return Err(invalid(format!("the header has no {} column", names[0])));
+    }
+    if let Some(column) =
+        (1..names.len()).find(|&i| fields[i].is_none() && schema.defaults[i].is_none())
+    {
+        return Err(invalid(format!(
+            "{} has no default, so the header needs it",
+            names[column]
+        )));
+    }
+
+    let mut res = CsvImport::default();
+    let mut batch = vec![];
+    let mut lines = vec![];
+    loop {
+        let start = reader.line + 1;
+        let record = reader.record()?;
+        if let Some(record) = &record {
+            match row(db, &fields, &names, record) {
+                Ok((id, vals)) => {
+                    batch.push((id, vals));
+                    lines.push(start);
+                }
+                Err(reason) => res.rejected.push((start, reason)),
+            }
+        }
+        if batch.len() == BATCH_ROWS || (record.is_none() && !batch.is_empty()) {
+            insert(db, &mut batch, &mut lines, &mut res);
+        }
+        if record.is_none() {
+            break;
+        }
+    }
+    Ok(res)
+}
+
+/// Inserts a batch of rows at once, or one at a time if the batch is
+/// rejected, so only the rows that are get reported.
+fn insert(
+    db: &mut DB,
+    batch: &mut Vec<(NonZeroU32, Vec<RowVal>)>,
+    lines: &mut Vec<u64>,
+    res: &mut CsvImport,
+) {
+    if db.bulk_insert(batch).is_ok() {
+        res.inserted += batch.len() as u64;
+    } else {
+        for ((id, vals), line) in batch.iter().zip(lines.iter()) {
+            match db.insert(*id, vals) {
+                Ok(()) => res.inserted += 1,
+                Err(e) => res.rejected.push((*line, e.to_string())),
+            }
+        }
+    }
+    batch.clear();
+    lines.clear();
+}
+
+/// The row a record holds, or why it can't be inserted.
+fn row(
+    db: &DB,
+    fields: &[Option<usize>],
+    names: &[String],
+    record: &[String],
+) -> Result<(NonZeroU32, Vec<RowVal>), String> {
+    if record.len() != fields.iter().flatten().count() {
+        return Err(format!(
+            "expected {} fields, not {}",
+            fields.iter().flatten().count(),
+            record.len()
+        ));
+    }
+    let schema = &db.schema;
+    let mut vals = vec![];
+    for (column, field) in fields.iter().enumerate() {
+        let val = match field.map(|i| record[i].as_str()) {
+            Some(field) => coerce(field, schema.schema[column], &schema.defaults[column]),
+            None => schema.defaults[column].clone(),
+        };
+        let val = val.ok_or_else(|| {
+            format!(
+                "{} isn't a {} for {}",
+                field.map_or("", |i| record[i].as_str()),
+                type_name(schema.schema[column]),
+                names[column]
+            )
+        })?;
+        vals.push(val);
+    }
+    let RowVal::Id(id) = vals.remove(0) else {
+        unreachable!("the first column is the id")
+    };
+    db.validate(id, &vals).map_err(|e| e.to_string())?;
+    Ok((id, vals))
+}
+
+/// Reads a field as `row_type`. An empty field is the column's default,
+/// except in a `Bytes` column, where it's an empty value.
+pub fn coerce(field: &str, row_type: RowType, default: &Option<RowVal>) -> Option<RowVal> {
+    let trimmed = field.trim();
+    if trimmed.is_empty() && row_type != RowType::Bytes {
+        return default.clone();
+    }
+    match row_type {
+        RowType::Id => trimmed.parse().ok().map(RowVal::Id),
+        RowType::U32 | RowType::Ttl => trimmed.parse().ok().map(RowVal::U32),
+        RowType::Bytes => Some(RowVal::Bytes(field.as_bytes().to_vec())),
+        RowType::Bool => match trimmed.to_ascii_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "1" => Some(RowVal::Bool(true)),
+            "false" | "f" | "no" | "n" | "0" => Some(RowVal::Bool(false)),
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::schema;
+
+    use super::*;
+
+    #[test]
+    fn records() {
+        let csv = "a,\"b, \"\"c\"\"\",\r\n\"multi\nline\",2,\n";
+        let mut reader = Reader::new(csv.as_bytes());
+        assert_eq!(reader.record().unwrap().unwrap(), ["a", "b, \"c\"", ""]);
+        assert_eq!(reader.record().unwrap().unwrap(), ["multi\nline", "2", ""]);
+        assert_eq!(reader.line, 3);
+        assert_eq!(reader.record().unwrap(), None);
+        assert!(Reader::new("\"open\n".as_bytes()).record().is_err());
+    }
+
+    #[test]
+    fn import_csv() {
+        let file_name = "tests/import_csv";
+        let _ = fs::remove_dir_all(file_name);
+        let columns =
+            schema::parse(r#"id, name:text, count:u32 = 0 max 100, active:bool = true"#).unwrap();
+        let mut db = DB::from_columns(file_name, &columns).unwrap();
+        let id = |i| NonZeroU32::new(i).unwrap();
+
+        // columns in any order, and left out if they have defaults
+        let csv = "count,id,name\n\
+                   5,1,one\n\
+                   ,2,\"two, \"\"2\"\"\"\n\
+                   x,3,three\n\
+                   500,4,four\n\
+                   1,0,zero\n\
+                   1,5\n\
+                   7,6,six\n";
+        let report = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(report.inserted, 3);
+        let lines: Vec<_> = report.rejected.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [4, 5, 6, 7]);
+        assert_eq!(report.rejected[0].1, "x isn't a u32 for count");
+        assert_eq!(
+            db.get(id(2)),
+            Some(vec![
+                RowVal::Bytes(b"two, \"2\"".to_vec()),
+                RowVal::U32(0),
+                RowVal::Bool(true)
+            ])
+        );
+        assert_eq!(db.scan(..).len(), 3);
+
+        let mut err = |csv: &str| db.import_csv(csv.as_bytes()).unwrap_err().to_string();
+        assert_eq!(err("id,size\n"), "there's no column size");
+        assert_eq!(
+            err("id,count\n"),
+            "name has no default, so the header needs it"
+        );
+        assert_eq!(err("name\n"), "the header has no id column");
+
+        // more rows than fit in a batch
+        let mut csv = "id,name,active\n".to_string();
+        for i in 1..=2500 {
+            csv.push_str(&format!("{i},row {i},{}\n", ["no", "yes"][i % 2]));
+        }
+        let report = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!((report.inserted, report.rejected.len()), (2500, 0));
+        assert_eq!(
+            db.get(id(2)),
+            Some(vec![
+                RowVal::Bytes(b"row 2".to_vec()),
+                RowVal::U32(0),
+                RowVal::Bool(false)
+            ])
+        );
+    }
+}
@@ -1,361 +1,4676 @@
 use std::{
-    collections::BTreeMap,
-    fs::{File, OpenOptions},
-    io::{BufWriter, Seek as _, SeekFrom, Write as _},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufRead, Write},
     num::NonZeroU32,
+    ops::RangeBounds,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    row::{RowType, RowVal, Schema},
-    wal::WAL,
+    backup::{self, Backup},
+    btree,
+    checksum::Checksum,
+    csv::{self, CsvImport},
+    direct,
+    double_write::{self, PageWrite},
+    dump,
+    error::DbError,
+    failpoint::{self, DbFile},
+    fsync::FsyncScheduler,
+    header::{FileHeader, HEADER_SIZE},
+    history::History,
+    latency::Latencies,
+    lsm::{self, Compaction, Engine, Entry, Lsm, Task},
+    manifest,
+    mmap::MappedPages,
+    options::DbOptions,
+    pages::{PageId, Pages},
+    requests::Requests,
+    row::{
+        row_size, stored_row_size, verify_row, RowType, RowVal, Schema, SchemaChange, MAX_BYTES_LEN,
+    },
+    schema::Column,
+    snapshot::{Snapshot, SnapshotPage},
+    stats::{self, TableStats},
+    transaction::{Txn, WriteBatch},
+    utils::unix_now,
+    wal::{Commit, GroupCommit, GroupCommitter, WALRecord, DEFAULT_SEGMENT_SIZE, WAL},
 };
 
-use crate::page::{Page, PageHeader, PAGE_SIZE};
-use indexset::{BTreeSet, Range};
+use crate::page::{Layout, Page, PageHeader, SplitPolicy, MAX_ROW_SIZE, PAGE_SIZE};
+
+/// Drops rows older than `max_age`, judged by a `U32` column holding a unix
+/// timestamp in seconds. Only whole pages are dropped, so an expired row may
+/// linger until every row on its page has expired too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub column: usize,
+    pub max_age: Duration,
+}
+
+/// When writes are forced to disk with `fsync`, trading speed for crash
+/// safety.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Never fsync. The OS flushes writes when it gets to them, so a power
+    /// loss can drop anything written recently.
+    #[default]
+    Off,
+    /// Fsync the WAL after every write, so every committed write survives a
+    /// power loss. A write whose fsync fails returns the error, since it
+    /// may not. With `DB::set_group_commit`, writes made at about
+    /// the same time share one. Like every level but `Off`, pages go through the
+    /// double-write buffer and are fsynced whenever they're written, so a
+    /// page torn by a power loss is redone on the next open.
+    WalPerCommit,
+    /// Like `WalPerCommit`, but the WAL is fsynced again after `sync`
+    /// empties it.
+    Full,
+    /// Fsync the WAL at most once per interval, so writes in quick
+    /// succession share one fsync. A power loss can drop the writes of the
+    /// last interval. Pages are still fsynced whenever they're written.
+    Batched(Duration),
+}
+
+pub type CheckFn = Box<dyn Fn(&[RowVal]) -> bool + Send + Sync>;
+
+/// A validation rule run against every row before it's written. The row
+/// passed in starts with its id.
+pub struct Check {
+    pub description: String,
+    pub check: CheckFn,
+}
+
+impl std::fmt::Debug for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Check")
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+/// Called before a row is written with its id, its current value if it has
+/// one and the value replacing it. Returning an error vetoes the write.
+pub type InsertHook =
+    Box<dyn Fn(NonZeroU32, Option<&[RowVal]>, &[RowVal]) -> Result<(), String> + Send + Sync>;
+
+/// Called before a row is deleted with its id and value. Returning an error
+/// vetoes the delete.
+pub type DeleteHook = Box<dyn Fn(NonZeroU32, &[RowVal]) -> Result<(), String> + Send + Sync>;
+
+/// Combines a row's current value, or `None` if it has none, with an operand
+/// passed to `DB::merge`, giving the row's new value. Like adding to a
+/// counter, it should be associative, so merges give the same row however
+/// they're grouped.
+pub type MergeFn = dyn Fn(Option<&[RowVal]>, &[RowVal]) -> Vec<RowVal> + Send + Sync;
+
+/// A `MergeFn` shared between the `DB` and the options it was opened with.
+#[derive(Clone)]
+pub struct MergeOperator(Arc<MergeFn>);
+
+impl MergeOperator {
+    pub fn new(
+        merge: impl Fn(Option<&[RowVal]>, &[RowVal]) -> Vec<RowVal> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(merge))
+    }
+
+    pub fn apply(&self, current: Option<&[RowVal]>, operand: &[RowVal]) -> Vec<RowVal> {
+        (self.0)(current, operand)
+    }
+}
+
+impl std::fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+/// The callbacks registered with `DB::on_insert` and `DB::on_delete`, run in
+/// the order they were added.
+#[derive(Default)]
+pub struct Hooks {
+    pub on_insert: Vec<InsertHook>,
+    pub on_delete: Vec<DeleteHook>,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_insert", &self.on_insert.len())
+            .field("on_delete", &self.on_delete.len())
+            .finish()
+    }
+}
+
+impl Hooks {
+    fn before_insert(
+        &self,
+        id: NonZeroU32,
+        before: Option<&[RowVal]>,
+        after: &[RowVal],
+    ) -> Result<(), DbError> {
+        for hook in &self.on_insert {
+            hook(id, before, after).map_err(DbError::Vetoed)?;
+        }
+        Ok(())
+    }
+
+    fn before_delete(&self, id: NonZeroU32, row: &[RowVal]) -> Result<(), DbError> {
+        for hook in &self.on_delete {
+            hook(id, row).map_err(DbError::Vetoed)?;
+        }
+        Ok(())
+    }
+}
+
+/// A summary of one page's header and where it lives, for inspecting the
+/// physical layout without going through `DB::pages`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageHeaderInfo {
+    pub start: Option<NonZeroU32>,
+    pub end: Option<NonZeroU32>,
+    pub count: u32,
+    /// The encoded size of the page, header included.
+    pub size: usize,
+    /// `size` as a fraction of `PAGE_SIZE`.
+    pub fill: f64,
+    pub dirty: bool,
+    /// The byte offset of the page in the `.db` file, or `None` if it hasn't
+    /// been written yet.
+    pub offset: Option<usize>,
+}
+
+/// Approximate memory use in bytes, going by the encoded size of what's held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub pages: usize,
+    pub wal: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.pages + self.wal
+    }
+}
+
+/// A write streamed to the receivers of `DB::subscribe` once it's committed
+/// to the WAL, with the LSN it was logged at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub lsn: u64,
+    pub record: WALRecord,
+}
+
+/// The thread started by `DB::start_background_tasks`. Dropping this stops
+/// it, waiting for a sync that's under way to finish.
+#[derive(Debug)]
+pub struct BackgroundTasks {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        // hanging up wakes the thread straight away instead of after its
+        // next interval
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// The pages changed since the last `sync`, so it can write and mark clean
+/// only those instead of going through every page.
+#[derive(Debug, Default)]
+struct DirtyPages {
+    /// The ids of the changed pages. An id can outlive its page.
+    ids: BTreeSet<PageId>,
+    /// Whether every page has to be gone through anyway, because pages were
+    /// added or removed, which moves the ones after them in the file, or
+    /// changed in bulk.
+    rescan: bool,
+}
+
+impl DirtyPages {
+    fn rescan() -> Self {
+        Self {
+            rescan: true,
+            ..Self::default()
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct DB {
-    pub pages: BTreeSet<(Page, Option<usize>)>,
-    pub file: File,
+    pub pages: Pages,
+    /// The name the database was created with, which is the directory its
+    /// files are kept in, see `manifest`.
+    pub file_name: String,
+    pub file: DbFile,
     pub wal: WAL,
+    /// Bumped by every `checkpoint`.
     pub epoch: u64,
     pub schema: Schema,
+    pub checks: Vec<Check>,
+    pub hooks: Hooks,
+    /// What `merge` combines rows with. A WAL holding merges can only be
+    /// replayed with it, so it's passed to `DbOptions::merge_operator`.
+    pub merge_operator: Option<MergeOperator>,
+    pub latencies: Latencies,
+    pub memory_limit: Option<usize>,
+    /// How many rows the WAL can hold before an insert syncs it, see
+    /// `set_wal_record_limit`.
+    pub wal_record_limit: Option<usize>,
+    /// How many bytes of rows the WAL can hold before inserts are refused,
+    /// see `set_stall_limit`.
+    pub stall_limit: Option<usize>,
+    pub durability: Durability,
+    /// Tracks the fsyncs put off by `Durability::Batched`.
+    pub fsyncs: FsyncScheduler,
+    /// Fsyncs the WAL for `WalPerCommit` and `Full` writes when set, see
+    /// `set_group_commit`.
+    pub group_commit: Option<GroupCommitter>,
+    /// The group commits `without_waiting` is putting off while it runs.
+    deferred_commits: Option<Vec<Commit>>,
+    /// Whether the database was opened with `DbOptions::read_only`, taking
+    /// a lock other readers can share. Writes are refused with
+    /// `DbError::ReadOnly`, and nothing is written back when it's dropped.
+    pub read_only: bool,
+    /// While the `.db` file is at most this many bytes, it's written by
+    /// rewriting all of it and renaming it into place instead of writing
+    /// pages in place, so it's replaced as a whole or not at all.
+    pub rewrite_limit: Option<usize>,
+    /// Whether pages are written with direct I/O, bypassing the OS page
+    /// cache. Where the filesystem doesn't support it, or pages aren't
+    /// 4KB, they're written through the cache as usual.
+    pub direct_io: bool,
+    /// When pages split as rows are added to them, and where. Changed with
+    /// `set_split_policy`.
+    pub split_policy: SplitPolicy,
+    /// The values recent writes replaced, for `get_as_of`. Off until its
+    /// retention is set.
+    pub history: History,
+    /// The idempotency keys of recent `apply_once` writes.
+    pub requests: Requests,
+    /// Where committed writes are streamed, see `subscribe`.
+    pub subscribers: Vec<Sender<ChangeEvent>>,
+    /// What the WAL and double-write buffer are checksummed with. Changed
+    /// with `set_checksum`.
+    pub checksum: Checksum,
+    /// How the rows on each page are laid out. Changed with `set_layout`.
+    pub layout: Layout,
+    /// Whether leaves are written with bloom filters over their ids, which
+    /// `get` on a mapped database checks before reading a page in. Only
+    /// pages written after it's set get one.
+    pub bloom_filters: bool,
+    /// What the rows are stored with. Changed with `set_engine`.
+    pub engine: Engine,
+    /// The sorted runs and memtable deletes of the LSM engine, which are
+    /// empty with the other one.
+    pub lsm: Lsm,
+    /// Statistics about each column's values, gathered by `analyze` and
+    /// again on every `sync` after that. `None` until the database is
+    /// analyzed.
+    pub stats: Option<TableStats>,
+    /// Shared with every `Snapshot` taken, so while the count is above one
+    /// the `.db` file is only ever replaced, never written in place.
+    pub snapshots: Arc<()>,
+    /// How many transactions `begin` has started since the database was
+    /// opened, which numbers the next one.
+    pub txns: u32,
+    /// The pages of a database opened with `open_mapped`, which are read
+    /// from the mapped `.db` file through a buffer pool. `pages` stays empty
+    /// unless something needs every page read in, see `sync_mapped`.
+    mapped: Option<MappedPages>,
+    dirty: DirtyPages,
+    /// How many bytes have been written to `.db` files since the database
+    /// was opened, which counts what compacting into the pages writes.
+    written: AtomicU64,
+    /// The `LOCK` file, locked for as long as the database is open.
+    _lock: File,
 }
 
-impl DB {
-    pub fn new(file_name: &str, schema: &[RowType]) -> Self {
-        let epoch = 1;
-        let (db_file, wal_file, schema_file) = Self::setup_files(file_name, epoch);
-        Self {
-            file: db_file,
-            pages: BTreeSet::new(),
-            wal: WAL {
-                file: wal_file,
-                records: BTreeMap::new(),
-            },
-            epoch,
-            schema: Schema {
-                schema: schema.to_vec(),
-                file: schema_file,
-            },
+impl DB {
+    /// Creates a database under `file_name`, replacing any already there.
+    /// Fails if another process has it open, or its files can't be written.
+    pub fn new(file_name: &str, schema: &[RowType]) -> Result<Self, DbError> {
+        Self::new_with_pages(Pages::new(), file_name, schema)
+    }
+
+    pub fn new_with_pages(
+        pages: Pages,
+        file_name: &str,
+        schema: &[RowType],
+    ) -> Result<Self, DbError> {
+        let schema = Schema::new(schema);
+        schema.check_size()?;
+        fs::create_dir_all(file_name)?;
+        let lock = lock(file_name, false)?;
+        // a new database replaces every epoch of an old one with the same
+        // name, which stops being a database the moment its manifest goes
+        manifest::remove(file_name);
+        manifest::remove_stale(file_name, None);
+        stats::remove(file_name);
+        Lsm::remove(file_name);
+        let epoch = 1;
+        let (db_file, wal) = Self::setup_files(file_name, epoch)?;
+
+        let mut db = Self {
+            file_name: file_name.to_string(),
+            file: db_file,
+            pages,
+            wal,
+            epoch,
+            checks: vec![],
+            hooks: Hooks::default(),
+            merge_operator: None,
+            latencies: Latencies::default(),
+            memory_limit: None,
+            wal_record_limit: None,
+            stall_limit: None,
+            durability: Durability::default(),
+            fsyncs: FsyncScheduler::default(),
+            group_commit: None,
+            deferred_commits: None,
+            read_only: false,
+            rewrite_limit: None,
+            direct_io: false,
+            split_policy: SplitPolicy::default(),
+            history: History::new(0),
+            requests: Requests::default(),
+            subscribers: vec![],
+            checksum: Checksum::default(),
+            layout: Layout::default(),
+            bloom_filters: false,
+            engine: Engine::default(),
+            lsm: Lsm::default(),
+            stats: None,
+            snapshots: Arc::new(()),
+            txns: 0,
+            schema,
+            mapped: None,
+            dirty: DirtyPages::rescan(),
+            written: AtomicU64::new(0),
+            _lock: lock,
+        };
+        // write the header straight away so the file is readable even if we
+        // crash before the first sync
+        db.serialize();
+        manifest::write(file_name, epoch)?;
+        Ok(db)
+    }
+
+    /// Creates a database from columns parsed with `schema::parse`, keeping
+    /// their names, defaults and constraints.
+    pub fn from_columns(file_name: &str, columns: &[Column]) -> Result<Self, DbError> {
+        let schema: Vec<_> = columns.iter().map(|column| column.row_type).collect();
+        let mut named = Schema::new(&schema);
+        for (i, column) in columns.iter().enumerate() {
+            named.names[i] = column.name.clone();
+            named.defaults[i] = column.default.clone();
+            named.constraints[i] = column.constraints.clone();
+        }
+        named.check_size()?;
+        let mut db = Self::new(file_name, &schema)?;
+        db.schema = named;
+        db.serialize();
+        Ok(db)
+    }
+
+    /// Options to open or create a database with, like
+    /// `DB::options().create(true).schema(schema).open(file_name)`.
+    pub fn options() -> DbOptions {
+        DbOptions::default()
+    }
+
+    /// Opens a database written by an earlier run, replaying whatever is left
+    /// in its WAL. Returns `None` if there is no database under `file_name`, and
+    /// an error if the file isn't a database this build can read.
+    pub fn open(file_name: &str) -> Result<Option<Self>, DbError> {
+        Self::open_with(file_name, false, false, None)
+    }
+
+    /// Like `open`, but the `.db` file is mapped into memory instead of read
+    /// whole, and pages are read from it on demand into a buffer pool that
+    /// holds at most `set_pool_capacity` of them, so the database can be
+    /// larger than memory. A `sync` writes back only the pages that changed.
+    pub fn open_mapped(file_name: &str) -> Result<Option<Self>, DbError> {
+        Self::open_with(file_name, true, false, None)
+    }
+
+    /// Opens the database under `file_name`, mapped if `mmap` is set, with
+    /// `merge_operator` to replay any merges in its WAL. A `read_only` open
+    /// shares its lock with other read-only ones and changes no files: its
+    /// WAL is replayed into memory only, and it fails with
+    /// `DbError::NeedsRecovery` if a crash left page writes to redo.
+    pub(crate) fn open_with(
+        file_name: &str,
+        mmap: bool,
+        read_only: bool,
+        merge_operator: Option<MergeOperator>,
+    ) -> Result<Option<Self>, DbError> {
+        if !read_only {
+            manifest::migrate(file_name)?;
+        }
+        if !Path::new(file_name).is_dir() {
+            return Ok(None);
+        }
+        let lock = lock(file_name, read_only)?;
+        let Some(epoch) = manifest::read(file_name) else {
+            return Ok(None);
+        };
+        let db_file_name = manifest::db_file(file_name, epoch);
+        let wal_file_name = manifest::wal_file(file_name, epoch);
+        let buffer_file_name = manifest::buffer_file(file_name, epoch);
+        if read_only {
+            if double_write::read(&buffer_file_name).is_some() {
+                return Err(DbError::NeedsRecovery(file_name.to_string()));
+            }
+        } else {
+            // anything else was left behind by a checkpoint or rewrite that
+            // crashed before it could clean up
+            manifest::remove_stale(file_name, Some(epoch));
+            // finish any page writes a crash cut short
+            double_write::recover(&buffer_file_name, &db_file_name).map_err(|e| {
+                DbError::Io(format!(
+                    "recovering page writes from {buffer_file_name}: {e}"
+                ))
+            })?;
+        }
+
+        let mapped = mmap
+            .then(|| MappedPages::new(&File::open(&db_file_name)?))
+            .transpose()?;
+        let bytes = match &mapped {
+            Some(mapped) => Cow::Borrowed(mapped.bytes()),
+            None => Cow::Owned(fs::read(&db_file_name)?),
+        };
+        let FileHeader {
+            schema,
+            checksum,
+            layout,
+            bloom_filters,
+            engine,
+            ..
+        } = FileHeader::from_bytes(&bytes)?;
+        if !(bytes.len() - HEADER_SIZE).is_multiple_of(PAGE_SIZE) {
+            return Err(DbError::IncompatibleFile(
+                "file does not hold a whole number of pages".to_string(),
+            ));
+        }
+        let pages = match mapped {
+            Some(_) => Pages::new(),
+            None => deserialize(&bytes, &schema),
+        };
+        drop(bytes);
+
+        let db_file = failpoint::open(
+            OpenOptions::new().read(true).write(!read_only),
+            db_file_name,
+        )?;
+        let (wal, wal_records) = if read_only {
+            WAL::open_read_only(
+                &wal_file_name,
+                DEFAULT_SEGMENT_SIZE,
+                &schema.schema,
+                checksum,
+            )?
+        } else {
+            WAL::open(
+                &wal_file_name,
+                DEFAULT_SEGMENT_SIZE,
+                &schema.schema,
+                checksum,
+            )?
+        };
+
+        let merges = wal_records
+            .iter()
+            .any(|(_, record)| matches!(record, WALRecord::Merge(..)));
+        if merges && merge_operator.is_none() {
+            return Err(DbError::NoMergeOperator);
+        }
+        let stats = stats::read(file_name, &schema);
+        let lsm = Lsm::open(file_name, &schema)
+            .map_err(|e| DbError::IncompatibleFile(format!("sorted run: {e}")))?;
+        let mut db = Self {
+            pages,
+            file_name: file_name.to_string(),
+            file: db_file,
+            wal,
+            epoch,
+            schema,
+            checks: vec![],
+            hooks: Hooks::default(),
+            merge_operator,
+            latencies: Latencies::default(),
+            memory_limit: None,
+            wal_record_limit: None,
+            stall_limit: None,
+            durability: Durability::default(),
+            fsyncs: FsyncScheduler::default(),
+            group_commit: None,
+            deferred_commits: None,
+            read_only,
+            rewrite_limit: None,
+            direct_io: false,
+            split_policy: SplitPolicy::default(),
+            history: History::default(),
+            requests: Requests::default(),
+            subscribers: vec![],
+            checksum,
+            layout,
+            bloom_filters,
+            engine,
+            lsm,
+            stats,
+            snapshots: Arc::new(()),
+            txns: 0,
+            mapped,
+            dirty: DirtyPages::rescan(),
+            written: AtomicU64::new(0),
+            _lock: lock,
+        };
+
+        // new records have to sort after everything the pages already reflect,
+        // even if the WAL they came from was cleared
+        let mapped_lsn = db.mapped.as_ref().map(|mapped| mapped.lsn());
+        let page_lsn = db
+            .pages
+            .iter()
+            .map(|(page, _)| page.header.lsn)
+            .chain(mapped_lsn)
+            .chain([db.lsm.lsn()])
+            .max();
+        db.wal.lsn = db.wal.lsn.max(page_lsn.unwrap_or(0));
+        db.history.clear(db.wal.lsn);
+
+        // a transaction's writes are only replayed once its commit is read
+        let mut txn: Option<(u32, Vec<(u64, WALRecord)>)> = None;
+        for (lsn, record) in wal_records {
+            match record {
+                WALRecord::Begin(id) => txn = Some((id, vec![])),
+                WALRecord::Commit(_) => {
+                    for (lsn, record) in txn.take().map(|(_, writes)| writes).unwrap_or_default() {
+                        db.replay(lsn, record);
+                    }
+                }
+                WALRecord::Rollback(_) => txn = None,
+                record => match &mut txn {
+                    Some((_, writes)) => writes.push((lsn, record)),
+                    None => db.replay(lsn, record),
+                },
+            }
+        }
+        // a crash cut this transaction short before its commit was logged
+        if let Some((id, _)) = txn.filter(|_| !read_only) {
+            db.wal.rollback(id)?;
+        }
+
+        Ok(Some(db))
+    }
+
+    /// Applies a record read back from the WAL on open.
+    fn replay(&mut self, lsn: u64, record: WALRecord) {
+        // a delete can target a row that's already on a page or in a run, so
+        // replay it against those too instead of only dropping it from the
+        // cache. Records the pages or runs already reflect are skipped.
+        match record {
+            WALRecord::Insert(id, _)
+            | WALRecord::Delete(id)
+            | WALRecord::Tombstone(id)
+            | WALRecord::Restore(id)
+            | WALRecord::Merge(id, _)
+                if self.page_lsn(id) >= lsn || self.lsm.lsn() >= lsn => {}
+            WALRecord::Insert(id, val) => {
+                self.wal.cache(id, val);
+            }
+            WALRecord::Delete(id) => {
+                self.wal.uncache(id);
+                self.remove_stored(id);
+            }
+            WALRecord::Tombstone(id) => {
+                self.wal.tombstones.insert(id);
+            }
+            WALRecord::Restore(id) => {
+                self.wal.tombstones.remove(&id);
+            }
+            WALRecord::Merge(id, operand) => {
+                // checked for before replaying anything
+                let operator = self.merge_operator.as_ref().unwrap();
+                let merged = operator.apply(self.lookup(id).as_deref(), &operand);
+                self.wal.cache(id, merged);
+            }
+            WALRecord::Request(key) => self.requests.record(key),
+            WALRecord::Checkpoint(_)
+            | WALRecord::Begin(_)
+            | WALRecord::Commit(_)
+            | WALRecord::Rollback(_) => {}
+        }
+    }
+
+    fn setup_files(file_name: &str, epoch: u64) -> io::Result<(DbFile, WAL)> {
+        let db_file = failpoint::open(
+            OpenOptions::new().create(true).read(true).write(true),
+            manifest::db_file(file_name, epoch),
+        )?;
+        // a new database starts with an empty log, even if an old one is
+        // lying around under the same name
+        let wal = WAL::create(
+            &manifest::wal_file(file_name, epoch),
+            DEFAULT_SEGMENT_SIZE,
+            Checksum::default(),
+        )?;
+        Ok((db_file, wal))
+    }
+
+    /// Writes every page to the next epoch's file and switches over to it,
+    /// then removes the old epoch's files.
+    ///
+    /// The new epoch only becomes current once its file is on disk and the
+    /// manifest names it, so a crash part way through leaves the previous
+    /// epoch intact for `open` to pick up.
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.check_writable().map_err(io::Error::other)?;
+        self.sync();
+
+        // the new file is written from `pages`, so a mapped database reads
+        // them all in first, and maps the new file once it's in place
+        let pool_capacity = self.mapped.as_ref().map(MappedPages::pool_capacity);
+        self.load_mapped();
+        // every page is written out at the current schema version, so unless
+        // sorted runs were written at older ones, the changes that brought
+        // older pages up to it can go, making room for more
+        if self.lsm.is_empty() && !self.schema.changes.is_empty() {
+            self.schema.changes.clear();
+            for id in self.pages.ids().collect::<Vec<_>>() {
+                self.pages.update(id, |page| page.header.version = 0);
+            }
+        }
+        let epoch = self.epoch + 1;
+        let db_file = self.write_file(epoch)?;
+
+        let mut wal = WAL::create(
+            &manifest::wal_file(&self.file_name, epoch),
+            self.wal.segment_size,
+            self.checksum,
+        )?;
+        wal.lsn = self.wal.lsn;
+        wal.checkpoint(epoch)?;
+        wal.requests(&self.requests.keys())?;
+        manifest::write(&self.file_name, epoch)?;
+
+        self.file = db_file;
+        self.wal = wal;
+        self.epoch = epoch;
+        self.commit_wal().map_err(io::Error::other)?;
+        manifest::remove_stale(&self.file_name, Some(epoch));
+        if let Some(capacity) = pool_capacity {
+            let mapped = MappedPages::new(&File::open(manifest::db_file(&self.file_name, epoch))?)?;
+            mapped.set_pool_capacity(capacity);
+            self.mapped = Some(mapped);
+            self.pages = Pages::new();
+            self.dirty = DirtyPages::rescan();
+        }
+        Ok(())
+    }
+
+    /// Syncs, then maps the `.db` file into memory as a read-only snapshot
+    /// that can be read from other threads while this handle keeps writing.
+    pub fn snapshot_mmap(&mut self) -> io::Result<Snapshot> {
+        self.sync();
+        let file = File::open(manifest::db_file(&self.file_name, self.epoch))?;
+        Snapshot::new(&file, Arc::clone(&self.snapshots))
+    }
+
+    /// Takes a read-only snapshot of every write made so far, without
+    /// syncing. Pages that are on disk are read from the mapped `.db` file
+    /// and the rest are copied, along with the rows in the WAL, so taking
+    /// one costs about as much memory as the writes since the last `sync`.
+    pub fn snapshot(&self) -> io::Result<Snapshot> {
+        let mapped_pages = self.mapped.iter().flat_map(|mapped| {
+            mapped.leaves().map(|(_, i)| match mapped.dirty(i) {
+                Some(page) => SnapshotPage::Memory(page),
+                None => SnapshotPage::File(i),
+            })
+        });
+        let pages = self
+            .pages
+            .iter()
+            .map(|(page, offset)| match offset {
+                Some(i) if !page.dirty => SnapshotPage::File(*i),
+                _ => SnapshotPage::Memory(page.clone()),
+            })
+            .chain(mapped_pages)
+            .collect();
+        // the runs sit between the pages and the WAL, and what they delete
+        // is hidden unless the WAL has it again
+        let (mut rows, deleted) = self.lsm.overlay();
+        rows.extend(self.wal.records.clone());
+        let mut tombstones = self.wal.tombstones.clone();
+        tombstones.extend(
+            deleted
+                .into_iter()
+                .filter(|id| !self.wal.records.contains_key(id)),
+        );
+        let file = File::open(manifest::db_file(&self.file_name, self.epoch))?;
+        Snapshot::pinned(
+            &file,
+            self.schema.clone(),
+            pages,
+            rows,
+            tombstones,
+            Arc::clone(&self.snapshots),
+        )
+    }
+
+    /// Starts a backup of every write up to `lsn`, or every one so far if
+    /// `lsn` is later. The WAL only goes back to the last sync, so an `lsn`
+    /// before it is refused. Writes can carry on while the backup is copied
+    /// with `Backup::write_to`, since it pins the `.db` file like a snapshot.
+    pub fn backup(&self, lsn: u64) -> io::Result<Backup> {
+        let records = self.wal.read_records(&self.schema.schema)?;
+        let synced = records.first().map_or(self.wal.lsn, |(first, _)| first - 1);
+        if lsn < synced {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the WAL only goes back to LSN {synced}, not {lsn}"),
+            ));
+        }
+        let lsn = lsn.min(self.wal.lsn);
+        let wal = records
+            .into_iter()
+            .take_while(|(record_lsn, _)| *record_lsn <= lsn)
+            .flat_map(|(record_lsn, record)| record.to_frame(record_lsn, self.checksum))
+            .collect();
+        let runs = self
+            .lsm
+            .runs
+            .iter()
+            .map(|run| {
+                let file = File::open(manifest::run_file(&self.file_name, run.number))?;
+                Ok((run.number, run.level, file))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Backup {
+            lsn,
+            epoch: self.epoch,
+            db_file: File::open(manifest::db_file(&self.file_name, self.epoch))?,
+            wal,
+            runs,
+            _pinned: Arc::clone(&self.snapshots),
+        })
+    }
+
+    /// Backs up every write so far to the directory `path`, returning the
+    /// LSN of the last one, see `backup`.
+    pub fn backup_to(&self, path: &str) -> io::Result<u64> {
+        let backup = self.backup(self.wal.lsn)?;
+        backup.write_to(path)?;
+        Ok(backup.lsn)
+    }
+
+    /// Writes a dump of the table to `out`: a `create table` with its
+    /// columns and an `insert` for each row, which the shell can replay.
+    pub fn dump(&self, out: &mut impl Write) -> io::Result<()> {
+        dump::write(self, out)
+    }
+
+    /// Replays a dump written by `dump` into a new database under
+    /// `file_name`, see `dump::import`.
+    pub fn import_dump(file_name: &str, reader: impl BufRead) -> Result<Self, DbError> {
+        dump::import(file_name, reader)
+    }
+
+    /// Inserts the rows of a CSV file, reporting the ones that couldn't be,
+    /// see `csv::import`.
+    pub fn import_csv(&mut self, reader: impl BufRead) -> io::Result<CsvImport> {
+        csv::import(self, reader)
+    }
+
+    /// Restores the backup in `backup_dir` to a new database at `dest`, see
+    /// `backup::restore`.
+    pub fn restore_backup(backup_dir: &str, dest: &str) -> io::Result<()> {
+        backup::restore(backup_dir, dest)
+    }
+
+    /// Writes the header and every page to `{epoch}.db.tmp`, then renames it
+    /// over `{epoch}.db` once it's on disk.
+    fn write_file(&self, epoch: u64) -> io::Result<DbFile> {
+        let db_file_name = manifest::db_file(&self.file_name, epoch);
+        let tmp_file_name = format!("{db_file_name}.tmp");
+        let db_file = failpoint::open(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true),
+            &tmp_file_name,
+        )?;
+        // pages keep their positions, so they stay clean in the new file
+        let writes = self.page_writes(epoch, true);
+        self.count_written(&writes);
+        double_write::apply(&db_file, &writes, self.file_len())?;
+        db_file.sync_all()?;
+        fs::rename(&tmp_file_name, &db_file_name)?;
+        File::open(&self.file_name)?.sync_all()?;
+        Ok(db_file)
+    }
+
+    fn file_len(&self) -> u64 {
+        let count = self.pages.len() + btree::node_count(self.pages.len()) + self.overflow_count();
+        (HEADER_SIZE + count * PAGE_SIZE) as u64
+    }
+
+    /// How many overflow pages the spilled values of every page take.
+    fn overflow_count(&self) -> usize {
+        self.pages
+            .iter()
+            .map(|(page, _)| page.overflow_count())
+            .sum()
+    }
+
+    /// Saves the writes in the WAL, then clears it: to the pages, or with
+    /// the LSM engine to a new sorted run. Returns whether the WAL was
+    /// cleared.
+    pub fn sync(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let _timer = self.latencies.sync.time();
+        let cleared = match self.engine {
+            Engine::BTree => self.sync_pages(),
+            Engine::Lsm => self.flush_memtable(),
+        };
+        if self.stats.is_some() {
+            let _ = self.analyze();
+        }
+        cleared
+    }
+
+    /// Applies the WAL to the pages and writes them out.
+    fn sync_pages(&mut self) -> bool {
+        // the WAL still backs the pages if they couldn't be mapped again
+        let Ok(synced) = self.sync_mapped() else {
+            return false;
+        };
+        if !synced {
+            self.load_mapped();
+            // apply all updates in wal to pages
+            for (id, val) in self.wal.records.clone() {
+                self.insert_to_page(id, &val);
+            }
+
+            // rows with a tombstone are purged for good, since the WAL that
+            // could restore them is about to be cleared
+            for id in self.wal.tombstones.clone() {
+                self.remove_from_page(id);
+            }
+            self.drop_expired_rows();
+            self.drop_expired_pages();
+            self.stamp_lsn();
+            self.serialize();
+            // the pages have to be on disk before the WAL that backs them is
+            // gone, which `serialize` sees to unless durability is off
+            self.mark_clean();
+        }
+        self.clear_wal()
+    }
+
+    /// Clears the WAL once the pages or a sorted run hold what it did, and
+    /// logs the idempotency keys it held again, so they're still known after
+    /// a restart.
+    fn clear_wal(&mut self) -> bool {
+        let cleared = self.wal.clear();
+        if cleared {
+            let _ = self.wal.requests(&self.requests.keys());
+        }
+        if self.durability == Durability::Full {
+            let _ = self.wal.file.sync_all();
+        }
+        cleared
+    }
+
+    /// Flushes the memtable, the WAL's rows and the ids deleted since the
+    /// last flush, to a new sorted run. Rows soft deleted are purged, as a
+    /// sync to the pages would.
+    fn flush_memtable(&mut self) -> bool {
+        let mut entries: BTreeMap<NonZeroU32, Entry> =
+            self.lsm.deletes.iter().map(|id| (*id, None)).collect();
+        entries.extend(
+            self.wal
+                .records
+                .iter()
+                .map(|(id, vals)| (*id, Some(vals.clone()))),
+        );
+        entries.extend(self.wal.tombstones.iter().map(|id| (*id, None)));
+        if !entries.is_empty() {
+            // the WAL stays until the run that replaces it is on disk
+            let flushed = self
+                .lsm
+                .flush(&self.file_name, entries, self.wal.lsn, &self.schema);
+            if flushed.is_err() {
+                return false;
+            }
+        }
+        self.lsm.deletes.clear();
+        self.clear_wal()
+    }
+
+    /// Merges every sorted run of the LSM engine into the pages, after
+    /// flushing the memtable to one last run. Any other database is synced.
+    pub fn compact(&mut self) -> bool {
+        let cleared = self.sync();
+        match self.lsm.runs.len() {
+            0 => cleared,
+            count => self.merge_into_pages(count),
+        }
+    }
+
+    /// Runs the compactions the LSM engine's `compaction` strategy has due,
+    /// one after another until none is. Returns whether any ran.
+    pub fn compact_due(&mut self) -> bool {
+        if self.read_only {
+            return false;
+        }
+        let mut compacted = false;
+        while let Some(task) = self.lsm.due() {
+            let done = match task {
+                Task::Merge { runs, level } => self
+                    .lsm
+                    .merge(&self.file_name, runs, level, &self.schema)
+                    .is_ok(),
+                Task::IntoPages { runs } => {
+                    // a flush puts a new run in front, so the oldest runs
+                    // are still the same ones
+                    self.sync();
+                    self.merge_into_pages(runs)
+                }
+            };
+            if !done {
+                break;
+            }
+            compacted = true;
+        }
+        compacted
+    }
+
+    /// Sets how the LSM engine's runs are merged as they pile up. Refused
+    /// unless the strategy `is_valid`.
+    pub fn set_compaction(&mut self, compaction: Compaction) -> bool {
+        if !compaction.is_valid() {
+            return false;
+        }
+        self.lsm.compaction = compaction;
+        true
+    }
+
+    /// Merges the oldest `count` runs into the pages, which the memtable has
+    /// to have been flushed for: the rows it left in the WAL would go to the
+    /// pages too, under the runs that are newer.
+    fn merge_into_pages(&mut self, count: usize) -> bool {
+        let before = self.written.load(Ordering::Relaxed);
+        let start = self.lsm.runs.len() - count;
+        // the merged rows go through the WAL's records without being logged,
+        // since the runs hold them until the pages do
+        for (id, entry) in lsm::merge(&self.lsm.runs[start..]) {
+            match entry {
+                Some(vals) => self.wal.cache(id, vals),
+                None => {
+                    self.wal.tombstones.insert(id);
+                }
+            }
+        }
+        self.sync_pages();
+        let metrics = &mut self.lsm.metrics;
+        metrics.compacted += self.written.load(Ordering::Relaxed) - before;
+        metrics.compactions += 1;
+        self.lsm.drop_oldest(&self.file_name, count).is_ok()
+    }
+
+    /// Switches what the rows are stored with from here on. Leaving the LSM
+    /// engine merges its runs into the pages first.
+    pub fn set_engine(&mut self, engine: Engine) {
+        if engine == Engine::BTree {
+            self.compact();
+        }
+        self.engine = engine;
+        self.serialize();
+    }
+
+    /// Gathers statistics about the values of every column and saves them,
+    /// after which every `sync` gathers them again.
+    pub fn analyze(&mut self) -> io::Result<&TableStats> {
+        self.check_writable().map_err(io::Error::other)?;
+        let stats = TableStats::collect(self.scan(..), &self.schema);
+        stats::write(&self.file_name, &stats)?;
+        Ok(self.stats.insert(stats))
+    }
+
+    /// Syncs a mapped database by applying the WAL to the pages it touches,
+    /// through the buffer pool, and writing back only those. A page that
+    /// outgrows itself keeps its first part in place and has the rest
+    /// appended to the file. Returns `false`, having done nothing, if the
+    /// database isn't mapped, or if the file can't be written in place
+    /// because a snapshot is reading it, `rewrite_limit` applies, or
+    /// retention or a `Ttl` column needs every page looked at.
+    ///
+    /// Fails if the file can't be mapped again once it's written, after
+    /// reading it into `pages` instead. If that fails too, the database
+    /// becomes read only, with the WAL kept, so reopening it recovers.
+    fn sync_mapped(&mut self) -> Result<bool, DbError> {
+        let Some(mapped) = &self.mapped else {
+            return Ok(false);
+        };
+        let snapshotted = Arc::strong_count(&self.snapshots) > 1;
+        let rewrite = self
+            .rewrite_limit
+            .is_some_and(|limit| mapped.bytes().len() <= limit);
+        if snapshotted
+            || rewrite
+            || self.schema.retention.is_some()
+            || self.schema.schema.contains(&RowType::Ttl)
+            || mapped.is_empty()
+        {
+            return Ok(false);
+        }
+
+        // a page updated here is read back from the pool if this gives up
+        for (id, val) in &self.wal.records {
+            let mut row = vec![RowVal::Id(*id)];
+            row.extend_from_slice(val);
+            let Some(i) = mapped.target(*id) else {
+                return Ok(false);
+            };
+            mapped.update(i, &self.schema, |page| page.insert(&row));
+        }
+        for &id in &self.wal.tombstones {
+            let Some(i) = mapped.target(id) else {
+                return Ok(false);
+            };
+            if mapped.with_page(i, &self.schema, |page| page.data.contains_key(&id)) {
+                mapped.update(i, &self.schema, |page| page.remove(id));
+            }
+        }
+
+        let offset = |i: usize| (HEADER_SIZE + i * PAGE_SIZE) as u64;
+        let (nodes, leaves) = mapped.tree().walk();
+        let mut count = mapped.page_count();
+        let mut writes = vec![];
+        // what each written page is replaced with in its parent
+        let mut replaced = HashMap::new();
+        // rows past the end of the last page are packed onto new pages
+        // rather than splitting it
+        let last = mapped.target(NonZeroU32::MAX);
+        for (i, page) in mapped.take_dirty() {
+            let parts = match mapped.header(i).end {
+                Some(end) if Some(i) == last => page.split_after(end, &self.split_policy),
+                _ => page.split_with(&self.split_policy),
+            };
+            let indexes: Vec<_> = (0..parts.len())
+                .map(|j| {
+                    count += usize::from(j > 0);
+                    if j == 0 {
+                        i
+                    } else {
+                        count - 1
+                    }
+                })
+                .collect();
+            // the last part leads on to wherever the page did
+            let next = mapped.header(i).next;
+            let mut children = vec![];
+            for (j, mut part) in parts.into_iter().enumerate() {
+                part.header.lsn = self.wal.lsn;
+                let part_next = indexes.get(j + 1).map(|&i| i as u32).or(next);
+                // an emptied page stays in the chain of leaves, but not in
+                // the tree
+                if let Some(end) = part.header.end {
+                    children.push((end, indexes[j] as u32, part.header.lsn));
+                }
+                // spilled values go on new overflow pages at the end of the
+                // file, leaving the old ones unused until it's next rewritten
+                let (bytes, spilled) =
+                    part.to_leaf_pages(part_next, self.layout, self.bloom_filters, || {
+                        count += 1;
+                        count as u32 - 1
+                    });
+                writes.push((offset(indexes[j]), bytes));
+                writes.extend(
+                    spilled
+                        .into_iter()
+                        .map(|(i, bytes)| (offset(i as usize), bytes)),
+                );
+            }
+            replaced.insert(i as u32, children);
+        }
+
+        // the interior pages are rebuilt over the leaves, in the places the
+        // old ones were in and then at the end of the file
+        let leaves = leaves
+            .into_iter()
+            .flat_map(|child| replaced.remove(&child.1).unwrap_or_else(|| vec![child]))
+            .collect();
+        let mut nodes = nodes.into_iter();
+        let (root, nodes) = btree::build(leaves, || {
+            nodes.next().unwrap_or_else(|| {
+                count += 1;
+                count as u32 - 1
+            })
+        });
+        writes.extend(
+            nodes
+                .into_iter()
+                .map(|(i, node)| (offset(i as usize), node.to_page_bytes())),
+        );
+        let mut header =
+            FileHeader::new(count as u32, self.epoch, self.schema.clone(), self.checksum);
+        header.root = root;
+        header.layout = self.layout;
+        header.bloom_filters = self.bloom_filters;
+        header.engine = self.engine;
+        writes.insert(0, (0, header.to_bytes()));
+        let len = (HEADER_SIZE + count * PAGE_SIZE) as u64;
+        // the pages have to be on disk before the WAL that backs them is
+        // gone, which this sees to unless durability is off
+        if self.durability == Durability::Off {
+            let _ = self.write_pages(&writes, len);
+        } else {
+            let _ = self.write_through_buffer(&writes, len);
+        }
+
+        let db_file = manifest::db_file(&self.file_name, self.epoch);
+        let remapped = File::open(&db_file).and_then(|file| {
+            let mapped = self.mapped.take();
+            mapped.map(|mapped| mapped.remap(&file)).transpose()
+        });
+        match remapped {
+            Ok(mapped) => {
+                self.mapped = mapped;
+                Ok(true)
+            }
+            Err(e) => {
+                self.mapped = None;
+                self.dirty = DirtyPages::rescan();
+                match fs::read(&db_file) {
+                    Ok(bytes) => self.pages = deserialize(&bytes, &self.schema),
+                    Err(_) => self.read_only = true,
+                }
+                Err(DbError::Io(format!("mapping {db_file} again: {e}")))
+            }
+        }
+    }
+
+    /// Reads every page still in the mapped file into `pages`, so they can be
+    /// written to, and unmaps it.
+    fn load_mapped(&mut self) {
+        if let Some(mapped) = self.mapped.take() {
+            self.pages = mapped.load(&self.schema);
+            self.dirty.rescan = true;
+        }
+    }
+
+    /// Fsyncs the WAL after a write if the durability level asks for it.
+    /// Fails if the fsync did, in which case the write is made but may not
+    /// survive a crash.
+    fn commit_wal(&mut self) -> Result<(), DbError> {
+        match self.durability {
+            Durability::Off => {}
+            Durability::Batched(interval) => {
+                self.fsyncs.wal_written();
+                let now = Instant::now();
+                self.fsyncs
+                    .tick(now, interval, &self.file, &self.wal.file)?;
+            }
+            _ => match &self.group_commit {
+                Some(committer) => {
+                    // the committer sends the group's fsync error to every
+                    // write waiting on it
+                    let commit = self.wal.request_sync(committer)?;
+                    match &mut self.deferred_commits {
+                        Some(deferred) => deferred.push(commit),
+                        None => commit.wait()?,
+                    }
+                }
+                None => self.wal.file.sync_data()?,
+            },
+        }
+        Ok(())
+    }
+
+    /// Has the WAL's fsyncs for `WalPerCommit` and `Full` writes done by a
+    /// thread of their own, grouped as `group_commit` bounds them. A write
+    /// still waits for its fsync before it returns, but writes through a
+    /// `SharedDB` wait with the database unlocked, so writes made at about
+    /// the same time share one fsync. `None` goes back to an fsync per write.
+    pub fn set_group_commit(&mut self, group_commit: Option<GroupCommit>) {
+        self.group_commit = group_commit.map(GroupCommitter::new);
+    }
+
+    /// Runs the write `f` without waiting for the group commits it makes,
+    /// and returns them with its result to be waited on once the database is
+    /// unlocked.
+    pub fn without_waiting<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> (T, Vec<Commit>) {
+        self.deferred_commits = Some(vec![]);
+        let res = f(self);
+        (res, self.deferred_commits.take().unwrap_or_default())
+    }
+
+    /// Records that every page is now on disk at its current position, so the
+    /// next `serialize` only writes pages that change after this point.
+    fn mark_clean(&mut self) {
+        match self.changed_pages() {
+            Some(changed) => {
+                for id in changed {
+                    self.pages.update(id, |page| page.dirty = false);
+                }
+            }
+            None => {
+                let ids: Vec<_> = self.pages.ids().collect();
+                for (i, id) in ids.into_iter().enumerate() {
+                    self.pages.update(id, |page| page.dirty = false);
+                    self.pages.set_position(id, Some(i));
+                }
+            }
+        }
+        self.dirty = DirtyPages::default();
+    }
+
+    /// Records on every changed page that it reflects the WAL up to its last
+    /// record.
+    fn stamp_lsn(&mut self) {
+        let lsn = self.wal.lsn;
+        let changed = self.changed_pages().unwrap_or_else(|| {
+            self.pages
+                .ids()
+                .filter(|&id| self.pages.get(id).unwrap().0.dirty)
+                .collect()
+        });
+        for id in changed {
+            self.pages.update(id, |page| page.header.lsn = lsn);
+        }
+    }
+
+    /// Every changed page, found through `dirty`, or `None` if they have
+    /// to be looked for among all the pages.
+    fn changed_pages(&self) -> Option<Vec<PageId>> {
+        if self.dirty.rescan {
+            return None;
+        }
+        let changed: Vec<_> = self
+            .dirty
+            .ids
+            .iter()
+            .copied()
+            .filter(|&id| self.pages.get(id).is_some_and(|(page, _)| page.dirty))
+            .collect();
+        changed
+            .iter()
+            .all(|&id| self.pages.get(id).unwrap().1.is_some())
+            .then_some(changed)
+    }
+
+    /// Notes that the page `id` changed, or that the pages have to be
+    /// rescanned if the change emptied it, which removed it.
+    fn touch(&mut self, id: PageId) {
+        if self.pages.get(id).is_some() {
+            self.dirty.ids.insert(id);
+        } else {
+            self.dirty.rescan = true;
+        }
+    }
+
+    /// The page `id` is on, if there's one.
+    fn page_of(&self, id: NonZeroU32) -> Option<&Page> {
+        let page = &self.pages.get(self.pages.find(id)?)?.0;
+        page.header
+            .start
+            .is_some_and(|start| start <= id)
+            .then_some(page)
+    }
+
+    /// The LSN of the page that `id` falls on, or 0 if there's no such page.
+    fn page_lsn(&self, id: NonZeroU32) -> u64 {
+        if let Some(mapped) = &self.mapped {
+            return mapped.find(id).map_or(0, |(header, _)| header.lsn);
+        }
+        self.page_of(id).map_or(0, |page| page.header.lsn)
+    }
+
+    /// Switches the checksum used from here on. The WAL is synced first so
+    /// that every frame in it uses the algorithm the header names.
+    pub fn set_checksum(&mut self, checksum: Checksum) {
+        self.sync();
+        self.checksum = checksum;
+        self.wal.checksum = checksum;
+        self.serialize();
+    }
+
+    /// Switches how the rows on each page are laid out, rewriting every page
+    /// with the new layout.
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.sync();
+        self.load_mapped();
+        self.layout = layout;
+        if let Ok(file) = self.write_file(self.epoch) {
+            self.file = file;
+        }
+    }
+
+    /// Sets how full pages get before they're split, and where they're
+    /// split, for pages that grow from here on. Refused unless the policy
+    /// `is_valid`.
+    pub fn set_split_policy(&mut self, policy: SplitPolicy) -> bool {
+        if !policy.is_valid() {
+            return false;
+        }
+        self.split_policy = policy;
+        true
+    }
+
+    /// Sets the retention policy applied on every `sync`. The column has to be
+    /// a `U32` holding unix seconds. The policy is saved with the schema, so
+    /// it still applies after the database is reopened.
+    pub fn set_retention(&mut self, retention: Retention) -> bool {
+        if self.schema.schema.get(retention.column) != Some(&RowType::U32) {
+            return false;
+        }
+        self.schema.retention = Some(retention);
+        true
+    }
+
+    fn drop_expired_pages(&mut self) {
+        let Some(retention) = self.schema.retention else {
+            return;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let cutoff = now.saturating_sub(retention.max_age).as_secs();
+
+        let len = self.pages.len();
+        self.pages.retain(|page| {
+            !page.data.values().all(|row| {
+                matches!(row.get(retention.column - 1), Some(RowVal::U32(ts)) if (*ts as u64) < cutoff)
+            })
+        });
+        if self.pages.len() != len {
+            self.dirty.rescan = true;
+        }
+    }
+
+    /// Removes every row whose `Ttl` column has passed from the pages.
+    fn drop_expired_rows(&mut self) {
+        if !self.schema.schema.contains(&RowType::Ttl) {
+            return;
+        }
+        let now = unix_now();
+        let expired: Vec<_> = self
+            .pages
+            .ids()
+            .map(|page_id| {
+                let (page, _) = self.pages.get(page_id).unwrap();
+                let ids: Vec<_> = page
+                    .data
+                    .iter()
+                    .filter(|(_, row)| self.schema.expired(row, now))
+                    .map(|(id, _)| *id)
+                    .collect();
+                (page_id, ids)
+            })
+            .filter(|(_, ids)| !ids.is_empty())
+            .collect();
+
+        for (page_id, ids) in expired {
+            self.pages.update(page_id, |page| {
+                for id in ids {
+                    page.remove(id);
+                }
+            });
+            self.touch(page_id);
+        }
+    }
+
+    /// Describes every page in key order.
+    pub fn page_headers(&self) -> Vec<PageHeaderInfo> {
+        let info = |page: &Page, position: Option<usize>| PageHeaderInfo {
+            start: page.header.start,
+            end: page.header.end,
+            count: page.header.count,
+            size: page.size(),
+            fill: page.size() as f64 / PAGE_SIZE as f64,
+            dirty: page.dirty,
+            offset: position.map(|i| HEADER_SIZE + i * PAGE_SIZE),
+        };
+        if let Some(mapped) = &self.mapped {
+            return mapped
+                .leaves()
+                .map(|(_, i)| info(&mapped.page(i, &self.schema), Some(i)))
+                .collect();
+        }
+        self.pages
+            .iter()
+            .map(|(page, position)| info(page, *position))
+            .collect()
+    }
+
+    /// Lists the rows whose encoded size is at least `min_size` bytes, with
+    /// their sizes. Rows close to `MAX_ROW_SIZE` leave pages nearly unsplittable.
+    pub fn wide_rows(&self, min_size: usize) -> Vec<(NonZeroU32, usize)> {
+        let wal_rows = self.wal.records.iter();
+        let page_rows = self.pages.iter().flat_map(|(page, _)| page.data.iter());
+        let mapped_pages = self.mapped_pages(..);
+        let mapped_rows = mapped_pages.iter().flat_map(|page| page.data.iter());
+
+        let mut res: Vec<_> = wal_rows
+            .chain(page_rows)
+            .chain(mapped_rows)
+            .map(|(id, vals)| (*id, row_size(vals)))
+            .filter(|(_, size)| *size >= min_size)
+            .collect();
+        res.sort();
+        res.dedup_by_key(|(id, _)| *id);
+        res
+    }
+
+    /// Writes the header and every page that changed or moved since the last
+    /// write. A read-only database writes nothing, so dropping it leaves its
+    /// files as they were.
+    pub fn serialize(&mut self) {
+        if self.read_only {
+            return;
+        }
+        let snapshotted = Arc::strong_count(&self.snapshots) > 1;
+        let (writes, len) = match &self.mapped {
+            // no page has changed since the file was mapped, but the schema
+            // in the header might have
+            Some(mapped) if !snapshotted => {
+                let mut header = FileHeader::new(
+                    mapped.page_count() as u32,
+                    self.epoch,
+                    self.schema.clone(),
+                    self.checksum,
+                );
+                header.root = mapped.root();
+                header.layout = self.layout;
+                header.bloom_filters = self.bloom_filters;
+                header.engine = self.engine;
+                (vec![(0, header.to_bytes())], mapped.bytes().len() as u64)
+            }
+            _ => {
+                self.load_mapped();
+                let len = self.file_len();
+                if snapshotted || self.rewrite_limit.is_some_and(|limit| len <= limit as u64) {
+                    if let Ok(file) = self.write_file(self.epoch) {
+                        self.file = file;
+                    }
+                    return;
+                }
+                (self.page_writes(self.epoch, false), len)
+            }
+        };
+        if self.durability == Durability::Off {
+            let _ = self.write_pages(&writes, len);
+        } else {
+            let _ = self.write_through_buffer(&writes, len);
+        }
+    }
+
+    /// Writes pages through the double-write buffer and fsyncs them, so a
+    /// power loss can't leave a page half written.
+    fn write_through_buffer(&self, writes: &[PageWrite], len: u64) -> io::Result<()> {
+        let path = manifest::buffer_file(&self.file_name, self.epoch);
+        double_write::write(&path, writes, len, self.checksum)?;
+        self.write_pages(writes, len)?;
+        self.file.sync_all()?;
+        double_write::clear(&path)
+    }
+
+    /// Copies `writes` to their places in the `.db` file and cuts it to
+    /// `len`, with direct I/O if it's on and can be used.
+    fn write_pages(&self, writes: &[PageWrite], len: u64) -> io::Result<()> {
+        self.count_written(writes);
+        if self.direct_io && direct::aligned(writes) {
+            if let Ok(file) = direct::open(manifest::db_file(&self.file_name, self.epoch)) {
+                direct::write_all_at(&file, writes)?;
+                return self.file.set_len(len);
+            }
+        }
+        double_write::apply(&self.file, writes, len)
+    }
+
+    fn count_written(&self, writes: &[PageWrite]) {
+        let bytes: usize = writes.iter().map(|(_, bytes)| bytes.len()).sum();
+        self.written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The header and the pages to write, either all of them or only those
+    /// that changed or moved, along with where they go. The leaves are laid
+    /// out in key order, followed by the interior pages of the tree over
+    /// them and then the overflow pages, which are written every time, along
+    /// with the leaves that spill to them.
+    fn page_writes(&self, epoch: u64, all: bool) -> Vec<PageWrite> {
+        let count = self.pages.len();
+        let leaves = self
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(i, (page, _))| (page.header.end.unwrap(), i as u32, page.header.lsn))
+            .collect();
+        let mut slot = count as u32;
+        let (root, nodes) = btree::build(leaves, || {
+            slot += 1;
+            slot - 1
+        });
+
+        let mut header = FileHeader::new(
+            (count + nodes.len() + self.overflow_count()) as u32,
+            epoch,
+            self.schema.clone(),
+            self.checksum,
+        );
+        header.root = root;
+        header.layout = self.layout;
+        header.bloom_filters = self.bloom_filters;
+        header.engine = self.engine;
+        let mut writes = vec![(0, header.to_bytes())];
+        let offset = |i: usize| (HEADER_SIZE + i * PAGE_SIZE) as u64;
+        let mut page_write = |writes: &mut Vec<PageWrite>, page: &Page, i: usize| {
+            let next = (i + 1 < count).then_some(i as u32 + 1);
+            let (bytes, spilled) =
+                page.to_leaf_pages(next, self.layout, self.bloom_filters, || {
+                    slot += 1;
+                    slot - 1
+                });
+            writes.push((offset(i), bytes));
+            writes.extend(
+                spilled
+                    .into_iter()
+                    .map(|(i, bytes)| (offset(i as usize), bytes)),
+            );
+        };
+        match self.changed_pages().filter(|_| !all) {
+            // nothing has moved, so every page is where it was last written
+            Some(changed) => {
+                let changed: HashSet<_> = changed.into_iter().collect();
+                for (id, (page, position)) in self.pages.ids().zip(self.pages.iter()) {
+                    if changed.contains(&id) || page.overflow_count() > 0 {
+                        page_write(&mut writes, page, position.unwrap());
+                    }
+                }
+            }
+            None => {
+                for (i, page) in self.pages.iter().enumerate() {
+                    if all || page.0.dirty || page.1 != Some(i) || page.0.overflow_count() > 0 {
+                        page_write(&mut writes, &page.0, i);
+                    }
+                }
+            }
+        }
+        writes.extend(
+            nodes
+                .into_iter()
+                .map(|(i, node)| (offset(i as usize), node.to_page_bytes())),
+        );
+        writes
+    }
+
+    /// Reads the pages still in the mapped file that could hold ids in
+    /// `range`.
+    fn mapped_pages(&self, range: impl RangeBounds<NonZeroU32>) -> Vec<Page> {
+        let Some(mapped) = &self.mapped else {
+            return vec![];
+        };
+        mapped
+            .in_range(&range)
+            .map(|i| mapped.page(i, &self.schema))
+            .collect()
+    }
+
+    pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        let _timer = self.latencies.get.time();
+        self.lookup(id)
+    }
+
+    /// Runs `f` on the value `get` would return for `id`, without copying it
+    /// out of the WAL or the page it's on.
+    pub fn with_row<T>(&self, id: NonZeroU32, f: impl FnOnce(&[RowVal]) -> T) -> Option<T> {
+        let _timer = self.latencies.get.time();
+        self.visit(id, f)
+    }
+
+    /// The value of `id` as it was once the write at `lsn` was made, rolled
+    /// back from its current value through `history`.
+    pub fn get_as_of(&self, id: NonZeroU32, lsn: u64) -> Result<Option<Vec<RowVal>>, DbError> {
+        self.history.as_of(id, lsn, self.lookup(id))
+    }
+
+    /// The value a write to `id` replaces, looked up only if history or an
+    /// insert hook needs it.
+    fn before_write(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        if self.history.retention() == 0 && self.hooks.on_insert.is_empty() {
+            return None;
+        }
+        self.lookup(id)
+    }
+
+    /// The current value of `id`, unless it has expired or been soft deleted.
+    fn lookup(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.visit(id, <[RowVal]>::to_vec)
+    }
+
+    /// Runs `f` on the current value of `id` where it is, unless it has
+    /// expired or been soft deleted.
+    fn visit<T>(&self, id: NonZeroU32, f: impl FnOnce(&[RowVal]) -> T) -> Option<T> {
+        if self.wal.tombstones.contains(&id) {
+            return None;
+        }
+        let now = unix_now();
+        let f = |row: &[RowVal]| (!self.schema.expired(row, now)).then(|| f(row));
+
+        // check wal first, then the runs
+        if let Some(row) = self.wal.records.get(&id) {
+            return f(row);
+        }
+        if let Some(entry) = self.lsm.get(id) {
+            return f(entry?);
+        }
+
+        if let Some(mapped) = &self.mapped {
+            let (_, i) = mapped.find(id)?;
+            if self.bloom_filters && !mapped.may_contain(i, id) {
+                return None;
+            }
+            return mapped.with_page(i, &self.schema, |page| f(page.data.get(&id)?));
+        }
+
+        f(self.page_of(id)?.data.get(&id)?)
+    }
+
+    /// Every row with an id in `range`, in id order, with rows still in the WAL
+    /// taking precedence over the copies on pages. Soft deleted rows are left
+    /// out.
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<NonZeroU32> + Clone,
+    ) -> Vec<(NonZeroU32, Vec<RowVal>)> {
+        let mut rows = self.scan_with_deleted(range);
+        rows.retain(|(id, _)| !self.wal.tombstones.contains(id));
+        rows
+    }
+
+    /// Like `scan`, but with the rows soft deleted since the last `sync` too.
+    pub fn scan_with_deleted(
+        &self,
+        range: impl RangeBounds<NonZeroU32> + Clone,
+    ) -> Vec<(NonZeroU32, Vec<RowVal>)> {
+        let mut rows = BTreeMap::new();
+        for (page, _) in self.pages.iter() {
+            rows.extend(
+                page.data
+                    .range(range.clone())
+                    .map(|(id, vals)| (*id, vals.clone())),
+            );
+        }
+        for page in self.mapped_pages(range.clone()) {
+            rows.extend(
+                page.data
+                    .range(range.clone())
+                    .map(|(id, vals)| (*id, vals.clone())),
+            );
+        }
+        if !self.lsm.is_empty() {
+            let (overlay, deleted) = self.lsm.overlay();
+            rows.retain(|id, _| !deleted.contains(id));
+            rows.extend(overlay.into_iter().filter(|(id, _)| range.contains(id)));
+        }
+        rows.extend(
+            self.wal
+                .records
+                .range(range)
+                .map(|(id, vals)| (*id, vals.clone())),
+        );
+        let now = unix_now();
+        rows.into_iter()
+            .filter(|(_, row)| !self.schema.expired(row, now))
+            .collect()
+    }
+
+    /// Every row whose `column`th value, which has to be a `Bytes` value,
+    /// starts with `prefix`, in order of that value and then of id. Ids are
+    /// the only keys, and there's no index over other columns, so the
+    /// column is read for every row to find them, see `scan_column`.
+    /// `None` if the column isn't a `Bytes` column.
+    pub fn prefix(&self, column: usize, prefix: &[u8]) -> Option<Vec<(NonZeroU32, Vec<RowVal>)>> {
+        if self.schema.schema.get(column) != Some(&RowType::Bytes) {
+            return None;
+        }
+        let mut matches: Vec<_> = self
+            .scan_column(column, ..)?
+            .into_iter()
+            .filter(|(_, val)| matches!(val, RowVal::Bytes(bytes) if bytes.starts_with(prefix)))
+            .collect();
+        matches.sort_by(|(a, a_val), (b, b_val)| a_val.cmp(b_val).then(a.cmp(b)));
+        Some(
+            matches
+                .into_iter()
+                .filter_map(|(id, _)| Some((id, self.get(id)?)))
+                .collect(),
+        )
+    }
+
+    /// The id and `column`th value of every row with an id in `range`, in id
+    /// order, as `scan` would return them. Pages laid out by column only have
+    /// that column read. `None` if there's no such column besides the id.
+    pub fn scan_column(
+        &self,
+        column: usize,
+        range: impl RangeBounds<NonZeroU32> + Clone,
+    ) -> Option<Vec<(NonZeroU32, RowVal)>> {
+        if column == 0 || column >= self.schema.schema.len() {
+            return None;
+        }
+        // whether a row has expired depends on the rest of it, and rows in
+        // runs are stored whole
+        if self.schema.schema.contains(&RowType::Ttl) || !self.lsm.is_empty() {
+            let rows = self.scan(range);
+            return Some(
+                rows.into_iter()
+                    .map(|(id, mut vals)| (id, vals.swap_remove(column - 1)))
+                    .collect(),
+            );
+        }
+
+        let in_range = |(id, _): &(NonZeroU32, RowVal)| range.contains(id);
+        let mut vals = BTreeMap::new();
+        for (page, _) in self.pages.iter() {
+            vals.extend(page.column(column - 1).into_iter().filter(in_range));
+        }
+        if let Some(mapped) = &self.mapped {
+            for i in mapped.in_range(&range) {
+                let column = mapped.with_page(i, &self.schema, |page| page.column(column - 1));
+                vals.extend(column.into_iter().filter(in_range));
+            }
+        }
+        vals.extend(
+            self.wal
+                .records
+                .range(range)
+                .map(|(id, row)| (*id, row[column - 1].clone())),
+        );
+        Some(
+            vals.into_iter()
+                .filter(|(id, _)| !self.wal.tombstones.contains(id))
+                .collect(),
+        )
+    }
+
+    /// Deletes a row, returning its value. A delete vetoed by an `on_delete`
+    /// hook leaves the row where it is and returns `None`; `try_remove`
+    /// reports why.
+    pub fn remove(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.try_remove(id).ok().flatten()
+    }
+
+    pub fn try_remove(&mut self, id: NonZeroU32) -> Result<Option<Vec<RowVal>>, DbError> {
+        self.check_writable()?;
+        let _timer = self.latencies.remove.time();
+        if !self.hooks.on_delete.is_empty() {
+            if let Some(row) = self.lookup(id) {
+                self.hooks.before_delete(id, &row)?;
+            }
+        }
+        // remove from wal, and from the pages too since an older copy of
+        // the row may have been synced to one
+        let res = self.wal.remove(id)?;
+        let synced = self.commit_wal();
+        let res = res.or(self.remove_stored(id));
+        self.history.record(self.wal.lsn, id, res.clone());
+        if res.is_some() {
+            self.publish(self.wal.lsn, WALRecord::Delete(id));
+        }
+        synced?;
+        Ok(res)
+    }
+
+    /// Hides a row from reads and scans without removing it, returning
+    /// whether there was a row to hide. It can be brought back with `restore`
+    /// until the next `sync`, which purges it. `on_delete` hooks run as they
+    /// would for `try_remove`.
+    pub fn soft_delete(&mut self, id: NonZeroU32) -> Result<bool, DbError> {
+        self.check_writable()?;
+        let _timer = self.latencies.remove.time();
+        let Some(row) = self.lookup(id) else {
+            return Ok(false);
+        };
+        self.hooks.before_delete(id, &row)?;
+        self.wal.tombstone(id)?;
+        let synced = self.commit_wal();
+        self.history.record(self.wal.lsn, id, Some(row));
+        self.publish(self.wal.lsn, WALRecord::Tombstone(id));
+        synced?;
+        Ok(true)
+    }
+
+    /// Brings back a row hidden by `soft_delete`, returning its value, or
+    /// `None` if it wasn't soft deleted or has since been purged.
+    pub fn restore(&mut self, id: NonZeroU32) -> Result<Option<Vec<RowVal>>, DbError> {
+        self.check_writable()?;
+        if !self.wal.tombstones.contains(&id) {
+            return Ok(None);
+        }
+        self.wal.restore(id)?;
+        let synced = self.commit_wal();
+        self.history.record(self.wal.lsn, id, None);
+        self.publish(self.wal.lsn, WALRecord::Restore(id));
+        synced?;
+        Ok(self.lookup(id))
+    }
+
+    /// Removes what's stored for `id` below the WAL, returning its value.
+    /// The LSM engine leaves the row where it is and records the delete in
+    /// the memtable, for the next flush to write to a run.
+    fn remove_stored(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        if self.engine == Engine::BTree {
+            return self.remove_from_page(id);
+        }
+        let res = match self.lsm.get(id) {
+            Some(entry) => entry.map(<[RowVal]>::to_vec),
+            None => match &self.mapped {
+                Some(mapped) => mapped.find(id).and_then(|(_, i)| {
+                    mapped.with_page(i, &self.schema, |page| page.data.get(&id).cloned())
+                }),
+                None => self
+                    .page_of(id)
+                    .and_then(|page| page.data.get(&id).cloned()),
+            },
+        };
+        self.lsm.deletes.insert(id);
+        res
+    }
+
+    fn remove_from_page(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        if let Some(mapped) = &self.mapped {
+            let (_, i) = mapped.find(id)?;
+            if !mapped.with_page(i, &self.schema, |page| page.data.contains_key(&id)) {
+                return None;
+            }
+            return mapped.update(i, &self.schema, |page| page.remove(id));
+        }
+
+        // the page `id` would be on, which drops out of the pages if it's
+        // left empty
+        let page_id = self.pages.find(id)?;
+        let res = self.pages.update(page_id, |page| page.remove(id));
+        if res.is_some() {
+            self.touch(page_id);
+        }
+
+        res
+    }
+
+    /// Sets the value used for `column` when an insert leaves it out. The id
+    /// column can't have a default, and the value has to match the column type.
+    pub fn set_default(&mut self, column: usize, val: RowVal) -> Result<(), DbError> {
+        self.check_writable()?;
+        if column == 0 || column >= self.schema.schema.len() {
+            return Err(DbError::SchemaMismatch);
+        }
+        if !self.schema.schema[column].accepts(&val) {
+            return Err(DbError::SchemaMismatch);
+        }
+        let old = self.schema.defaults[column].replace(val);
+        if let Err(e) = self.schema.check_size() {
+            self.schema.defaults[column] = old;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Registers a check that every inserted row has to pass. The description
+    /// is reported when a row is rejected, and saved with the schema on the
+    /// next sync, so registering the check again after reopening doesn't add
+    /// it twice. Fails if the description doesn't fit in the file header.
+    pub fn add_check(
+        &mut self,
+        description: &str,
+        check: impl Fn(&[RowVal]) -> bool + Send + Sync + 'static,
+    ) -> Result<(), DbError> {
+        if !self.schema.checks.iter().any(|d| d == description) {
+            self.schema.checks.push(description.to_string());
+            if let Err(e) = self.schema.check_size() {
+                self.schema.checks.pop();
+                return Err(e);
+            }
+        }
+        self.checks.push(Check {
+            description: description.to_string(),
+            check: Box::new(check),
+        });
+        Ok(())
+    }
+
+    /// Registers a hook run before every insert, which can veto it. Hooks
+    /// run before anything is written, so a hook can see a write that a later
+    /// hook, or a later row of a bulk insert, goes on to veto.
+    pub fn on_insert(
+        &mut self,
+        hook: impl Fn(NonZeroU32, Option<&[RowVal]>, &[RowVal]) -> Result<(), String>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.hooks.on_insert.push(Box::new(hook));
+    }
+
+    /// Registers a hook run before every delete of an existing row, which
+    /// can veto it.
+    pub fn on_delete(
+        &mut self,
+        hook: impl Fn(NonZeroU32, &[RowVal]) -> Result<(), String> + Send + Sync + 'static,
+    ) {
+        self.hooks.on_delete.push(Box::new(hook));
+    }
+
+    pub(crate) fn validate(&self, id: NonZeroU32, val: &[RowVal]) -> Result<(), DbError> {
+        if !verify_row(val, &self.schema.schema) {
+            return Err(DbError::SchemaMismatch);
+        }
+
+        // a value whose length can't be encoded would be cut short when the
+        // row is written, so the row can be no larger than with every value
+        // at its longest
+        let size = row_size(val);
+        let excess: usize = val
+            .iter()
+            .map(|val| match val {
+                RowVal::Bytes(bytes) => bytes.len().saturating_sub(MAX_BYTES_LEN),
+                _ => 0,
+            })
+            .sum();
+        if excess > 0 {
+            return Err(DbError::RowTooLarge {
+                size,
+                max: size - excess,
+            });
+        }
+        let size = stored_row_size(val);
+        if size > MAX_ROW_SIZE {
+            return Err(DbError::RowTooLarge {
+                size,
+                max: MAX_ROW_SIZE,
+            });
+        }
+
+        for (i, val) in val.iter().enumerate() {
+            for constraint in &self.schema.constraints[i + 1] {
+                if !constraint.check(val) {
+                    let name = &self.schema.names[i + 1];
+                    return Err(DbError::CheckFailed(format!("{name} {constraint}")));
+                }
+            }
+        }
+
+        let mut row = vec![RowVal::Id(id)];
+        row.extend_from_slice(val);
+        for check in &self.checks {
+            if !(check.check)(&row) {
+                return Err(DbError::CheckFailed(check.description.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn insert(&mut self, id: NonZeroU32, val: &[RowVal]) -> Result<(), DbError> {
+        self.check_writable()?;
+        let _timer = self.latencies.insert.time();
+        // fill in missing trailing columns from their defaults
+        let val = self
+            .schema
+            .with_defaults(val)
+            .unwrap_or_else(|| val.to_vec());
+
+        self.validate(id, &val)?;
+        self.check_stall()?;
+        let before = self.before_write(id);
+        self.hooks.before_insert(id, before.as_deref(), &val)?;
+
+        // if in wal, insert into wal
+        if self.wal.insert(id, &val)? {
+            let synced = self.commit_wal();
+            self.history.record(self.wal.lsn, id, before);
+            self.publish(self.wal.lsn, WALRecord::Insert(id, val));
+            self.enforce_wal_limits();
+            return synced;
+        }
+
+        self.insert_to_page(id, &val);
+        Ok(())
+    }
+
+    /// Merges `operand` into `id` with the merge operator, without the caller
+    /// reading the row first. Only the operand is logged, and replaying it
+    /// merges it again. The value it gives is validated and passed to the
+    /// insert hooks like any insert's, and is what subscribers are sent.
+    pub fn merge(&mut self, id: NonZeroU32, operand: &[RowVal]) -> Result<(), DbError> {
+        self.check_writable()?;
+        let operator = self
+            .merge_operator
+            .clone()
+            .ok_or(DbError::NoMergeOperator)?;
+        let _timer = self.latencies.insert.time();
+        let before = self.lookup(id);
+        let merged = operator.apply(before.as_deref(), operand);
+        let merged = self.schema.with_defaults(&merged).unwrap_or(merged);
+
+        self.validate(id, &merged)?;
+        self.check_stall()?;
+        self.hooks.before_insert(id, before.as_deref(), &merged)?;
+
+        self.wal.merge(id, operand, merged.clone())?;
+        let synced = self.commit_wal();
+        self.history.record(self.wal.lsn, id, before);
+        self.publish(self.wal.lsn, WALRecord::Insert(id, merged));
+        self.enforce_wal_limits();
+        synced
+    }
+
+    /// Writes `new` to `id`, or deletes it if `new` is `None`, but only if
+    /// its current value is `expected`, where `None` means it doesn't exist.
+    /// Returns whether the write was made, so callers can retry with the
+    /// value they read instead of holding a transaction open.
+    pub fn compare_and_swap(
+        &mut self,
+        id: NonZeroU32,
+        expected: Option<&[RowVal]>,
+        new: Option<&[RowVal]>,
+    ) -> Result<bool, DbError> {
+        if self.lookup(id).as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(vals) => self.insert(id, vals)?,
+            None if expected.is_some() => {
+                self.try_remove(id)?;
+            }
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Inserts many rows as one WAL batch. Missing trailing columns are
+    /// filled in from their defaults, as `insert` fills them. Every row is
+    /// validated and passed to the insert hooks first; if any row is
+    /// rejected, nothing is written.
+    pub fn bulk_insert(&mut self, rows: &[(NonZeroU32, Vec<RowVal>)]) -> Result<(), DbError> {
+        self.check_writable()?;
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|(id, vals)| {
+                let vals = self
+                    .schema
+                    .with_defaults(vals)
+                    .unwrap_or_else(|| vals.clone());
+                (*id, vals)
+            })
+            .collect();
+        for (id, vals) in &rows {
+            self.validate(*id, vals)?;
+        }
+        self.check_stall()?;
+
+        // a row can show up more than once, replacing its own earlier value
+        let mut befores = BTreeMap::new();
+        let mut lsn = self.wal.lsn;
+        let mut versions = vec![];
+        for (id, vals) in &rows {
+            lsn += 1;
+            let before = match befores.insert(*id, vals.clone()) {
+                Some(before) => Some(before),
+                None => self.before_write(*id),
+            };
+            self.hooks.before_insert(*id, before.as_deref(), vals)?;
+            versions.push((lsn, *id, before));
+        }
+
+        self.wal.insert_many(&rows)?;
+        let synced = self.commit_wal();
+        for ((lsn, id, before), (_, vals)) in versions.into_iter().zip(rows) {
+            self.history.record(lsn, id, before);
+            self.publish(lsn, WALRecord::Insert(id, vals));
+        }
+        self.enforce_wal_limits();
+        synced
+    }
+
+    /// Starts a transaction. Its writes are buffered in the `Txn` until it's
+    /// passed to `commit`.
+    pub fn begin(&mut self) -> Txn {
+        self.txns = self.txns.wrapping_add(1);
+        Txn::new(self.txns)
+    }
+
+    /// Makes every write of `txn` visible at once. They're validated and
+    /// passed to the hooks first, and if any is rejected, nothing is written.
+    /// They're logged between a begin and a commit record with a single
+    /// write, so after a crash either all of them are replayed or none are.
+    pub fn commit(&mut self, txn: Txn) -> Result<(), DbError> {
+        self.commit_writes(txn.id, None, txn.records()?)
+    }
+
+    /// Applies every insert and delete in `batch` atomically, committing
+    /// them as a transaction of their own.
+    pub fn apply(&mut self, batch: WriteBatch) -> Result<(), DbError> {
+        let txn = self.begin();
+        self.commit_writes(txn.id, None, batch.records)
+    }
+
+    /// Like `apply`, but only if no write was applied with the idempotency
+    /// key `key` before, so a client can retry a write it never heard back
+    /// about. The key is logged in the same write as the batch, and is
+    /// remembered for the last `REQUEST_KEYS` keyed writes. Returns whether
+    /// the batch was applied.
+    pub fn apply_once(&mut self, key: u64, batch: WriteBatch) -> Result<bool, DbError> {
+        if self.requests.contains(key) {
+            return Ok(false);
+        }
+        let txn = self.begin();
+        self.commit_writes(txn.id, Some(key), batch.records)?;
+        Ok(true)
+    }
+
+    fn commit_writes(
+        &mut self,
+        txn: u32,
+        key: Option<u64>,
+        writes: Vec<WALRecord>,
+    ) -> Result<(), DbError> {
+        self.check_writable()?;
+        let mut records = vec![];
+        for record in writes {
+            if let WALRecord::Insert(id, vals) = record {
+                let vals = self.schema.with_defaults(&vals).unwrap_or(vals);
+                self.validate(id, &vals)?;
+                records.push(WALRecord::Insert(id, vals));
+            } else {
+                records.push(record);
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.check_stall()?;
+
+        // later writes see the earlier ones, not what's committed
+        let mut written = BTreeMap::new();
+        // the begin record takes the first LSN, and the key the next
+        let mut lsn = self.wal.lsn + 1 + u64::from(key.is_some());
+        let mut versions = vec![];
+        for record in &records {
+            lsn += 1;
+            let (id, after) = match record {
+                WALRecord::Insert(id, vals) => (*id, Some(vals.clone())),
+                WALRecord::Delete(id) => (*id, None),
+                _ => unreachable!(),
+            };
+            let before = match written.insert(id, after.clone()) {
+                Some(before) => before,
+                None => self.lookup(id),
+            };
+            match (&after, &before) {
+                (Some(vals), _) => self.hooks.before_insert(id, before.as_deref(), vals)?,
+                (None, Some(row)) => self.hooks.before_delete(id, row)?,
+                (None, None) => {}
+            }
+            versions.push((lsn, id, before));
+        }
+
+        self.wal.commit(txn, key, &records)?;
+        if let Some(key) = key {
+            self.requests.record(key);
+        }
+        // an older copy of a deleted row may have been synced to a page
+        for (id, after) in written {
+            if after.is_none() {
+                self.remove_stored(id);
+            }
+        }
+        let synced = self.commit_wal();
+        for ((lsn, id, before), record) in versions.into_iter().zip(records) {
+            let existed = before.is_some();
+            self.history.record(lsn, id, before);
+            if existed || matches!(record, WALRecord::Insert(..)) {
+                self.publish(lsn, record);
+            }
+        }
+        self.enforce_wal_limits();
+        synced
+    }
+
+    /// Streams every insert, delete, soft delete and restore committed from
+    /// now on, in LSN order. Deletes of rows that didn't exist aren't sent. Dropping the receiver
+    /// unsubscribes it.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn publish(&mut self, lsn: u64, record: WALRecord) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = ChangeEvent { lsn, record };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Reports roughly how much memory the pages and the WAL's rows take, by
+    /// their encoded size. Pages of a mapped database count while they're
+    /// held in its buffer pool.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let pool = self.mapped.as_ref().map_or(0, |mapped| mapped.pool_size());
+        MemoryUsage {
+            pages: self
+                .pages
+                .iter()
+                .map(|(page, _)| page.size())
+                .sum::<usize>()
+                + pool,
+            wal: self.wal.size,
+        }
+    }
+
+    /// How many pages a database opened with `open_mapped` keeps in its
+    /// buffer pool, or `None` if it wasn't.
+    pub fn pool_capacity(&self) -> Option<usize> {
+        self.mapped.as_ref().map(|mapped| mapped.pool_capacity())
+    }
+
+    /// Caps how many pages a database opened with `open_mapped` keeps in its
+    /// buffer pool, evicting the least recently used clean pages if it holds
+    /// more. Pages changed since the last `sync` are held regardless.
+    pub fn set_pool_capacity(&mut self, pages: usize) {
+        if let Some(mapped) = &self.mapped {
+            mapped.set_pool_capacity(pages);
+        }
+    }
+
+    /// Caps how much memory the WAL's rows can take before an insert writes
+    /// them back to the pages with a `sync`. Unless the database is mapped,
+    /// every page stays in memory, so only the WAL can be written back;
+    /// `None` removes the cap.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+        self.enforce_wal_limits();
+    }
+
+    /// Caps how many rows the WAL holds before an insert syncs them to the
+    /// pages, however small they are; `None` removes the cap.
+    pub fn set_wal_record_limit(&mut self, limit: Option<usize>) {
+        self.wal_record_limit = limit;
+        self.enforce_wal_limits();
+    }
+
+    /// Caps how many bytes of rows the WAL can hold before inserts are
+    /// refused with `DbError::Busy`, until a `sync` empties it. Unlike the
+    /// memory limit, inserts never sync themselves; this is for when syncs
+    /// are left to `start_background_tasks` and falling behind should push
+    /// back on writers instead of letting the WAL grow. `None` removes the
+    /// cap.
+    pub fn set_stall_limit(&mut self, limit: Option<usize>) {
+        self.stall_limit = limit;
+    }
+
+    /// Refuses a write to a database opened read-only.
+    fn check_writable(&self) -> Result<(), DbError> {
+        if self.read_only {
+            return Err(DbError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Refuses a write while the WAL is past its stall limit.
+    fn check_stall(&self) -> Result<(), DbError> {
+        match self.stall_limit {
+            Some(limit) if self.wal.size > limit => Err(DbError::Busy {
+                wal_size: self.wal.size,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn enforce_wal_limits(&mut self) {
+        let too_large = self.memory_limit.is_some_and(|limit| self.wal.size > limit);
+        let too_long = self
+            .wal_record_limit
+            .is_some_and(|limit| self.wal.records.len() > limit);
+        if too_large || too_long {
+            self.sync();
+        }
+    }
+
+    /// Starts a thread that checks the WAL every `interval` and syncs it once
+    /// its rows take more than `wal_threshold` bytes, so nobody has to call
+    /// `sync` by hand, and runs the compactions the LSM engine has due, see
+    /// `compact_due`. The thread runs until the returned handle is dropped,
+    /// or until another thread panics while holding the lock, since whatever
+    /// it was writing may be half done and shouldn't be synced.
+    pub fn start_background_tasks(
+        db: &Arc<Mutex<Self>>,
+        interval: Duration,
+        wal_threshold: usize,
+    ) -> BackgroundTasks {
+        let (stop, stopped) = channel::<()>();
+        let db = Arc::clone(db);
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let Ok(mut db) = db.lock() else {
+                    eprintln!("Stopped syncing in the background: the database lock is poisoned");
+                    break;
+                };
+                if db.wal.size > wal_threshold {
+                    db.sync();
+                }
+                db.compact_due();
+            }
+        });
+        BackgroundTasks {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Appends a column to the schema. Existing rows read as having `default`;
+    /// pages already on disk keep their old format until they're next written.
+    pub fn add_column(
+        &mut self,
+        name: &str,
+        row_type: RowType,
+        default: RowVal,
+    ) -> Result<(), DbError> {
+        self.check_writable()?;
+        if row_type == RowType::Id || !row_type.accepts(&default) {
+            return Err(DbError::SchemaMismatch);
+        }
+        let mut schema = self.schema.clone();
+        schema.schema.push(row_type);
+        schema.names.push(name.to_string());
+        schema.defaults.push(None);
+        schema.constraints.push(vec![]);
+        schema
+            .changes
+            .push(SchemaChange::AddColumn { row_type, default });
+        schema.check_size()?;
+
+        // the wal and the runs are encoded with the current schema, so
+        // flush them to the pages first
+        self.compact();
+        // old values have the old schema's columns
+        self.history.clear(self.wal.lsn);
+
+        self.schema = schema;
+        self.serialize();
+
+        for (page, offset) in std::mem::take(&mut self.pages) {
+            let migrated = migrate_page(page, &self.schema);
+            if migrated.size() > PAGE_SIZE {
+                self.dirty.rescan = true;
+                for page in migrated.split_to_fit() {
+                    self.pages.insert(page, None);
+                }
+            } else {
+                self.pages.insert(migrated, offset);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes a column (by index, counting the id) from the schema. Pages are
+    /// stripped in memory right away and rewritten on disk by the next `sync`.
+    pub fn drop_column(&mut self, column: usize) -> Result<(), DbError> {
+        self.check_writable()?;
+        if column == 0 || column >= self.schema.schema.len() {
+            return Err(DbError::SchemaMismatch);
+        }
+        let mut schema = self.schema.clone();
+        let row_type = schema.schema.remove(column);
+        schema.names.remove(column);
+        schema.defaults.remove(column);
+        schema.constraints.remove(column);
+        // the retention column goes with it, or moves down a place
+        schema.retention = schema
+            .retention
+            .filter(|retention| retention.column != column)
+            .map(|retention| Retention {
+                column: retention.column - usize::from(retention.column > column),
+                ..retention
+            });
+        schema.changes.push(SchemaChange::DropColumn {
+            index: column as u16,
+            row_type,
+        });
+        schema.check_size()?;
+
+        // the wal and the runs are encoded with the current schema, so
+        // flush them to the pages first
+        self.compact();
+        // old values have the old schema's columns
+        self.history.clear(self.wal.lsn);
+
+        self.schema = schema;
+        self.serialize();
+
+        for (page, offset) in std::mem::take(&mut self.pages) {
+            self.pages.insert(migrate_page(page, &self.schema), offset);
+        }
+        self.dirty.rescan = true;
+
+        Ok(())
+    }
+
+    fn insert_to_page(&mut self, id: NonZeroU32, val: &[RowVal]) {
+        let mut new_record = vec![RowVal::Id(id)];
+        new_record.extend_from_slice(val);
+
+        // the page the row falls on, or the last one if it's past them all
+        let page_id = self.pages.find(id).or_else(|| self.pages.ids().next_back());
+        // a row past the end of a full last page starts a page of its own
+        // instead of splitting it, so ids that only go up leave full pages
+        // behind rather than half empty ones. An empty db starts its first.
+        let fresh = page_id.is_none_or(|page_id| {
+            let (page, _) = self.pages.get(page_id).unwrap();
+            page.header.end < Some(id)
+                && page.size() + stored_row_size(val) > self.split_policy.threshold()
+        });
+        let Some(page_id) = page_id.filter(|_| !fresh) else {
+            self.dirty.rescan = true;
+            let mut new_page = Page::new_dirty(&[new_record], &self.schema.schema);
+            new_page.header.version = self.schema.version();
+            self.pages.insert(new_page, None);
+            return;
+        };
+
+        self.pages.update(page_id, |page| page.insert(&new_record));
+        self.dirty.ids.insert(page_id);
+
+        // split page that is too big, which moves the pages after it
+        if self.pages.get(page_id).unwrap().0.size() > self.split_policy.threshold() {
+            self.dirty.rescan = true;
+            let (page, _) = self.pages.remove(page_id).unwrap();
+            for page in page.split_with(&self.split_policy) {
+                self.pages.insert(page, None);
+            }
+        }
+    }
+}
+
+/// Rebuilds a page written with an older schema version so its rows match the
+/// current schema. Pages that only gained columns aren't marked dirty, so
+/// they're rewritten lazily once something else changes them. Pages that lost
+/// a column are marked dirty so the next sync reclaims the space.
+pub(crate) fn migrate_page(page: Page, schema: &Schema) -> Page {
+    if page.header.version == schema.version() {
+        return page;
+    }
+
+    let rows: Vec<_> = page
+        .data
+        .into_iter()
+        .map(|(id, mut vals)| {
+            schema.migrate(&mut vals, page.header.version);
+            let mut row = vec![RowVal::Id(id)];
+            row.extend(vals);
+            row
+        })
+        .collect();
+
+    let mut migrated = Page::new(&rows, &schema.schema);
+    migrated.header.version = schema.version();
+    migrated.dirty = schema.changes[page.header.version as usize..]
+        .iter()
+        .any(|change| matches!(change, SchemaChange::DropColumn { .. }));
+    migrated
+}
+
+/// Reads the pages of a `.db` file, skipping over its header, and over the
+/// interior and overflow pages, which look empty.
+pub fn deserialize(bytes: &[u8], schema: &Schema) -> Pages {
+    assert!((bytes.len() - HEADER_SIZE).is_multiple_of(PAGE_SIZE));
+
+    let mut pages = vec![];
+
+    for i in 0..((bytes.len() - HEADER_SIZE) / PAGE_SIZE) {
+        let start = HEADER_SIZE + i * PAGE_SIZE;
+        let header =
+            PageHeader::from_bytes(bytes[start..start + PageHeader::size()].try_into().unwrap());
+        let page = Page::read(bytes, i, &schema.schema_at(header.version));
+        let page = migrate_page(page, schema);
+
+        // empty pages are never indexed; the space is reclaimed on the next write
+        if page.is_empty() {
+            continue;
+        }
+        if page.size() > PAGE_SIZE {
+            pages.extend(page.split_to_fit().into_iter().map(|page| (page, None)));
+        } else {
+            pages.push((page, Some(i)));
+        }
+    }
+
+    Pages::from_iter(pages)
+}
+
+/// Locks the database's `LOCK` file, so only one process has the database open at
+/// a time. Otherwise both would write their own pages over the other's.
+/// A `shared` lock is for read-only opens, which can have the database open
+/// together, but not alongside a writer. The lock is advisory, and goes away
+/// with the process holding it, even if it crashes.
+fn lock(file_name: &str, shared: bool) -> Result<File, DbError> {
+    let path = manifest::lock_file(file_name);
+    let file = if shared {
+        File::open(path)?
+    } else {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?
+    };
+    let locked = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(DbError::Locked(file_name.to_string())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+impl Drop for DB {
+    fn drop(&mut self) {
+        self.serialize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, num::NonZero};
+
+    use insta::assert_yaml_snapshot as snapshot;
+
+    use quickcheck::{Arbitrary, Gen};
+    use quickcheck_macros::quickcheck;
+
+    use crate::{header::MAX_SCHEMA_SIZE, row::Constraint, schema};
+
+    use super::*;
+
+    const DEFAULT_SCHEMA: &[RowType] = &[RowType::Id, RowType::U32];
+
+    /// Leaves the database as a crash would: nothing more is written, and its
+    /// lock goes away along with the process that held it.
+    fn crash(db: DB) {
+        db._lock.unlock().unwrap();
+        std::mem::forget(db);
+    }
+
+    /// A random schema with rows that fit it.
+    #[derive(Debug, Clone)]
+    struct Table {
+        schema: Vec<RowType>,
+        rows: HashMap<NonZeroU32, Vec<RowVal>>,
+    }
+
+    fn arbitrary_val(row_type: RowType, g: &mut Gen) -> RowVal {
+        match row_type {
+            RowType::Id => RowVal::Id(NonZeroU32::arbitrary(g)),
+            RowType::U32 | RowType::Ttl => RowVal::U32(u32::arbitrary(g)),
+            RowType::Bytes => RowVal::Bytes(Vec::arbitrary(g)),
+            RowType::Bool => RowVal::Bool(bool::arbitrary(g)),
+        }
+    }
+
+    impl Arbitrary for Table {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let columns = usize::arbitrary(g) % 6 + 1;
+            let mut schema = vec![RowType::Id];
+            schema.extend((0..columns).map(|_| RowType::arbitrary(g)));
+
+            let rows = Vec::<NonZeroU32>::arbitrary(g)
+                .into_iter()
+                .map(|id| {
+                    let vals: Vec<_> = schema[1..]
+                        .iter()
+                        .map(|row_type| arbitrary_val(*row_type, g))
+                        .collect();
+                    (id, vals)
+                })
+                .filter(|(_, vals)| row_size(vals) <= MAX_ROW_SIZE)
+                .collect();
+
+            Self { schema, rows }
+        }
+    }
+
+    #[test]
+    fn read_write() {
+        let mut db = DB::new("tests/read_write", DEFAULT_SCHEMA).unwrap();
+
+        for i in 1..=5 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+
+        db.serialize();
+        db.sync();
+
+        let bytes = fs::read("tests/read_write/1.db").unwrap();
+
+        let deserialized = deserialize(&bytes, &db.schema);
+
+        snapshot!(deserialized);
+    }
+
+    #[test]
+    fn insert_loop() {
+        let mut db = DB::new("tests/insert_loop", DEFAULT_SCHEMA).unwrap();
+
+        for i in 1..=510 {
+            db.insert(NonZero::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+
+        db.sync();
+
+        snapshot!(db.pages);
+    }
+
+    #[test]
+    fn sequential_inserts() {
+        let mut db = DB::new("tests/sequential_inserts", DEFAULT_SCHEMA).unwrap();
+        // each id takes a byte on the page
+        let rows = 4 * PAGE_SIZE / (1 + RowVal::U32(0).size());
+        for i in 1..=rows as u32 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+            // syncing after every few rows appends them to the last page
+            if i % 100 == 0 {
+                db.sync();
+            }
+        }
+        db.sync();
+
+        // every page but the last is left full, not half empty
+        let headers = db.page_headers();
+        assert_eq!(headers.len(), 5);
+        assert!(headers[..4].iter().all(|header| header.fill > 0.99));
+    }
+
+    #[test]
+    fn bulk_insert() {
+        let mut db = DB::new("tests/bulk_insert", DEFAULT_SCHEMA).unwrap();
+
+        let rows: Vec<_> = (1..=100)
+            .map(|i| (NonZeroU32::new(i).unwrap(), vec![RowVal::U32(i * 10)]))
+            .collect();
+        db.bulk_insert(&rows).unwrap();
+
+        let bad_rows = vec![(NonZeroU32::new(101).unwrap(), vec![RowVal::Bool(true)])];
+        assert_eq!(db.bulk_insert(&bad_rows), Err(DbError::SchemaMismatch));
+        assert_eq!(db.get(NonZeroU32::new(101).unwrap()), None);
+
+        db.sync();
+
+        for (id, vals) in rows {
+            assert_eq!(db.get(id), Some(vals));
+        }
+    }
+
+    #[test]
+    fn default_values() {
+        let schema = &[RowType::Id, RowType::U32, RowType::Bytes, RowType::Bool];
+        let mut db = DB::new("tests/default_values", schema).unwrap();
+
+        assert!(db.set_default(0, RowVal::U32(0)).is_err());
+        assert!(db.set_default(1, RowVal::Bool(true)).is_err());
+        db.set_default(2, RowVal::Bytes(b"none".to_vec())).unwrap();
+        db.set_default(3, RowVal::Bool(false)).unwrap();
+
+        let id = NonZeroU32::new(1).unwrap();
+        db.insert(id, &[RowVal::U32(5)]).unwrap();
+
+        assert_eq!(
+            db.get(id),
+            Some(vec![
+                RowVal::U32(5),
+                RowVal::Bytes(b"none".to_vec()),
+                RowVal::Bool(false)
+            ])
+        );
+
+        // bulk inserts fill them in the same way
+        let rows: Vec<_> = (2..=3)
+            .map(|i| (NonZeroU32::new(i).unwrap(), vec![RowVal::U32(i)]))
+            .collect();
+        db.bulk_insert(&rows).unwrap();
+        assert_eq!(
+            db.get(NonZeroU32::new(3).unwrap()),
+            Some(vec![
+                RowVal::U32(3),
+                RowVal::Bytes(b"none".to_vec()),
+                RowVal::Bool(false)
+            ])
+        );
+    }
+
+    #[test]
+    fn retention() {
+        let schema = &[RowType::Id, RowType::U32];
+        let mut db = DB::new("tests/retention", schema).unwrap();
+
+        assert!(!db.set_retention(Retention {
+            column: 0,
+            max_age: Duration::from_secs(60),
+        }));
+        assert!(db.set_retention(Retention {
+            column: 1,
+            max_age: Duration::from_secs(60),
+        }));
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+
+        for i in 1..=1000 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        for i in 1001..=1010 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(now)])
+                .unwrap();
+        }
+
+        db.sync();
+
+        assert_eq!(db.get(NonZeroU32::new(1).unwrap()), None);
+        assert_eq!(
+            db.get(NonZeroU32::new(1005).unwrap()),
+            Some(vec![RowVal::U32(now)])
+        );
+
+        // the policy is saved with the schema, so it still applies after
+        // reopening
+        drop(db);
+        let mut db = DB::open("tests/retention").unwrap().unwrap();
+        for i in 1..=1000 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        db.sync();
+        assert_eq!(db.get(NonZeroU32::new(1).unwrap()), None);
+        assert_eq!(
+            db.get(NonZeroU32::new(1005).unwrap()),
+            Some(vec![RowVal::U32(now)])
+        );
+    }
+
+    #[test]
+    fn ttl() {
+        let columns = schema::parse("id, expires:ttl").unwrap();
+        let mut db = DB::from_columns("tests/ttl", &columns).unwrap();
+        let now = unix_now() as u32;
+        let ids: Vec<_> = (1..=3).map(|i| NonZeroU32::new(i).unwrap()).collect();
+        db.insert(ids[0], &[RowVal::U32(now - 10)]).unwrap();
+        db.insert(ids[1], &[RowVal::U32(now + 3600)]).unwrap();
+        // 0 never expires
+        db.insert(ids[2], &[RowVal::U32(0)]).unwrap();
+
+        assert_eq!(db.get(ids[0]), None);
+        assert_eq!(db.get(ids[1]), Some(vec![RowVal::U32(now + 3600)]));
+        let live = |db: &DB| {
+            db.scan(..)
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(live(&db), ids[1..]);
+
+        // the expired row is only hidden until a sync drops it for good
+        db.sync();
+        let stored: usize = db.pages.iter().map(|(page, _)| page.data.len()).sum();
+        assert_eq!(stored, 2);
+        assert_eq!(live(&db), ids[1..]);
+    }
+
+    #[test]
+    fn checks() {
+        let file_name = "tests/checks";
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        let even = |row: &[RowVal]| matches!(row[1], RowVal::U32(n) if n % 2 == 0);
+        db.add_check("value must be even", even).unwrap();
+
+        let id = NonZeroU32::new(1).unwrap();
+        assert_eq!(
+            db.insert(id, &[RowVal::U32(3)]),
+            Err(DbError::CheckFailed("value must be even".to_string()))
+        );
+        assert_eq!(db.get(id), None);
+
+        db.insert(id, &[RowVal::U32(4)]).unwrap();
+        assert_eq!(db.get(id), Some(vec![RowVal::U32(4)]));
+
+        // the description is saved with the schema, and registering the
+        // check again after reopening doesn't repeat it
+        db.sync();
+        drop(db);
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.schema.checks, ["value must be even"]);
+        db.add_check("value must be even", even).unwrap();
+        assert_eq!(db.schema.checks.len(), 1);
+        assert!(db.insert(id, &[RowVal::U32(5)]).is_err());
+
+        let long = "x".repeat(MAX_SCHEMA_SIZE);
+        assert!(matches!(
+            db.add_check(&long, even),
+            Err(DbError::SchemaTooLarge { .. })
+        ));
+        assert_eq!(db.schema.checks.len(), 1);
+    }
+
+    #[test]
+    fn hooks() {
+        let mut db = DB::new("tests/hooks", DEFAULT_SCHEMA).unwrap();
+        let audit = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&audit);
+        db.on_insert(move |id, before, after| {
+            if before.is_some_and(|before| before[0] > after[0]) {
+                return Err("values only go up".to_string());
+            }
+            log.lock()
+                .unwrap()
+                .push(format!("{id}: {before:?} -> {after:?}"));
+            Ok(())
+        });
+        db.on_delete(|_, row| match row[0] {
+            RowVal::U32(0) => Ok(()),
+            _ => Err("only zeroes can be deleted".to_string()),
+        });
+
+        let one = NonZeroU32::new(1).unwrap();
+        let two = NonZeroU32::new(2).unwrap();
+        db.insert(one, &[RowVal::U32(1)]).unwrap();
+        db.insert(one, &[RowVal::U32(3)]).unwrap();
+        let vetoed = Err(DbError::Vetoed("values only go up".to_string()));
+        assert_eq!(db.insert(one, &[RowVal::U32(2)]), vetoed);
+        // one vetoed row stops the whole batch
+        assert_eq!(
+            db.bulk_insert(&[(two, vec![RowVal::U32(0)]), (one, vec![RowVal::U32(0)])]),
+            vetoed
+        );
+        assert_eq!(db.get(two), None);
+        // hooks run before the write, so they can see one that's vetoed later
+        assert_eq!(
+            *audit.lock().unwrap(),
+            [
+                "1: None -> [U32(1)]",
+                "1: Some([U32(1)]) -> [U32(3)]",
+                "2: None -> [U32(0)]"
+            ]
+        );
+
+        assert_eq!(
+            db.try_remove(one),
+            Err(DbError::Vetoed("only zeroes can be deleted".to_string()))
+        );
+        assert_eq!(db.remove(one), None);
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(3)]));
+        db.insert(two, &[RowVal::U32(0)]).unwrap();
+        assert_eq!(db.try_remove(two), Ok(Some(vec![RowVal::U32(0)])));
+    }
+
+    #[test]
+    fn constraints() {
+        let columns = schema::parse("id, count:u32 = 1 min 1 max 10, name:text max 4").unwrap();
+        let mut db = DB::from_columns("tests/constraints", &columns).unwrap();
+        let id = NonZeroU32::new(1).unwrap();
+
+        assert_eq!(
+            db.insert(id, &[RowVal::U32(0), RowVal::Bytes(vec![])]),
+            Err(DbError::CheckFailed("count min 1".to_string()))
+        );
+        assert_eq!(
+            db.insert(id, &[RowVal::U32(1), RowVal::Bytes(b"toolong".to_vec())]),
+            Err(DbError::CheckFailed("name max 4".to_string()))
+        );
+        db.insert(id, &[RowVal::U32(10), RowVal::Bytes(b"ok".to_vec())])
+            .unwrap();
+        db.sync();
+        drop(db);
+
+        let db = DB::open("tests/constraints").unwrap().unwrap();
+        assert_eq!(db.schema.names, vec!["id", "count", "name"]);
+        assert_eq!(db.schema.defaults[1], Some(RowVal::U32(1)));
+        assert_eq!(
+            db.schema.constraints[1],
+            vec![Constraint::Min(1), Constraint::Max(10)]
+        );
+    }
+
+    #[test]
+    fn row_too_large() {
+        let schema = &[RowType::Id, RowType::Bytes, RowType::Bytes];
+        let mut db = DB::new("tests/row_too_large", schema).unwrap();
+
+        let id = NonZeroU32::new(1).unwrap();
+        let row = |len| vec![RowVal::Bytes(vec![0; len]), RowVal::Bytes(vec![])];
+        assert_eq!(
+            db.insert(id, &row(MAX_BYTES_LEN + 1)),
+            Err(DbError::RowTooLarge {
+                size: MAX_BYTES_LEN + 9,
+                max: MAX_BYTES_LEN + 8
+            })
+        );
+        assert_eq!(db.get(id), None);
+
+        db.insert(id, &row(MAX_BYTES_LEN)).unwrap();
+        db.sync();
+        assert_eq!(db.get(id), Some(row(MAX_BYTES_LEN)));
+    }
+
+    #[test]
+    fn overflow_pages() {
+        let file_name = "tests/overflow_pages";
+        let schema = &[RowType::Id, RowType::Bytes, RowType::U32];
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let large = |byte| {
+            vec![
+                RowVal::Bytes(vec![byte; 3 * PAGE_SIZE]),
+                RowVal::U32(byte as u32),
+            ]
+        };
+        let small = vec![RowVal::Bytes(vec![0; 10]), RowVal::U32(0)];
+        {
+            let mut db = DB::new(file_name, schema).unwrap();
+            db.insert(id(1), &large(1)).unwrap();
+            db.insert(id(2), &small).unwrap();
+            db.sync();
+            // the large row leaves only references on its page, so both fit
+            assert_eq!(db.page_headers().len(), 1);
+            assert_eq!(
+                db.wide_rows(MAX_ROW_SIZE),
+                vec![(id(1), 3 * PAGE_SIZE + 10)]
+            );
+            // one leaf, followed by its four overflow pages
+            assert_eq!(db.file_len(), (HEADER_SIZE + 5 * PAGE_SIZE) as u64);
+
+            // a leaf with spilled values is rewritten with the rest, even if
+            // it hasn't changed
+            db.insert(id(3), &large(3)).unwrap();
+            db.sync();
+            assert_eq!(db.get(id(1)), Some(large(1)));
+        }
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(id(1)), Some(large(1)));
+        assert_eq!(db.get(id(2)), Some(small.clone()));
+        assert_eq!(db.get(id(3)), Some(large(3)));
+        drop(db);
+
+        // a mapped database appends the overflow pages of the leaves it
+        // writes back to the end of the file
+        let mut db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert_eq!(db.get(id(3)), Some(large(3)));
+        db.insert(id(1), &large(4)).unwrap();
+        db.sync();
+        assert!(db.pages.is_empty());
+        assert_eq!(db.get(id(1)), Some(large(4)));
+        assert_eq!(db.scan(..).len(), 3);
+        drop(db);
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(id(1)), Some(large(4)));
+        assert_eq!(db.get(id(3)), Some(large(3)));
+    }
+
+    #[test]
+    fn columnar() {
+        let file_name = "tests/columnar";
+        let _ = fs::remove_dir_all(file_name);
+        let schema = &[RowType::Id, RowType::U32, RowType::Bytes];
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let row = |i: u32| vec![RowVal::U32(i), RowVal::Bytes(i.to_string().into_bytes())];
+        {
+            let mut db = DB::options()
+                .create(true)
+                .schema(schema)
+                .layout(Layout::Columns)
+                .open(file_name)
+                .unwrap();
+            for i in 1..=1000 {
+                db.insert(id(i), &row(i)).unwrap();
+            }
+            db.sync();
+        }
+
+        let mut db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert_eq!(db.layout, Layout::Columns);
+        let column = db.scan_column(2, id(10)..=id(12)).unwrap();
+        assert_eq!(
+            column,
+            (10..=12)
+                .map(|i| (id(i), RowVal::Bytes(i.to_string().into_bytes())))
+                .collect::<Vec<_>>()
+        );
+        // reading a column leaves the rest of the page undecoded
+        let mapped = db.mapped.as_ref().unwrap();
+        let (_, i) = mapped.find(id(10)).unwrap();
+        assert!(!mapped.with_page(i, &db.schema, |page| page.data.is_decoded()));
+        assert_eq!(db.scan_column(3, ..), None);
+
+        db.insert(id(5), &row(50)).unwrap();
+        assert_eq!(
+            db.scan_column(1, id(5)..=id(5)),
+            Some(vec![(id(5), RowVal::U32(50))])
+        );
+        assert_eq!(db.get(id(999)), Some(row(999)));
+        assert_eq!(db.scan(..).len(), 1000);
+
+        // switching back rewrites the pages by row
+        db.set_layout(Layout::Rows);
+        drop(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.layout, Layout::Rows);
+        assert_eq!(db.get(id(5)), Some(row(50)));
+        assert_eq!(db.scan(..).len(), 1000);
+    }
+
+    #[test]
+    fn lsm_engine() {
+        let file_name = "tests/lsm_engine";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        {
+            let mut db = DB::options()
+                .create(true)
+                .schema(DEFAULT_SCHEMA)
+                .engine(Engine::Lsm)
+                .open(file_name)
+                .unwrap();
+            for i in (1..=100).rev() {
+                db.insert(id(i), &[RowVal::U32(i)]).unwrap();
+            }
+            db.sync();
+            // the rows went to a run rather than to pages
+            assert!(db.pages.is_empty());
+            assert_eq!(db.lsm.runs.len(), 1);
+
+            assert_eq!(db.remove(id(10)), Some(vec![RowVal::U32(10)]));
+            db.insert(id(20), &[RowVal::U32(200)]).unwrap();
+            db.soft_delete(id(30)).unwrap();
+            assert_eq!(db.get(id(10)), None);
+            db.sync();
+            db.remove(id(40));
+            db.insert(id(101), &[RowVal::U32(101)]).unwrap();
+            // left in the WAL, to be replayed
+        }
+
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.engine, Engine::Lsm);
+        assert_eq!(db.lsm.runs.len(), 2);
+        let expected = |db: &DB| {
+            assert_eq!(db.get(id(10)), None);
+            assert_eq!(db.get(id(20)), Some(vec![RowVal::U32(200)]));
+            assert_eq!(db.get(id(30)), None);
+            assert_eq!(db.get(id(40)), None);
+            assert_eq!(db.get(id(101)), Some(vec![RowVal::U32(101)]));
+            let ids: Vec<_> = db
+                .scan(id(8)..=id(12))
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect();
+            assert_eq!(ids, [id(8), id(9), id(11), id(12)]);
+            assert_eq!(db.scan(..).len(), 98);
+        };
+        expected(&db);
+        let snapshot = db.snapshot().unwrap();
+        assert_eq!(snapshot.get(id(20)), Some(vec![RowVal::U32(200)]));
+        assert_eq!(snapshot.get(id(40)), None);
+        assert_eq!(snapshot.scan(..).len(), 98);
+        drop(snapshot);
+
+        // compacting merges the runs into the pages
+        db.compact();
+        assert!(db.lsm.is_empty());
+        assert!(!db.pages.is_empty());
+        expected(&db);
+
+        db.insert(id(102), &[RowVal::U32(102)]).unwrap();
+        db.sync();
+        db.set_engine(Engine::BTree);
+        assert!(db.lsm.is_empty());
+        drop(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.engine, Engine::BTree);
+        assert_eq!(db.scan(..).len(), 99);
+    }
+
+    #[test]
+    fn compaction_strategies() {
+        let file_name = "tests/compaction_strategies";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::options()
+            .create(true)
+            .schema(DEFAULT_SCHEMA)
+            .engine(Engine::Lsm)
+            .compaction(Compaction::Leveled {
+                level0_runs: 2,
+                base_size: 500,
+                ratio: 2,
+                levels: 2,
+            })
+            .open(file_name)
+            .unwrap();
+        let flush = |db: &mut DB, round: u32| {
+            for i in 1..=100 {
+                db.insert(id(i), &[RowVal::U32(round * i)]).unwrap();
+            }
+            db.sync();
+        };
+        flush(&mut db, 1);
+        assert!(!db.compact_due());
+        flush(&mut db, 2);
+        assert_eq!(db.lsm.debt(), 2 * db.lsm.runs[0].size);
+        // the two runs in level 0 merge into one in level 1, which is over
+        // its size and so merged into level 2, the last
+        assert!(db.compact_due());
+        assert_eq!(db.lsm.runs.len(), 1);
+        assert_eq!(db.lsm.runs[0].level, 2);
+        assert_eq!(db.lsm.metrics.compactions, 2);
+        assert!(db.pages.is_empty());
+        assert_eq!(db.get(id(7)), Some(vec![RowVal::U32(14)]));
+
+        // runs keep their levels across a reopen
+        drop(db);
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.lsm.runs[0].level, 2);
+        db.set_compaction(Compaction::SizeTiered {
+            min_runs: 2,
+            ratio: 1.5,
+            max_runs: 3,
+        });
+        flush(&mut db, 3);
+        flush(&mut db, 4);
+        assert_eq!(db.lsm.runs.len(), 3);
+        // three runs are merged into the pages
+        assert!(db.compact_due());
+        assert!(db.lsm.runs.is_empty());
+        assert_eq!(db.scan(..).len(), 100);
+        assert_eq!(db.get(id(7)), Some(vec![RowVal::U32(28)]));
+        let metrics = db.lsm.metrics;
+        assert!(metrics.compacted > 0);
+        assert!(metrics.write_amplification() > 1.0);
+        assert!(!db.set_compaction(Compaction::Leveled {
+            level0_runs: 2,
+            base_size: 500,
+            ratio: 1,
+            levels: 2,
+        }));
+    }
+
+    #[test]
+    fn merge_operator() {
+        let file_name = "tests/merge_operator";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let add = |current: Option<&[RowVal]>, operand: &[RowVal]| match (current, operand) {
+            (Some([RowVal::U32(n)]), [RowVal::U32(m)]) => vec![RowVal::U32(n + m)],
+            _ => operand.to_vec(),
+        };
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            assert_eq!(
+                db.merge(id(1), &[RowVal::U32(1)]),
+                Err(DbError::NoMergeOperator)
+            );
+            db.merge_operator = Some(MergeOperator::new(add));
+            db.insert(id(1), &[RowVal::U32(10)]).unwrap();
+            db.sync();
+            for _ in 0..3 {
+                db.merge(id(1), &[RowVal::U32(1)]).unwrap();
+            }
+            db.merge(id(2), &[RowVal::U32(5)]).unwrap();
+            assert_eq!(db.get(id(1)), Some(vec![RowVal::U32(13)]));
+            assert_eq!(db.get(id(2)), Some(vec![RowVal::U32(5)]));
+        }
+
+        // the merges are replayed from the WAL, which needs the operator
+        assert_eq!(DB::open(file_name).unwrap_err(), DbError::NoMergeOperator);
+        let mut db = DB::options().merge_operator(add).open(file_name).unwrap();
+        assert_eq!(db.get(id(1)), Some(vec![RowVal::U32(13)]));
+        assert_eq!(db.get(id(2)), Some(vec![RowVal::U32(5)]));
+        db.merge(id(2), &[RowVal::U32(5)]).unwrap();
+        db.sync();
+        drop(db);
+        assert_eq!(
+            DB::open(file_name).unwrap().unwrap().get(id(2)),
+            Some(vec![RowVal::U32(10)])
+        );
+    }
+
+    #[test]
+    fn prefix() {
+        let file_name = "tests/prefix";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::new(file_name, &[RowType::Id, RowType::U32, RowType::Bytes]).unwrap();
+        let names = [
+            "user:carol",
+            "group:admins",
+            "user:alice",
+            "users",
+            "user:bob",
+        ];
+        for (i, name) in (1..).zip(names) {
+            db.insert(id(i), &[RowVal::U32(i), RowVal::Bytes(name.into())])
+                .unwrap();
+            if i == 3 {
+                db.sync();
+            }
+        }
+        let ids = |db: &DB, prefix: &[u8]| -> Vec<u32> {
+            let rows = db.prefix(2, prefix).unwrap();
+            rows.into_iter().map(|(id, _)| id.get()).collect()
+        };
+        assert_eq!(ids(&db, b"user:"), [3, 5, 1]);
+        assert_eq!(ids(&db, b"user"), [3, 5, 1, 4]);
+        assert_eq!(ids(&db, b""), [2, 3, 5, 1, 4]);
+        db.remove(id(5));
+        assert_eq!(ids(&db, b"user:"), [3, 1]);
+        assert_eq!(
+            db.prefix(2, b"group")
+                .unwrap()
+                .into_iter()
+                .next()
+                .unwrap()
+                .1,
+            [RowVal::U32(2), RowVal::Bytes(b"group:admins".to_vec())]
+        );
+        assert_eq!(db.prefix(1, b"user"), None);
+    }
+
+    #[test]
+    fn bloom_filters() {
+        let file_name = "tests/bloom_filters";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        {
+            let mut db = DB::options()
+                .create(true)
+                .schema(DEFAULT_SCHEMA)
+                .bloom_filters(true)
+                .split_policy(SplitPolicy {
+                    fill_factor: 0.5,
+                    split_point: 0.5,
+                })
+                .open(file_name)
+                .unwrap();
+            for i in 1..=1000 {
+                db.insert(id(2 * i), &[RowVal::U32(i)]).unwrap();
+            }
+            db.sync();
+        }
+
+        // the setting is saved with the database
+        let db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert!(db.bloom_filters);
+        let mapped = db.mapped.as_ref().unwrap();
+        // most missing ids are turned away without reading their page in
+        let read = (1..=1000)
+            .filter(|&i| {
+                assert_eq!(db.get(id(2 * i - 1)), None);
+                let read = mapped.pool_size() > 0;
+                mapped.set_pool_capacity(0);
+                mapped.set_pool_capacity(crate::pool::DEFAULT_POOL_CAPACITY);
+                read
+            })
+            .count();
+        assert!(read < 50, "{read} of 1000 missing ids read a page");
+        assert_eq!(db.get(id(500)), Some(vec![RowVal::U32(250)]));
+        assert_eq!(db.scan(..).len(), 1000);
+    }
+
+    #[test]
+    fn full_leaves_without_bloom_filters() {
+        let file_name = "tests/full_leaves_without_bloom_filters";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        // 39 rows of 103 bytes and one of 51 fill a leaf to the last byte,
+        // ending in what reads as the length of a filter of 16 zero bytes
+        let mut last = vec![b'x'; 30];
+        last.extend([0; 16]);
+        last.extend(16u16.to_le_bytes());
+        {
+            let mut db = DB::options()
+                .create(true)
+                .schema(&[RowType::Id, RowType::Bytes])
+                .split_policy(SplitPolicy {
+                    fill_factor: 1.0,
+                    split_point: 0.5,
+                })
+                .open(file_name)
+                .unwrap();
+            // the last row goes in first, or it would start a page of its own
+            db.insert(id(40), &[RowVal::Bytes(last.clone())]).unwrap();
+            db.sync();
+            for i in 1..=39 {
+                db.insert(id(i), &[RowVal::Bytes(vec![b'x'; 100])]).unwrap();
+            }
+            db.sync();
+        }
+
+        let db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert!(!db.bloom_filters);
+        let mapped = db.mapped.as_ref().unwrap();
+        let (header, i) = mapped.leaves().next().unwrap();
+        assert_eq!(header.count, 40);
+        assert!(!header.bloom);
+        assert_eq!(
+            &mapped.bytes()[HEADER_SIZE + (i + 1) * PAGE_SIZE - 2..][..2],
+            &[16, 0]
+        );
+        assert_eq!(db.get(id(40)), Some(vec![RowVal::Bytes(last)]));
+        assert!((1..=40).all(|i| db.get(id(i)).is_some()));
+    }
+
+    #[test]
+    fn analyze() {
+        let file_name = "tests/analyze";
+        let id = |i| NonZeroU32::new(i).unwrap();
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            for i in 1..=10 {
+                db.insert(id(i), &[RowVal::U32(i % 4)]).unwrap();
+            }
+            // nothing's gathered until the database is analyzed
+            db.sync();
+            assert_eq!(db.stats, None);
+            let stats = db.analyze().unwrap();
+            assert_eq!(stats.rows, 10);
+            assert_eq!(stats.columns[1].distinct, 4);
+            assert_eq!(stats.columns[1].max, Some(RowVal::U32(3)));
+
+            // after which every sync gathers them again
+            db.insert(id(11), &[RowVal::U32(7)]).unwrap();
+            db.sync();
+        }
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        let stats = db.stats.as_ref().unwrap();
+        assert_eq!(stats.rows, 11);
+        assert_eq!(stats.columns[0].max, Some(RowVal::Id(id(11))));
+        assert_eq!(stats.columns[1].max, Some(RowVal::U32(7)));
+        drop(db);
+        // a new database under the same name starts without them
+        let db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        assert_eq!(db.stats, None);
+        drop(db);
+        assert_eq!(DB::open(file_name).unwrap().unwrap().stats, None);
+    }
+
+    #[test]
+    fn add_column() {
+        let mut db = DB::new("tests/add_column", DEFAULT_SCHEMA).unwrap();
+        for i in 1..=5 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        db.sync();
+
+        assert_eq!(
+            db.add_column("active", RowType::U32, RowVal::Bool(true)),
+            Err(DbError::SchemaMismatch)
+        );
+        db.add_column("active", RowType::Bool, RowVal::Bool(true))
+            .unwrap();
+
+        let id = NonZeroU32::new(1).unwrap();
+        assert_eq!(db.get(id), Some(vec![RowVal::U32(1), RowVal::Bool(true)]));
+        db.insert(
+            NonZeroU32::new(6).unwrap(),
+            &[RowVal::U32(6), RowVal::Bool(false)],
+        )
+        .unwrap();
+        assert_eq!(
+            db.insert(NonZeroU32::new(7).unwrap(), &[RowVal::U32(7)]),
+            Err(DbError::SchemaMismatch)
+        );
+
+        // the old page hasn't been rewritten yet
+        db.serialize();
+        let bytes = fs::read("tests/add_column/1.db").unwrap();
+        assert_eq!(
+            PageHeader::from_bytes(
+                bytes[HEADER_SIZE..HEADER_SIZE + PageHeader::size()]
+                    .try_into()
+                    .unwrap()
+            )
+            .version,
+            0
+        );
+
+        let pages = deserialize(&bytes, &db.schema);
+        let (page, _) = pages.first().unwrap();
+        assert_eq!(page.get(id), Some(vec![RowVal::U32(1), RowVal::Bool(true)]));
+    }
+
+    #[test]
+    fn drop_column() {
+        let schema = &[RowType::Id, RowType::U32, RowType::Bytes];
+        let mut db = DB::new("tests/drop_column", schema).unwrap();
+        for i in 1..=5 {
+            db.insert(
+                NonZeroU32::new(i).unwrap(),
+                &[RowVal::U32(i), RowVal::Bytes(b"dropped".to_vec())],
+            )
+            .unwrap();
+        }
+        db.sync();
+
+        assert_eq!(db.drop_column(0), Err(DbError::SchemaMismatch));
+        // old enough that none of the rows expire
+        let retention = Retention {
+            column: 1,
+            max_age: Duration::from_secs(u64::MAX),
+        };
+        assert!(db.set_retention(retention));
+        db.drop_column(2).unwrap();
+        assert_eq!(db.schema.retention, Some(retention));
+
+        let id = NonZeroU32::new(1).unwrap();
+        assert_eq!(db.get(id), Some(vec![RowVal::U32(1)]));
+        assert!(db.pages.iter().all(|(page, _)| page.dirty));
+
+        db.sync();
+        let bytes = fs::read("tests/drop_column/1.db").unwrap();
+        assert_eq!(
+            PageHeader::from_bytes(
+                bytes[HEADER_SIZE..HEADER_SIZE + PageHeader::size()]
+                    .try_into()
+                    .unwrap()
+            )
+            .version,
+            1
+        );
+        let pages = deserialize(&bytes, &db.schema);
+        assert_eq!(pages.first().unwrap().0.get(id), Some(vec![RowVal::U32(1)]));
+    }
+
+    #[test]
+    fn schema_size() {
+        let file_name = "tests/schema_size";
+        let wide = "tests/schema_size_wide";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        fn too_large<T>(res: Result<T, DbError>) -> bool {
+            matches!(res, Err(DbError::SchemaTooLarge { .. }))
+        }
+
+        // a schema that doesn't fit in the header is refused before anything
+        // is written, as is any change that would grow it past that
+        let mut schema = vec![RowType::Id];
+        schema.extend([RowType::U32; 2000]);
+        assert!(too_large(DB::new(wide, &schema)));
+        assert!(!std::path::Path::new(wide).exists());
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        db.insert(id(1), &[RowVal::U32(1)]).unwrap();
+        let large = RowVal::Bytes(vec![0; MAX_SCHEMA_SIZE]);
+        assert!(too_large(db.add_column(
+            "large",
+            RowType::Bytes,
+            large.clone()
+        )));
+        assert_eq!(db.schema.schema, DEFAULT_SCHEMA);
+        db.add_column("small", RowType::Bytes, RowVal::Bytes(vec![]))
+            .unwrap();
+        assert!(too_large(db.set_default(2, large)));
+        assert_eq!(db.schema.defaults[2], None);
+
+        // every change is kept for the pages still written before it
+        let default = RowVal::Bytes(vec![1; 100]);
+        let mut added = 0;
+        while db
+            .add_column(&format!("c{added}"), RowType::Bytes, default.clone())
+            .is_ok()
+        {
+            added += 1;
+        }
+        assert_eq!(db.schema.schema.len(), 3 + added);
+        // until a checkpoint writes every page at the current version
+        db.checkpoint().unwrap();
+        assert!(db.schema.changes.is_empty());
+        db.add_column("more", RowType::U32, RowVal::U32(7)).unwrap();
+        let columns = db.schema.schema.len();
+        drop(db);
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        let row = db.get(id(1)).unwrap();
+        assert_eq!(row.len(), columns - 1);
+        assert_eq!(row[2], default);
+        assert_eq!(row.last(), Some(&RowVal::U32(7)));
+    }
+
+    #[test]
+    fn page_headers() {
+        let schema = &[RowType::Id, RowType::Bytes];
+        let mut db = DB::new("tests/page_headers", schema).unwrap();
+        let row = vec![RowVal::Bytes(vec![0; MAX_ROW_SIZE / 2])];
+        for i in 1..=4 {
+            db.insert(NonZeroU32::new(i).unwrap(), &row).unwrap();
+        }
+        db.sync();
+        db.insert(NonZeroU32::new(5).unwrap(), &row).unwrap();
+        db.sync();
+        db.remove(NonZeroU32::new(1).unwrap());
+
+        // the emptied page is gone, the rest keep their place until the next sync
+        let headers = db.page_headers();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[0].start, NonZeroU32::new(2));
+        assert_eq!(headers[0].end, NonZeroU32::new(2));
+        assert_eq!(headers[0].offset, Some(HEADER_SIZE + PAGE_SIZE));
+        assert!(headers.iter().all(|header| !header.dirty
+            && header.count == 1
+            && header.fill > 0.5
+            && header.fill <= 1.0));
+    }
+
+    #[test]
+    fn split_policy() {
+        let mut db = DB::new("tests/split_policy", DEFAULT_SCHEMA).unwrap();
+        let invalid = SplitPolicy {
+            fill_factor: 1.5,
+            ..SplitPolicy::default()
+        };
+        assert!(!db.set_split_policy(invalid));
+        // pages are split once they're half full, keeping most rows on the
+        // left
+        assert!(db.set_split_policy(SplitPolicy {
+            fill_factor: 0.5,
+            split_point: 0.75,
+        }));
+        let rows = PAGE_SIZE / (1 + RowVal::U32(0).size());
+        for i in 1..=rows as u32 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
         }
+        db.sync();
+
+        let headers = db.page_headers();
+        assert!(headers.len() > 2);
+        assert!(headers.iter().all(|header| header.fill <= 0.5));
+        assert!(headers[0].fill > 0.3);
+        assert_eq!(db.scan(..).len(), rows);
     }
 
-    pub fn new_with_pages(
-        pages: BTreeSet<(Page, Option<usize>)>,
-        file_name: &str,
-        schema: &[RowType],
-    ) -> Self {
-        let epoch = 1;
-        let (db_file, wal_file, schema_file) = Self::setup_files(file_name, epoch);
+    #[test]
+    fn dirty_pages() {
+        let file_name = "tests/dirty_pages";
+        let schema = &[RowType::Id, RowType::Bytes];
+        // two rows to a page
+        let row = |byte| vec![RowVal::Bytes(vec![byte; MAX_ROW_SIZE / 3])];
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::new(file_name, schema).unwrap();
+        for i in 1..=8 {
+            db.insert(id(i), &row(0)).unwrap();
+        }
+        db.sync();
+        assert_eq!(db.pages.len(), 4);
+        // the header and the interior pages are always written
+        let always = 1 + btree::node_count(4);
+        assert_eq!(db.page_writes(db.epoch, false).len(), always);
 
-        Self {
-            file: db_file,
-            pages,
-            wal: WAL {
-                file: wal_file,
-                records: BTreeMap::new(),
-            },
-            epoch,
-            schema: Schema {
-                schema: schema.to_vec(),
-                file: schema_file,
-            },
+        // changing rows in place only writes their pages, found through the
+        // dirty set rather than by going through every page
+        db.insert(id(3), &row(1)).unwrap();
+        db.insert(id(4), &row(1)).unwrap();
+        db.remove(id(8));
+        for (id, val) in db.wal.records.clone() {
+            db.insert_to_page(id, &val);
+        }
+        assert!(!db.dirty.rescan);
+        assert_eq!(db.changed_pages().unwrap().len(), 2);
+        assert_eq!(db.page_writes(db.epoch, false).len(), always + 2);
+        db.sync();
+        assert!(db.pages.iter().all(|(page, _)| !page.dirty));
+        assert_eq!(db.page_writes(db.epoch, false).len(), always);
+
+        // emptying a page moves the ones after it, so they're all looked at
+        db.remove(id(1));
+        db.remove(id(2));
+        assert!(db.changed_pages().is_none());
+        db.sync();
+        drop(db);
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        let rows: Vec<_> = db
+            .scan(..)
+            .into_iter()
+            .map(|(id, row)| (id.get(), row))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (3, row(1)),
+                (4, row(1)),
+                (5, row(0)),
+                (6, row(0)),
+                (7, row(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        for checksum in [Checksum::Crc32c, Checksum::XxHash64] {
+            let file_name = "tests/checksums";
+            {
+                let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+                db.set_checksum(checksum);
+                db.insert(NonZeroU32::new(1).unwrap(), &[RowVal::U32(1)])
+                    .unwrap();
+                db.checkpoint().unwrap();
+                db.insert(NonZeroU32::new(2).unwrap(), &[RowVal::U32(2)])
+                    .unwrap();
+            }
+
+            // the WAL is only readable with the checksum the header names
+            let db = DB::open(file_name).unwrap().unwrap();
+            assert_eq!(db.checksum, checksum);
+            assert_eq!(db.wal.checksum, checksum);
+            for i in 1..=2 {
+                assert_eq!(
+                    db.get(NonZeroU32::new(i).unwrap()),
+                    Some(vec![RowVal::U32(i)])
+                );
+            }
         }
     }
 
-    fn setup_files(file_name: &str, epoch: u64) -> (File, File, File) {
-        let db_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(format!("{file_name}.{epoch}.db"))
-            .unwrap();
-        let wal_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .append(true)
-            .open(format!("{file_name}.{epoch}.wal"))
-            .unwrap();
-        let schema_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(format!("{file_name}.{epoch}.schema"))
+    #[test]
+    fn snapshot_mmap() {
+        let mut db = DB::new("tests/snapshot_mmap", DEFAULT_SCHEMA).unwrap();
+        for i in 1..=100 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        let snapshot = db.snapshot_mmap().unwrap();
+
+        // writes after the snapshot, in place or through a checkpoint, don't
+        // show up in it
+        for i in 1..=50 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(0)])
+                .unwrap();
+        }
+        db.remove(NonZeroU32::new(100).unwrap());
+        db.sync();
+        db.checkpoint().unwrap();
+        db.insert(NonZeroU32::new(1).unwrap(), &[RowVal::U32(1000)])
             .unwrap();
-        (db_file, wal_file, schema_file)
+        db.sync();
+
+        let scan = thread::spawn(move || {
+            let one = NonZeroU32::new(1).unwrap();
+            let hundred = NonZeroU32::new(100).unwrap();
+            assert_eq!(snapshot.get(one), Some(vec![RowVal::U32(1)]));
+            assert_eq!(snapshot.get(hundred), Some(vec![RowVal::U32(100)]));
+            let rows = snapshot.scan(NonZeroU32::new(41).unwrap()..=NonZeroU32::new(60).unwrap());
+            let expected: Vec<_> = (41..=60)
+                .map(|i| (NonZeroU32::new(i).unwrap(), vec![RowVal::U32(i)]))
+                .collect();
+            assert_eq!(rows, expected);
+            assert_eq!(snapshot.scan(..).len(), 100);
+        });
+        scan.join().unwrap();
+
+        assert_eq!(Arc::strong_count(&db.snapshots), 1);
+        assert_eq!(
+            db.get(NonZeroU32::new(1).unwrap()),
+            Some(vec![RowVal::U32(1000)])
+        );
     }
 
-    pub fn sync(&mut self) -> bool {
-        // apply all updates in wal to pages
-        for (id, val) in self.wal.records.clone() {
-            self.insert_to_page(id, &val);
+    #[test]
+    fn snapshot() {
+        let ids: [_; 5] = std::array::from_fn(|i| NonZeroU32::new(i as u32 + 1).unwrap());
+        let mut db = DB::new("tests/snapshot", DEFAULT_SCHEMA).unwrap();
+        assert_eq!(db.snapshot().unwrap().iter().count(), 0);
+        for &id in &ids[..3] {
+            db.insert(id, &[RowVal::U32(id.get())]).unwrap();
         }
+        db.sync();
+        // the snapshot takes in writes that haven't been synced yet
+        db.insert(ids[3], &[RowVal::U32(4)]).unwrap();
+        db.remove(ids[0]);
+        db.soft_delete(ids[1]).unwrap();
+        let snapshot = db.snapshot().unwrap();
 
-        self.serialize();
-        self.wal.records.clear();
-        self.wal.file.set_len(0).is_ok()
-    }
-
-    pub fn serialize(&self) {
-        let mut f = BufWriter::new(&self.file);
-        for (i, page) in self.pages.iter().enumerate() {
-            if page.0.dirty || page.1 != Some(i) {
-                let pos = SeekFrom::Start((i * PAGE_SIZE) as u64);
-                let _ = f.seek(pos);
-                let _ = f.write_all(&page.0.to_page_bytes());
-            }
-        }
-        // truncation is required otherwise the page might have stale pages that have been deleted.
-        let _ = self.file.set_len((self.pages.len() * PAGE_SIZE) as u64);
-    }
-
-    fn range_iter(&self, id: NonZeroU32) -> Range<(Page, Option<usize>)> {
-        self.pages.range(
-            (
-                Page {
-                    header: PageHeader {
-                        end: id,
-                        start: NonZeroU32::MIN,
-                        count: u32::MIN,
-                    },
-                    dirty: false,
-                    data: BTreeMap::new(),
-                    size: 0,
-                    schema: vec![],
-                },
-                None,
-            )
-                ..=(
-                    Page {
-                        header: PageHeader {
-                            end: NonZeroU32::MAX,
-                            start: id,
-                            count: u32::MAX,
-                        },
-                        dirty: true,
-                        data: BTreeMap::new(),
-                        size: usize::MAX,
-                        schema: vec![],
-                    },
-                    Some(usize::MAX),
-                ),
-        )
+        db.insert(ids[4], &[RowVal::U32(5)]).unwrap();
+        db.insert(ids[2], &[RowVal::U32(0)]).unwrap();
+        db.sync();
+        db.checkpoint().unwrap();
+
+        let scan = thread::spawn(move || {
+            let expected = vec![
+                (ids[2], vec![RowVal::U32(3)]),
+                (ids[3], vec![RowVal::U32(4)]),
+            ];
+            assert_eq!(snapshot.iter().collect::<Vec<_>>(), expected);
+            assert_eq!(snapshot.scan(..), expected);
+            assert_eq!(snapshot.get(ids[0]), None);
+            assert_eq!(snapshot.get(ids[1]), None);
+            assert_eq!(snapshot.get(ids[4]), None);
+        });
+        scan.join().unwrap();
+        assert_eq!(db.get(ids[2]), Some(vec![RowVal::U32(0)]));
     }
 
-    pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
-        // check wal first
-        if let Some(val) = self.wal.get(id) {
-            return Some(val);
+    #[test]
+    fn torn_wal_tail() {
+        let file_name = "tests/torn_wal_tail";
+        let wal_file_name = format!("{file_name}/1.wal.0001");
+        let (one, two) = (NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap());
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            db.insert(one, &[RowVal::U32(1)]).unwrap();
+            db.insert(two, &[RowVal::U32(2)]).unwrap();
+            crash(db);
         }
+        // the second record was only half written when we crashed
+        let wal = fs::read(&wal_file_name).unwrap();
+        let valid = wal.len() / 2;
+        fs::write(&wal_file_name, &wal[..wal.len() - 3]).unwrap();
 
-        // if not in pages, return None
-        if self.pages.is_empty() {
-            return None;
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(fs::metadata(&wal_file_name).unwrap().len(), valid as u64);
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.get(two), None);
+
+        // records appended after recovery replay too
+        db.insert(two, &[RowVal::U32(3)]).unwrap();
+        crash(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(two), Some(vec![RowVal::U32(3)]));
+    }
+
+    #[test]
+    fn checkpoint() {
+        let file_name = "tests/checkpoint";
+        let (one, two) = (NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap());
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            db.insert(one, &[RowVal::U32(1)]).unwrap();
+            db.checkpoint().unwrap();
+            assert_eq!(db.epoch, 2);
+            assert!(!fs::exists(format!("{file_name}/1.db")).unwrap());
+            assert!(!fs::exists(format!("{file_name}/1.wal.0001")).unwrap());
+
+            db.insert(two, &[RowVal::U32(2)]).unwrap();
+            crash(db);
         }
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.epoch, 2);
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.get(two), Some(vec![RowVal::U32(2)]));
+        drop(db);
 
-        // otherwise, find the page where start <= id <= end
-        let mut range = self.range_iter(id);
+        // a checkpoint that crashed before its rename leaves a partial file
+        // behind, which is ignored in favour of the last complete epoch
+        fs::write(format!("{file_name}/3.db.tmp"), [0; 10]).unwrap();
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.epoch, 2);
+        assert!(!fs::exists(format!("{file_name}/3.db.tmp")).unwrap());
+        assert_eq!(db.get(two), Some(vec![RowVal::U32(2)]));
+    }
 
-        match range.next() {
-            Some(next_page) => next_page.0.get(id),
-            None => None,
+    #[test]
+    fn mapped_checkpoint() {
+        let file_name = "tests/mapped_checkpoint";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        for i in 1..=500 {
+            db.insert(id(i), &[RowVal::U32(i)]).unwrap();
         }
+        db.sync();
+        drop(db);
+        let mut db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert!(db.mapped.is_some());
+        db.insert(id(1), &[RowVal::U32(0)]).unwrap();
+        db.checkpoint().unwrap();
+        assert!(db.mapped.is_some());
+        db.insert(id(501), &[RowVal::U32(501)]).unwrap();
+        db.sync();
+        assert_eq!(db.scan(..).len(), 501);
+        assert_eq!(db.get(id(250)), Some(vec![RowVal::U32(250)]));
+        drop(db);
+
+        let db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert_eq!(db.epoch, 2);
+        assert_eq!(db.scan(..).len(), 501);
+        assert_eq!(db.get(id(501)), Some(vec![RowVal::U32(501)]));
+        assert_eq!(db.get(id(1)), Some(vec![RowVal::U32(0)]));
     }
 
-    pub fn remove(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
-        // if in wal, remove from wal
-        if let Some(val) = self.wal.remove(id) {
-            return Some(val);
+    #[test]
+    fn background_sync() {
+        let file_name = "tests/background_sync";
+        let db = Arc::new(Mutex::new(DB::new(file_name, DEFAULT_SCHEMA).unwrap()));
+        let tasks = DB::start_background_tasks(&db, Duration::from_millis(1), 0);
+
+        db.lock()
+            .unwrap()
+            .insert(NonZeroU32::new(1).unwrap(), &[RowVal::U32(1)])
+            .unwrap();
+        let start = std::time::Instant::now();
+        while !db.lock().unwrap().wal.records.is_empty() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "WAL was never synced"
+            );
+            thread::sleep(Duration::from_millis(1));
         }
+        drop(tasks);
 
-        // if empty, return None
-        if self.pages.is_empty() {
-            return None;
+        let db = db.lock().unwrap();
+        assert_eq!(db.pages.len(), 1);
+        assert_eq!(
+            db.get(NonZeroU32::new(1).unwrap()),
+            Some(vec![RowVal::U32(1)])
+        );
+    }
+
+    #[test]
+    fn replay_skips_applied_records() {
+        let file_name = "tests/replay_skips_applied_records";
+        let wal_file_name = format!("{file_name}/1.wal.0001");
+        let (one, two) = (NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap());
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            db.insert(one, &[RowVal::U32(1)]).unwrap();
+            db.insert(two, &[RowVal::U32(2)]).unwrap();
+            let wal = fs::read(&wal_file_name).unwrap();
+            db.sync();
+            crash(db);
+            // crash after the pages were written but before the WAL was cleared
+            fs::write(&wal_file_name, wal).unwrap();
         }
 
-        // handle case when id is too small
-        if let Some(first_page) = self.pages.first() {
-            if id < first_page.0.header.start {
-                return None;
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert!(db.wal.records.is_empty());
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.pages.first().unwrap().0.header.lsn, 2);
+
+        // later records carry on from the pages' LSN, so they do replay
+        db.insert(one, &[RowVal::U32(3)]).unwrap();
+        assert_eq!(db.wal.lsn, 3);
+        crash(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(3)]));
+    }
+
+    #[test]
+    fn torn_page() {
+        let file_name = "tests/torn_page";
+        let db_file_name = format!("{file_name}/1.db");
+        let buffer_file_name = format!("{file_name}/1.dwb");
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            db.durability = Durability::WalPerCommit;
+            for i in 1..=3 {
+                db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                    .unwrap();
             }
+            db.sync();
+            assert!(!fs::exists(&buffer_file_name).unwrap());
+
+            // crash after the buffer was written, part way through the page
+            let writes = db.page_writes(db.epoch, true);
+            let len = fs::metadata(&db_file_name).unwrap().len();
+            double_write::write(&buffer_file_name, &writes, len, db.checksum).unwrap();
+            crash(db);
         }
+        let mut bytes = fs::read(&db_file_name).unwrap();
+        bytes[HEADER_SIZE + 8..HEADER_SIZE + PAGE_SIZE].fill(0xff);
+        fs::write(&db_file_name, bytes).unwrap();
 
-        // handle case when id is too large
-        if let Some(last_page) = self.pages.last() {
-            if id > last_page.0.header.end {
-                return None;
-            }
+        // the rows are only on the page, so they come back from the buffer
+        let db = DB::open(file_name).unwrap().unwrap();
+        for i in 1..=3 {
+            assert_eq!(
+                db.get(NonZeroU32::new(i).unwrap()),
+                Some(vec![RowVal::U32(i)])
+            );
         }
+        assert!(!fs::exists(&buffer_file_name).unwrap());
 
-        // otherwise, find the page where start <= id <= end
-        let mut range = self.range_iter(id);
+        // pages that can't be written back fail the open rather than
+        // panicking, and stay in the buffer for the next one
+        let writes = db.page_writes(db.epoch, true);
+        let len = fs::metadata(&db_file_name).unwrap().len();
+        crash(db);
+        double_write::write(&buffer_file_name, &writes, len, Checksum::default()).unwrap();
+        let bytes = fs::read(&db_file_name).unwrap();
+        fs::remove_file(&db_file_name).unwrap();
+        assert!(matches!(DB::open(file_name), Err(DbError::Io(_))));
+        assert!(fs::exists(&buffer_file_name).unwrap());
+        fs::write(&db_file_name, bytes).unwrap();
+        assert!(DB::open(file_name).unwrap().is_some());
+        assert!(!fs::exists(&buffer_file_name).unwrap());
+    }
 
-        let next_page = range.next().unwrap();
-        let mut fetched_page = next_page.clone();
+    #[test]
+    fn rewrite_limit() {
+        let file_name = "tests/rewrite_limit";
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        db.rewrite_limit = Some(HEADER_SIZE + PAGE_SIZE);
+        for i in 1..=3 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        db.remove(NonZeroU32::new(2).unwrap());
+        db.sync();
+        assert!(!fs::exists(format!("{file_name}/1.db.tmp")).unwrap());
 
-        self.pages.remove(&fetched_page);
-        let res = fetched_page.0.remove(id);
+        // pages written in place go to the file that was renamed into place
+        db.rewrite_limit = None;
+        db.insert(NonZeroU32::new(4).unwrap(), &[RowVal::U32(4)])
+            .unwrap();
+        db.sync();
+        drop(db);
 
-        // if the page still has items, readd it in
-        if fetched_page.0.header.count != 0 {
-            self.pages.insert(fetched_page);
+        let db = DB::open(file_name).unwrap().unwrap();
+        for (i, expected) in [(1, Some(1)), (2, None), (3, Some(3)), (4, Some(4))] {
+            assert_eq!(
+                db.get(NonZeroU32::new(i).unwrap()),
+                expected.map(|val| vec![RowVal::U32(val)])
+            );
         }
-
-        res
     }
 
-    pub fn insert(&mut self, id: NonZeroU32, val: &[RowVal]) {
-        // if in wal, insert into wal
-        if self.wal.insert(id, val) {
-            return;
-        }
+    #[test]
+    fn direct_io() {
+        let file_name = "tests/direct_io";
+        let id = |i| NonZeroU32::new(i).unwrap();
+        for durability in [Durability::Off, Durability::Full] {
+            let mut db = DB::new(file_name, &[RowType::Id, RowType::Bytes]).unwrap();
+            db.direct_io = true;
+            db.durability = durability;
+            let row = vec![RowVal::Bytes(vec![7; MAX_ROW_SIZE / 2])];
+            for i in 1..=3 {
+                db.insert(id(i), &row).unwrap();
+            }
+            db.sync();
+            db.remove(id(2));
+            db.sync();
+            drop(db);
 
-        self.insert_to_page(id, val)
+            let db = DB::open(file_name).unwrap().unwrap();
+            assert_eq!(db.get(id(1)), Some(row.clone()));
+            assert_eq!(db.get(id(2)), None);
+            assert_eq!(db.scan(..).len(), 2);
+            // two pages and the interior page over them
+            assert_eq!(
+                fs::metadata(format!("{file_name}/1.db")).unwrap().len(),
+                (HEADER_SIZE + 3 * PAGE_SIZE) as u64
+            );
+        }
     }
 
-    fn insert_to_page(&mut self, id: NonZeroU32, val: &[RowVal]) {
-        let mut new_record = vec![RowVal::Id(id)];
-        new_record.extend_from_slice(val);
-        let row_size = val.iter().map(|x| x.size()).sum::<u16>() as usize + 4;
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn fsync_errors() {
+        use crate::failpoint::{self, Failure};
 
-        // in case of an empty db
-        if self.pages.is_empty() {
-            let mut new_page = (Page::new_dirty(&[new_record], &self.schema.schema), None);
-            new_page.0.size += row_size;
-            self.pages.insert(new_page);
-            return;
+        let mut db = DB::new("tests/fsync_errors", DEFAULT_SCHEMA).unwrap();
+        let id = NonZeroU32::new(1).unwrap();
+        for durability in [Durability::WalPerCommit, Durability::Full] {
+            db.durability = durability;
+            failpoint::arm(1, Failure::Error);
+            assert!(matches!(
+                db.insert(id, &[RowVal::U32(1)]),
+                Err(DbError::Io(_))
+            ));
+            assert!(matches!(db.try_remove(id), Err(DbError::Io(_))));
+            failpoint::disarm();
         }
+        // without fsyncs, a write that can't be appended to the WAL still
+        // fails, and isn't made
+        db.durability = Durability::Off;
+        failpoint::arm(1, Failure::Error);
+        assert!(matches!(
+            db.insert(id, &[RowVal::U32(1)]),
+            Err(DbError::Io(_))
+        ));
+        failpoint::disarm();
+        assert_eq!(db.get(id), None);
+        assert_eq!(db.insert(id, &[RowVal::U32(1)]), Ok(()));
+    }
+
+    #[cfg(feature = "failpoints")]
+    #[test]
+    fn crash_recovery() {
+        use crate::failpoint::{self, Failure};
 
-        // handle prepend
-        if let Some(first_page) = self.pages.first() {
-            if id < first_page.0.header.start {
-                let mut first_page = self.pages.pop_first().unwrap();
-                first_page.0.size += row_size;
-                first_page.0.insert(&new_record);
-                self.pages.insert(first_page);
-
-                // split page that is too big
-                if let Some(first_page) = self.pages.first() {
-                    if first_page.0.size() > PAGE_SIZE {
-                        let (head, tail) = first_page.0.split();
-                        self.pages.pop_first();
-                        self.pages.insert((head, None));
-                        self.pages.insert((tail, None));
+        let file_name = "tests/crash_recovery";
+        for failure in [Failure::Error, Failure::Truncate] {
+            // crash at every write the workload makes in turn, until it gets
+            // through without reaching the failpoint
+            for nth in 1.. {
+                let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+                db.durability = Durability::WalPerCommit;
+                let mut committed = HashMap::new();
+
+                failpoint::arm(nth, failure);
+                for i in 1..=30 {
+                    let id = NonZeroU32::new(i % 7 + 1).unwrap();
+                    // an insert the failpoint cut short fails, as its fsync
+                    // does
+                    let inserted = db.insert(id, &[RowVal::U32(i)]);
+                    if failpoint::crashed() {
+                        break;
                     }
-                }
-                return;
-            }
-        }
-
-        // handle append
-        if let Some(last_page) = self.pages.last() {
-            if id > last_page.0.header.end {
-                let mut last_page = self.pages.pop_last().unwrap();
-                last_page.0.size += row_size;
-                last_page.0.insert(&new_record);
-                self.pages.insert(last_page);
-                // split page that is too big
-                if let Some(last_page) = self.pages.last() {
-                    if last_page.0.size() > PAGE_SIZE {
-                        let (head, tail) = last_page.0.split();
-                        self.pages.pop_last();
-                        self.pages.insert((head, None));
-                        self.pages.insert((tail, None));
+                    inserted.unwrap();
+                    committed.insert(id, i);
+
+                    if i % 4 == 0 {
+                        let id = NonZeroU32::new(i % 5 + 1).unwrap();
+                        db.remove(id);
+                        if failpoint::crashed() {
+                            break;
+                        }
+                        committed.remove(&id);
+                    }
+                    if i % 5 == 0 {
+                        db.sync();
                     }
+                    if i % 9 == 0 {
+                        let _ = db.checkpoint();
+                    }
+                }
+                let crashed = failpoint::crashed();
+                crash(db);
+                failpoint::disarm();
+
+                let db = DB::open(file_name).unwrap().unwrap();
+                for i in 1..=7 {
+                    let id = NonZeroU32::new(i).unwrap();
+                    assert_eq!(
+                        db.get(id),
+                        committed.get(&id).map(|val| vec![RowVal::U32(*val)]),
+                        "{failure:?} at write {nth}"
+                    );
+                }
+                if !crashed {
+                    break;
                 }
-                return;
             }
         }
+    }
 
-        let mut range = self.range_iter(id);
+    #[test]
+    fn durability() {
+        for durability in [
+            Durability::Off,
+            Durability::WalPerCommit,
+            Durability::Full,
+            Durability::Batched(Duration::from_millis(10)),
+        ] {
+            let file_name = "tests/durability";
+            let id = NonZeroU32::new(1).unwrap();
+            {
+                let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+                db.durability = durability;
+                db.insert(id, &[RowVal::U32(1)]).unwrap();
+                db.sync();
+                db.insert(id, &[RowVal::U32(2)]).unwrap();
+                crash(db);
+            }
+            let db = DB::open(file_name).unwrap().unwrap();
+            assert_eq!(db.get(id), Some(vec![RowVal::U32(2)]));
+        }
+    }
 
-        let next_page = range.next().unwrap();
-        let mut fetched_page = next_page.clone();
+    #[test]
+    fn scan() {
+        let mut db = DB::new("tests/scan", DEFAULT_SCHEMA).unwrap();
+        for i in 1..=10 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        db.sync();
+        db.insert(NonZeroU32::new(4).unwrap(), &[RowVal::U32(40)])
+            .unwrap();
+        db.remove(NonZeroU32::new(5).unwrap());
 
-        self.pages.remove(&fetched_page);
-        fetched_page.0.insert(&new_record);
+        let rows = db.scan(NonZeroU32::new(3).unwrap()..NonZeroU32::new(7).unwrap());
+        assert_eq!(
+            rows,
+            [(3, 3), (4, 40), (6, 6)]
+                .map(|(id, val)| (NonZeroU32::new(id).unwrap(), vec![RowVal::U32(val)]))
+        );
+        assert_eq!(db.scan(..).len(), 9);
+    }
 
-        if fetched_page.0.size() > PAGE_SIZE {
-            let (head, tail) = fetched_page.0.split();
-            self.pages.insert((head, None));
-            self.pages.insert((tail, None));
-        } else {
-            self.pages.insert(fetched_page);
+    #[test]
+    fn wal_segments() {
+        let file_name = "tests/wal_segments";
+        let segment = |n| format!("{file_name}/1.wal.{n:04}");
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            db.wal.segment_size = 64;
+            for i in 1..=20 {
+                db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                    .unwrap();
+            }
+            db.remove(NonZeroU32::new(3).unwrap());
+            assert!(db.wal.segment > 1);
+            assert!(fs::exists(segment(db.wal.segment)).unwrap());
+            crash(db);
         }
+
+        // records are replayed across every segment in order, and appends
+        // carry on in the last one
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        let segments = db.wal.segment;
+        assert!(segments > 1);
+        assert_eq!(db.get(NonZeroU32::new(3).unwrap()), None);
+        assert_eq!(db.scan(..).len(), 19);
+        db.insert(NonZeroU32::new(21).unwrap(), &[RowVal::U32(21)])
+            .unwrap();
+
+        // a sync leaves a single empty segment
+        db.sync();
+        assert_eq!(db.wal.segment, 1);
+        assert_eq!(fs::metadata(segment(1)).unwrap().len(), 0);
+        assert!(!fs::exists(segment(2)).unwrap());
+        assert_eq!(db.scan(..).len(), 20);
     }
-}
 
-pub fn deserialize(bytes: Vec<u8>, schema: &[RowType]) -> BTreeSet<(Page, Option<usize>)> {
-    assert!(bytes.len() % PAGE_SIZE == 0);
+    #[test]
+    fn open_mapped() {
+        let file_name = "tests/open_mapped";
+        let schema = &[RowType::Id, RowType::Bytes];
+        let row = vec![RowVal::Bytes(vec![1; MAX_ROW_SIZE / 2])];
+        let id = |i| NonZeroU32::new(i).unwrap();
+        {
+            let mut db = DB::new(file_name, schema).unwrap();
+            for i in 1..=4 {
+                db.insert(id(i), &row).unwrap();
+            }
+            db.sync();
+            db.insert(id(5), &row).unwrap();
+        }
+
+        let mut db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert!(db.pages.is_empty());
+        assert_eq!(db.memory_usage().pages, 0);
+        assert_eq!(db.get(id(2)), Some(row.clone()));
+        assert_eq!(db.get(id(5)), Some(row.clone()));
+        assert_eq!(db.get(id(6)), None);
+        assert_eq!(db.scan(id(2)..=id(5)).len(), 4);
+        assert_eq!(db.page_headers().len(), 4);
+        assert_eq!(db.wide_rows(MAX_ROW_SIZE / 2).len(), 5);
+        // the header is still written on close, without reading any pages
+        db.set_default(1, RowVal::Bytes(vec![2])).unwrap();
+        drop(db);
 
-    let mut pages = vec![];
+        let mut db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert_eq!(db.schema.defaults[1], Some(RowVal::Bytes(vec![2])));
+        // pages are written to in the buffer pool, which only holds as many
+        // as it's allowed unless they're dirty
+        db.set_pool_capacity(1);
+        assert_eq!(db.remove(id(3)), Some(row.clone()));
+        assert_eq!(db.get(id(1)), Some(row.clone()));
+        assert_eq!(db.get(id(4)), Some(row.clone()));
+        assert_eq!(db.get(id(3)), None);
+        // the pool holds the emptied page and the one with 4 on it
+        assert!(db.memory_usage().pages < 2 * row_size(&row));
+        db.insert(id(6), &row).unwrap();
+        db.insert(id(7), &row).unwrap();
+        db.sync();
+        assert!(db.pages.is_empty());
+        assert_eq!(db.pool_capacity(), Some(1));
+        assert_eq!(db.scan(..).len(), 6);
+        drop(db);
 
-    for i in 0..(bytes.len() / PAGE_SIZE) {
-        pages.push((
-            Page::from_bytes(&bytes[i * PAGE_SIZE..(i + 1) * PAGE_SIZE], schema),
-            Some(i),
+        // the tree is rebuilt over the pages split off to the end of the
+        // file, and the leaves still lead from one to the next in key order,
+        // past the emptied one
+        let db = DB::open_mapped(file_name).unwrap().unwrap();
+        assert_eq!(db.get(id(7)), Some(row.clone()));
+        assert_eq!(db.get(id(3)), None);
+        let headers = db.page_headers();
+        assert!(headers.iter().all(|header| header.count > 0));
+        assert!(headers.is_sorted_by_key(|header| header.start));
+        assert_eq!(headers.iter().map(|header| header.count).sum::<u32>(), 6);
+        drop(db);
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        let ids: Vec<_> = db.scan(..).into_iter().map(|(id, _)| id.get()).collect();
+        assert_eq!(ids, [1, 2, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn open_incompatible() {
+        fs::create_dir_all("tests/open_incompatible").unwrap();
+        fs::write("tests/open_incompatible/1.db", b"not a database").unwrap();
+        manifest::write("tests/open_incompatible", 1).unwrap();
+        assert!(matches!(
+            DB::open("tests/open_incompatible"),
+            Err(DbError::IncompatibleFile(_))
         ));
+        assert!(matches!(DB::open("tests/open_missing"), Ok(None)));
     }
 
-    BTreeSet::from_iter(pages)
-}
+    #[test]
+    fn memory_limit() {
+        let mut db = DB::new("tests/memory_limit", DEFAULT_SCHEMA).unwrap();
+        let row_size = row_size(&[RowVal::U32(0)]);
+        for i in 1..=4 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        db.insert(NonZeroU32::new(4).unwrap(), &[RowVal::U32(0)])
+            .unwrap();
+        db.remove(NonZeroU32::new(1).unwrap());
+        assert_eq!(
+            db.memory_usage(),
+            MemoryUsage {
+                pages: 0,
+                wal: 3 * row_size
+            }
+        );
 
-impl Drop for DB {
-    fn drop(&mut self) {
-        self.serialize();
+        // going over the limit writes the WAL back to the pages
+        db.set_memory_limit(Some(4 * row_size));
+        db.insert(NonZeroU32::new(5).unwrap(), &[RowVal::U32(5)])
+            .unwrap();
+        assert_eq!(db.memory_usage().wal, 4 * row_size);
+        db.insert(NonZeroU32::new(6).unwrap(), &[RowVal::U32(6)])
+            .unwrap();
+        assert_eq!(
+            db.memory_usage(),
+            MemoryUsage {
+                // ids 2 through 6 take a byte each on the page, rather than 4
+                pages: PageHeader::size() + 5 * (row_size - 3),
+                wal: 0
+            }
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{collections::HashMap, fs, num::NonZero};
+    #[test]
+    fn wal_record_limit() {
+        let mut db = DB::new("tests/wal_record_limit", DEFAULT_SCHEMA).unwrap();
+        db.set_wal_record_limit(Some(2));
+        for i in 1..=2 {
+            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)])
+                .unwrap();
+        }
+        assert_eq!(db.wal.records.len(), 2);
 
-    use insta::assert_yaml_snapshot as snapshot;
+        db.insert(NonZeroU32::new(3).unwrap(), &[RowVal::U32(3)])
+            .unwrap();
+        assert!(db.wal.records.is_empty());
+        assert_eq!(db.pages.first().unwrap().0.header.count, 3);
 
-    use quickcheck_macros::quickcheck;
+        // lowering the limit syncs straight away
+        db.insert(NonZeroU32::new(4).unwrap(), &[RowVal::U32(4)])
+            .unwrap();
+        db.set_wal_record_limit(Some(0));
+        assert!(db.wal.records.is_empty());
+    }
 
-    use super::*;
+    #[test]
+    fn stall_limit() {
+        let mut db = DB::new("tests/stall_limit", DEFAULT_SCHEMA).unwrap();
+        db.set_stall_limit(Some(16));
+        let id = |i| NonZeroU32::new(i).unwrap();
+        // each row takes 8 bytes, and the limit is checked before writing
+        for i in 1..=3 {
+            db.insert(id(i), &[RowVal::U32(i)]).unwrap();
+        }
+        let busy = Err(DbError::Busy {
+            wal_size: 24,
+            limit: 16,
+        });
+        assert_eq!(db.insert(id(4), &[RowVal::U32(4)]), busy);
+        assert_eq!(db.bulk_insert(&[(id(4), vec![RowVal::U32(4)])]), busy);
+        assert_eq!(db.get(id(4)), None);
 
-    const DEFAULT_SCHEMA: &[RowType] = &[RowType::Id, RowType::U32];
+        // deletes still go through, and a sync lets inserts through again
+        assert_eq!(db.remove(id(1)), Some(vec![RowVal::U32(1)]));
+        db.sync();
+        db.insert(id(4), &[RowVal::U32(4)]).unwrap();
+        assert_eq!(db.get(id(4)), Some(vec![RowVal::U32(4)]));
+    }
 
     #[test]
-    fn read_write() {
-        let mut db = DB::new("tests/read_write", DEFAULT_SCHEMA);
+    fn remove_last_row() {
+        let mut db = DB::new("tests/remove_last_row", DEFAULT_SCHEMA).unwrap();
+        let one = NonZeroU32::new(1).unwrap();
+        let two = NonZeroU32::new(2).unwrap();
 
-        for i in 1..=5 {
-            db.insert(NonZeroU32::new(i).unwrap(), &[RowVal::U32(i)]);
+        db.insert(two, &[RowVal::U32(2)]).unwrap();
+        db.sync();
+        assert_eq!(db.remove(two), Some(vec![RowVal::U32(2)]));
+        assert!(db.pages.is_empty());
+
+        db.insert(one, &[RowVal::U32(1)]).unwrap();
+        db.sync();
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.get(two), None);
+        assert_eq!(db.pages.first().unwrap().0.header.start, Some(one));
+    }
+
+    #[test]
+    fn soft_delete() {
+        let file_name = "tests/soft_delete";
+        let ids: Vec<_> = (1..=3).map(|i| NonZeroU32::new(i).unwrap()).collect();
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        for &id in &ids {
+            db.insert(id, &[RowVal::U32(id.get())]).unwrap();
         }
+        db.sync();
 
-        db.serialize();
+        assert_eq!(db.soft_delete(ids[1]), Ok(true));
+        assert_eq!(db.soft_delete(ids[1]), Ok(false));
+        assert_eq!(db.get(ids[1]), None);
+        let scanned = |rows: Vec<(NonZeroU32, Vec<RowVal>)>| {
+            rows.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+        assert_eq!(scanned(db.scan(..)), [ids[0], ids[2]]);
+        assert_eq!(scanned(db.scan_with_deleted(..)), ids);
+
+        assert_eq!(db.restore(ids[1]), Ok(Some(vec![RowVal::U32(2)])));
+        assert_eq!(db.restore(ids[1]), Ok(None));
+        assert_eq!(db.get(ids[1]), Some(vec![RowVal::U32(2)]));
+
+        // tombstones are replayed from the WAL, and a sync purges their rows
+        db.soft_delete(ids[1]).unwrap();
+        db.soft_delete(ids[2]).unwrap();
+        db.insert(ids[2], &[RowVal::U32(4)]).unwrap();
+        drop(db);
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(ids[1]), None);
+        assert_eq!(db.get(ids[2]), Some(vec![RowVal::U32(4)]));
         db.sync();
+        assert_eq!(db.restore(ids[1]), Ok(None));
+        assert_eq!(scanned(db.scan_with_deleted(..)), [ids[0], ids[2]]);
+    }
+
+    #[test]
+    fn with_row() {
+        let ids: Vec<_> = (1..=3).map(|i| NonZeroU32::new(i).unwrap()).collect();
+        let mut db = DB::new("tests/with_row", DEFAULT_SCHEMA).unwrap();
+        db.insert(ids[0], &[RowVal::U32(1)]).unwrap();
+        db.sync();
+        db.insert(ids[1], &[RowVal::U32(2)]).unwrap();
 
-        let bytes = fs::read("tests/read_write.1.db").unwrap();
+        // one row is on a page and the other in the WAL
+        let first = |row: &[RowVal]| row[0].clone();
+        assert_eq!(db.with_row(ids[0], first), Some(RowVal::U32(1)));
+        assert_eq!(db.with_row(ids[1], first), Some(RowVal::U32(2)));
+        assert_eq!(db.with_row(ids[2], first), None);
+        db.soft_delete(ids[0]).unwrap();
+        assert_eq!(db.with_row(ids[0], first), None);
+    }
 
-        let deserialized = deserialize(bytes, DEFAULT_SCHEMA);
+    #[test]
+    fn transactions() {
+        let file_name = "tests/transactions";
+        let wal_file_name = format!("{file_name}/1.wal.0001");
+        let ids: Vec<_> = (1..=3).map(|i| NonZeroU32::new(i).unwrap()).collect();
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        db.insert(ids[0], &[RowVal::U32(1)]).unwrap();
+        db.sync();
 
-        snapshot!(deserialized);
+        let mut txn = db.begin();
+        txn.insert(ids[1], &[RowVal::U32(2)]);
+        txn.remove(ids[0]);
+        // only the transaction sees its writes until it commits
+        assert_eq!(txn.get(&db, ids[0]), Ok(None));
+        assert_eq!(txn.get(&db, ids[1]), Ok(Some(vec![RowVal::U32(2)])));
+        assert_eq!(db.get(ids[0]), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.get(ids[1]), None);
+        db.commit(txn).unwrap();
+        assert_eq!(db.get(ids[0]), None);
+        assert_eq!(db.get(ids[1]), Some(vec![RowVal::U32(2)]));
+
+        // one bad row rejects the whole transaction
+        let mut txn = db.begin();
+        txn.insert(ids[2], &[RowVal::U32(3)]);
+        txn.insert(ids[0], &[RowVal::Bool(true)]);
+        assert_eq!(db.commit(txn), Err(DbError::SchemaMismatch));
+        assert_eq!(db.get(ids[2]), None);
+
+        // a transaction cut short by a crash isn't replayed
+        let mut txn = db.begin();
+        txn.insert(ids[2], &[RowVal::U32(3)]);
+        txn.remove(ids[1]);
+        db.commit(txn).unwrap();
+        crash(db);
+        let torn = fs::read(&wal_file_name).unwrap();
+        fs::write(&wal_file_name, &torn[..torn.len() - 3]).unwrap();
+
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(ids[1]), Some(vec![RowVal::U32(2)]));
+        assert_eq!(db.get(ids[2]), None);
+        // nor are writes logged after it taken as part of it
+        db.insert(ids[0], &[RowVal::U32(4)]).unwrap();
+        crash(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(ids[0]), Some(vec![RowVal::U32(4)]));
+        assert_eq!(db.get(ids[2]), None);
     }
 
     #[test]
-    fn insert_loop() {
-        let mut db = DB::new("tests/insert_loop", DEFAULT_SCHEMA);
+    fn write_batch() {
+        let file_name = "tests/write_batch";
+        let wal_file_name = format!("{file_name}/1.wal.0001");
+        let (one, two) = (NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap());
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        db.insert(one, &[RowVal::U32(1)]).unwrap();
 
-        for i in 1..=510 {
-            db.insert(NonZero::new(i).unwrap(), &[RowVal::U32(i)]);
-        }
+        let mut batch = WriteBatch::new();
+        batch.insert(two, &[RowVal::U32(2)]);
+        batch.remove(one);
+        db.apply(batch.clone()).unwrap();
+        assert_eq!(db.get(one), None);
+        assert_eq!(db.get(two), Some(vec![RowVal::U32(2)]));
+
+        // a batch torn by a crash is dropped as a whole
+        db.insert(one, &[RowVal::U32(1)]).unwrap();
+        db.apply(batch).unwrap();
+        crash(db);
+        let wal = fs::read(&wal_file_name).unwrap();
+        fs::write(&wal_file_name, &wal[..wal.len() - 3]).unwrap();
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.get(two), Some(vec![RowVal::U32(2)]));
+    }
 
+    #[test]
+    fn apply_once() {
+        let file_name = "tests/apply_once";
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.insert(id(1), &[RowVal::U32(1)]);
+        assert_eq!(db.apply_once(7, batch.clone()), Ok(true));
+        db.insert(id(1), &[RowVal::U32(2)]).unwrap();
+        // a retry doesn't overwrite the write made since
+        assert_eq!(db.apply_once(7, batch.clone()), Ok(false));
+        assert_eq!(db.get(id(1)), Some(vec![RowVal::U32(2)]));
+
+        // the key is replayed from the WAL, and logged again whenever the
+        // WAL is cleared
+        crash(db);
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert!(db.requests.contains(7));
         db.sync();
+        crash(db);
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert!(db.requests.contains(7));
+        db.checkpoint().unwrap();
+        drop(db);
+        let mut db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.apply_once(7, batch.clone()), Ok(false));
+        assert_eq!(db.apply_once(8, batch), Ok(true));
+        assert_eq!(db.get(id(1)), Some(vec![RowVal::U32(1)]));
+    }
 
-        snapshot!(db.pages);
+    #[test]
+    fn compare_and_swap() {
+        let mut db = DB::new("tests/compare_and_swap", DEFAULT_SCHEMA).unwrap();
+        let one = NonZeroU32::new(1).unwrap();
+        let (a, b) = ([RowVal::U32(1)], [RowVal::U32(2)]);
+
+        assert_eq!(db.compare_and_swap(one, Some(&a), Some(&b)), Ok(false));
+        assert_eq!(db.compare_and_swap(one, None, Some(&a)), Ok(true));
+        assert_eq!(db.compare_and_swap(one, None, Some(&b)), Ok(false));
+        assert_eq!(db.get(one), Some(a.to_vec()));
+        assert_eq!(db.compare_and_swap(one, Some(&a), Some(&b)), Ok(true));
+        assert_eq!(db.get(one), Some(b.to_vec()));
+
+        db.sync();
+        assert_eq!(db.compare_and_swap(one, Some(&a), None), Ok(false));
+        assert_eq!(db.compare_and_swap(one, Some(&b), None), Ok(true));
+        assert_eq!(db.get(one), None);
+        assert_eq!(
+            db.compare_and_swap(one, None, Some(&[RowVal::Bool(true)])),
+            Err(DbError::SchemaMismatch)
+        );
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut db = DB::new("tests/subscribe", DEFAULT_SCHEMA).unwrap();
+        let one = NonZeroU32::new(1).unwrap();
+        let two = NonZeroU32::new(2).unwrap();
+        let events = db.subscribe();
+        let dropped = db.subscribe();
+        drop(dropped);
+
+        db.insert(one, &[RowVal::U32(1)]).unwrap();
+        db.sync();
+        db.bulk_insert(&[(two, vec![RowVal::U32(2)])]).unwrap();
+        db.remove(one);
+        // nothing was removed, so nothing is sent
+        db.remove(one);
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                ChangeEvent {
+                    lsn: 1,
+                    record: WALRecord::Insert(one, vec![RowVal::U32(1)]),
+                },
+                ChangeEvent {
+                    lsn: 2,
+                    record: WALRecord::Insert(two, vec![RowVal::U32(2)]),
+                },
+                ChangeEvent {
+                    lsn: 3,
+                    record: WALRecord::Delete(one),
+                },
+            ]
+        );
+        assert_eq!(db.subscribers.len(), 1);
+    }
+
+    #[test]
+    fn get_as_of() {
+        let mut db = DB::new("tests/get_as_of", DEFAULT_SCHEMA).unwrap();
+        db.history.set_retention(10);
+        let one = NonZeroU32::new(1).unwrap();
+        let two = NonZeroU32::new(2).unwrap();
+
+        db.insert(two, &[RowVal::U32(2)]).unwrap();
+        db.insert(one, &[RowVal::U32(1)]).unwrap();
+        let inserted = db.wal.lsn;
+        db.sync();
+        db.bulk_insert(&[(one, vec![RowVal::U32(2)]), (one, vec![RowVal::U32(3)])])
+            .unwrap();
+        db.remove(one);
+
+        // history reaches back past the sync that wrote the row to a page
+        assert_eq!(db.get_as_of(one, inserted - 1), Ok(None));
+        assert_eq!(db.get_as_of(one, inserted), Ok(Some(vec![RowVal::U32(1)])));
+        assert_eq!(
+            db.get_as_of(one, inserted + 1),
+            Ok(Some(vec![RowVal::U32(2)]))
+        );
+        assert_eq!(
+            db.get_as_of(one, inserted + 2),
+            Ok(Some(vec![RowVal::U32(3)]))
+        );
+        assert_eq!(db.get_as_of(one, db.wal.lsn), Ok(None));
+
+        // history isn't kept across a restart
+        db.sync();
+        drop(db);
+        let db = DB::open("tests/get_as_of").unwrap().unwrap();
+        assert!(db.get_as_of(one, inserted).is_err());
+    }
+
+    #[quickcheck]
+    fn fuzz_db_variable_rows(records: HashMap<NonZeroU32, u16>) -> bool {
+        let schema = &[RowType::Id, RowType::Bytes];
+        let mut db = DB::new("tests/fuzz_db_variable_rows", schema).unwrap();
+
+        let records: HashMap<_, _> = records
+            .into_iter()
+            .map(|(id, len)| {
+                (
+                    id,
+                    vec![RowVal::Bytes(vec![0; len as usize % (MAX_ROW_SIZE - 6)])],
+                )
+            })
+            .collect();
+        for (id, vals) in &records {
+            db.insert(*id, vals).unwrap();
+        }
+        db.sync();
+
+        db.pages.iter().all(|(page, _)| page.size() <= PAGE_SIZE)
+            && records
+                .into_iter()
+                .all(|(id, vals)| db.get(id) == Some(vals))
     }
 
     #[quickcheck]
     fn fuzz_db_get_insert(records: HashMap<NonZeroU32, u32>) -> bool {
-        let mut db = DB::new("tests/fuzz_db_get", DEFAULT_SCHEMA);
+        let mut db = DB::new("tests/fuzz_db_get", DEFAULT_SCHEMA).unwrap();
 
         for (id, val) in &records {
-            db.insert(*id, &[RowVal::U32(*val)]);
+            db.insert(*id, &[RowVal::U32(*val)]).unwrap();
         }
 
         records
@@ -363,4 +4678,76 @@ mod tests {
             .map(|(id, val)| db.get(id) == Some(vec![RowVal::U32(val)]))
             .all(|f| f)
     }
+
+    #[quickcheck]
+    fn fuzz_db_reopen(table: Table) -> bool {
+        let file_name = "tests/fuzz_db_reopen";
+        {
+            let mut db = DB::new(file_name, &table.schema).unwrap();
+            for (id, vals) in &table.rows {
+                db.insert(*id, vals).unwrap();
+            }
+            db.sync();
+        }
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        db.schema.schema == table.schema
+            && table
+                .rows
+                .into_iter()
+                .all(|(id, vals)| db.get(id) == Some(vals))
+    }
+
+    #[test]
+    fn reopen_replays_wal() {
+        let file_name = "tests/reopen_replays_wal";
+        let (one, two, three) = (
+            NonZero::new(1).unwrap(),
+            NonZero::new(2).unwrap(),
+            NonZero::new(3).unwrap(),
+        );
+        {
+            let mut db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+            db.insert(one, &[RowVal::U32(1)]).unwrap();
+            db.insert(two, &[RowVal::U32(2)]).unwrap();
+            db.sync();
+            db.remove(one);
+            db.insert(three, &[RowVal::U32(3)]).unwrap();
+            // skip Drop so the pages on disk still hold the deleted row,
+            // like they would after a crash
+            crash(db);
+        }
+
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.get(one), None);
+        assert_eq!(db.get(two), Some(vec![RowVal::U32(2)]));
+        assert_eq!(db.get(three), Some(vec![RowVal::U32(3)]));
+    }
+
+    #[test]
+    fn locked() {
+        let file_name = "tests/locked";
+        let db = DB::new(file_name, DEFAULT_SCHEMA).unwrap();
+        assert_eq!(
+            DB::open(file_name).unwrap_err(),
+            DbError::Locked(file_name.to_string())
+        );
+        // creating one in its place fails the same way, and leaves it be
+        assert_eq!(
+            DB::new(file_name, DEFAULT_SCHEMA).unwrap_err(),
+            DbError::Locked(file_name.to_string())
+        );
+        assert_eq!(
+            DB::options()
+                .create(true)
+                .schema(DEFAULT_SCHEMA)
+                .open(file_name)
+                .unwrap_err(),
+            DbError::Locked(file_name.to_string())
+        );
+        drop(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        crash(db);
+        assert!(DB::open(file_name).unwrap().is_some());
+    }
 }
@@ -0,0 +1,166 @@
+//! Direct I/O, for `DB::direct_io`. Pages are copied into 4KB-aligned
+//! buffers and written with `O_DIRECT`, straight to the disk instead of
+//! through the OS page cache, so writing out a large database doesn't evict
+//! everything else from the cache or leave a backlog of writeback to stall
+//! on later.
+
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    io,
+    ops::{Deref, DerefMut},
+    path::Path,
+    ptr::NonNull,
+};
+
+use crate::double_write::PageWrite;
+
+/// What direct I/O needs buffers, offsets and lengths to be multiples of.
+pub const ALIGN: usize = 4096;
+
+/// A zeroed buffer whose start is aligned to `ALIGN`.
+#[derive(Debug)]
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// the buffer is owned, like a `Vec<u8>`
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize) -> Self {
+        if len == 0 {
+            return Self {
+                ptr: NonNull::dangling(),
+                len,
+            };
+        }
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, ALIGN).unwrap()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+        }
+    }
+}
+
+/// Whether every write starts and ends on an `ALIGN` boundary, as direct
+/// I/O needs. Pages do unless the crate is built with `small_pages`.
+pub fn aligned(writes: &[PageWrite]) -> bool {
+    writes
+        .iter()
+        .all(|(offset, bytes)| offset % ALIGN as u64 == 0 && bytes.len() % ALIGN == 0)
+}
+
+/// Opens `path` for direct writes. Fails where the OS or the filesystem
+/// doesn't support direct I/O, as tmpfs doesn't.
+#[cfg(target_os = "linux")]
+pub fn open(path: impl AsRef<Path>) -> io::Result<File> {
+    use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
+
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open(_path: impl AsRef<Path>) -> io::Result<File> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Writes every `(offset, bytes)` in `writes` through `file`, copying each
+/// into an aligned buffer first. The writes have to be `aligned`.
+#[cfg(target_os = "linux")]
+pub fn write_all_at(file: &File, writes: &[PageWrite]) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    if !aligned(writes) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("direct writes have to be aligned to {ALIGN} bytes"),
+        ));
+    }
+    let mut buf = AlignedBuf::new(
+        writes
+            .iter()
+            .map(|(_, bytes)| bytes.len())
+            .max()
+            .unwrap_or(0),
+    );
+    for (offset, bytes) in writes {
+        let buf = &mut buf[..bytes.len()];
+        buf.copy_from_slice(bytes);
+        file.write_all_at(buf, *offset)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn write_all_at(_file: &File, _writes: &[PageWrite]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn aligned_writes() {
+        let buf = AlignedBuf::new(3 * ALIGN);
+        assert_eq!(buf.as_ptr() as usize % ALIGN, 0);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        assert!(aligned(&[
+            (0, vec![0; ALIGN]),
+            (2 * ALIGN as u64, vec![0; 2 * ALIGN])
+        ]));
+        assert!(!aligned(&[(1, vec![0; ALIGN])]));
+        assert!(!aligned(&[(0, vec![0; 10])]));
+
+        let path = "tests/direct_writes";
+        fs::write(path, []).unwrap();
+        // not every filesystem supports direct I/O
+        let Ok(file) = open(path) else {
+            return;
+        };
+        let writes = [(ALIGN as u64, vec![2; ALIGN]), (0, vec![1; ALIGN])];
+        write_all_at(&file, &writes).unwrap();
+        assert!(write_all_at(&file, &[(0, vec![1; 10])]).is_err());
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(bytes.len(), 2 * ALIGN);
+        assert!(bytes[..ALIGN].iter().all(|&b| b == 1));
+        assert!(bytes[ALIGN..].iter().all(|&b| b == 2));
+        fs::remove_file(path).unwrap();
+    }
+}
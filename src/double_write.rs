@@ -0,0 +1,127 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+};
+
+use crate::{
+    checksum::Checksum,
+    failpoint::{self, DbFile},
+};
+
+/// A write of `bytes` at `offset` in the `.db` file.
+pub type PageWrite = (u64, Vec<u8>);
+
+/// Writes land in the double-write buffer first, and are only copied to their
+/// place in the `.db` file once the buffer is on disk. A write torn by a power
+/// loss can then be redone from the buffer on the next open.
+///
+/// The buffer holds the checksum algorithm it uses, the number of writes and
+/// the length the `.db` file ends up with, then each write as its offset,
+/// length, checksum and bytes. It names its own algorithm since it's redone
+/// before the header saying which one the database uses is read.
+pub fn write(path: &str, writes: &[PageWrite], len: u64, checksum: Checksum) -> io::Result<()> {
+    let mut res = vec![checksum.id()];
+    res.extend((writes.len() as u32).to_le_bytes());
+    res.extend(len.to_le_bytes());
+    for (offset, bytes) in writes {
+        res.extend(offset.to_le_bytes());
+        res.extend((bytes.len() as u32).to_le_bytes());
+        res.extend(checksum.hash(bytes).to_le_bytes());
+        res.extend(bytes);
+    }
+    let file = failpoint::open(
+        OpenOptions::new().write(true).create(true).truncate(true),
+        path,
+    )?;
+    (&file).write_all(&res)?;
+    file.sync_all()
+}
+
+/// Reads back the buffer at `path`. Returns `None` if it's empty, missing or
+/// not completely written, since then the `.db` file was never touched.
+pub fn read(path: &str) -> Option<(Vec<PageWrite>, u64)> {
+    let bytes = fs::read(path).ok()?;
+    let checksum = Checksum::from_id(*bytes.first()?)?;
+    let count = u32::from_le_bytes(bytes.get(1..5)?.try_into().unwrap());
+    let len = u64::from_le_bytes(bytes.get(5..13)?.try_into().unwrap());
+
+    let mut writes = vec![];
+    let mut i = 13;
+    for _ in 0..count {
+        let offset = u64::from_le_bytes(bytes.get(i..i + 8)?.try_into().unwrap());
+        let size = u32::from_le_bytes(bytes.get(i + 8..i + 12)?.try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes.get(i + 12..i + 16)?.try_into().unwrap());
+        let page = bytes.get(i + 16..i + 16 + size)?;
+        if checksum.hash(page) != crc {
+            return None;
+        }
+        writes.push((offset, page.to_vec()));
+        i += 16 + size;
+    }
+    Some((writes, len))
+}
+
+/// Copies `writes` to their places in `file` and cuts it to `len`.
+pub fn apply(file: &DbFile, writes: &[PageWrite], len: u64) -> io::Result<()> {
+    write_pages(file, writes)?;
+    // truncation is required otherwise the page might have stale pages that have been deleted.
+    file.set_len(len)
+}
+
+#[cfg(not(all(feature = "io_uring", target_os = "linux", not(feature = "failpoints"))))]
+fn write_pages(file: &DbFile, writes: &[PageWrite]) -> io::Result<()> {
+    use std::io::{BufWriter, Seek, SeekFrom};
+
+    let mut f = BufWriter::new(file);
+    for (offset, bytes) in writes {
+        f.seek(SeekFrom::Start(*offset))?;
+        f.write_all(bytes)?;
+    }
+    f.flush()
+}
+
+/// With io_uring, every page is written at once. Failpoints count writes
+/// made through `DbFile`, so they keep to the plain writes above.
+#[cfg(all(feature = "io_uring", target_os = "linux", not(feature = "failpoints")))]
+fn write_pages(file: &DbFile, writes: &[PageWrite]) -> io::Result<()> {
+    crate::uring::write_all_at(file, writes)
+}
+
+/// Redoes the writes left in the buffer at `path` against the `.db` file at
+/// `db_path`, in case a crash tore them, then empties the buffer.
+pub fn recover(path: &str, db_path: &str) -> io::Result<()> {
+    if let Some((writes, len)) = read(path) {
+        let file = failpoint::open(OpenOptions::new().write(true), db_path)?;
+        apply(&file, &writes, len)?;
+        file.sync_all()?;
+    }
+    clear(path)
+}
+
+/// Empties the buffer once its writes are safely in the `.db` file.
+pub fn clear(path: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torn_buffer_is_ignored() {
+        let path = "tests/torn_buffer_is_ignored.dwb";
+        let writes = vec![(0, vec![1; 16]), (16, vec![2; 16])];
+        write(path, &writes, 32, Checksum::XxHash64).unwrap();
+        assert_eq!(read(path), Some((writes, 32)));
+
+        let bytes = fs::read(path).unwrap();
+        fs::write(path, &bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(read(path), None);
+
+        clear(path).unwrap();
+        assert_eq!(read(path), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! Logical dumps: a database written out as the shell commands that make it
+//! again, a `create table` with its columns and then an `insert` for each
+//! row. Unlike a backup, a dump doesn't depend on the file format, so it can
+//! move a table between versions of the database, or just be read. `import`
+//! replays one into a new database.
+
+use std::{
+    io::{self, BufRead, Write},
+    num::NonZeroU32,
+};
+
+use crate::{
+    db::DB,
+    error::DbError,
+    row::{RowType, RowVal, Schema},
+    schema::{self, format_value, parse_value, split_columns, type_name},
+};
+
+/// Writes a dump of every visible row of `db` to `out`.
+pub fn write(db: &DB, out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "create table {} ({})",
+        db.file_name,
+        columns(&db.schema)
+    )?;
+    for (id, vals) in db.scan(..) {
+        write!(out, "insert {id}")?;
+        for val in &vals {
+            write!(out, ", {}", format_value(val))?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/// The name of a column of `schema`. Columns made without names are named
+/// after their position.
+pub fn column_name(schema: &Schema, i: usize) -> String {
+    match schema.names[i].as_str() {
+        "" if i == 0 => "id".to_string(),
+        "" => format!("column{i}"),
+        name => name.to_string(),
+    }
+}
+
+/// The columns of `schema` the way `schema::parse` reads them.
+pub fn columns(schema: &Schema) -> String {
+    let mut res = vec![];
+    for (i, &row_type) in schema.schema.iter().enumerate() {
+        let mut column = column_name(schema, i);
+        if row_type != RowType::Id {
+            column.push_str(&format!(":{}", type_name(row_type)));
+        }
+        if let Some(default) = &schema.defaults[i] {
+            column.push_str(&format!(" = {}", format_value(default)));
+        }
+        for constraint in &schema.constraints[i] {
+            column.push_str(&format!(" {constraint}"));
+        }
+        res.push(column);
+    }
+    res.join(", ")
+}
+
+/// Replays the dump read from `reader` into a new database under
+/// `file_name`, whatever table the dump was taken of, and syncs it. The dump
+/// has to start with its `create table`; after that every line is an
+/// `insert`, and blank lines are skipped.
+pub fn import(file_name: &str, reader: impl BufRead) -> Result<DB, DbError> {
+    let mut db: Option<DB> = None;
+    for (i, line) in reader.lines().enumerate() {
+        let invalid = |reason: String| DbError::InvalidDump {
+            line: i + 1,
+            reason,
+        };
+        let line = line.map_err(|e| invalid(e.to_string()))?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match &mut db {
+            None => {
+                let columns = line
+                    .strip_prefix("create table ")
+                    .and_then(|create| create.split_once('('))
+                    .and_then(|(_, columns)| columns.trim_end().strip_suffix(')'))
+                    .ok_or_else(|| invalid("expected a create table".to_string()))?;
+                let columns = schema::parse(columns).map_err(|e| invalid(e.to_string()))?;
+                db = Some(DB::from_columns(file_name, &columns)?);
+            }
+            Some(db) => {
+                let (id, vals) = line
+                    .strip_prefix("insert ")
+                    .and_then(parse_insert)
+                    .ok_or_else(|| invalid("expected an insert".to_string()))?;
+                db.insert(id, &vals).map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+    }
+    let mut db = db.ok_or(DbError::InvalidDump {
+        line: 0,
+        reason: "the dump is empty".to_string(),
+    })?;
+    db.sync();
+    Ok(db)
+}
+
+/// The id and values of an insert, `$id, $val, ...`.
+fn parse_insert(s: &str) -> Option<(NonZeroU32, Vec<RowVal>)> {
+    let mut vals = split_columns(s).into_iter();
+    let id = vals.next()?.trim().parse().ok()?;
+    let vals = vals.map(parse_value).collect::<Option<_>>()?;
+    Some((id, vals))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, num::NonZeroU32};
+
+    use crate::{row::RowVal, schema};
+
+    use super::*;
+
+    #[test]
+    fn dump() {
+        let file_name = "tests/dump";
+        let _ = fs::remove_dir_all(file_name);
+        let parsed =
+            schema::parse(r#"id, count:u32 = 0 min 1, name:text = "a \"b\"" max 20, active:bool"#)
+                .unwrap();
+        let mut db = DB::from_columns(file_name, &parsed).unwrap();
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let row = |count, name: &[u8], active| {
+            vec![
+                RowVal::U32(count),
+                RowVal::Bytes(name.to_vec()),
+                RowVal::Bool(active),
+            ]
+        };
+        db.insert(id(2), &row(5, b"x, y", true)).unwrap();
+        db.insert(id(1), &row(1, b"\\\n\xff", false)).unwrap();
+
+        let mut out = vec![];
+        db.dump(&mut out).unwrap();
+        let dump = String::from_utf8(out).unwrap();
+        assert_eq!(
+            dump,
+            r#"create table tests/dump (id, count:u32 = 0 min 1, name:text = "a \"b\"" max 20, active:bool)
+insert 1, 1, "\\\x0a\xff", false
+insert 2, 5, "x, y", true
+"#
+        );
+
+        // the columns read back as they were
+        let create = dump.lines().next().unwrap();
+        let (_, read) = create.split_once('(').unwrap();
+        assert_eq!(
+            schema::parse(read.strip_suffix(')').unwrap()).unwrap(),
+            parsed
+        );
+        let unnamed = Schema::new(&[RowType::Id, RowType::Ttl]);
+        assert_eq!(columns(&unnamed), "id, column1:ttl");
+    }
+
+    #[test]
+    fn import_dump() {
+        let file_name = "tests/import_dump";
+        let copy = "tests/import_dump_copy";
+        let _ = fs::remove_dir_all(file_name);
+        let _ = fs::remove_dir_all(copy);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let parsed = schema::parse(r#"id, name:text = "none", n:u32 max 100"#).unwrap();
+        let mut db = DB::from_columns(file_name, &parsed).unwrap();
+        for i in 1..=30 {
+            let name = format!("row \"{i}\", \\ {i}");
+            db.insert(id(i), &[RowVal::Bytes(name.into()), RowVal::U32(i)])
+                .unwrap();
+        }
+        db.remove(id(7));
+        let mut dump = vec![];
+        db.dump(&mut dump).unwrap();
+
+        let imported = DB::import_dump(copy, &dump[..]).unwrap();
+        assert!(imported.wal.records.is_empty());
+        assert_eq!(imported.scan(..), db.scan(..));
+        assert_eq!(imported.schema.defaults, db.schema.defaults);
+        drop(imported);
+        assert_eq!(DB::open(copy).unwrap().unwrap().scan(..).len(), 29);
+
+        let err = |dump: &str| DB::import_dump(copy, dump.as_bytes()).unwrap_err();
+        assert_eq!(
+            err("insert 1, \"a\", 1"),
+            DbError::InvalidDump {
+                line: 1,
+                reason: "expected a create table".to_string()
+            }
+        );
+        // constraints are checked like any insert's
+        let DbError::InvalidDump { line, .. } =
+            err("create table t (id, n:u32 max 1)\n\ninsert 1, 1\ninsert 2, 2")
+        else {
+            panic!("expected an invalid dump");
+        };
+        assert_eq!(line, 4);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{fmt::Display, io, num::NonZeroU32};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    /// The row's values don't match the column types of the schema.
+    SchemaMismatch,
+    /// A check registered with `DB::add_check` rejected the row.
+    CheckFailed(String),
+    /// The encoded row wouldn't fit on a page even with its values spilled,
+    /// or has a `Bytes` value longer than `MAX_BYTES_LEN`.
+    RowTooLarge { size: usize, max: usize },
+    /// No partition covers the id.
+    NoPartition(NonZeroU32),
+    /// A schema definition couldn't be parsed.
+    InvalidSchema(String),
+    /// The file isn't a database this build can read.
+    IncompatibleFile(String),
+    /// Ids start at 1, so 0 can't be used as a key.
+    ZeroId,
+    /// A shell command used a `:name` that was never `\set`.
+    UnknownVariable(String),
+    /// A read went further back than the history kept for `DB::get_as_of`.
+    HistoryUnavailable { lsn: u64, oldest: u64 },
+    /// An insert or delete hook refused the write.
+    Vetoed(String),
+    /// The WAL is past its stall limit. The write can be retried once a
+    /// `sync` has caught up.
+    Busy { wal_size: usize, limit: usize },
+    /// A transaction was rolled back to a savepoint it never set.
+    UnknownSavepoint(String),
+    /// Another process has the database open.
+    Locked(String),
+    /// There's no database under the name, and it wasn't to be created.
+    NotFound(String),
+    /// A setting passed to `DbOptions` is out of range.
+    InvalidOption(String),
+    /// `DB::merge` was called, or a WAL holding merges replayed, without a
+    /// merge operator.
+    NoMergeOperator,
+    /// A line of a dump passed to `DB::import_dump` couldn't be replayed.
+    InvalidDump { line: usize, reason: String },
+    /// The schema, with its names, defaults, constraints and changes, would
+    /// no longer fit in the file header.
+    SchemaTooLarge { size: usize, max: usize },
+    /// Reading or writing the database's files failed.
+    Io(String),
+    /// The database was opened with `DbOptions::read_only`, so it can't be
+    /// written to.
+    ReadOnly,
+    /// A crash cut page writes to the database short, which only opening it
+    /// for writing can finish.
+    NeedsRecovery(String),
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::SchemaMismatch => f.write_str("Schema did not match"),
+            DbError::CheckFailed(description) => {
+                f.write_str(&format!("Check failed: {description}"))
+            }
+            DbError::RowTooLarge { size, max } => f.write_str(&format!(
+                "Row is {size} bytes, larger than the maximum of {max}"
+            )),
+            DbError::NoPartition(id) => f.write_str(&format!("No partition covers {id}")),
+            DbError::InvalidSchema(reason) => f.write_str(&format!("Invalid schema: {reason}")),
+            DbError::IncompatibleFile(reason) => {
+                f.write_str(&format!("Incompatible database file: {reason}"))
+            }
+            DbError::ZeroId => f.write_str("Ids start at 1"),
+            DbError::UnknownVariable(name) => f.write_str(&format!("Unknown variable :{name}")),
+            DbError::HistoryUnavailable { lsn, oldest } => f.write_str(&format!(
+                "History only goes back to LSN {oldest}, not {lsn}"
+            )),
+            DbError::Vetoed(reason) => f.write_str(&format!("Vetoed: {reason}")),
+            DbError::Busy { wal_size, limit } => f.write_str(&format!(
+                "Busy: the WAL holds {wal_size} bytes, past its stall limit of {limit}"
+            )),
+            DbError::UnknownSavepoint(name) => f.write_str(&format!("Unknown savepoint {name}")),
+            DbError::Locked(name) => {
+                f.write_str(&format!("Database {name} is locked by another process"))
+            }
+            DbError::NotFound(name) => f.write_str(&format!("No database named {name}")),
+            DbError::InvalidOption(reason) => f.write_str(&format!("Invalid option: {reason}")),
+            DbError::NoMergeOperator => f.write_str("No merge operator is set"),
+            DbError::InvalidDump { line, reason } => {
+                f.write_str(&format!("Invalid dump at line {line}: {reason}"))
+            }
+            DbError::SchemaTooLarge { size, max } => f.write_str(&format!(
+                "Schema is {size} bytes, larger than the maximum of {max}"
+            )),
+            DbError::Io(reason) => f.write_str(&format!("I/O error: {reason}")),
+            DbError::ReadOnly => f.write_str("The database was opened read-only"),
+            DbError::NeedsRecovery(name) => f.write_str(&format!(
+                "Database {name} has unfinished page writes, open it for writing to recover them"
+            )),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::Io(e.to_string())
+    }
+}
@@ -0,0 +1,178 @@
+//! Writing rows out for other tools, as CSV or JSON Lines. `Export` is
+//! implemented for any iterator of rows, so the rows of `DB::scan`, or of
+//! `Snapshot::iter` without holding them all at once, can be written the
+//! same way.
+
+use std::{
+    io::{self, Write},
+    num::NonZeroU32,
+    str::FromStr,
+};
+
+use crate::{
+    dump::column_name,
+    row::{RowVal, Schema},
+};
+
+/// What rows are written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A header of column names, then a record per row, quoted as RFC 4180
+    /// quotes them, which `DB::import_csv` reads back.
+    Csv,
+    /// A JSON object per line, keyed by column name.
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" | "jsonl" => Ok(Format::JsonLines),
+            _ => Err(()),
+        }
+    }
+}
+
+pub trait Export: Iterator<Item = (NonZeroU32, Vec<RowVal>)> + Sized {
+    /// Writes every row to `out` as `format`, naming columns after
+    /// `schema`, and returns how many rows were written. `Bytes` values that
+    /// aren't UTF-8 are written with the invalid bytes replaced.
+    fn export(self, schema: &Schema, format: Format, out: &mut impl Write) -> io::Result<u64> {
+        let names: Vec<_> = (0..schema.schema.len())
+            .map(|i| column_name(schema, i))
+            .collect();
+        if format == Format::Csv {
+            let header: Vec<_> = names.iter().map(|name| csv_field(name)).collect();
+            writeln!(out, "{}", header.join(","))?;
+        }
+        let mut count = 0;
+        for (id, vals) in self {
+            let vals = std::iter::once(RowVal::Id(id)).chain(vals);
+            match format {
+                Format::Csv => {
+                    let fields: Vec<_> = vals.map(|val| csv_field(&text(&val))).collect();
+                    writeln!(out, "{}", fields.join(","))?;
+                }
+                Format::JsonLines => {
+                    let fields: Vec<_> = names
+                        .iter()
+                        .zip(vals)
+                        .map(|(name, val)| format!("{}:{}", json_string(name), json_value(&val)))
+                        .collect();
+                    writeln!(out, "{{{}}}", fields.join(","))?;
+                }
+            }
+            count += 1;
+        }
+        out.flush()?;
+        Ok(count)
+    }
+}
+
+impl<I: Iterator<Item = (NonZeroU32, Vec<RowVal>)>> Export for I {}
+
+/// A value as plain text, without the quotes `RowVal`'s `Display` puts
+/// around bytes.
+fn text(val: &RowVal) -> String {
+    match val {
+        RowVal::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        val => val.to_string(),
+    }
+}
+
+/// A CSV field, quoted if it holds a comma, quote or line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn json_value(val: &RowVal) -> String {
+    match val {
+        RowVal::Bytes(_) => json_string(&text(val)),
+        val => val.to_string(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{db::DB, schema};
+
+    use super::*;
+
+    #[test]
+    fn export() {
+        let file_name = "tests/export";
+        let copy = "tests/export_copy";
+        let _ = fs::remove_dir_all(file_name);
+        let _ = fs::remove_dir_all(copy);
+        let columns = schema::parse("id, name:text, count:u32, active:bool").unwrap();
+        let mut db = DB::from_columns(file_name, &columns).unwrap();
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let row = |name: &str, count, active| {
+            vec![
+                RowVal::Bytes(name.into()),
+                RowVal::U32(count),
+                RowVal::Bool(active),
+            ]
+        };
+        db.insert(id(1), &row("plain", 1, true)).unwrap();
+        db.insert(id(2), &row("a, \"quoted\"\nline", 2, false))
+            .unwrap();
+        db.insert(id(3), &row("left out", 3, true)).unwrap();
+
+        let mut csv = vec![];
+        let count = db
+            .scan(id(1)..=id(2))
+            .into_iter()
+            .export(&db.schema, Format::Csv, &mut csv)
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            String::from_utf8(csv.clone()).unwrap(),
+            "id,name,count,active\n1,plain,1,true\n2,\"a, \"\"quoted\"\"\nline\",2,false\n"
+        );
+        // which reads back as it was
+        let mut imported = DB::from_columns(copy, &columns).unwrap();
+        assert_eq!(imported.import_csv(&csv[..]).unwrap().inserted, 2);
+        assert_eq!(imported.scan(..), db.scan(..id(3)));
+
+        let mut json = vec![];
+        db.scan(id(2)..)
+            .into_iter()
+            .export(&db.schema, Format::JsonLines, &mut json)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            r#"{"id":2,"name":"a, \"quoted\"\nline","count":2,"active":false}
+{"id":3,"name":"left out","count":3,"active":true}
+"#
+        );
+        assert_eq!("jsonl".parse(), Ok(Format::JsonLines));
+        assert!("xml".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! The files the database writes through. With the `failpoints` feature
+//! they're wrapped in `FailpointFile`, which can be told to fail at the Nth
+//! write, so tests can crash the database part way through anything it does
+//! and check what survives.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+#[cfg(feature = "failpoints")]
+use std::{
+    cell::Cell,
+    fs::Metadata,
+    io::{Seek, SeekFrom, Write},
+};
+
+#[cfg(not(feature = "failpoints"))]
+pub type DbFile = File;
+
+#[cfg(feature = "failpoints")]
+pub type DbFile = FailpointFile;
+
+/// Opens a file the database writes through.
+pub fn open(options: &OpenOptions, path: impl AsRef<Path>) -> io::Result<DbFile> {
+    #[cfg(feature = "failpoints")]
+    return options.open(path).map(|file| FailpointFile { file });
+    #[cfg(not(feature = "failpoints"))]
+    options.open(path)
+}
+
+/// What happens to the write a failpoint fires on.
+#[cfg(feature = "failpoints")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The write fails without writing anything.
+    Error,
+    /// Only the first half of the write makes it to the file, like a write
+    /// torn by a power loss.
+    Truncate,
+}
+
+#[cfg(feature = "failpoints")]
+thread_local! {
+    static ARMED: Cell<Option<(u64, Failure)>> = const { Cell::new(None) };
+    static CRASHED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes the `nth` write on this thread from now on fail, counting from 1.
+/// After it fires every write, sync and truncation fails too, as if the
+/// process had died, until `disarm` is called.
+#[cfg(feature = "failpoints")]
+pub fn arm(nth: u64, failure: Failure) {
+    ARMED.set(Some((nth, failure)));
+    CRASHED.set(false);
+}
+
+#[cfg(feature = "failpoints")]
+pub fn disarm() {
+    ARMED.set(None);
+    CRASHED.set(false);
+}
+
+/// Whether the armed failpoint has fired.
+#[cfg(feature = "failpoints")]
+pub fn crashed() -> bool {
+    CRASHED.get()
+}
+
+#[cfg(feature = "failpoints")]
+fn crash_error() -> io::Error {
+    io::Error::other("failpoint")
+}
+
+#[cfg(feature = "failpoints")]
+fn check_alive() -> io::Result<()> {
+    if CRASHED.get() {
+        return Err(crash_error());
+    }
+    Ok(())
+}
+
+/// A `File` that fails once the armed failpoint fires.
+#[cfg(feature = "failpoints")]
+#[derive(Debug)]
+pub struct FailpointFile {
+    file: File,
+}
+
+#[cfg(feature = "failpoints")]
+impl FailpointFile {
+    pub fn sync_all(&self) -> io::Result<()> {
+        check_alive()?;
+        self.file.sync_all()
+    }
+
+    pub fn sync_data(&self) -> io::Result<()> {
+        check_alive()?;
+        self.file.sync_data()
+    }
+
+    pub fn set_len(&self, len: u64) -> io::Result<()> {
+        check_alive()?;
+        self.file.set_len(len)
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.file.metadata()
+    }
+
+    /// A plain handle to the file, for syncing it from another thread.
+    pub fn try_clone(&self) -> io::Result<File> {
+        check_alive()?;
+        self.file.try_clone()
+    }
+}
+
+#[cfg(feature = "failpoints")]
+impl Write for &FailpointFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        check_alive()?;
+        match ARMED.get() {
+            Some((1, failure)) => {
+                ARMED.set(None);
+                CRASHED.set(true);
+                if failure == Failure::Truncate {
+                    (&self.file).write_all(&buf[..buf.len() / 2])?;
+                }
+                Err(crash_error())
+            }
+            Some((nth, failure)) => {
+                ARMED.set(Some((nth - 1, failure)));
+                (&self.file).write(buf)
+            }
+            None => (&self.file).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        check_alive()?;
+        (&self.file).flush()
+    }
+}
+
+#[cfg(feature = "failpoints")]
+impl Write for FailpointFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(feature = "failpoints")]
+impl Seek for &FailpointFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        (&self.file).seek(pos)
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs::File,
+    io,
+    time::{Duration, Instant},
+};
+
+/// A file that can be forced to disk. Tests swap in fakes to see which
+/// syncs happen and in what order.
+pub trait SyncFile {
+    fn sync(&self) -> io::Result<()>;
+}
+
+impl SyncFile for File {
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+#[cfg(feature = "failpoints")]
+impl SyncFile for crate::failpoint::FailpointFile {
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// Batches the fsyncs of the page file and the WAL, so each is synced at
+/// most once per interval however many writes land in between.
+#[derive(Debug, Default)]
+pub struct FsyncScheduler {
+    pages_pending: bool,
+    wal_pending: bool,
+    last_flush: Option<Instant>,
+}
+
+impl FsyncScheduler {
+    pub fn pages_written(&mut self) {
+        self.pages_pending = true;
+    }
+
+    pub fn wal_written(&mut self) {
+        self.wal_pending = true;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pages_pending || self.wal_pending
+    }
+
+    /// Flushes whatever is pending if `interval` has passed since the last
+    /// flush. Returns whether anything was flushed.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        interval: Duration,
+        pages: &impl SyncFile,
+        wal: &impl SyncFile,
+    ) -> io::Result<bool> {
+        let due = self
+            .last_flush
+            .is_none_or(|last| now.duration_since(last) >= interval);
+        if !due || !self.is_pending() {
+            return Ok(false);
+        }
+        self.flush(now, pages, wal)?;
+        Ok(true)
+    }
+
+    /// Syncs whatever is pending straight away. The pages go first, since
+    /// the WAL can only be truncated once the pages it backs are on disk.
+    pub fn flush(
+        &mut self,
+        now: Instant,
+        pages: &impl SyncFile,
+        wal: &impl SyncFile,
+    ) -> io::Result<()> {
+        if self.pages_pending {
+            pages.sync()?;
+            self.pages_pending = false;
+        }
+        if self.wal_pending {
+            wal.sync()?;
+            self.wal_pending = false;
+        }
+        self.last_flush = Some(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    struct Recorder<'a> {
+        name: &'static str,
+        log: &'a RefCell<Vec<&'static str>>,
+    }
+
+    impl SyncFile for Recorder<'_> {
+        fn sync(&self) -> io::Result<()> {
+            self.log.borrow_mut().push(self.name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn coalesces_and_orders_syncs() {
+        let log = RefCell::new(vec![]);
+        let pages = Recorder {
+            name: "pages",
+            log: &log,
+        };
+        let wal = Recorder {
+            name: "wal",
+            log: &log,
+        };
+        let interval = Duration::from_millis(10);
+        let start = Instant::now();
+        let mut scheduler = FsyncScheduler::default();
+
+        // nothing to sync yet
+        assert!(!scheduler.tick(start, interval, &pages, &wal).unwrap());
+
+        scheduler.wal_written();
+        assert!(scheduler.tick(start, interval, &pages, &wal).unwrap());
+
+        // writes inside the interval wait for the next flush, and share it
+        for ms in 1..5 {
+            scheduler.wal_written();
+            let now = start + Duration::from_millis(ms);
+            assert!(!scheduler.tick(now, interval, &pages, &wal).unwrap());
+        }
+        let now = start + interval;
+        assert!(scheduler.tick(now, interval, &pages, &wal).unwrap());
+        assert_eq!(*log.borrow(), ["wal", "wal"]);
+
+        // the pages are always synced before the WAL
+        log.borrow_mut().clear();
+        scheduler.wal_written();
+        scheduler.pages_written();
+        scheduler.flush(now, &pages, &wal).unwrap();
+        assert_eq!(*log.borrow(), ["pages", "wal"]);
+        assert!(!scheduler.is_pending());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    btree::Root,
+    checksum::Checksum,
+    error::DbError,
+    lsm::Engine,
+    page::{Layout, PAGE_SIZE},
+    row::Schema,
+};
+
+/// Bytes reserved at the start of the `.db` file for the header. Pages start
+/// right after it.
+pub const HEADER_SIZE: usize = 4096;
+
+/// The most bytes the schema can take, in what's left of the header after
+/// the fields before it.
+pub const MAX_SCHEMA_SIZE: usize = HEADER_SIZE - 36;
+
+/// Identifies a file as one of ours.
+pub const MAGIC: [u8; 4] = *b"TKDB";
+
+/// Bumped whenever the on-disk layout changes in a way older builds can't read.
+pub const FORMAT_VERSION: u16 = 5;
+
+/// The low flag bits hold the checksum algorithm's id. CRC32 is 0, so files
+/// using it read the same as before the algorithm could be picked, while
+/// builds that predate the others refuse files using them.
+pub const CHECKSUM_FLAGS: u16 = 0b11;
+
+/// Set if the pages are laid out by column rather than by row.
+pub const COLUMNAR_FLAG: u16 = 1 << 2;
+
+/// Set if leaves are written with bloom filters over their ids. Leaves
+/// written without them read the same either way.
+pub const BLOOM_FLAG: u16 = 1 << 3;
+
+/// Set if the database stores its rows with the LSM engine.
+pub const LSM_FLAG: u16 = 1 << 4;
+
+/// Flags this build knows about. Any other set bit means the file was written
+/// by a newer build.
+pub const KNOWN_FLAGS: u16 = CHECKSUM_FLAGS | COLUMNAR_FLAG | BLOOM_FLAG | LSM_FLAG;
+
+/// The header stored at the start of the `.db` file, so a database file carries
+/// everything needed to read it back.
+#[derive(Debug, Clone)]
+pub struct FileHeader {
+    pub format_version: u16,
+    /// Flags besides the checksum, layout, bloom filters and engine, which
+    /// have their own fields.
+    pub flags: u16,
+    pub checksum: Checksum,
+    pub layout: Layout,
+    pub bloom_filters: bool,
+    pub engine: Engine,
+    pub page_size: u32,
+    pub page_count: u32,
+    pub epoch: u64,
+    /// The root of the B+tree over the pages, or `None` if there are no
+    /// rows.
+    pub root: Option<Root>,
+    pub schema: Schema,
+}
+
+impl FileHeader {
+    pub fn new(page_count: u32, epoch: u64, schema: Schema, checksum: Checksum) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            flags: 0,
+            checksum,
+            layout: Layout::default(),
+            bloom_filters: false,
+            engine: Engine::default(),
+            page_size: PAGE_SIZE as u32,
+            page_count,
+            epoch,
+            root: None,
+            schema,
+        }
+    }
+
+    /// Serializes the magic, format version, flags, page size, page count,
+    /// epoch, root, then the length prefixed schema, padded out to
+    /// `HEADER_SIZE`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let schema_bytes = self.schema.to_bytes();
+
+        let mut res = MAGIC.to_vec();
+        res.extend(self.format_version.to_le_bytes());
+        let layout = match self.layout {
+            Layout::Rows => 0,
+            Layout::Columns => COLUMNAR_FLAG,
+        };
+        let bloom = match self.bloom_filters {
+            true => BLOOM_FLAG,
+            false => 0,
+        };
+        let engine = match self.engine {
+            Engine::BTree => 0,
+            Engine::Lsm => LSM_FLAG,
+        };
+        let flags = self.flags | self.checksum.id() as u16 | layout | bloom | engine;
+        res.extend(flags.to_le_bytes());
+        res.extend(self.page_size.to_le_bytes());
+        res.extend(self.page_count.to_le_bytes());
+        res.extend(self.epoch.to_le_bytes());
+        // page 0 can be the root, so the index is stored one up and 0 marks
+        // no root
+        res.extend(self.root.map_or(0, |root| root.index + 1).to_le_bytes());
+        res.extend(self.root.map_or(0, |root| root.height).to_le_bytes());
+        res.extend((schema_bytes.len() as u32).to_le_bytes());
+        res.extend(schema_bytes);
+
+        // every change to the schema is checked with `Schema::check_size`, so
+        // this only catches a bug
+        assert!(
+            res.len() <= HEADER_SIZE,
+            "schema does not fit in the file header"
+        );
+        res.resize(HEADER_SIZE, 0);
+        res
+    }
+
+    /// Reads the header, refusing files this build can't read.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DbError> {
+        if bytes.len() < HEADER_SIZE || bytes[0..4] != MAGIC {
+            return Err(incompatible("not a database file".to_string()));
+        }
+        let format_version = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+        if format_version != FORMAT_VERSION {
+            return Err(incompatible(format!(
+                "format version {format_version}, expected {FORMAT_VERSION}"
+            )));
+        }
+        let flags = u16::from_le_bytes(bytes[6..8].try_into().unwrap());
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(incompatible(format!("unknown flags {flags:#06x}")));
+        }
+        let checksum_id = (flags & CHECKSUM_FLAGS) as u8;
+        let Some(checksum) = Checksum::from_id(checksum_id) else {
+            return Err(incompatible(format!("unknown checksum {checksum_id}")));
+        };
+        let page_size = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if page_size as usize != PAGE_SIZE {
+            return Err(incompatible(format!(
+                "page size {page_size}, expected {PAGE_SIZE}"
+            )));
+        }
+        let page_count = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let epoch = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let root = Root::from_bytes(bytes[24..32].try_into().unwrap());
+        let schema_len = u32::from_le_bytes(bytes[32..36].try_into().unwrap()) as usize;
+        if schema_len > MAX_SCHEMA_SIZE {
+            return Err(incompatible(format!(
+                "schema of {schema_len} bytes runs past the header"
+            )));
+        }
+        let schema = Schema::from_bytes(&bytes[36..36 + schema_len]);
+
+        Ok(Self {
+            format_version,
+            flags: flags & !(CHECKSUM_FLAGS | COLUMNAR_FLAG | BLOOM_FLAG | LSM_FLAG),
+            checksum,
+            layout: layout(bytes),
+            bloom_filters: flags & BLOOM_FLAG != 0,
+            engine: match flags & LSM_FLAG {
+                0 => Engine::BTree,
+                _ => Engine::Lsm,
+            },
+            page_size,
+            page_count,
+            epoch,
+            root,
+            schema,
+        })
+    }
+}
+
+/// The layout of the pages, from the flags of a file header that's already
+/// been read successfully.
+pub fn layout(bytes: &[u8]) -> Layout {
+    let flags = u16::from_le_bytes(bytes[6..8].try_into().unwrap());
+    match flags & COLUMNAR_FLAG {
+        0 => Layout::Rows,
+        _ => Layout::Columns,
+    }
+}
+
+fn incompatible(reason: String) -> DbError {
+    DbError::IncompatibleFile(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        db::Retention,
+        row::{RowType, RowVal, SchemaChange},
+    };
+
+    use super::*;
+
+    #[test]
+    fn serde() {
+        let mut schema = Schema::new(&[RowType::Id, RowType::Bytes]);
+        schema.names = vec!["id".to_string(), "name".to_string()];
+        schema.defaults[1] = Some(RowVal::Bytes(b"none".to_vec()));
+        schema.schema.push(RowType::Bool);
+        schema.names.push("active".to_string());
+        schema.defaults.push(None);
+        schema.constraints.push(vec![]);
+        schema.changes.push(SchemaChange::AddColumn {
+            row_type: RowType::Bool,
+            default: RowVal::Bool(false),
+        });
+        schema.checks.push("active rows have names".to_string());
+        schema.retention = Some(Retention {
+            column: 1,
+            max_age: Duration::from_secs(86400),
+        });
+        let mut header = FileHeader::new(3, 2, schema, Checksum::XxHash64);
+        header.layout = Layout::Columns;
+        header.bloom_filters = true;
+        header.engine = Engine::Lsm;
+        header.root = Some(Root {
+            index: 0,
+            height: 1,
+        });
+
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+
+        let deserialized = FileHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(deserialized.page_count, 3);
+        assert_eq!(deserialized.epoch, 2);
+        assert_eq!(deserialized.root, header.root);
+        assert_eq!(deserialized.checksum, Checksum::XxHash64);
+        assert_eq!(deserialized.layout, Layout::Columns);
+        assert!(deserialized.bloom_filters);
+        assert_eq!(deserialized.engine, Engine::Lsm);
+        assert_eq!(deserialized.schema.schema, header.schema.schema);
+        assert_eq!(deserialized.schema.names, header.schema.names);
+        assert_eq!(deserialized.schema.defaults, header.schema.defaults);
+        assert_eq!(deserialized.schema.changes, header.schema.changes);
+        assert_eq!(deserialized.schema.checks, header.schema.checks);
+        assert_eq!(deserialized.schema.retention, header.schema.retention);
+    }
+
+    #[test]
+    fn incompatible_files() {
+        let bytes = FileHeader::new(0, 1, Schema::new(&[RowType::Id]), Checksum::Crc32).to_bytes();
+
+        let mut not_ours = bytes.clone();
+        not_ours[0..4].copy_from_slice(b"SQLi");
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let mut flagged = bytes.clone();
+        flagged[6..8].copy_from_slice(&(1u16 << 15).to_le_bytes());
+        let mut unknown_checksum = bytes.clone();
+        unknown_checksum[6..8].copy_from_slice(&CHECKSUM_FLAGS.to_le_bytes());
+        let mut other_page_size = bytes.clone();
+        other_page_size[8..12].copy_from_slice(&(PAGE_SIZE as u32 * 2).to_le_bytes());
+        let mut long_schema = bytes.clone();
+        long_schema[32..36].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+
+        for bytes in [
+            &bytes[..10],
+            &not_ours,
+            &newer,
+            &flagged,
+            &unknown_checksum,
+            &other_page_size,
+            &long_schema,
+        ] {
+            assert!(matches!(
+                FileHeader::from_bytes(bytes),
+                Err(DbError::IncompatibleFile(_))
+            ));
+        }
+    }
+}
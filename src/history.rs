@@ -0,0 +1,133 @@
+use std::{collections::VecDeque, num::NonZeroU32};
+
+use crate::{error::DbError, row::RowVal};
+
+/// The values that recent writes replaced, so a read can roll a row back to
+/// how it was at an earlier LSN. Only the last `retention` writes are kept,
+/// and nothing is kept across a restart.
+#[derive(Debug, Default)]
+pub struct History {
+    /// Each write's LSN, the row it touched and the row's value before it.
+    versions: VecDeque<(u64, NonZeroU32, Option<Vec<RowVal>>)>,
+    retention: usize,
+    /// The earliest LSN reads can go back to. The writes at and before it
+    /// have been forgotten.
+    oldest: u64,
+}
+
+impl History {
+    /// An empty history that can serve reads from `lsn` on.
+    pub fn new(lsn: u64) -> Self {
+        Self {
+            oldest: lsn,
+            ..Self::default()
+        }
+    }
+
+    pub fn retention(&self) -> usize {
+        self.retention
+    }
+
+    pub fn oldest(&self) -> u64 {
+        self.oldest
+    }
+
+    /// Keeps the last `retention` writes, forgetting older ones straight
+    /// away. 0 turns history off.
+    pub fn set_retention(&mut self, retention: usize) {
+        self.retention = retention;
+        self.trim();
+    }
+
+    /// Records that the write at `lsn` replaced `before` as the value of `id`.
+    pub fn record(&mut self, lsn: u64, id: NonZeroU32, before: Option<Vec<RowVal>>) {
+        self.versions.push_back((lsn, id, before));
+        self.trim();
+    }
+
+    /// Forgets every write, for when old values can no longer be read back,
+    /// like after a schema change.
+    pub fn clear(&mut self, lsn: u64) {
+        self.versions.clear();
+        self.oldest = lsn;
+    }
+
+    fn trim(&mut self) {
+        while self.versions.len() > self.retention {
+            let Some((lsn, _, _)) = self.versions.pop_front() else {
+                break;
+            };
+            self.oldest = lsn;
+        }
+    }
+
+    /// Rolls `current`, the value of `id` now, back to what it was as of
+    /// `lsn` by undoing every later write to it.
+    pub fn as_of(
+        &self,
+        id: NonZeroU32,
+        lsn: u64,
+        current: Option<Vec<RowVal>>,
+    ) -> Result<Option<Vec<RowVal>>, DbError> {
+        if lsn < self.oldest {
+            return Err(DbError::HistoryUnavailable {
+                lsn,
+                oldest: self.oldest,
+            });
+        }
+        let mut val = current;
+        for (version_lsn, version_id, before) in self.versions.iter().rev() {
+            if *version_lsn <= lsn {
+                break;
+            }
+            if *version_id == id {
+                val = before.clone();
+            }
+        }
+        Ok(val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolls_back_and_forgets() {
+        let id = NonZeroU32::new(1).unwrap();
+        let other = NonZeroU32::new(2).unwrap();
+        let mut history = History::new(0);
+        history.set_retention(3);
+
+        // 1 is inserted at LSN 1, updated at 2 and deleted at 4
+        history.record(1, id, None);
+        history.record(2, id, Some(vec![RowVal::U32(1)]));
+        history.record(3, other, None);
+        let current = None;
+        assert_eq!(history.as_of(id, 0, current.clone()), Ok(None));
+
+        history.record(4, id, Some(vec![RowVal::U32(2)]));
+        assert_eq!(history.oldest(), 1);
+        assert_eq!(history.as_of(id, 4, current.clone()), Ok(None));
+        assert_eq!(
+            history.as_of(id, 3, current.clone()),
+            Ok(Some(vec![RowVal::U32(2)]))
+        );
+        assert_eq!(
+            history.as_of(id, 2, current.clone()),
+            Ok(Some(vec![RowVal::U32(2)]))
+        );
+        assert_eq!(
+            history.as_of(id, 1, current.clone()),
+            Ok(Some(vec![RowVal::U32(1)]))
+        );
+        assert_eq!(
+            history.as_of(id, 0, current.clone()),
+            Err(DbError::HistoryUnavailable { lsn: 0, oldest: 1 })
+        );
+
+        history.set_retention(0);
+        assert_eq!(history.oldest(), 4);
+        assert_eq!(history.as_of(id, 4, current), Ok(None));
+    }
+}
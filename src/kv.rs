@@ -0,0 +1,111 @@
+use std::{
+    num::NonZeroU32,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    db::DB,
+    error::DbError,
+    row::{RowType, RowVal},
+};
+
+const KV_SCHEMA: &[RowType] = &[RowType::Id, RowType::Bytes];
+
+/// A key-value store on top of a single `(Id, Bytes)` table, for when the
+/// storage engine is all that's needed.
+#[derive(Debug)]
+pub struct Kv {
+    pub db: DB,
+}
+
+fn key(k: u32) -> Result<NonZeroU32, DbError> {
+    NonZeroU32::new(k).ok_or(DbError::ZeroId)
+}
+
+fn value(mut vals: Vec<RowVal>) -> Vec<u8> {
+    match vals.pop() {
+        Some(RowVal::Bytes(bytes)) => bytes,
+        _ => unreachable!("kv tables only hold bytes"),
+    }
+}
+
+impl Kv {
+    /// Opens the store at `path`, creating it if it doesn't exist yet.
+    pub fn open(path: &str) -> Result<Self, DbError> {
+        let db = DB::options().create(true).schema(KV_SCHEMA).open(path)?;
+        Ok(Self { db })
+    }
+
+    pub fn put(&mut self, k: u32, v: &[u8]) -> Result<(), DbError> {
+        self.db.insert(key(k)?, &[RowVal::Bytes(v.to_vec())])
+    }
+
+    pub fn get(&self, k: u32) -> Option<Vec<u8>> {
+        self.db.get(key(k).ok()?).map(value)
+    }
+
+    /// Removes a key, returning its value if it was there.
+    pub fn delete(&mut self, k: u32) -> Option<Vec<u8>> {
+        self.db.remove(key(k).ok()?).map(value)
+    }
+
+    /// Every key and value with a key in `range`, in key order.
+    pub fn scan(&self, range: impl RangeBounds<u32>) -> Vec<(u32, Vec<u8>)> {
+        // every id is above 0, so a start of 0 is the same as no start, and
+        // a range ending at 0 is empty
+        let start = match range.start_bound() {
+            Bound::Included(&k) | Bound::Excluded(&k) if k == 0 => Bound::Unbounded,
+            bound => bound.map(|&k| key(k).unwrap()),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&0) | Bound::Excluded(&0) => return vec![],
+            bound => bound.map(|&k| key(k).unwrap()),
+        };
+        self.db
+            .scan((start, end))
+            .into_iter()
+            .map(|(id, vals)| (id.get(), value(vals)))
+            .collect()
+    }
+
+    /// Writes everything out to the page file.
+    pub fn flush(&mut self) -> bool {
+        self.db.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn put_get_delete_scan() {
+        let path = "tests/kv";
+        let _ = fs::remove_dir_all(path);
+        {
+            let mut kv = Kv::open(path).unwrap();
+            assert_eq!(kv.put(0, b"zero"), Err(DbError::ZeroId));
+            for k in 1..=5 {
+                kv.put(k, format!("value {k}").as_bytes()).unwrap();
+            }
+            kv.flush();
+            kv.put(2, b"two").unwrap();
+            assert_eq!(kv.delete(3), Some(b"value 3".to_vec()));
+            assert_eq!(kv.delete(3), None);
+        }
+
+        let kv = Kv::open(path).unwrap();
+        assert_eq!(kv.get(2), Some(b"two".to_vec()));
+        assert_eq!(kv.get(3), None);
+        assert_eq!(kv.get(0), None);
+        assert_eq!(
+            kv.scan(2..=4),
+            vec![(2, b"two".to_vec()), (4, b"value 4".to_vec())]
+        );
+        assert_eq!(kv.scan(0..2), vec![(1, b"value 1".to_vec())]);
+        assert_eq!(kv.scan(..0), vec![]);
+        assert_eq!(kv.scan(..).len(), 4);
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Each power of two is split into this many buckets, so a recorded value is
+/// off by at most 1/16th (about 6%).
+const SUB_BUCKETS: u64 = 16;
+const BUCKETS: usize = 61 * SUB_BUCKETS as usize;
+
+/// A log-linear (HDR style) histogram of microsecond latencies. Recording only
+/// touches atomics, so it works through a shared reference.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+fn bucket(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros() as u64;
+    let sub_bucket = (micros >> (exponent - 4)) & (SUB_BUCKETS - 1);
+    ((exponent - 3) * SUB_BUCKETS + sub_bucket) as usize
+}
+
+/// The smallest value that lands in `bucket`.
+fn bucket_start(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let exponent = bucket / SUB_BUCKETS + 3;
+    (SUB_BUCKETS + bucket % SUB_BUCKETS) << (exponent - 4)
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[bucket(micros)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Starts timing an operation, which is recorded when the timer drops.
+    pub fn time(self: &Arc<Self>) -> Timer {
+        Timer {
+            histogram: Arc::clone(self),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max.load(Ordering::Relaxed))
+    }
+
+    /// The latency at or below which `percentile` percent of operations
+    /// finished, rounded down to its bucket.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let target = ((percentile / 100.0) * self.count() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return Duration::from_micros(bucket_start(i));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// Records the time since it was created into a histogram when dropped.
+pub struct Timer {
+    histogram: Arc<Histogram>,
+    start: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.histogram.record(self.start.elapsed());
+    }
+}
+
+/// Latency histograms for each kind of operation on a `DB`.
+#[derive(Debug, Default)]
+pub struct Latencies {
+    pub get: Arc<Histogram>,
+    pub insert: Arc<Histogram>,
+    pub remove: Arc<Histogram>,
+    pub sync: Arc<Histogram>,
+}
+
+impl Display for Latencies {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:<8}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+            "op", "count", "p50", "p90", "p99", "p99.9", "max"
+        )?;
+        for (name, histogram) in [
+            ("get", &self.get),
+            ("insert", &self.insert),
+            ("remove", &self.remove),
+            ("sync", &self.sync),
+        ] {
+            writeln!(
+                f,
+                "{:<8}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+                name,
+                histogram.count(),
+                format!("{}us", histogram.percentile(50.0).as_micros()),
+                format!("{}us", histogram.percentile(90.0).as_micros()),
+                format!("{}us", histogram.percentile(99.0).as_micros()),
+                format!("{}us", histogram.percentile(99.9).as_micros()),
+                format!("{}us", histogram.max().as_micros()),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck_macros::quickcheck;
+
+    use super::*;
+
+    #[quickcheck]
+    fn fuzz_bucket_bounds(micros: u64) -> bool {
+        let i = bucket(micros);
+        bucket_start(i) <= micros && (i + 1 == BUCKETS || micros < bucket_start(i + 1))
+    }
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::default();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        assert_eq!(histogram.count(), 1000);
+        assert_eq!(histogram.max(), Duration::from_micros(1000));
+        // values are rounded down to their bucket, which is within 1/16th
+        for (percentile, expected) in [(50.0, 500), (90.0, 900), (99.0, 990)] {
+            let micros = histogram.percentile(percentile).as_micros() as u64;
+            assert!(micros <= expected && micros >= expected - expected / 16);
+        }
+    }
+}
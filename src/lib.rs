@@ -1,6 +1,47 @@
+#[cfg(feature = "tokio")]
+pub mod aio;
+pub mod backup;
+pub mod bloom;
+pub mod btree;
+pub mod checksum;
+pub mod client;
+pub mod copy;
+pub mod csv;
 pub mod db;
+pub mod direct;
+pub mod double_write;
+pub mod dump;
+pub mod error;
+pub mod export;
+pub mod failpoint;
+pub mod fsync;
+pub mod header;
+pub mod history;
+pub mod kv;
+pub mod latency;
+pub mod lsm;
+pub mod manifest;
+pub mod mmap;
+pub mod options;
+pub mod overflow;
 pub mod page;
+pub mod pages;
+pub mod partition;
+pub mod pool;
+pub mod replication;
+pub mod requests;
+pub mod resp;
 pub mod row;
+pub mod schema;
+pub mod session;
+pub mod shared;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
 pub mod transaction;
+pub mod undo;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub mod uring;
 pub mod utils;
 pub mod wal;
@@ -0,0 +1,654 @@
+//! The LSM engine, an alternative to rewriting pages on every `sync` for
+//! databases taking many writes to ids all over the table. With it, the
+//! WAL's rows and the ids deleted since the last sync make up the memtable,
+//! which a sync flushes to a new sorted run: one file written front to back,
+//! rather than a page rewritten for each row that changed. Reads look at the
+//! memtable, then the runs from newest to oldest, then the pages, which are
+//! the bottom level. How runs are merged as they pile up is up to the
+//! `Compaction` strategy, which the background tasks follow, and
+//! `DB::compact` merges every run into the pages at once.
+//!
+//! The runs that are live are listed in the `RUNS` file, which is replaced
+//! whole, so a crash part way through writing or compacting runs leaves the
+//! old list, and any run not on it is removed on open.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    num::NonZeroU32,
+    ops::Range,
+};
+
+use crate::{
+    checksum::Checksum,
+    manifest,
+    row::{bytes_to_values, RowVal, Schema},
+    utils::bytes_to_u32,
+};
+
+/// Identifies a file as one of our runs.
+const RUN_MAGIC: [u8; 4] = *b"TKRN";
+
+/// Which engine a database stores its rows with, saved in its header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Engine {
+    /// Every sync writes the rows in the WAL to the pages they go on.
+    #[default]
+    BTree,
+    /// Every sync flushes the rows in the WAL to a new sorted run, and runs
+    /// are merged into the pages later.
+    Lsm,
+}
+
+/// What a run holds for an id: its row, or `None` if it was deleted.
+pub type Entry = Option<Vec<RowVal>>;
+
+/// How the runs are merged as they pile up, see `Lsm::due`. Merging runs
+/// together keeps reads from looking through too many of them, at the cost
+/// of writing their rows again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compaction {
+    /// Merges `min_runs` or more runs of about the same size, the largest at
+    /// most `ratio` times the smallest, into one, so runs grow in tiers.
+    /// Once `max_runs` pile up anyway, they're all merged into the pages.
+    SizeTiered {
+        min_runs: usize,
+        ratio: f64,
+        max_runs: usize,
+    },
+    /// Keeps the runs flushed from the memtable in level 0, and at most one
+    /// run in each level below it, which can grow to `base_size` bytes in
+    /// level 1 and `ratio` times the level above in each after. Level 0 is
+    /// merged into level 1 once `level0_runs` pile up, and a level that's
+    /// grown too large into the next, the last of `levels` into the pages.
+    Leveled {
+        level0_runs: usize,
+        base_size: u64,
+        ratio: u64,
+        levels: u32,
+    },
+}
+
+impl Default for Compaction {
+    fn default() -> Self {
+        Self::SizeTiered {
+            min_runs: 4,
+            ratio: 2.0,
+            max_runs: 12,
+        }
+    }
+}
+
+impl Compaction {
+    /// Leveled compaction with a 4 run level 0, and 3 levels of 1 MiB, 10 MiB
+    /// and 100 MiB.
+    pub fn leveled() -> Self {
+        Self::Leveled {
+            level0_runs: 4,
+            base_size: 1 << 20,
+            ratio: 10,
+            levels: 3,
+        }
+    }
+
+    /// Whether every merge takes at least two runs, or for leveled
+    /// compaction one and a level with room for it, and levels grow.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Self::SizeTiered {
+                min_runs,
+                ratio,
+                max_runs,
+            } => min_runs >= 2 && ratio >= 1.0 && max_runs >= min_runs,
+            Self::Leveled {
+                level0_runs,
+                base_size,
+                ratio,
+                levels,
+            } => level0_runs >= 1 && base_size > 0 && ratio >= 2 && levels >= 1,
+        }
+    }
+}
+
+/// A compaction that's due, see `Lsm::due`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Task {
+    /// Merge the runs at these positions in `Lsm::runs` into one run in
+    /// `level`, which takes their place.
+    Merge { runs: Range<usize>, level: u32 },
+    /// Merge this many of the oldest runs into the pages.
+    IntoPages { runs: usize },
+}
+
+/// What the LSM engine has written since the database was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionMetrics {
+    /// Bytes of runs flushed from the memtable.
+    pub flushed: u64,
+    /// Bytes written by compactions, to merged runs and to the pages.
+    pub compacted: u64,
+    /// How many compactions have run.
+    pub compactions: u64,
+}
+
+impl CompactionMetrics {
+    /// How many bytes have been written for each byte flushed, or 1.0 if
+    /// nothing has been.
+    pub fn write_amplification(&self) -> f64 {
+        match self.flushed {
+            0 => 1.0,
+            flushed => (flushed + self.compacted) as f64 / flushed as f64,
+        }
+    }
+}
+
+/// Every id `runs` hold, with the newest entry for it.
+pub fn merge(runs: &[Run]) -> BTreeMap<NonZeroU32, Entry> {
+    let mut merged = BTreeMap::new();
+    for run in runs.iter().rev() {
+        merged.extend(run.entries.iter().map(|(id, entry)| (*id, entry.clone())));
+    }
+    merged
+}
+
+/// A sorted run, flushed from the memtable once and never changed after.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    /// Names the run's file. Later runs have higher numbers.
+    pub number: u64,
+    /// The LSN of the last WAL record the run reflects.
+    pub lsn: u64,
+    /// The level it's in, which is always 0 with size-tiered compaction.
+    pub level: u32,
+    /// The size of its file in bytes.
+    pub size: u64,
+    pub entries: BTreeMap<NonZeroU32, Entry>,
+}
+
+impl Run {
+    /// Serializes the magic, the schema version the rows are written with,
+    /// the LSN and the number of entries, then each entry's id, whether it
+    /// holds a row and the row, followed by a CRC32 of everything before it.
+    pub fn to_bytes(&self, schema: &Schema) -> Vec<u8> {
+        let mut res = RUN_MAGIC.to_vec();
+        res.extend(schema.version().to_le_bytes());
+        res.extend(self.lsn.to_le_bytes());
+        res.extend((self.entries.len() as u32).to_le_bytes());
+        for (id, entry) in &self.entries {
+            res.extend(id.get().to_le_bytes());
+            match entry {
+                Some(vals) => {
+                    res.push(1);
+                    for val in vals {
+                        res.extend(val.to_bytes());
+                    }
+                }
+                None => res.push(0),
+            }
+        }
+        res.extend(Checksum::Crc32.hash(&res).to_le_bytes());
+        res
+    }
+
+    /// Reads back a run in level 0, with its rows migrated to `schema`.
+    /// Refuses a run that's been cut short or corrupted.
+    pub fn from_bytes(number: u64, bytes: &[u8], schema: &Schema) -> io::Result<Self> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+        let Some(body_len) = bytes.len().checked_sub(4).filter(|&len| len >= 20) else {
+            return Err(invalid("run is too short"));
+        };
+        let (body, checksum) = bytes.split_at(body_len);
+        if body[0..4] != RUN_MAGIC {
+            return Err(invalid("not a run"));
+        }
+        if Checksum::Crc32.hash(body) != bytes_to_u32(checksum) {
+            return Err(invalid("run checksum mismatch"));
+        }
+        let version = bytes_to_u32(&body[4..8]);
+        let lsn = u64::from_le_bytes(body[8..16].try_into().unwrap());
+        let count = bytes_to_u32(&body[16..20]);
+        let row_types = schema.schema_at(version);
+        let mut i = 20;
+        let entries = (0..count)
+            .map(|_| {
+                let id = NonZeroU32::new(bytes_to_u32(&body[i..i + 4])).expect("ids start at 1");
+                let present = body[i + 4] == 1;
+                i += 5;
+                let entry = present.then(|| {
+                    let (mut vals, len) = bytes_to_values(&body[i..], &row_types[1..]);
+                    i += len;
+                    schema.migrate(&mut vals, version);
+                    vals
+                });
+                (id, entry)
+            })
+            .collect();
+        Ok(Self {
+            number,
+            lsn,
+            level: 0,
+            size: bytes.len() as u64,
+            entries,
+        })
+    }
+}
+
+/// The runs of a database using the LSM engine, and the deletes in its
+/// memtable. A database using the other engine has neither.
+#[derive(Debug, Clone)]
+pub struct Lsm {
+    /// Every live run, newest first.
+    pub runs: Vec<Run>,
+    /// Ids deleted since the last flush, which hide their rows in the runs
+    /// and on the pages until the next flush writes the deletes to a run.
+    /// A row inserted since it was deleted is in the WAL, which takes
+    /// precedence.
+    pub deletes: BTreeSet<NonZeroU32>,
+    /// How runs are merged as they pile up.
+    pub compaction: Compaction,
+    pub metrics: CompactionMetrics,
+    next: u64,
+}
+
+impl Default for Lsm {
+    fn default() -> Self {
+        Self {
+            runs: vec![],
+            deletes: BTreeSet::new(),
+            compaction: Compaction::default(),
+            metrics: CompactionMetrics::default(),
+            next: 1,
+        }
+    }
+}
+
+impl Lsm {
+    /// Reads every run listed for the database under `file_name`, removing
+    /// any that aren't listed. Each is listed as `run $number $level`, and a
+    /// run listed without a level is in level 0.
+    pub fn open(file_name: &str, schema: &Schema) -> io::Result<Self> {
+        let listed: Vec<(u64, u32)> = match fs::read_to_string(manifest::runs_file(file_name)) {
+            Ok(list) => list
+                .lines()
+                .filter_map(|line| {
+                    let mut words = line.strip_prefix("run ")?.split_whitespace();
+                    let number = words.next()?.parse().ok()?;
+                    let level = words.next().map_or(Some(0), |level| level.parse().ok())?;
+                    Some((number, level))
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        for entry in fs::read_dir(file_name)?.flatten() {
+            let name = entry.file_name();
+            let Some(number) = name.to_str().and_then(|name| name.strip_prefix("run.")) else {
+                continue;
+            };
+            if !number
+                .parse()
+                .is_ok_and(|number: u64| listed.iter().any(|(listed, _)| *listed == number))
+            {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        let runs = listed
+            .iter()
+            .map(|&(number, level)| {
+                let bytes = fs::read(manifest::run_file(file_name, number))?;
+                Ok(Run {
+                    level,
+                    ..Run::from_bytes(number, &bytes, schema)?
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            next: listed
+                .iter()
+                .map(|(number, _)| number + 1)
+                .max()
+                .unwrap_or(1),
+            runs,
+            ..Self::default()
+        })
+    }
+
+    /// Removes every run of the database under `file_name`, for a new
+    /// database taking its place.
+    pub fn remove(file_name: &str) {
+        let _ = fs::remove_file(manifest::runs_file(file_name));
+        for entry in fs::read_dir(file_name).into_iter().flatten().flatten() {
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with("run."))
+            {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty() && self.deletes.is_empty()
+    }
+
+    /// The LSN of the last WAL record the runs reflect, or 0 if there are
+    /// none.
+    pub fn lsn(&self) -> u64 {
+        self.runs.iter().map(|run| run.lsn).max().unwrap_or(0)
+    }
+
+    /// What the memtable's deletes and the runs hold for `id`, newest
+    /// first, or `None` if none of them have it.
+    pub fn get(&self, id: NonZeroU32) -> Option<Option<&[RowVal]>> {
+        if self.deletes.contains(&id) {
+            return Some(None);
+        }
+        self.runs
+            .iter()
+            .find_map(|run| run.entries.get(&id))
+            .map(Option::as_deref)
+    }
+
+    /// The rows the runs put over the pages, and the ids the runs and the
+    /// memtable's deletes take off them.
+    pub fn overlay(&self) -> (BTreeMap<NonZeroU32, Vec<RowVal>>, BTreeSet<NonZeroU32>) {
+        let mut rows = BTreeMap::new();
+        let mut deleted = self.deletes.clone();
+        for (id, entry) in merge(&self.runs) {
+            match entry {
+                Some(vals) if !deleted.contains(&id) => {
+                    rows.insert(id, vals);
+                }
+                _ => {
+                    deleted.insert(id);
+                }
+            }
+        }
+        (rows, deleted)
+    }
+
+    /// Writes `entries` to a new run reflecting the WAL up to `lsn`, and
+    /// lists it once it's on disk.
+    pub fn flush(
+        &mut self,
+        file_name: &str,
+        entries: BTreeMap<NonZeroU32, Entry>,
+        lsn: u64,
+        schema: &Schema,
+    ) -> io::Result<()> {
+        let run = self.write_run(file_name, entries, lsn, 0, schema)?;
+        self.metrics.flushed += run.size;
+        self.runs.insert(0, run);
+        self.write_list(file_name)
+    }
+
+    /// The compaction the `compaction` strategy has due next, if any.
+    pub fn due(&self) -> Option<Task> {
+        let len = self.runs.len();
+        match self.compaction {
+            Compaction::SizeTiered {
+                min_runs,
+                ratio,
+                max_runs,
+            } => {
+                if len >= max_runs {
+                    return Some(Task::IntoPages { runs: len });
+                }
+                // the first stretch of runs, newest first, close enough in
+                // size to merge
+                (0..len).find_map(|start| {
+                    let (mut min, mut max) = (self.runs[start].size, self.runs[start].size);
+                    let mut end = start + 1;
+                    while let Some(run) = self.runs.get(end) {
+                        if max.max(run.size) as f64 > min.min(run.size) as f64 * ratio {
+                            break;
+                        }
+                        (min, max) = (min.min(run.size), max.max(run.size));
+                        end += 1;
+                    }
+                    (end - start >= min_runs).then(|| Task::Merge {
+                        runs: start..end,
+                        level: self.runs[end - 1].level,
+                    })
+                })
+            }
+            Compaction::Leveled {
+                level0_runs,
+                base_size,
+                ratio,
+                levels,
+            } => {
+                let in_level =
+                    |i: usize, level| self.runs.get(i).is_some_and(|run| run.level == level);
+                let level0 = self.runs.iter().take_while(|run| run.level == 0).count();
+                if level0 >= level0_runs {
+                    let end = level0 + usize::from(in_level(level0, 1));
+                    return Some(Task::Merge {
+                        runs: 0..end,
+                        level: 1,
+                    });
+                }
+                let (i, run) = self.runs.iter().enumerate().skip(level0).find(|(_, run)| {
+                    run.size > base_size.saturating_mul(ratio.saturating_pow(run.level - 1))
+                })?;
+                if run.level >= levels {
+                    return Some(Task::IntoPages { runs: len - i });
+                }
+                let end = i + 1 + usize::from(in_level(i + 1, run.level + 1));
+                Some(Task::Merge {
+                    runs: i..end,
+                    level: run.level + 1,
+                })
+            }
+        }
+    }
+
+    /// How many bytes of runs the compaction due next would merge, or 0 if
+    /// none is due.
+    pub fn debt(&self) -> u64 {
+        let runs = match self.due() {
+            Some(Task::Merge { runs, .. }) => &self.runs[runs],
+            Some(Task::IntoPages { runs }) => &self.runs[self.runs.len() - runs..],
+            None => &[],
+        };
+        runs.iter().map(|run| run.size).sum()
+    }
+
+    /// Merges the runs at `runs` into one new run in `level`, which takes
+    /// their place once it's on disk.
+    pub fn merge(
+        &mut self,
+        file_name: &str,
+        runs: Range<usize>,
+        level: u32,
+        schema: &Schema,
+    ) -> io::Result<()> {
+        let inputs = &self.runs[runs.clone()];
+        let lsn = inputs.iter().map(|run| run.lsn).max().unwrap_or(0);
+        let run = self.write_run(file_name, merge(inputs), lsn, level, schema)?;
+        self.metrics.compacted += run.size;
+        self.metrics.compactions += 1;
+        let merged: Vec<_> = self.runs.splice(runs, [run]).collect();
+        self.write_list(file_name)?;
+        for run in merged {
+            fs::remove_file(manifest::run_file(file_name, run.number))?;
+        }
+        Ok(())
+    }
+
+    /// Drops the oldest `count` runs, once they've been merged into the
+    /// pages.
+    pub fn drop_oldest(&mut self, file_name: &str, count: usize) -> io::Result<()> {
+        let runs = self.runs.split_off(self.runs.len() - count);
+        self.write_list(file_name)?;
+        for run in runs {
+            fs::remove_file(manifest::run_file(file_name, run.number))?;
+        }
+        Ok(())
+    }
+
+    /// Writes a new run's file, without listing it.
+    fn write_run(
+        &mut self,
+        file_name: &str,
+        entries: BTreeMap<NonZeroU32, Entry>,
+        lsn: u64,
+        level: u32,
+        schema: &Schema,
+    ) -> io::Result<Run> {
+        let mut run = Run {
+            number: self.next,
+            lsn,
+            level,
+            size: 0,
+            entries,
+        };
+        let bytes = run.to_bytes(schema);
+        run.size = bytes.len() as u64;
+        let path = manifest::run_file(file_name, run.number);
+        let tmp_path = format!("{path}.tmp");
+        fs::write(&tmp_path, bytes)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        self.next += 1;
+        Ok(run)
+    }
+
+    /// Replaces the list of live runs, the same way the manifest is.
+    fn write_list(&self, file_name: &str) -> io::Result<()> {
+        let path = manifest::runs_file(file_name);
+        let tmp_path = format!("{path}.tmp");
+        let list: String = self
+            .runs
+            .iter()
+            .map(|run| format!("run {} {}\n", run.number, run.level))
+            .collect();
+        fs::write(&tmp_path, list)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        File::open(file_name)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::row::RowType;
+
+    use super::*;
+
+    #[test]
+    fn runs() {
+        let file_name = "tests/lsm_runs";
+        let _ = fs::remove_dir_all(file_name);
+        fs::create_dir_all(file_name).unwrap();
+        let schema = Schema::new(&[RowType::Id, RowType::U32, RowType::Bytes]);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let row = |i: u32| Some(vec![RowVal::U32(i), RowVal::Bytes(vec![i as u8; 3])]);
+
+        let mut lsm = Lsm::open(file_name, &schema).unwrap();
+        assert!(lsm.is_empty());
+        lsm.flush(
+            file_name,
+            BTreeMap::from([(id(1), row(1)), (id(2), row(2))]),
+            2,
+            &schema,
+        )
+        .unwrap();
+        lsm.flush(
+            file_name,
+            BTreeMap::from([(id(1), None), (id(3), row(3))]),
+            4,
+            &schema,
+        )
+        .unwrap();
+        lsm.deletes.insert(id(3));
+        assert_eq!(lsm.get(id(1)), Some(None));
+        assert_eq!(lsm.get(id(2)), Some(row(2).as_deref()));
+        assert_eq!(lsm.get(id(3)), Some(None));
+        assert_eq!(lsm.get(id(4)), None);
+        let (rows, deleted) = lsm.overlay();
+        assert_eq!(rows, BTreeMap::from([(id(2), row(2).unwrap())]));
+        assert_eq!(deleted, BTreeSet::from([id(1), id(3)]));
+
+        // a run left behind unlisted, or cut short, is removed on open
+        fs::write(manifest::run_file(file_name, 9), b"TKRN").unwrap();
+        let reopened = Lsm::open(file_name, &schema).unwrap();
+        assert_eq!(reopened.runs, lsm.runs);
+        assert_eq!(reopened.lsn(), 4);
+        assert!(!fs::exists(manifest::run_file(file_name, 9)).unwrap());
+        let bytes = lsm.runs[0].to_bytes(&schema);
+        assert!(Run::from_bytes(2, &bytes[..bytes.len() - 1], &schema).is_err());
+
+        lsm.drop_oldest(file_name, 2).unwrap();
+        assert!(Lsm::open(file_name, &schema).unwrap().runs.is_empty());
+    }
+
+    #[test]
+    fn strategies() {
+        let run = |number, level, size| Run {
+            number,
+            lsn: number,
+            level,
+            size,
+            entries: BTreeMap::new(),
+        };
+        let mut lsm = Lsm {
+            runs: vec![
+                run(5, 0, 100),
+                run(4, 0, 120),
+                run(3, 0, 90),
+                run(2, 0, 800),
+            ],
+            ..Lsm::default()
+        };
+        // 3 runs of about the same size aren't enough for the default 4
+        assert_eq!(lsm.due(), None);
+        assert_eq!(lsm.debt(), 0);
+        lsm.runs.insert(0, run(6, 0, 110));
+        assert_eq!(
+            lsm.due(),
+            Some(Task::Merge {
+                runs: 0..4,
+                level: 0
+            })
+        );
+        assert_eq!(lsm.debt(), 420);
+        lsm.compaction = Compaction::SizeTiered {
+            min_runs: 2,
+            ratio: 2.0,
+            max_runs: 5,
+        };
+        assert_eq!(lsm.due(), Some(Task::IntoPages { runs: 5 }));
+
+        lsm.compaction = Compaction::Leveled {
+            level0_runs: 2,
+            base_size: 1000,
+            ratio: 10,
+            levels: 2,
+        };
+        lsm.runs = vec![run(4, 0, 100), run(3, 1, 900), run(2, 2, 5000)];
+        assert_eq!(lsm.due(), None);
+        lsm.runs.insert(0, run(5, 0, 100));
+        assert_eq!(
+            lsm.due(),
+            Some(Task::Merge {
+                runs: 0..3,
+                level: 1
+            })
+        );
+        lsm.runs = vec![run(6, 1, 1100), run(2, 2, 5000)];
+        assert_eq!(
+            lsm.due(),
+            Some(Task::Merge {
+                runs: 0..2,
+                level: 2
+            })
+        );
+        // the last level goes into the pages once it's too large
+        lsm.runs = vec![run(7, 2, 10001)];
+        assert_eq!(lsm.due(), Some(Task::IntoPages { runs: 1 }));
+        assert_eq!(lsm.debt(), 10001);
+    }
+}
@@ -1,247 +1,1384 @@
-use std::collections::BTreeMap;
-use std::env::args;
-use std::fs::{self, OpenOptions};
+use std::env::{self, args};
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::NonZeroU32;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 
-use db::db::{deserialize, DB};
+use db::checksum::Checksum;
+use db::copy;
+use db::db::{BackgroundTasks, Durability, DB};
+use db::dump;
+use db::error::DbError;
+use db::export::{Export, Format};
+use db::kv::Kv;
+use db::lsm::{Compaction, Engine};
+use db::partition::PartitionedDB;
 
-use db::row::{schema_from_bytes, RowType, RowVal, Schema};
-use db::wal::{deserialize_wal, WALRecord, WAL};
+use db::resp;
+use db::row::RowVal;
+use db::schema::{self, Column};
+use db::session::Session;
+use db::transaction::{Txn, WriteBatch};
+use db::undo::UndoLog;
+use db::wal::WALRecord;
 use rustyline::error::ReadlineError;
 use rustyline::{Config, DefaultEditor, EditMode, Result};
 
 fn main() -> Result<()> {
-    let args: Vec<_> = args().collect();
-    let file_name = if args.len() > 1 {
-        args[1].to_string()
-    } else {
-        "test".to_string()
-    };
+    let args: Vec<_> = args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "serve") {
+        return Ok(serve(&args[1..])?);
+    }
+    if args.first().is_some_and(|arg| arg == "import-sqlite") {
+        return Ok(import_sqlite(&args[1..])?);
+    }
+    let file_name = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .cloned()
+        .unwrap_or_else(|| "test".to_string());
+    let config = ShellConfig::new(&args);
 
     let mut rl = DefaultEditor::with_config(Config::builder().edit_mode(EditMode::Vi).build())?;
-    if rl.load_history("history.txt").is_err() {
-        println!("No previous history.");
-    }
-
-    let db_file_name = format!("{file_name}.1.db");
-    let wal_file_name = format!("{file_name}.1.wal");
-    let schema_file_name = format!("{file_name}.1.schema");
-
-    let mut db = None;
-
-    if fs::exists(&db_file_name).unwrap() {
-        let schema_bytes = fs::read(&schema_file_name).unwrap();
-        let schema = schema_from_bytes(&schema_bytes);
-        let schema_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&schema_file_name)
-            .unwrap();
-        let schema = Schema {
-            schema,
-            file: schema_file,
-        };
+    if let Some(history) = &config.history {
+        if !history.exists() {
+            println!("No previous history.");
+        } else if let Err(e) = rl.load_history(history) {
+            println!("Could not read history from {}: {e}", history.display());
+        }
+    }
+
+    let table = match open_table(&file_name) {
+        Ok(table) => table,
+        Err(e) => {
+            println!("{e}");
+            return Ok(());
+        }
+    };
+    let mut shell = Shell::new(Arc::new(Mutex::new(table)));
 
-        let page_bytes = fs::read(&db_file_name).unwrap();
-        let pages = deserialize(page_bytes, &schema.schema);
+    loop {
+        let readline = rl.readline(">> ");
+        match readline {
+            Ok(line) => {
+                rl.add_history_entry(line.as_str())?;
+                if !shell.run(&line, &mut io::stdout())? {
+                    break;
+                }
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) | Err(_) => {
+                break;
+            }
+        }
+    }
+    drop(shell);
+    if let Some(history) = &config.history {
+        if let Some(dir) = history.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            let _ = fs::create_dir_all(dir);
+        }
+        // not being able to save history shouldn't make the session fail
+        if let Err(e) = rl.save_history(history) {
+            println!("Could not save history to {}: {e}", history.display());
+        }
+    }
+    Ok(())
+}
 
-        let wal_bytes = fs::read(&wal_file_name).unwrap();
-        let wal_records = deserialize_wal(&wal_bytes, &schema.schema);
+/// Opens the table saved under `file_name`, if there is one, and syncs
+/// whatever its WAL replayed.
+fn open(file_name: &str) -> std::result::Result<Option<Arc<Mutex<DB>>>, DbError> {
+    Ok(DB::open(file_name)?.map(|mut old_db| {
+        old_db.sync();
+        Arc::new(Mutex::new(old_db))
+    }))
+}
 
-        let mut wal_cache = BTreeMap::new();
+/// The table saved under `file_name`, or the partitioned table saved under
+/// it if there's no plain one.
+fn open_table(file_name: &str) -> std::result::Result<Table, DbError> {
+    let mut table = Table::new(open(file_name)?);
+    if table.db.is_none() {
+        table.partitions = PartitionedDB::open(file_name)?;
+    }
+    Ok(table)
+}
 
-        for record in &wal_records {
-            match record {
-                WALRecord::Insert(id, val) => {
-                    wal_cache.insert(*id, val.to_vec());
-                }
-                WALRecord::Delete(id) => {
-                    wal_cache.remove(id);
+/// `db import-sqlite $file [$dir]` makes a database under `$dir`, the
+/// current directory by default, of each table of the SQLite database
+/// `$file`, see `db::sqlite`.
+#[cfg(feature = "sqlite")]
+fn import_sqlite(args: &[String]) -> io::Result<()> {
+    let Some(path) = args.first() else {
+        println!("Usage: db import-sqlite $file [$dir]");
+        return Ok(());
+    };
+    let dir = args.get(1).map_or(".", String::as_str);
+    match db::sqlite::import(path, dir) {
+        Ok(tables) => {
+            for table in tables {
+                println!("Imported {} rows into {}", table.inserted, table.table);
+                for (rowid, reason) in table.rejected {
+                    println!("Rowid {rowid}: {reason}");
                 }
             }
         }
+        Err(e) => println!("{e}"),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn import_sqlite(_: &[String]) -> io::Result<()> {
+    println!("This build can't read SQLite files, rebuild it with --features sqlite");
+    Ok(())
+}
 
-        let db_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(db_file_name)
-            .unwrap();
-        let wal_file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(wal_file_name)
-            .unwrap();
-        let mut old_db = DB {
-            pages,
-            file: db_file,
-            wal: WAL {
-                file: wal_file,
-                records: wal_cache,
-            },
-            epoch: 1,
-            schema,
+/// `db serve [--listen $addr] [--resp] [$name]` takes the same commands as
+/// the REPL over TCP, one per line, with every connection sharing one table.
+/// Each connection has its own variables, transaction and undo history, and
+/// every response ends with an empty line. `exit` closes the connection.
+///
+/// With `--resp` it speaks the Redis protocol instead, serving a key/value
+/// store.
+fn serve(args: &[String]) -> io::Result<()> {
+    let mut listen = "127.0.0.1:4000".to_string();
+    let mut file_name = "test".to_string();
+    let mut args = args.iter();
+    let mut redis = false;
+    while let Some(arg) = args.next() {
+        if arg == "--listen" {
+            if let Some(addr) = args.next() {
+                listen = addr.clone();
+            }
+        } else if arg == "--resp" {
+            redis = true;
+        } else if !arg.starts_with("--") {
+            file_name = arg.clone();
+        }
+    }
+    if redis {
+        return serve_resp(&listen, &file_name);
+    }
+    let table = match open_table(&file_name) {
+        Ok(table) => Arc::new(Mutex::new(table)),
+        Err(e) => {
+            println!("{e}");
+            return Ok(());
+        }
+    };
+    let listener = TcpListener::bind(&listen)?;
+    println!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Could not accept connection: {e}");
+                continue;
+            }
         };
-        old_db.sync();
+        let mut shell = Shell::new(Arc::clone(&table));
+        thread::spawn(move || {
+            // a client hanging up mid-response only ends its own connection
+            let _ = handle(&mut shell, stream);
+        });
+    }
+    Ok(())
+}
 
-        db = Some(old_db);
+/// Serves the key/value store at `file_name` to Redis clients. Without
+/// background tasks to sync it, the store syncs whenever its WAL takes more
+/// than 1 MiB.
+fn serve_resp(listen: &str, file_name: &str) -> io::Result<()> {
+    let mut kv = match Kv::open(file_name) {
+        Ok(kv) => kv,
+        Err(e) => {
+            println!("{e}");
+            return Ok(());
+        }
+    };
+    kv.db.set_memory_limit(Some(1024 * 1024));
+    let kv = Arc::new(Mutex::new(kv));
+    let listener = TcpListener::bind(listen)?;
+    println!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Could not accept connection: {e}");
+                continue;
+            }
+        };
+        let kv = Arc::clone(&kv);
+        thread::spawn(move || {
+            let reader = stream.try_clone().map(BufReader::new)?;
+            resp::handle(&kv, reader, BufWriter::new(stream))
+        });
+    }
+    Ok(())
+}
+
+/// The row count and byte length of `copy $rows $bytes`.
+fn parse_copy(args: &str) -> Option<(usize, usize)> {
+    let (rows, len) = args.trim().split_once(' ')?;
+    let len = len
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&len| len <= copy::MAX_BYTES)?;
+    Some((rows.parse().ok()?, len))
+}
+
+/// The idempotency key and the command of `request $key $command`.
+fn parse_request(args: &str) -> Option<(u64, &str)> {
+    let (key, command) = args.trim_start().split_once(' ')?;
+    Some((key.parse().ok()?, command))
+}
+
+/// Runs commands from one connection until it sends `exit` or hangs up.
+fn handle(shell: &mut Shell, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (key, command) = match line.strip_prefix("request ").and_then(parse_request) {
+            Some((key, command)) => (Some(key), command),
+            None => (None, line),
+        };
+        let running = if let Some(args) = command.strip_prefix("copy ") {
+            // the rows of a copy follow its line as bytes the shell can't
+            // read, so they're read here
+            match parse_copy(args) {
+                Some((rows, len)) => {
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body)?;
+                    shell.copy(key, rows, &body, &mut out)?;
+                    true
+                }
+                None => {
+                    // without the length there's no telling where the rows
+                    // end and the next command starts, so the connection ends
+                    writeln!(
+                        out,
+                        "Copy takes a row count and a length of at most {} bytes, copy $rows $bytes.",
+                        copy::MAX_BYTES
+                    )?;
+                    false
+                }
+            }
+        } else {
+            shell.run(line, &mut out)?
+        };
+        writeln!(out)?;
+        out.flush()?;
+        if !running {
+            break;
+        }
     }
-    let help_string = r#"Commands:
-Insert takes two u32s, comma delimited, and inserts them into the DB:
+    Ok(())
+}
+
+const HELP: &str = r#"Commands:
+Create table takes a table name and a list of columns, each with a name, a type
+(id, u32, text, bool or ttl), an optional default and optional min/max constraints,
+which bound u32 values and text lengths. A ttl column holds the unix second its row
+expires at (0 for never); expired rows are hidden and dropped on the next sync.
+The first column must be the id:
+create table $name (id, count:u32 = 0 min 1, name:text = "none" max 20, active:bool)
+Alter table adds a column to the end of the table. Existing rows get its default:
+alter table $name add column $column:$type = $default
+Alter table can also drop a column. Pages are rewritten without it on the next sync:
+alter table $name drop column $column
+Insert takes an id and the row's values, comma delimited, and inserts them into the DB.
+Trailing values can be left out if their columns have defaults:
 insert $id, $val
+Request inserts only if no write was made with the same u64 key before, so a client
+can retry an insert it never heard back about:
+request $key insert $id, $val
 Get takes a u32, the id of the tuple to fetch:
 get $id
+Get can also read a tuple as it was at an LSN, as long as history goes back that far.
+History keeps the values replaced by the last $writes writes, and is off by default.
+Lsn shows the LSN of the last write:
+get $id as of $lsn
+history $writes (or off)
+lsn
+Scan shows every tuple with an id from $from to $to:
+scan $from $to
+Export writes the tuples of a scan to a file, as CSV with a header of column names
+(csv) or as a JSON object per line (json):
+export csv $path scan $from $to
+export json $path scan $from $to
 Delete takes a u32, the id of the tuple to delete:
 delete $id
+Partition by splits a new table into key ranges, each starting at one of its ids and
+kept in files of its own. A partitioned table takes inserts, gets, scans, deletes,
+syncs and show, each sent to the partitions it covers, and no defaults or
+constraints. Add partition splits a range at $start, moving the rows from there on
+into the new partition, and drop partition deletes one along with all of its rows:
+create table $name (id, count:u32) partition by 1, 1000
+add partition $start
+drop partition $start
+Begin starts a transaction. Inserts, deletes and gets go through it until commit
+makes its writes visible all at once, or rollback throws them away:
+begin
+commit
+rollback
+Savepoint names a point in the open transaction that rollback to undoes writes back to:
+savepoint $name
+rollback to $name
+Undo reverts the last insert, delete or committed transaction made in the shell,
+and redo makes it again:
+undo
+redo
+Soft delete hides a tuple until it's restored. The next sync purges it for good:
+soft delete $id
+restore $id
 Sync merges the WAL and pages together, and saves to disk. The WAL is then cleared.
+This also happens in the background once the WAL holds more than 1 MiB of rows.
 sync (clears the WAL and saves the DB to disk).
-Show shows the state of the database.
+Checkpoint syncs, then copies every page to a new epoch's file and removes the old one:
+checkpoint
+Dump writes the table out as a create table and an insert per row, which the
+shell can replay, to $path or to the screen:
+dump $path
+dump
+Import csv inserts the rows of a CSV file. Its header names the column each field
+goes to, and columns it leaves out get their defaults. Rows that don't fit the
+table are reported by line and skipped:
+import csv $path
+Read runs every line of a file as a command, like a dump to load it back:
+.read $path
+Backup copies every write so far to a new directory, which opens as a database:
+.backup $path
+Engine picks what rows are stored with, saved in the file's header. The btree engine
+(the default) writes synced rows to their pages. The lsm engine writes each sync to a
+new sorted run instead, which is cheaper for writes all over the table, and merges
+the runs as they pile up, or all of them into the pages when compact is run:
+engine btree or engine lsm
+compact
+Compaction picks how the lsm engine merges runs. Size-tiered (the default) merges
+$min_runs runs within $ratio times each other's size, and every run into the pages
+once $max_runs pile up. Leveled merges level 0 into level 1 once $level0_runs pile
+up, and each level past its size into the next, starting at $base_size bytes and
+growing $ratio times a level, the last of $levels into the pages. Compaction on its
+own shows the runs, the bytes due to be merged, and the write amplification:
+compaction size-tiered $min_runs $ratio $max_runs
+compaction leveled $level0_runs $base_size $ratio $levels
+compaction
+Durability sets when writes are fsynced: never (off, the default), after every
+WAL write (wal), also after every page write (full), or at most once every
+$ms milliseconds (batched $ms):
+durability $level
+Checksum picks what the WAL is checksummed with, saved in the file's header:
+checksum crc32 (the default), checksum crc32c or checksum xxhash64
+Memory shows roughly how many bytes the pages and the WAL take. Memory limit caps
+the WAL, syncing it to the pages whenever an insert takes it over the limit:
+memory
+memory limit $bytes (or off)
+WAL limit caps how many rows the WAL holds, syncing once an insert goes over it:
+wal limit $rows (or off)
+Stall limit refuses inserts once the WAL's rows take more than $bytes, until a sync
+(by hand or in the background) catches up:
+stall limit $bytes (or off)
+Analyze gathers statistics about each column's values: the smallest and largest,
+how many different values there are, and a histogram. They're saved with the
+database and gathered again on every sync from then on:
+analyze
+Latency shows percentiles of how long each get, insert, remove and sync took.
+latency (shows latency percentiles)
+Show shows the state of the database. Show schema shows the table's columns as
+create table takes them, and the description of each check registered on it:
 show (shows database info)
+show schema
+Set stores a variable, which later commands use as :$name. Unset removes it:
+\set $name $value
+\unset $name
 Exit quits the repl. This can also be done with CTRL-C or CTRL-D.
-exit (quits the repl)"#;
+exit (quits the repl)
+Run as db serve [--listen $addr] [$name], the same commands are taken over TCP,
+one per line, from any number of clients at once. Each response ends with an
+empty line, and exit closes the connection. Over TCP, copy $rows $bytes followed
+by $bytes bytes of length-prefixed binary rows inserts them all as one batch.
+With --resp, it serves a key/value table to Redis clients instead, taking GET,
+SET, DEL, EXISTS and SCAN.
+Run as db import-sqlite $file [$dir], with --features sqlite, each table of the
+SQLite database $file is copied into a database of the same name under $dir."#;
 
-    loop {
-        let readline = rl.readline(">> ");
-        match readline {
-            Ok(line) => {
-                rl.add_history_entry(line.as_str())?;
-                if line.trim() == "?" {
-                    println!("{}", help_string);
-                    continue;
-                }
-                if line.starts_with("create ") {
-                    let trimmed = line.strip_prefix("create ").unwrap();
-                    let schema_types = parse_create_table(trimmed);
+/// The table every shell works on, and the thread syncing it in the
+/// background, or the partitioned table it works on instead. `create table`
+/// replaces them.
+struct Table {
+    tasks: Option<BackgroundTasks>,
+    db: Option<Arc<Mutex<DB>>>,
+    partitions: Option<PartitionedDB>,
+}
+
+impl Table {
+    fn new(db: Option<Arc<Mutex<DB>>>) -> Self {
+        Self {
+            tasks: db.as_ref().map(start_background_tasks),
+            db,
+            partitions: None,
+        }
+    }
+}
 
-                    db = Some(DB::new("test", &schema_types));
-                    continue;
+/// Locks the table's database. Commands parse their arguments before they
+/// take the lock, so bad input is answered with an error rather than a
+/// panic that would poison it.
+fn lock(db: &Option<Arc<Mutex<DB>>>) -> MutexGuard<'_, DB> {
+    db.as_ref().unwrap().lock().unwrap()
+}
+
+/// A session of commands against the shared table, with its own variables,
+/// open transaction and undo history.
+struct Shell {
+    table: Arc<Mutex<Table>>,
+    session: Session,
+    txn: Option<Txn>,
+    undo_log: UndoLog,
+}
+
+impl Shell {
+    fn new(table: Arc<Mutex<Table>>) -> Self {
+        Self {
+            table,
+            session: Session::default(),
+            txn: None,
+            undo_log: UndoLog::default(),
+        }
+    }
+
+    /// Locks the table, which like its database is never left poisoned for
+    /// the other sessions.
+    fn table(&self) -> MutexGuard<'_, Table> {
+        self.table.lock().unwrap()
+    }
+
+    /// Runs one command, writing what it prints to `out`. Returns false once
+    /// the session should end.
+    fn run(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        if let Some(path) = line.strip_prefix(".read ") {
+            let file = match File::open(path.trim()) {
+                Ok(file) => file,
+                Err(e) => {
+                    writeln!(out, "Could not read {}: {e}", path.trim())?;
+                    return Ok(true);
                 }
-                if line.trim() == "exit" {
-                    break;
+            };
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                if !line.trim().is_empty() && !self.run(&line, out)? {
+                    return Ok(false);
                 }
-                if line.starts_with("insert ") {
-                    let db = db.as_mut().unwrap();
-                    let copy = line.strip_prefix("insert ").unwrap();
-                    let vals: Vec<&str> = copy.split(", ").collect();
-                    let id = vals[0].parse().unwrap();
-                    let vals = parse_vals(&vals[1..]);
-                    if verify_insert(&vals, &db.schema.schema) {
-                        db.insert(id, &vals);
-                    } else {
-                        println!("Schema did not match, rejecting insert.");
-                    }
-                }
-                if line.starts_with("get ") {
-                    let db = db.as_ref().unwrap();
-                    let copy = line.strip_prefix("get ").unwrap();
-                    let id: u32 = copy.parse().unwrap();
-                    if let Some(val) = db.get(id.try_into().unwrap()) {
-                        let mut res = String::new();
-                        res.push_str(&format!("{id}: ["));
-                        for v in val {
-                            res.push_str(&v.to_string());
-                            res.push_str(", ");
+            }
+            return Ok(true);
+        }
+        let db = self.table().db.clone();
+        if self.session.command(line) {
+            return Ok(true);
+        }
+        let line = match self.session.substitute(line) {
+            Ok(line) => line,
+            Err(e) => {
+                writeln!(out, "{e}.")?;
+                return Ok(true);
+            }
+        };
+        if line.trim() == "?" {
+            writeln!(out, "{}", HELP)?;
+            return Ok(true);
+        }
+        if line.starts_with("create table ") {
+            let trimmed = line.strip_prefix("create table ").unwrap();
+            let (trimmed, starts) = match trimmed.rsplit_once(" partition by ") {
+                Some((trimmed, starts)) => match starts
+                    .split(',')
+                    .map(|start| start.trim().parse::<NonZeroU32>())
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                {
+                    Ok(starts) => (trimmed, Some(starts)),
+                    Err(_) => {
+                        writeln!(
+                            out,
+                            "Partition by takes a list of ids, rejecting create table."
+                        )?;
+                        return Ok(true);
+                    }
+                },
+                None => (trimmed, None),
+            };
+            let Some((table_name, columns)) = trimmed.split_once('(') else {
+                writeln!(out, "Could not parse create table statement.")?;
+                return Ok(true);
+            };
+            let Some(columns) = columns.trim().strip_suffix(')') else {
+                writeln!(out, "Could not parse create table statement.")?;
+                return Ok(true);
+            };
+            match schema::parse(columns) {
+                Ok(columns) if starts.is_some() => {
+                    let name = table_name.trim();
+                    if columns
+                        .iter()
+                        .any(|column| column.default.is_some() || !column.constraints.is_empty())
+                    {
+                        writeln!(
+                            out,
+                            "Partitioned tables don't take defaults or constraints, rejecting create table."
+                        )?;
+                        return Ok(true);
+                    }
+                    let schema: Vec<_> = columns.iter().map(|column| column.row_type).collect();
+                    // partitions under the same name are closed first, since
+                    // the new ones replace their files
+                    if self
+                        .table()
+                        .partitions
+                        .as_ref()
+                        .is_some_and(|partitions| partitions.name == name)
+                    {
+                        self.table().partitions = None;
+                    }
+                    drop(db);
+                    match PartitionedDB::create(name, &schema, &starts.unwrap()) {
+                        Ok(partitions) => {
+                            let (old_tasks, old) = {
+                                let mut table = self.table();
+                                table.partitions = Some(partitions);
+                                (table.tasks.take(), table.db.take())
+                            };
+                            drop(old_tasks);
+                            drop(old);
+                            self.txn = None;
+                            self.undo_log = UndoLog::default();
                         }
-                        res.pop();
-                        res.pop();
-                        res.push(']');
-                        println!("{}", res);
-                    } else {
-                        println!("Key {id} not found.");
-                    }
-                }
-                if line.starts_with("delete ") {
-                    let db = db.as_mut().unwrap();
-                    let copy = line.strip_prefix("delete ").unwrap();
-                    let id: u32 = copy.parse().unwrap();
-                    if let Some(val) = db.remove(id.try_into().unwrap()) {
-                        let mut res = String::new();
-                        res.push_str(&format!("Removing {id}: ["));
-                        for v in val {
-                            res.push_str(&v.to_string());
-                            res.push_str(", ");
+                        Err(e) => writeln!(out, "{e}, rejecting create table.")?,
+                    }
+                }
+                Ok(columns) => {
+                    let name = table_name.trim();
+                    // a table under the same name is closed first, since the
+                    // new one takes over its files. Any other stays open until
+                    // the new one is made, so failing to make it leaves the
+                    // table as it was
+                    if db.is_some() && lock(&db).file_name == name {
+                        self.txn = None;
+                        self.undo_log = UndoLog::default();
+                        let (tasks, old) = {
+                            let mut table = self.table();
+                            (table.tasks.take(), table.db.take())
+                        };
+                        // stop syncing the old table before it's closed
+                        drop(tasks);
+                        drop(old);
+                    }
+                    drop(db);
+                    match DB::from_columns(name, &columns) {
+                        Ok(new_db) => {
+                            let new_db = Arc::new(Mutex::new(new_db));
+                            let tasks = start_background_tasks(&new_db);
+                            let (old_tasks, old) = {
+                                let mut table = self.table();
+                                table.partitions = None;
+                                (table.tasks.replace(tasks), table.db.replace(new_db))
+                            };
+                            drop(old_tasks);
+                            drop(old);
+                            self.txn = None;
+                            self.undo_log = UndoLog::default();
                         }
-                        res.pop();
-                        res.pop();
-                        res.push(']');
-                        println!("{}", res);
-                    } else {
-                        println!("Key {id} not found.");
+                        Err(e) => writeln!(out, "{e}, rejecting create table.")?,
                     }
                 }
-                if line.starts_with("show") {
-                    let db = db.as_ref().unwrap();
-                    println!("Pages: ");
-                    println!("{:?}", db.pages);
-                    println!("WAL: ");
-                    println!("{:?}", db.wal);
-                    println!("Schema: ");
-                    println!("{:?}", db.schema);
+                Err(e) => writeln!(out, "{e}, rejecting create table.")?,
+            }
+            return Ok(true);
+        }
+        if self.table().partitions.is_some() {
+            return self.run_partitioned(&line, out);
+        }
+        if line.starts_with("add partition ") || line.starts_with("drop partition ") {
+            writeln!(
+                out,
+                "The table isn't partitioned, create one with partition by."
+            )?;
+            return Ok(true);
+        }
+        if db.is_none() && line.trim() != "exit" {
+            writeln!(out, "There's no table yet, create one with create table.")?;
+            return Ok(true);
+        }
+        if let Some(args) = line.strip_prefix("request ") {
+            match parse_request(args) {
+                Some((key, command)) => match command.strip_prefix("insert ") {
+                    Some(row) => self.insert_once(&db, key, row, out)?,
+                    None => writeln!(out, "Only inserts and copies take a request key.")?,
+                },
+                None => writeln!(
+                    out,
+                    "Request takes a u64 key and a command, request $key insert $id, $val."
+                )?,
+            }
+            return Ok(true);
+        }
+        if line.starts_with("alter table ") && line.contains(" drop column ") {
+            let mut db = lock(&db);
+            let (_, name) = line.split_once(" drop column ").unwrap();
+            match db.schema.names.iter().position(|n| n == name.trim()) {
+                Some(column) => {
+                    if let Err(e) = db.drop_column(column) {
+                        writeln!(out, "{e}, rejecting alter table.")?;
+                    }
                 }
-                if line.starts_with("sync") {
-                    let db = db.as_mut().unwrap();
-                    db.sync();
+                None => writeln!(out, "Column {} not found.", name.trim())?,
+            }
+            return Ok(true);
+        }
+        if line.starts_with("alter table ") {
+            let mut db = lock(&db);
+            let column = line
+                .strip_prefix("alter table ")
+                .unwrap()
+                .split_once(" add column ")
+                .map(|(_, column)| schema::parse_column(column));
+            match column {
+                Some(Ok(Column {
+                    name,
+                    row_type,
+                    default: Some(default),
+                    constraints,
+                })) if constraints.is_empty() => {
+                    if let Err(e) = db.add_column(&name, row_type, default) {
+                        writeln!(out, "{e}, rejecting alter table.")?;
+                    }
                 }
+                Some(Ok(_)) => writeln!(
+                    out,
+                    "Added columns need a default and can't have constraints."
+                )?,
+                Some(Err(e)) => writeln!(out, "{e}, rejecting alter table.")?,
+                _ => writeln!(out, "Could not parse alter table statement.")?,
             }
-            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) | Err(_) => {
-                break;
+            return Ok(true);
+        }
+        if line.trim() == "exit" {
+            return Ok(false);
+        }
+        if line.trim() == "undo" || line.trim() == "redo" {
+            let mut db = lock(&db);
+            let res = if line.trim() == "undo" {
+                self.undo_log.undo(&mut db)
+            } else {
+                self.undo_log.redo(&mut db)
+            };
+            match res {
+                Ok(true) => {}
+                Ok(false) => writeln!(out, "Nothing to {}.", line.trim())?,
+                Err(e) => writeln!(out, "{e}, could not {}.", line.trim())?,
+            }
+            return Ok(true);
+        }
+        if line.trim() == "begin" {
+            if self.txn.is_some() {
+                writeln!(out, "A transaction is already open.")?;
+            } else {
+                self.txn = Some(lock(&db).begin());
+            }
+            return Ok(true);
+        }
+        if line.trim() == "commit" {
+            match self.txn.take() {
+                Some(txn) => {
+                    let mut db = lock(&db);
+                    let writes = match txn.records() {
+                        Ok(writes) => writes,
+                        Err(e) => {
+                            writeln!(out, "{e}, rolling back.")?;
+                            return Ok(true);
+                        }
+                    };
+                    let inverse = UndoLog::inverse(&db, &writes);
+                    match db.commit(txn) {
+                        Ok(()) => self.undo_log.push(inverse, writes),
+                        Err(e) => writeln!(out, "{e}, rolling back.")?,
+                    }
+                }
+                None => writeln!(out, "No transaction is open.")?,
+            }
+            return Ok(true);
+        }
+        if let Some(name) = line.strip_prefix("savepoint ") {
+            match &mut self.txn {
+                Some(txn) => txn.savepoint(name.trim()),
+                None => writeln!(out, "No transaction is open.")?,
             }
+            return Ok(true);
         }
+        if let Some(name) = line.strip_prefix("rollback to ") {
+            match &mut self.txn {
+                Some(txn) => {
+                    if let Err(e) = txn.rollback_to(name.trim()) {
+                        writeln!(out, "{e}.")?;
+                    }
+                }
+                None => writeln!(out, "No transaction is open.")?,
+            }
+            return Ok(true);
+        }
+        if line.trim() == "rollback" {
+            if self.txn.take().is_none() {
+                writeln!(out, "No transaction is open.")?;
+            }
+            return Ok(true);
+        }
+        if line.starts_with("insert ") {
+            let copy = line.strip_prefix("insert ").unwrap();
+            let (id, vals) = match parse_insert(copy) {
+                Ok(row) => row,
+                Err(e) => {
+                    writeln!(out, "{e}, rejecting insert.")?;
+                    return Ok(true);
+                }
+            };
+            let mut db = lock(&db);
+            if let Some(txn) = &mut self.txn {
+                txn.insert(id, &vals);
+            } else {
+                let writes = vec![WALRecord::Insert(id, vals.clone())];
+                let inverse = UndoLog::inverse(&db, &writes);
+                match db.insert(id, &vals) {
+                    Ok(()) => self.undo_log.push(inverse, writes),
+                    Err(e) => writeln!(out, "{e}, rejecting insert.")?,
+                }
+            }
+        }
+        if line.starts_with("get ") {
+            let copy = line.strip_prefix("get ").unwrap();
+            let (copy, lsn) = match copy.split_once(" as of ") {
+                Some((id, lsn)) => (id, Some(lsn.trim().parse::<u64>())),
+                None => (copy, None),
+            };
+            let id = match parse_id(copy) {
+                Ok(id) => id,
+                Err(e) => {
+                    writeln!(out, "{e}.")?;
+                    return Ok(true);
+                }
+            };
+            let db = lock(&db);
+            let val = match lsn {
+                None => match self.txn.as_ref().map(|txn| txn.get(&db, id)) {
+                    Some(Ok(val)) => val,
+                    Some(Err(e)) => {
+                        writeln!(out, "{e}.")?;
+                        return Ok(true);
+                    }
+                    None => db.get(id),
+                },
+                Some(Ok(lsn)) => match db.get_as_of(id, lsn) {
+                    Ok(val) => val,
+                    Err(e) => {
+                        writeln!(out, "{e}.")?;
+                        return Ok(true);
+                    }
+                },
+                Some(Err(_)) => {
+                    writeln!(out, "Could not parse LSN.")?;
+                    return Ok(true);
+                }
+            };
+            if let Some(val) = val {
+                writeln!(out, "{}", format_row(id, &val))?;
+            } else {
+                writeln!(out, "Key {id} not found.")?;
+            }
+        }
+        if let Some(range) = line.strip_prefix("scan ") {
+            let db = lock(&db);
+            match range
+                .split_once(' ')
+                .and_then(|(from, to)| parse_range(from, to.trim()))
+            {
+                Some(range) => {
+                    for (id, vals) in db.scan(range) {
+                        writeln!(out, "{}", format_row(id, &vals))?;
+                    }
+                }
+                None => writeln!(out, "Scan takes two ids, scan $from $to.")?,
+            }
+        }
+        if line.starts_with("export ") {
+            let args: Vec<_> = line.split_whitespace().collect();
+            let (format, path, range) = match args[..] {
+                ["export", format, path, "scan", from, to] => {
+                    (format.parse::<Format>(), path, parse_range(from, to))
+                }
+                _ => (Err(()), "", None),
+            };
+            match (format, range) {
+                (Ok(format), Some(range)) => {
+                    let db = lock(&db);
+                    let exported = File::create(path).and_then(|mut file| {
+                        db.scan(range).into_iter().export(&db.schema, format, &mut file)
+                    });
+                    match exported {
+                        Ok(count) => writeln!(out, "Exported {count} rows to {path}")?,
+                        Err(e) => writeln!(out, "Export failed: {e}")?,
+                    }
+                }
+                _ => writeln!(
+                    out,
+                    "Export takes a format (csv or json), a path and a scan, like export csv out.csv scan 1 1000."
+                )?,
+            }
+        }
+        if line.starts_with("delete ") {
+            let copy = line.strip_prefix("delete ").unwrap();
+            let id = match parse_id(copy) {
+                Ok(id) => id,
+                Err(e) => {
+                    writeln!(out, "{e}, rejecting delete.")?;
+                    return Ok(true);
+                }
+            };
+            let mut db = lock(&db);
+            let val = match &mut self.txn {
+                Some(txn) => {
+                    let val = txn.get(&db, id);
+                    if val.is_ok() {
+                        txn.remove(id);
+                    }
+                    val
+                }
+                None => {
+                    let writes = vec![WALRecord::Delete(id)];
+                    let inverse = UndoLog::inverse(&db, &writes);
+                    let val = db.try_remove(id);
+                    if let Ok(Some(_)) = val {
+                        self.undo_log.push(inverse, writes);
+                    }
+                    val
+                }
+            };
+            let val = match val {
+                Ok(val) => val,
+                Err(e) => {
+                    writeln!(out, "{e}, rejecting delete.")?;
+                    return Ok(true);
+                }
+            };
+            if let Some(val) = val {
+                let mut res = String::new();
+                res.push_str(&format!("Removing {id}: ["));
+                for v in val {
+                    res.push_str(&v.to_string());
+                    res.push_str(", ");
+                }
+                res.pop();
+                res.pop();
+                res.push(']');
+                writeln!(out, "{}", res)?;
+            } else {
+                writeln!(out, "Key {id} not found.")?;
+            }
+        }
+        if line.starts_with("soft delete ") {
+            let copy = line.strip_prefix("soft delete ").unwrap();
+            let id = match parse_id(copy) {
+                Ok(id) => id,
+                Err(e) => {
+                    writeln!(out, "{e}, rejecting delete.")?;
+                    return Ok(true);
+                }
+            };
+            let mut db = lock(&db);
+            match db.soft_delete(id) {
+                Ok(true) => writeln!(out, "Soft deleted {id}.")?,
+                Ok(false) => writeln!(out, "Key {id} not found.")?,
+                Err(e) => writeln!(out, "{e}, rejecting delete.")?,
+            }
+        }
+        if line.starts_with("restore ") {
+            let copy = line.strip_prefix("restore ").unwrap();
+            let id = match parse_id(copy) {
+                Ok(id) => id,
+                Err(e) => {
+                    writeln!(out, "{e}, rejecting restore.")?;
+                    return Ok(true);
+                }
+            };
+            let mut db = lock(&db);
+            match db.restore(id) {
+                Ok(Some(_)) => writeln!(out, "Restored {id}.")?,
+                Ok(None) => writeln!(out, "Key {id} is not soft deleted.")?,
+                Err(e) => writeln!(out, "{e}, rejecting restore.")?,
+            }
+        }
+        if line.trim() == "show schema" {
+            let db = lock(&db);
+            writeln!(out, "{} ({})", db.file_name, dump::columns(&db.schema))?;
+            for check in &db.schema.checks {
+                writeln!(out, "check: {check}")?;
+            }
+            return Ok(true);
+        }
+        if line.starts_with("show") {
+            let db = lock(&db);
+            writeln!(out, "Pages: ")?;
+            writeln!(out, "{:?}", db.pages)?;
+            writeln!(out, "WAL: ")?;
+            writeln!(out, "{:?}", db.wal)?;
+            writeln!(out, "Schema: ")?;
+            writeln!(out, "{:?}", db.schema)?;
+        }
+        if line.starts_with("durability ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("durability ").unwrap().trim() {
+                "off" => db.durability = Durability::Off,
+                "wal" => db.durability = Durability::WalPerCommit,
+                "full" => db.durability = Durability::Full,
+                level => match level.strip_prefix("batched ").map(|ms| ms.trim().parse()) {
+                    Some(Ok(ms)) => db.durability = Durability::Batched(Duration::from_millis(ms)),
+                    _ => writeln!(out, "Durability has to be off, wal, full or batched $ms.")?,
+                },
+            }
+        }
+        if line.starts_with("checksum ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("checksum ").unwrap().trim() {
+                "crc32" => db.set_checksum(Checksum::Crc32),
+                "crc32c" => db.set_checksum(Checksum::Crc32c),
+                "xxhash64" => db.set_checksum(Checksum::XxHash64),
+                _ => writeln!(out, "Checksum has to be crc32, crc32c or xxhash64.")?,
+            }
+        }
+        if line.trim() == "memory" {
+            let db = lock(&db);
+            let usage = db.memory_usage();
+            writeln!(out, "Pages: {} bytes", usage.pages)?;
+            writeln!(out, "WAL: {} bytes", usage.wal)?;
+            writeln!(out, "Total: {} bytes", usage.total())?;
+            if let Some(limit) = db.memory_limit {
+                writeln!(out, "WAL limit: {limit} bytes")?;
+            }
+            if let Some(limit) = db.wal_record_limit {
+                writeln!(out, "WAL limit: {limit} rows")?;
+            }
+            if let Some(limit) = db.stall_limit {
+                writeln!(out, "Stall limit: {limit} bytes")?;
+            }
+        }
+        if line.starts_with("memory limit ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("memory limit ").unwrap().trim() {
+                "off" => db.set_memory_limit(None),
+                limit => match limit.parse() {
+                    Ok(limit) => db.set_memory_limit(Some(limit)),
+                    Err(_) => writeln!(out, "Could not parse memory limit.")?,
+                },
+            }
+        }
+        if line.starts_with("stall limit ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("stall limit ").unwrap().trim() {
+                "off" => db.set_stall_limit(None),
+                limit => match limit.parse() {
+                    Ok(limit) => db.set_stall_limit(Some(limit)),
+                    Err(_) => writeln!(out, "Could not parse stall limit.")?,
+                },
+            }
+        }
+        if line.starts_with("wal limit ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("wal limit ").unwrap().trim() {
+                "off" => db.set_wal_record_limit(None),
+                limit => match limit.parse() {
+                    Ok(limit) => db.set_wal_record_limit(Some(limit)),
+                    Err(_) => writeln!(out, "Could not parse WAL limit.")?,
+                },
+            }
+        }
+        if line.trim() == "lsn" {
+            let db = lock(&db);
+            writeln!(out, "{}", db.wal.lsn)?;
+        }
+        if line.starts_with("history ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("history ").unwrap().trim() {
+                "off" => db.history.set_retention(0),
+                writes => match writes.parse() {
+                    Ok(writes) => db.history.set_retention(writes),
+                    Err(_) => writeln!(out, "Could not parse history retention.")?,
+                },
+            }
+        }
+        if line.trim() == "analyze" {
+            let mut db = lock(&db);
+            let names = db.schema.names.clone();
+            match db.analyze() {
+                Ok(stats) => {
+                    writeln!(out, "Rows: {}", stats.rows)?;
+                    for (i, column) in stats.columns.iter().enumerate() {
+                        let name = names.get(i).filter(|name| !name.is_empty());
+                        let name = name.cloned().unwrap_or_else(|| format!("column {i}"));
+                        let (Some(min), Some(max)) = (&column.min, &column.max) else {
+                            writeln!(out, "{name}: empty")?;
+                            continue;
+                        };
+                        let buckets: Vec<_> = column
+                            .histogram
+                            .iter()
+                            .map(|(last, count)| format!("{last}: {count}"))
+                            .collect();
+                        writeln!(
+                            out,
+                            "{name}: min {min}, max {max}, {} distinct, histogram [{}]",
+                            column.distinct,
+                            buckets.join(", ")
+                        )?;
+                    }
+                }
+                Err(e) => writeln!(out, "Analyze failed: {e}")?,
+            }
+        }
+        if line.trim() == "latency" {
+            let db = lock(&db);
+            write!(out, "{}", db.latencies)?;
+        }
+        if line.starts_with("sync") {
+            let mut db = lock(&db);
+            db.sync();
+        }
+        if line.starts_with("engine ") {
+            let mut db = lock(&db);
+            match line.strip_prefix("engine ").unwrap().trim() {
+                "btree" => db.set_engine(Engine::BTree),
+                "lsm" => db.set_engine(Engine::Lsm),
+                _ => writeln!(out, "Engine has to be btree or lsm.")?,
+            }
+        }
+        if line.trim() == "compact" {
+            let mut db = lock(&db);
+            db.compact();
+        }
+        if line.trim() == "compaction" {
+            let db = lock(&db);
+            writeln!(
+                out,
+                "Runs: {}, debt: {} bytes, write amplification: {:.2}",
+                db.lsm.runs.len(),
+                db.lsm.debt(),
+                db.lsm.metrics.write_amplification()
+            )?;
+        }
+        if let Some(args) = line.strip_prefix("compaction ") {
+            let compaction = parse_compaction(args);
+            let mut db = lock(&db);
+            if !compaction.is_some_and(|compaction| db.set_compaction(compaction)) {
+                writeln!(
+                    out,
+                    "Compaction has to be size-tiered $min_runs $ratio $max_runs or leveled $level0_runs $base_size $ratio $levels, with merges of at least 2 runs."
+                )?;
+            }
+        }
+        if line.trim() == "dump" || line.starts_with("dump ") {
+            let db = lock(&db);
+            let path = line["dump".len()..].trim();
+            let dumped = if path.is_empty() {
+                db.dump(out)
+            } else {
+                File::create(path).and_then(|mut file| db.dump(&mut file))
+            };
+            if let Err(e) = dumped {
+                writeln!(out, "Dump failed: {e}")?;
+            }
+        }
+        if let Some(path) = line.strip_prefix("import csv ") {
+            let imported =
+                File::open(path.trim()).and_then(|file| lock(&db).import_csv(BufReader::new(file)));
+            match imported {
+                Ok(report) => {
+                    for (line, reason) in &report.rejected {
+                        writeln!(out, "Line {line}: {reason}")?;
+                    }
+                    writeln!(
+                        out,
+                        "Imported {} rows, rejected {}",
+                        report.inserted,
+                        report.rejected.len()
+                    )?;
+                }
+                Err(e) => writeln!(out, "Import failed: {e}")?,
+            }
+        }
+        if let Some(path) = line.strip_prefix(".backup ") {
+            // writes can carry on while the backup is copied
+            let backup = lock(&db).backup(u64::MAX);
+            match backup.and_then(|backup| backup.write_to(path.trim()).map(|()| backup.lsn)) {
+                Ok(lsn) => writeln!(out, "Backed up to LSN {lsn}")?,
+                Err(e) => writeln!(out, "Backup failed: {e}")?,
+            }
+        }
+        if line.starts_with("checkpoint") {
+            let mut db = lock(&db);
+            match db.checkpoint() {
+                Ok(()) => writeln!(out, "Checkpointed to epoch {}", db.epoch)?,
+                Err(e) => writeln!(out, "Checkpoint failed: {e}")?,
+            }
+        }
+        Ok(true)
     }
-    drop(db);
-    rl.save_history("history.txt")
-}
 
-pub fn verify_insert(vals: &[RowVal], schema: &[RowType]) -> bool {
-    if vals.len() != schema.len() - 1 {
-        return false;
+    /// Inserts the `rows` rows of a `copy` message's `body` as one batch, see
+    /// `db::copy`, unless a write with the idempotency key `key` was.
+    fn copy(
+        &mut self,
+        key: Option<u64>,
+        rows: usize,
+        body: &[u8],
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        if self.table().partitions.is_some() {
+            return writeln!(out, "copy isn't supported on a partitioned table.");
+        }
+        let db = self.table().db.clone();
+        if db.is_none() {
+            return writeln!(out, "There's no table yet, create one with create table.");
+        }
+        if self.txn.is_some() {
+            return writeln!(
+                out,
+                "Copy can't run in a transaction, commit or roll it back first."
+            );
+        }
+        let mut db = lock(&db);
+        match copy::decode(body, rows, &db.schema.schema) {
+            Ok(rows) => {
+                let copied = match key {
+                    Some(key) => {
+                        let mut batch = WriteBatch::new();
+                        for (id, vals) in &rows {
+                            batch.insert(*id, vals);
+                        }
+                        db.apply_once(key, batch).map(drop)
+                    }
+                    None => db.bulk_insert(&rows),
+                };
+                if let Err(e) = copied {
+                    writeln!(out, "{e}, rejecting copy.")?;
+                }
+            }
+            Err(reason) => writeln!(out, "{reason}, rejecting copy.")?,
+        }
+        Ok(())
+    }
+
+    /// Runs `insert $row` unless a write with the idempotency key `key` was
+    /// applied before, in which case it prints nothing, as the first did.
+    fn insert_once(
+        &mut self,
+        db: &Option<Arc<Mutex<DB>>>,
+        key: u64,
+        row: &str,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        if self.txn.is_some() {
+            return writeln!(
+                out,
+                "A request key can't be used in a transaction, commit or roll it back first."
+            );
+        }
+        let (id, vals) = match parse_insert(row) {
+            Ok(row) => row,
+            Err(e) => return writeln!(out, "{e}, rejecting insert."),
+        };
+        let mut db = lock(db);
+        let writes = vec![WALRecord::Insert(id, vals.clone())];
+        let inverse = UndoLog::inverse(&db, &writes);
+        let mut batch = WriteBatch::new();
+        batch.insert(id, &vals);
+        match db.apply_once(key, batch) {
+            Ok(true) => self.undo_log.push(inverse, writes),
+            Ok(false) => {}
+            Err(e) => writeln!(out, "{e}, rejecting insert.")?,
+        }
+        Ok(())
     }
-    for i in 0..vals.len() {
-        match (&vals[i], &schema[i + 1]) {
-            (RowVal::Id(_), RowType::Id)
-            | (RowVal::U32(_), RowType::U32)
-            | (RowVal::Bytes(_), RowType::Bytes)
-            | (RowVal::Bool(_), RowType::Bool) => continue,
-            _ => return false,
+
+    /// Runs a command against the partitioned table, sending each read and
+    /// write to the partitions it covers.
+    fn run_partitioned(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let mut table = self.table();
+        let db = table.partitions.as_mut().unwrap();
+        let line = line.trim();
+        if line == "exit" {
+            return Ok(false);
+        }
+        if let Some(copy) = line.strip_prefix("insert ") {
+            let inserted = parse_insert(copy)
+                .and_then(|(id, vals)| db.insert(id, &vals).map_err(|e| e.to_string()));
+            if let Err(e) = inserted {
+                writeln!(out, "{e}, rejecting insert.")?;
+            }
+        } else if let Some(id) = line.strip_prefix("get ") {
+            match parse_id(id).map(|id| (id, db.get(id))) {
+                Ok((id, Some(val))) => writeln!(out, "{}", format_row(id, &val))?,
+                Ok((id, None)) => writeln!(out, "Key {id} not found.")?,
+                Err(e) => writeln!(out, "{e}.")?,
+            }
+        } else if let Some(range) = line.strip_prefix("scan ") {
+            match range
+                .split_once(' ')
+                .and_then(|(from, to)| parse_range(from, to.trim()))
+            {
+                Some(range) => {
+                    for (id, vals) in db.scan(range) {
+                        writeln!(out, "{}", format_row(id, &vals))?;
+                    }
+                }
+                None => writeln!(out, "Scan takes two ids, scan $from $to.")?,
+            }
+        } else if let Some(id) = line.strip_prefix("delete ") {
+            match parse_id(id).map(|id| (id, db.remove(id))) {
+                Ok((id, Some(val))) => writeln!(out, "Removing {}", format_row(id, &val))?,
+                Ok((id, None)) => writeln!(out, "Key {id} not found.")?,
+                Err(e) => writeln!(out, "{e}, rejecting delete.")?,
+            }
+        } else if line == "sync" {
+            db.sync();
+        } else if line.starts_with("request ") {
+            writeln!(out, "Request keys aren't supported on a partitioned table.")?;
+        } else if let Some(start) = line.strip_prefix("add partition ") {
+            match start.trim().parse() {
+                Ok(start) => match db.add_partition(start) {
+                    Ok(true) => {}
+                    Ok(false) => writeln!(out, "Partition {start} already exists.")?,
+                    Err(e) => writeln!(out, "{e}, rejecting add partition.")?,
+                },
+                Err(_) => writeln!(out, "Add partition takes the id it starts at.")?,
+            }
+        } else if let Some(start) = line.strip_prefix("drop partition ") {
+            match start.trim().parse() {
+                Ok(start) if db.drop_partition(start) => {
+                    writeln!(out, "Dropped partition {start}.")?
+                }
+                Ok(start) => writeln!(out, "There's no partition starting at {start}.")?,
+                Err(_) => writeln!(out, "Drop partition takes the id it starts at.")?,
+            }
+        } else if line == "show" {
+            for (start, partition) in &db.partitions {
+                writeln!(out, "Partition {start}: {} rows", partition.scan(..).len())?;
+            }
+        } else {
+            let command = line.split_whitespace().next().unwrap_or(line);
+            writeln!(out, "{command} isn't supported on a partitioned table.")?;
         }
+        Ok(true)
     }
-    true
 }
 
-pub fn parse_create_table(s: &str) -> Vec<RowType> {
-    let mut res = vec![];
+/// State the shell keeps between runs.
+pub struct ShellConfig {
+    /// Where command history is read from and saved to, or `None` to keep no
+    /// history.
+    pub history: Option<PathBuf>,
+}
 
-    for column_type in s.split(", ") {
-        let t = column_type.trim();
-        match t {
-            "bool" => res.push(RowType::Bool),
-            "id" => res.push(RowType::Id),
-            "string" => res.push(RowType::Bytes),
-            "u32" => res.push(RowType::U32),
-            _ => todo!(),
+impl ShellConfig {
+    /// History is turned off by `--no-history` or `DB_HISTORY=off`, and
+    /// `DB_HISTORY` can point it at a file. Otherwise it lives in
+    /// `$XDG_STATE_HOME/db/history.txt` (`~/.local/state/db/history.txt`),
+    /// falling back to `history.txt` in the current directory.
+    pub fn new(args: &[String]) -> Self {
+        if args.iter().any(|arg| arg == "--no-history") {
+            return Self { history: None };
         }
+        let history = match env::var_os("DB_HISTORY") {
+            Some(path) if path == "off" => None,
+            Some(path) => Some(PathBuf::from(path)),
+            None => env::var_os("XDG_STATE_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| {
+                    env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+                })
+                .map(|dir| dir.join("db/history.txt"))
+                .or_else(|| Some(PathBuf::from("history.txt"))),
+        };
+        Self { history }
     }
+}
 
-    res
+/// Syncs the WAL in the background once its rows take more than 1 MiB,
+/// checking every second.
+fn start_background_tasks(db: &Arc<Mutex<DB>>) -> BackgroundTasks {
+    DB::start_background_tasks(db, Duration::from_secs(1), 1024 * 1024)
 }
 
-pub fn parse_vals(vals: &[&str]) -> Vec<RowVal> {
-    let mut res = vec![];
-    for val in vals {
-        let trimmed = val.trim();
-        // string
-        if trimmed.starts_with('"') {
-            let bytes = trimmed
-                .strip_prefix('"')
-                .unwrap()
-                .strip_suffix('"')
-                .unwrap();
-            res.push(RowVal::Bytes(bytes.into()));
-        } else if trimmed == "false" {
-            res.push(RowVal::Bool(false));
-        } else if trimmed == "true" {
-            res.push(RowVal::Bool(true));
-        } else {
-            res.push(RowVal::U32(trimmed.parse().unwrap()));
-        }
+/// Parses `size-tiered $min_runs $ratio $max_runs` or
+/// `leveled $level0_runs $base_size $ratio $levels`.
+fn parse_compaction(args: &str) -> Option<Compaction> {
+    let args: Vec<_> = args.split_whitespace().collect();
+    match args[..] {
+        ["size-tiered", min_runs, ratio, max_runs] => Some(Compaction::SizeTiered {
+            min_runs: min_runs.parse().ok()?,
+            ratio: ratio.parse().ok()?,
+            max_runs: max_runs.parse().ok()?,
+        }),
+        ["leveled", level0_runs, base_size, ratio, levels] => Some(Compaction::Leveled {
+            level0_runs: level0_runs.parse().ok()?,
+            base_size: base_size.parse().ok()?,
+            ratio: ratio.parse().ok()?,
+            levels: levels.parse().ok()?,
+        }),
+        _ => None,
     }
-    res
+}
+
+/// A row the way `get` and `scan` print it, `$id: [$val, ...]`.
+fn format_row(id: impl Display, vals: &[RowVal]) -> String {
+    let vals: Vec<_> = vals.iter().map(|val| val.to_string()).collect();
+    format!("{id}: [{}]", vals.join(", "))
+}
+
+/// The ids from `$from` to `$to` that `scan` and `export` take.
+fn parse_range(from: &str, to: &str) -> Option<RangeInclusive<NonZeroU32>> {
+    Some(from.parse().ok()?..=to.parse().ok()?)
+}
+
+/// The id an `insert`, `get` or `delete` takes. 0 is refused here, the way
+/// `DB::insert` refuses it, rather than left to panic.
+fn parse_id(s: &str) -> std::result::Result<NonZeroU32, String> {
+    let id: u32 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("Could not parse id {}", s.trim()))?;
+    NonZeroU32::new(id).ok_or_else(|| DbError::ZeroId.to_string())
+}
+
+/// The id and values of `insert $id, $val, ...`.
+fn parse_insert(s: &str) -> std::result::Result<(NonZeroU32, Vec<RowVal>), String> {
+    let vals = schema::split_columns(s);
+    let id = parse_id(vals[0])?;
+    Ok((id, parse_vals(&vals[1..])?))
+}
+
+pub fn parse_vals(vals: &[&str]) -> std::result::Result<Vec<RowVal>, String> {
+    vals.iter()
+        .map(|val| {
+            schema::parse_value(val).ok_or_else(|| format!("Could not parse value {}", val.trim()))
+        })
+        .collect()
 }
@@ -0,0 +1,184 @@
+//! Where a database's files live. Each database has a directory named
+//! after it, holding:
+//!
+//! - `MANIFEST`, which names the current epoch
+//! - `LOCK`, locked by the process that has the database open
+//! - `{epoch}.db`, the header and pages, written to `{epoch}.db.tmp` first
+//!   when it's rewritten whole
+//! - `{epoch}.dwb`, the double-write buffer
+//! - `{epoch}.wal.0001`, `{epoch}.wal.0002` and so on, the WAL's segments
+//! - `STATS`, the column statistics, once the database has been analyzed
+//! - `RUNS`, listing the live sorted runs of the LSM engine, and the runs
+//!   themselves, `run.1`, `run.2` and so on
+//! - `BACKUP`, in a backup only, naming the LSN it was taken at and the
+//!   CRC32 of each of its files
+//!
+//! Only the epoch in the manifest is live, so switching to a new one is a
+//! matter of renaming a new manifest into place, and anything left behind
+//! by another epoch can be removed.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+const MANIFEST: &str = "MANIFEST";
+
+pub fn db_file(file_name: &str, epoch: u64) -> String {
+    format!("{file_name}/{epoch}.db")
+}
+
+/// The WAL's path, which its segments are named after.
+pub fn wal_file(file_name: &str, epoch: u64) -> String {
+    format!("{file_name}/{epoch}.wal")
+}
+
+pub fn buffer_file(file_name: &str, epoch: u64) -> String {
+    format!("{file_name}/{epoch}.dwb")
+}
+
+pub fn stats_file(file_name: &str) -> String {
+    format!("{file_name}/STATS")
+}
+
+pub fn runs_file(file_name: &str) -> String {
+    format!("{file_name}/RUNS")
+}
+
+pub fn run_file(file_name: &str, number: u64) -> String {
+    format!("{file_name}/run.{number}")
+}
+
+pub fn backup_file(file_name: &str) -> String {
+    format!("{file_name}/BACKUP")
+}
+
+pub fn lock_file(file_name: &str) -> String {
+    format!("{file_name}/LOCK")
+}
+
+/// The current epoch, or `None` if there's no database under `file_name`.
+pub fn read(file_name: &str) -> Option<u64> {
+    let manifest = fs::read_to_string(format!("{file_name}/{MANIFEST}")).ok()?;
+    manifest.strip_prefix("epoch ")?.trim().parse().ok()
+}
+
+/// Makes `epoch` the current one. The manifest is written to a temporary
+/// file and renamed over the old one once it's on disk, so a crash leaves
+/// either the old epoch current or the new one.
+pub fn write(file_name: &str, epoch: u64) -> io::Result<()> {
+    let path = format!("{file_name}/{MANIFEST}");
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, format!("epoch {epoch}\n"))?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    File::open(file_name)?.sync_all()
+}
+
+/// Removes the manifest, after which `file_name` no longer holds a
+/// database, whatever files are left in it.
+pub fn remove(file_name: &str) {
+    let _ = fs::remove_file(format!("{file_name}/{MANIFEST}"));
+}
+
+/// The epoch a file in the directory belongs to, and whether it's one of
+/// the files that epoch keeps around, rather than a `.tmp` left behind.
+fn epoch_of(name: &str) -> Option<(u64, bool)> {
+    let (epoch, rest) = name.split_once('.')?;
+    let kept = rest == "db" || rest == "dwb" || rest.starts_with("wal.");
+    Some((epoch.parse().ok()?, kept))
+}
+
+/// Removes every file that isn't part of `epoch`: those of older epochs,
+/// of a newer one a crash cut short before it was made current, and any
+/// `.tmp` file. With no epoch, every epoch's files are removed.
+pub fn remove_stale(file_name: &str, epoch: Option<u64>) {
+    for entry in fs::read_dir(file_name).into_iter().flatten().flatten() {
+        let name = entry.file_name();
+        let Some((file_epoch, kept)) = name.to_str().and_then(epoch_of) else {
+            continue;
+        };
+        if Some(file_epoch) != epoch || !kept {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Moves a database from the layout before directories, with every file
+/// named `{file_name}.{epoch}.*`, into a directory, making the latest epoch
+/// with a `.db` file the current one, as it was then. Does nothing if the
+/// directory exists already or there's nothing to move.
+pub fn migrate(file_name: &str) -> io::Result<()> {
+    let path = Path::new(file_name);
+    if path.exists() {
+        return Ok(());
+    }
+    let Some(prefix) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
+    };
+    let prefix = format!("{prefix}.");
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let files: Vec<_> = fs::read_dir(parent)?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(&prefix)?
+                .to_string();
+            epoch_of(&name)?;
+            Some((entry.path(), name))
+        })
+        .collect();
+    let latest = files
+        .iter()
+        .filter_map(|(_, name)| name.strip_suffix(".db")?.parse::<u64>().ok())
+        .max();
+    let Some(latest) = latest else {
+        return Ok(());
+    };
+    fs::create_dir_all(file_name)?;
+    for (from, name) in files {
+        fs::rename(from, format!("{file_name}/{name}"))?;
+    }
+    let _ = fs::remove_file(format!("{file_name}.lock"));
+    write(file_name, latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_and_remove_stale() {
+        let file_name = "tests/manifest";
+        let _ = fs::remove_dir_all(file_name);
+        for name in ["1.db", "2.db", "2.wal.0001", "2.wal.0002", "3.db.tmp"] {
+            fs::write(format!("{file_name}.{name}"), name).unwrap();
+        }
+        fs::write(format!("{file_name}.other.db"), "").unwrap();
+
+        migrate(file_name).unwrap();
+        assert_eq!(read(file_name), Some(2));
+        assert_eq!(fs::read_to_string(db_file(file_name, 2)).unwrap(), "2.db");
+        assert!(Path::new(&format!("{file_name}.other.db")).exists());
+
+        remove_stale(file_name, Some(2));
+        let mut names: Vec<_> = fs::read_dir(file_name)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["2.db", "2.wal.0001", "2.wal.0002", "MANIFEST"]);
+
+        write(file_name, 3).unwrap();
+        assert_eq!(read(file_name), Some(3));
+        remove(file_name);
+        assert_eq!(read(file_name), None);
+        fs::remove_file(format!("{file_name}.other.db")).unwrap();
+    }
+}
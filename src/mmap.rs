@@ -0,0 +1,302 @@
+//! Reading pages straight out of a `.db` file mapped into memory, which is
+//! how snapshots and databases opened with `DbOptions::mmap` read pages
+//! without copying the whole file first.
+
+use std::{
+    fs::File,
+    io,
+    num::NonZeroU32,
+    ops::{Bound, RangeBounds},
+    sync::Mutex,
+};
+
+use crate::{
+    bloom,
+    btree::{Root, Tree},
+    db::migrate_page,
+    header::{FileHeader, HEADER_SIZE},
+    page::{Page, PageHeader, PAGE_SIZE},
+    pages::Pages,
+    pool::{BufferPool, DEFAULT_POOL_CAPACITY},
+    row::Schema,
+    snapshot::overlaps,
+};
+
+/// The pages of a `.db` file, each read from the mapped file when it's
+/// asked for rather than all of them when the file is opened. Pages are
+/// found by walking down the file's B+tree from its root, so only the pages
+/// on the way are read. Pages read are kept in a `BufferPool`, along with
+/// the changes made to them until they're written back.
+#[derive(Debug)]
+pub(crate) struct MappedPages {
+    map: Mmap,
+    /// The root of the tree, from the file header.
+    root: Option<Root>,
+    pool: Mutex<BufferPool>,
+}
+
+impl MappedPages {
+    pub(crate) fn new(file: &File) -> io::Result<Self> {
+        let map = Mmap::new(file)?;
+        // a file that isn't a database is refused by the caller
+        let root = FileHeader::from_bytes(map.bytes())
+            .ok()
+            .and_then(|header| header.root);
+        Ok(Self {
+            map,
+            root,
+            pool: Mutex::new(BufferPool::new(DEFAULT_POOL_CAPACITY)),
+        })
+    }
+
+    /// Maps `file` again once pages have been written back to it, keeping
+    /// the pages in the pool, which have to be clean.
+    pub(crate) fn remap(self, file: &File) -> io::Result<Self> {
+        Ok(Self {
+            pool: self.pool,
+            ..Self::new(file)?
+        })
+    }
+
+    /// The whole file, header included.
+    pub(crate) fn bytes(&self) -> &[u8] {
+        self.map.bytes()
+    }
+
+    /// How many pages the file holds, empty and interior ones included.
+    pub(crate) fn page_count(&self) -> usize {
+        self.bytes().len().saturating_sub(HEADER_SIZE) / PAGE_SIZE
+    }
+
+    pub(crate) fn root(&self) -> Option<Root> {
+        self.root
+    }
+
+    pub(crate) fn tree(&self) -> Tree<'_> {
+        Tree::new(self.bytes(), self.root)
+    }
+
+    /// Whether there are no rows on any page.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// The header of the page at index `i`, as it is in the file.
+    pub(crate) fn header(&self, i: usize) -> PageHeader {
+        self.tree().header(i)
+    }
+
+    /// The highest LSN of any page.
+    pub(crate) fn lsn(&self) -> u64 {
+        self.tree().lsn()
+    }
+
+    /// The header and index of every page with rows on it, in key order.
+    pub(crate) fn leaves(&self) -> impl Iterator<Item = (PageHeader, usize)> + '_ {
+        self.tree().leaves()
+    }
+
+    /// Whether `id` might be on the page at index `i`, from its bloom filter
+    /// if its header says it has one and it hasn't changed since it was
+    /// written, without reading it into the pool.
+    pub(crate) fn may_contain(&self, i: usize, id: NonZeroU32) -> bool {
+        if !self.header(i).bloom || self.pool.lock().unwrap().dirty(i).is_some() {
+            return true;
+        }
+        let start = HEADER_SIZE + i * PAGE_SIZE;
+        bloom::may_contain(&self.bytes()[start..start + PAGE_SIZE], id)
+    }
+
+    /// The page at index `i` in the file, migrated to `schema`, read through
+    /// the pool.
+    pub(crate) fn page(&self, i: usize, schema: &Schema) -> Page {
+        self.with_page(i, schema, Page::clone)
+    }
+
+    /// Runs `f` on the page at index `i`, read through the pool, without
+    /// copying it out.
+    pub(crate) fn with_page<T>(&self, i: usize, schema: &Schema, f: impl FnOnce(&Page) -> T) -> T {
+        let mut pool = self.pool.lock().unwrap();
+        f(pool.get(i, || self.read(i, schema)))
+    }
+
+    /// Runs `f` on the page at index `i`, which is marked dirty and held in
+    /// the pool until it's written back.
+    pub(crate) fn update<T>(&self, i: usize, schema: &Schema, f: impl FnOnce(&mut Page) -> T) -> T {
+        let mut pool = self.pool.lock().unwrap();
+        f(pool.get_mut(i, || self.read(i, schema)))
+    }
+
+    fn read(&self, i: usize, schema: &Schema) -> Page {
+        let version = self.header(i).version;
+        let page = Page::read(self.bytes(), i, &schema.schema_at(version));
+        migrate_page(page, schema)
+    }
+
+    /// The page at index `i` if it has changed since it was read.
+    pub(crate) fn dirty(&self, i: usize) -> Option<Page> {
+        self.pool.lock().unwrap().dirty(i).cloned()
+    }
+
+    /// Removes every changed page from the pool, to be written back.
+    pub(crate) fn take_dirty(&self) -> Vec<(usize, Page)> {
+        self.pool.lock().unwrap().take_dirty()
+    }
+
+    pub(crate) fn pool_capacity(&self) -> usize {
+        self.pool.lock().unwrap().capacity()
+    }
+
+    pub(crate) fn set_pool_capacity(&self, capacity: usize) {
+        self.pool.lock().unwrap().set_capacity(capacity);
+    }
+
+    /// The encoded size of the pages held in the pool.
+    pub(crate) fn pool_size(&self) -> usize {
+        self.pool.lock().unwrap().size()
+    }
+
+    /// The index of the page a row with `id` goes on: the first one that
+    /// ends at or after it, or the last one if none does. `None` if there
+    /// are no pages.
+    pub(crate) fn target(&self, id: NonZeroU32) -> Option<usize> {
+        self.tree().leaf(id, true)
+    }
+
+    /// The header and index of the page `id` would be on, if there's one.
+    pub(crate) fn find(&self, id: NonZeroU32) -> Option<(PageHeader, usize)> {
+        let i = self.tree().leaf(id, false)?;
+        Some((self.header(i), i))
+            .filter(|(header, _)| header.start.is_some_and(|start| start <= id))
+    }
+
+    /// The indexes of the pages that could hold ids in `range`, starting
+    /// from the page its start falls on and following the leaves from there.
+    pub(crate) fn in_range<'a>(
+        &'a self,
+        range: &'a impl RangeBounds<NonZeroU32>,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let start = match range.start_bound() {
+            Bound::Included(id) | Bound::Excluded(id) => *id,
+            Bound::Unbounded => NonZeroU32::MIN,
+        };
+        self.tree()
+            .leaves_from(start)
+            .take_while(move |(header, _)| {
+                let start = header.start.unwrap();
+                match range.end_bound() {
+                    Bound::Included(end) => start <= *end,
+                    Bound::Excluded(end) => start < *end,
+                    Bound::Unbounded => true,
+                }
+            })
+            .filter(move |(header, _)| match (header.start, header.end) {
+                (Some(start), Some(end)) => overlaps(range, start, end),
+                _ => false,
+            })
+            .map(|(_, i)| i)
+    }
+
+    /// Reads every page, the way `deserialize` does, taking the ones that
+    /// changed from the pool.
+    pub(crate) fn load(&self, schema: &Schema) -> Pages {
+        let mut pages = vec![];
+        for (_, i) in self.leaves() {
+            let page = self.dirty(i).unwrap_or_else(|| self.read(i, schema));
+            if page.is_empty() {
+                continue;
+            }
+            if page.size() > PAGE_SIZE {
+                pages.extend(page.split_to_fit().into_iter().map(|page| (page, None)));
+            } else {
+                pages.push((page, Some(i)));
+            }
+        }
+        Pages::from_iter(pages)
+    }
+}
+
+/// A file mapped read-only into memory.
+#[cfg(unix)]
+#[derive(Debug)]
+pub(crate) struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// the mapping is read-only and unmapped only on drop, so it can be read from
+// any thread
+#[cfg(unix)]
+unsafe impl Send for Mmap {}
+#[cfg(unix)]
+unsafe impl Sync for Mmap {}
+
+#[cfg(unix)]
+impl Mmap {
+    pub(crate) fn new(file: &File) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        // a database that hasn't been written yet has an empty file, which
+        // can't be mapped
+        if len == 0 {
+            return Ok(Self {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+/// Without `mmap` the file is read into memory instead.
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub(crate) struct Mmap(Vec<u8>);
+
+#[cfg(not(unix))]
+impl Mmap {
+    pub(crate) fn new(mut file: &File) -> io::Result<Self> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut bytes = vec![];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        Ok(Self(bytes))
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
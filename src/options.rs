@@ -0,0 +1,420 @@
+use crate::{
+    checksum::Checksum,
+    db::{Durability, MergeOperator, Retention, DB},
+    error::DbError,
+    lsm::{Compaction, Engine},
+    page::{Layout, SplitPolicy},
+    row::{RowType, RowVal},
+    wal::GroupCommit,
+};
+
+/// How to open a `DB`, from `DB::options`. Each setting is the same as
+/// setting it on the `DB` once it's open.
+#[derive(Debug, Clone, Default)]
+pub struct DbOptions {
+    /// Whether a database that doesn't exist yet is created.
+    pub create: bool,
+    /// Whether the database is opened read-only, see `DB::read_only`. One
+    /// that doesn't exist isn't created, and settings that would rewrite it
+    /// are refused.
+    pub read_only: bool,
+    /// The schema to create the database with. An existing database with a
+    /// different one is refused.
+    pub schema: Option<Vec<RowType>>,
+    pub durability: Durability,
+    /// How the WAL's fsyncs are grouped, see `DB::set_group_commit`.
+    pub group_commit: Option<GroupCommit>,
+    /// What the WAL is checksummed with. An existing database switches to
+    /// it if it's different, which syncs it.
+    pub checksum: Option<Checksum>,
+    /// How the rows on each page are laid out. An existing database switches
+    /// to it if it's different, which rewrites every page.
+    pub layout: Option<Layout>,
+    /// Whether leaves are written with bloom filters, see
+    /// `DB::bloom_filters`. An existing database keeps its setting if this
+    /// is left unset.
+    pub bloom_filters: Option<bool>,
+    /// What the rows are stored with. An existing database switches to it if
+    /// it's different, see `DB::set_engine`.
+    pub engine: Option<Engine>,
+    /// How the LSM engine's runs are merged, see `DB::set_compaction`.
+    pub compaction: Option<Compaction>,
+    pub memory_limit: Option<usize>,
+    pub wal_record_limit: Option<usize>,
+    pub stall_limit: Option<usize>,
+    /// How many writes `DB::get_as_of` can go back. 0 keeps no history.
+    pub history: usize,
+    pub direct_io: bool,
+    /// Whether an existing database is opened with `DB::open_mapped`,
+    /// reading pages from the mapped file until it's first written to.
+    pub mmap: bool,
+    /// How many pages a mapped database keeps in its buffer pool, see
+    /// `DB::set_pool_capacity`.
+    pub pool_capacity: Option<usize>,
+    /// When pages split and where, see `DB::set_split_policy`.
+    pub split_policy: Option<SplitPolicy>,
+    /// The rows dropped on every `sync`, see `DB::set_retention`. An
+    /// existing database keeps the policy saved with it if this is left
+    /// unset.
+    pub retention: Option<Retention>,
+    /// What `DB::merge` combines rows with, which replaying the merges in
+    /// an existing database's WAL needs.
+    pub merge_operator: Option<MergeOperator>,
+}
+
+impl DbOptions {
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn schema(mut self, schema: &[RowType]) -> Self {
+        self.schema = Some(schema.to_vec());
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn group_commit(mut self, group_commit: GroupCommit) -> Self {
+        self.group_commit = Some(group_commit);
+        self
+    }
+
+    pub fn checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    pub fn bloom_filters(mut self, bloom_filters: bool) -> Self {
+        self.bloom_filters = Some(bloom_filters);
+        self
+    }
+
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = Some(engine);
+        self
+    }
+
+    pub fn compaction(mut self, compaction: Compaction) -> Self {
+        self.compaction = Some(compaction);
+        self
+    }
+
+    pub fn memory_limit(mut self, limit: Option<usize>) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    pub fn wal_record_limit(mut self, limit: Option<usize>) -> Self {
+        self.wal_record_limit = limit;
+        self
+    }
+
+    pub fn stall_limit(mut self, limit: Option<usize>) -> Self {
+        self.stall_limit = limit;
+        self
+    }
+
+    pub fn history(mut self, writes: usize) -> Self {
+        self.history = writes;
+        self
+    }
+
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    pub fn pool_capacity(mut self, pages: usize) -> Self {
+        self.pool_capacity = Some(pages);
+        self
+    }
+
+    pub fn split_policy(mut self, policy: SplitPolicy) -> Self {
+        self.split_policy = Some(policy);
+        self
+    }
+
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    pub fn merge_operator(
+        mut self,
+        merge: impl Fn(Option<&[RowVal]>, &[RowVal]) -> Vec<RowVal> + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_operator = Some(MergeOperator::new(merge));
+        self
+    }
+
+    /// Opens the database saved under `file_name`, or creates it if it
+    /// doesn't exist and `create` is set.
+    pub fn open(self, file_name: &str) -> Result<DB, DbError> {
+        if let Some(policy) = self.split_policy.filter(|policy| !policy.is_valid()) {
+            return Err(DbError::InvalidOption(format!(
+                "split policy {policy:?} needs a fill factor in (0, 1] and a split point in (0, 1)"
+            )));
+        }
+        if let Some(compaction) = self.compaction.filter(|compaction| !compaction.is_valid()) {
+            return Err(DbError::InvalidOption(format!(
+                "compaction {compaction:?} needs merges of at least 2 runs and levels that grow"
+            )));
+        }
+        let existing = DB::open_with(
+            file_name,
+            self.mmap,
+            self.read_only,
+            self.merge_operator.clone(),
+        )?;
+        let mut db = match existing {
+            Some(db) => {
+                if self
+                    .schema
+                    .as_ref()
+                    .is_some_and(|schema| *schema != db.schema.schema)
+                {
+                    return Err(DbError::SchemaMismatch);
+                }
+                db
+            }
+            None if self.create && !self.read_only => {
+                let schema = self.schema.as_ref().ok_or_else(|| {
+                    DbError::InvalidSchema(format!("{file_name} needs a schema to be created"))
+                })?;
+                DB::new(file_name, schema)?
+            }
+            None => return Err(DbError::NotFound(file_name.to_string())),
+        };
+        if self.read_only
+            && (self
+                .checksum
+                .is_some_and(|checksum| checksum != db.checksum)
+                || self.layout.is_some_and(|layout| layout != db.layout)
+                || self.engine.is_some_and(|engine| engine != db.engine))
+        {
+            return Err(DbError::InvalidOption(
+                "a read-only database can't switch its checksum, layout or engine".to_string(),
+            ));
+        }
+        db.durability = self.durability;
+        db.set_group_commit(self.group_commit);
+        db.merge_operator = self.merge_operator;
+        db.direct_io = self.direct_io;
+        if let Some(checksum) = self.checksum.filter(|&checksum| checksum != db.checksum) {
+            db.set_checksum(checksum);
+        }
+        if let Some(layout) = self.layout.filter(|&layout| layout != db.layout) {
+            db.set_layout(layout);
+        }
+        if let Some(engine) = self.engine.filter(|&engine| engine != db.engine) {
+            db.set_engine(engine);
+        }
+        if let Some(compaction) = self.compaction {
+            db.set_compaction(compaction);
+        }
+        if let Some(bloom_filters) = self.bloom_filters {
+            db.bloom_filters = bloom_filters;
+        }
+        db.set_memory_limit(self.memory_limit);
+        db.set_wal_record_limit(self.wal_record_limit);
+        db.set_stall_limit(self.stall_limit);
+        db.history.set_retention(self.history);
+        if let Some(pages) = self.pool_capacity {
+            db.set_pool_capacity(pages);
+        }
+        if let Some(policy) = self.split_policy {
+            db.set_split_policy(policy);
+        }
+        if let Some(retention) = self.retention {
+            if !db.set_retention(retention) {
+                return Err(DbError::InvalidOption(format!(
+                    "retention needs a u32 column, not column {}",
+                    retention.column
+                )));
+            }
+        }
+        Ok(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, num::NonZeroU32, time::Duration};
+
+    use crate::row::RowVal;
+
+    use super::*;
+
+    #[test]
+    fn open_with_options() {
+        let file_name = "tests/open_with_options";
+        let _ = fs::remove_dir_all(file_name);
+        let schema = [RowType::Id, RowType::U32];
+        assert_eq!(
+            DB::options().open(file_name).unwrap_err(),
+            DbError::NotFound(file_name.to_string())
+        );
+        assert!(matches!(
+            DB::options().create(true).open(file_name),
+            Err(DbError::InvalidSchema(_))
+        ));
+        assert!(matches!(
+            DB::options()
+                .split_policy(SplitPolicy {
+                    fill_factor: 0.0,
+                    split_point: 0.5
+                })
+                .open(file_name),
+            Err(DbError::InvalidOption(_))
+        ));
+        assert!(matches!(
+            DB::options()
+                .compaction(Compaction::SizeTiered {
+                    min_runs: 1,
+                    ratio: 2.0,
+                    max_runs: 4
+                })
+                .open(file_name),
+            Err(DbError::InvalidOption(_))
+        ));
+
+        // old enough that none of the rows expire
+        let retention = Retention {
+            column: 1,
+            max_age: Duration::from_secs(u64::MAX),
+        };
+        assert!(matches!(
+            DB::options()
+                .create(true)
+                .schema(&schema)
+                .retention(Retention {
+                    column: 0,
+                    ..retention
+                })
+                .open(file_name),
+            Err(DbError::InvalidOption(_))
+        ));
+        let mut db = DB::options()
+            .create(true)
+            .schema(&schema)
+            .durability(Durability::Full)
+            .checksum(Checksum::XxHash64)
+            .wal_record_limit(Some(2))
+            .history(10)
+            .retention(retention)
+            .open(file_name)
+            .unwrap();
+        assert_eq!(db.durability, Durability::Full);
+        assert_eq!(db.checksum, Checksum::XxHash64);
+        assert_eq!(db.history.retention(), 10);
+        for id in 1..=3 {
+            db.insert(NonZeroU32::new(id).unwrap(), &[RowVal::U32(id)])
+                .unwrap();
+        }
+        // the third insert took the WAL over its limit, which synced it
+        assert!(db.wal.records.is_empty());
+        drop(db);
+
+        // the checksum and retention are saved with the database, the rest
+        // isn't
+        let db = DB::options().open(file_name).unwrap();
+        assert_eq!(db.checksum, Checksum::XxHash64);
+        assert_eq!(db.schema.retention, Some(retention));
+        assert_eq!(db.durability, Durability::Off);
+        assert_eq!(db.scan(..).len(), 3);
+        drop(db);
+        assert_eq!(
+            DB::options()
+                .schema(&[RowType::Id, RowType::Bytes])
+                .open(file_name)
+                .unwrap_err(),
+            DbError::SchemaMismatch
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let file_name = "tests/read_only";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let files = || -> Vec<_> {
+            let mut files: Vec<_> = fs::read_dir(file_name)
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    (path.clone(), fs::read(path).unwrap())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(
+            DB::options()
+                .read_only(true)
+                .create(true)
+                .schema(&[RowType::Id, RowType::U32])
+                .open(file_name)
+                .unwrap_err(),
+            DbError::NotFound(file_name.to_string())
+        );
+        let mut db = DB::new(file_name, &[RowType::Id, RowType::U32]).unwrap();
+        db.insert(id(1), &[RowVal::U32(1)]).unwrap();
+        db.sync();
+        // left in the WAL
+        db.insert(id(2), &[RowVal::U32(2)]).unwrap();
+        drop(db);
+        let before = files();
+
+        // any number of readers share the database, but not with a writer
+        let mut db = DB::options().read_only(true).open(file_name).unwrap();
+        let other = DB::options().read_only(true).open(file_name).unwrap();
+        assert_eq!(
+            DB::open(file_name).unwrap_err(),
+            DbError::Locked(file_name.to_string())
+        );
+        assert_eq!(db.scan(..), other.scan(..));
+        assert_eq!(db.get(id(2)), Some(vec![RowVal::U32(2)]));
+
+        assert_eq!(db.insert(id(3), &[RowVal::U32(3)]), Err(DbError::ReadOnly));
+        assert_eq!(db.try_remove(id(1)), Err(DbError::ReadOnly));
+        assert_eq!(db.bulk_insert(&[(id(3), vec![])]), Err(DbError::ReadOnly));
+        assert!(!db.sync());
+        assert!(db.checkpoint().is_err());
+        drop(db);
+        drop(other);
+        assert_eq!(files(), before);
+
+        let writer = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(
+            DB::options().read_only(true).open(file_name).unwrap_err(),
+            DbError::Locked(file_name.to_string())
+        );
+        drop(writer);
+        assert!(matches!(
+            DB::options()
+                .read_only(true)
+                .checksum(Checksum::Crc32c)
+                .open(file_name),
+            Err(DbError::InvalidOption(_))
+        ));
+    }
+}
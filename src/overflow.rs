@@ -0,0 +1,114 @@
+//! Overflow pages, which hold the `Bytes` values of rows too large to fit on
+//! a leaf. Such a row has each of its `Bytes` values spilled, leaving a
+//! reference on the leaf in its place: `SPILLED` where an inline value's
+//! length would be, its real length, and the index of the first page holding
+//! it. Each overflow page points at the next like leaves do, and starts with
+//! the header of an empty leaf, so anything reading the pages in file order
+//! passes over it.
+
+use crate::{
+    header::HEADER_SIZE,
+    page::{PageHeader, MAX_ROW_SIZE, PAGE_SIZE},
+    row::{row_size, RowVal},
+    utils::bytes_to_u32,
+};
+
+/// Stands in for the length of a spilled value. It's never the length of a
+/// value kept on a leaf, which is at most `MAX_ROW_SIZE`.
+pub const SPILLED: u16 = u16::MAX;
+
+/// The size of the reference a spilled value leaves on its leaf.
+pub const SPILLED_SIZE: usize = 8;
+
+/// How many bytes of a value each overflow page holds.
+const CAPACITY: usize = PAGE_SIZE - PageHeader::size();
+
+/// Whether a row with `vals` is too large for a leaf, and so has its values
+/// spilled.
+pub fn spills(vals: &[RowVal]) -> bool {
+    row_size(vals) > MAX_ROW_SIZE
+}
+
+/// Whether `val` is spilled when its row is, which every `Bytes` value
+/// larger than the reference left in its place is.
+pub fn is_spilled(val: &RowVal) -> bool {
+    matches!(val, RowVal::Bytes(bytes) if bytes.len() + 2 > SPILLED_SIZE)
+}
+
+/// The size a row with `vals` takes on a leaf, which for a row that spills
+/// counts each spilled value as its reference.
+pub fn stored_size(vals: &[RowVal]) -> usize {
+    if !spills(vals) {
+        return row_size(vals);
+    }
+    4 + vals
+        .iter()
+        .map(|val| match is_spilled(val) {
+            true => SPILLED_SIZE,
+            false => val.size(),
+        })
+        .sum::<usize>()
+}
+
+/// How many overflow pages the values of a row take.
+pub fn page_count(vals: &[RowVal]) -> usize {
+    if !spills(vals) {
+        return 0;
+    }
+    vals.iter()
+        .map(|val| match val {
+            RowVal::Bytes(bytes) if is_spilled(val) => bytes.len().div_ceil(CAPACITY),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Spills `bytes` to overflow pages, putting each at the index `slot` hands
+/// out. Returns the reference to leave on the leaf along with the pages.
+pub fn spill(bytes: &[u8], mut slot: impl FnMut() -> u32) -> (Vec<u8>, Vec<(u32, Vec<u8>)>) {
+    let indexes: Vec<_> = bytes.chunks(CAPACITY).map(|_| slot()).collect();
+    let pages = bytes
+        .chunks(CAPACITY)
+        .enumerate()
+        .map(|(j, chunk)| {
+            let header = PageHeader {
+                end: None,
+                start: None,
+                count: 0,
+                version: 0,
+                lsn: 0,
+                next: indexes.get(j + 1).copied(),
+                bloom: false,
+            };
+            let mut page = header.to_bytes();
+            page.extend(chunk);
+            page.resize(PAGE_SIZE, 0);
+            (indexes[j], page)
+        })
+        .collect();
+
+    let mut reference = SPILLED.to_le_bytes().to_vec();
+    reference.extend((bytes.len() as u16).to_le_bytes());
+    reference.extend(indexes.first().copied().unwrap_or(0).to_le_bytes());
+    (reference, pages)
+}
+
+/// Reads back a value spilled from a whole `.db` file, header included,
+/// given the reference left on its leaf.
+pub fn read(file: &[u8], reference: &[u8]) -> Vec<u8> {
+    let len = u16::from_le_bytes(reference[2..4].try_into().unwrap()) as usize;
+    let mut i = bytes_to_u32(&reference[4..8]) as usize;
+    let mut res = Vec::with_capacity(len);
+    while res.len() < len {
+        let start = HEADER_SIZE + i * PAGE_SIZE;
+        let page = &file[start..start + PAGE_SIZE];
+        let take = (len - res.len()).min(CAPACITY);
+        res.extend(&page[PageHeader::size()..PageHeader::size() + take]);
+        let header = PageHeader::from_bytes(page[..PageHeader::size()].try_into().unwrap());
+        match header.next {
+            Some(next) => i = next as usize,
+            None => break,
+        }
+    }
+    res
+}
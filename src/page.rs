@@ -1,38 +1,90 @@
 use crate::{
-    row::{bytes_to_values, split_row, RowType, RowVal},
-    utils::bytes_to_u32,
+    bloom,
+    header::{self, HEADER_SIZE},
+    overflow,
+    row::{
+        bytes_to_values, bytes_to_values_with, encoded_row_len, split_row, stored_row_size,
+        RowType, RowVal,
+    },
+    utils::{bytes_to_u32, read_varint, varint_len, write_varint},
+};
+use std::{
+    collections::{btree_map, BTreeMap},
+    fmt,
+    hash::{Hash, Hasher},
+    num::NonZeroU32,
+    ops::{Deref, DerefMut},
+    slice,
+    sync::{Mutex, OnceLock},
 };
-use std::{collections::BTreeMap, num::NonZeroU32};
 
 #[cfg(test)]
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(test, derive(Serialize, Deserialize))]
 pub struct PageHeader {
-    pub end: NonZeroU32,
-    pub start: NonZeroU32,
+    /// The largest id on the page, or `None` if the page is empty.
+    pub end: Option<NonZeroU32>,
+    /// The smallest id on the page, or `None` if the page is empty.
+    pub start: Option<NonZeroU32>,
     pub count: u32,
+    /// The schema version the rows on this page were written with.
+    pub version: u32,
+    /// The LSN of the last WAL record reflected on this page. Replay skips
+    /// records at or below it.
+    pub lsn: u64,
+    /// The index in the file of the next leaf in key order, or `None` for
+    /// the last one, so a scan can follow the leaves without going back up
+    /// the tree. Set as the page is written.
+    pub next: Option<u32>,
+    /// Whether the page ends in a bloom filter over its ids, see `bloom`.
+    /// Set as the page is written, and kept in the top bit of the version,
+    /// which no schema gets anywhere near.
+    pub bloom: bool,
 }
 
+/// The bit of the version a page with a bloom filter sets.
+const BLOOM_BIT: u32 = 1 << 31;
+
 impl PageHeader {
     pub fn to_bytes(self) -> Vec<u8> {
-        let mut res = self.end.get().to_le_bytes().to_vec();
-        res.extend(self.start.get().to_le_bytes());
+        // ids are never 0, so 0 marks the bounds of an empty page
+        let mut res = self.end.map_or(0, NonZeroU32::get).to_le_bytes().to_vec();
+        res.extend(self.start.map_or(0, NonZeroU32::get).to_le_bytes());
         res.extend(self.count.to_le_bytes());
+        let bloom = if self.bloom { BLOOM_BIT } else { 0 };
+        res.extend((self.version | bloom).to_le_bytes());
+        res.extend(self.lsn.to_le_bytes());
+        // likewise 0 marks the last leaf, since no leaf points back to the
+        // first one
+        res.extend(self.next.map_or(0, |i| i + 1).to_le_bytes());
         res
     }
 
-    pub fn from_bytes(bytes: &[u8; 12]) -> Self {
-        let end = NonZeroU32::new(bytes_to_u32(&bytes[0..4])).unwrap();
-        let start = NonZeroU32::new(bytes_to_u32(&bytes[4..8])).unwrap();
+    pub fn from_bytes(bytes: &[u8; 28]) -> Self {
+        let end = NonZeroU32::new(bytes_to_u32(&bytes[0..4]));
+        let start = NonZeroU32::new(bytes_to_u32(&bytes[4..8]));
         let count = bytes_to_u32(&bytes[8..12]);
+        let version = bytes_to_u32(&bytes[12..16]);
+        let bloom = version & BLOOM_BIT != 0;
+        let version = version & !BLOOM_BIT;
+        let lsn = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+        let next = bytes_to_u32(&bytes[24..28]).checked_sub(1);
 
-        Self { end, start, count }
+        Self {
+            end,
+            start,
+            count,
+            version,
+            lsn,
+            next,
+            bloom,
+        }
     }
 
-    pub fn size() -> usize {
-        12
+    pub const fn size() -> usize {
+        28
     }
 }
 
@@ -40,7 +92,7 @@ impl PageHeader {
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Page {
     pub header: PageHeader,
-    pub data: BTreeMap<NonZeroU32, Vec<RowVal>>,
+    pub data: Rows,
     pub dirty: bool,
     pub size: usize,
     pub schema: Vec<RowType>,
@@ -52,35 +104,378 @@ pub const PAGE_SIZE: usize = if cfg!(feature = "small_pages") {
     4096
 };
 
+/// The largest encoded row (id included) that fits on a page by itself, with
+/// its spilled values counted as the references left in their place.
+pub const MAX_ROW_SIZE: usize = PAGE_SIZE - PageHeader::size();
+
+/// When a page that's grown is split, and where, see `DB::set_split_policy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SplitPolicy {
+    /// How full a page can get, as a fraction of `PAGE_SIZE`, before it's
+    /// split. Below 1.0 leaves room on every page for rows to grow in place.
+    pub fill_factor: f64,
+    /// The fraction of a page's rows, by size, that stay on the left when
+    /// it's split.
+    pub split_point: f64,
+}
+
+impl Default for SplitPolicy {
+    fn default() -> Self {
+        Self {
+            fill_factor: 1.0,
+            split_point: 0.5,
+        }
+    }
+}
+
+impl SplitPolicy {
+    /// Whether the fill factor is above 0 and at most 1, and the split
+    /// point strictly between 0 and 1.
+    pub fn is_valid(&self) -> bool {
+        self.fill_factor > 0.0
+            && self.fill_factor <= 1.0
+            && self.split_point > 0.0
+            && self.split_point < 1.0
+    }
+
+    /// The size past which a page is split.
+    pub fn threshold(&self) -> usize {
+        (PAGE_SIZE as f64 * self.fill_factor) as usize
+    }
+}
+
+/// How the rows on a page are laid out, which is the same for every page of
+/// a table, see `DB::set_layout`. Both take the same space.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// Each row's id followed by its values, one row after another.
+    #[default]
+    Rows,
+    /// Every row's id, then every row's value for the first column, and so
+    /// on, so reading one column of a page doesn't decode the rest.
+    Columns,
+}
+
+/// The rows on a page, by id, which it derefs to. Each row's id is stored as
+/// a varint of its distance from the id before it, the first row's from its
+/// own id, which the page header holds as `start`, so dense ids take a byte
+/// each rather than four. A page read from disk keeps
+/// its rows encoded until something first looks at them, so opening a
+/// database doesn't decode every row in it, and a page that's never read
+/// costs no more than its bytes.
+#[derive(Default)]
+pub struct Rows {
+    encoded: Mutex<Option<EncodedRows>>,
+    decoded: OnceLock<BTreeMap<NonZeroU32, Vec<RowVal>>>,
+}
+
+#[derive(Debug, Clone)]
+struct EncodedRows {
+    bytes: Vec<u8>,
+    count: u32,
+    /// The id of the first row, which the others are stored relative to.
+    start: Option<NonZeroU32>,
+    schema: Vec<RowType>,
+    layout: Layout,
+}
+
+impl Rows {
+    fn encoded(
+        bytes: Vec<u8>,
+        count: u32,
+        start: Option<NonZeroU32>,
+        schema: &[RowType],
+        layout: Layout,
+    ) -> Self {
+        Self {
+            encoded: Mutex::new(Some(EncodedRows {
+                bytes,
+                count,
+                start,
+                schema: schema.to_vec(),
+                layout,
+            })),
+            decoded: OnceLock::new(),
+        }
+    }
+
+    /// Whether the rows have been decoded yet.
+    pub fn is_decoded(&self) -> bool {
+        self.decoded.get().is_some()
+    }
+
+    /// The rows, decoded the first time they're asked for.
+    fn decode(&self) -> &BTreeMap<NonZeroU32, Vec<RowVal>> {
+        self.decoded.get_or_init(|| {
+            let encoded = self.encoded.lock().unwrap().take();
+            let Some(EncodedRows {
+                bytes,
+                count,
+                start,
+                schema,
+                layout,
+            }) = encoded
+            else {
+                return BTreeMap::new();
+            };
+            decode_rows(&bytes, count, start, &schema, layout, |_| {
+                unreachable!("a page with spilled values is decoded as it's read")
+            })
+        })
+    }
+
+    /// Appends the rows to `res` encoded with `layout`, copying them as they
+    /// were read if they haven't been decoded and were read with the same
+    /// layout. The values of rows too large for the page are handed to
+    /// `spill`, which returns the reference to leave in their place.
+    fn encode_into(
+        &self,
+        res: &mut Vec<u8>,
+        layout: Layout,
+        mut spill: impl FnMut(&[u8]) -> Vec<u8>,
+    ) {
+        if let Some(encoded) = self
+            .encoded
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|encoded| encoded.layout == layout)
+        {
+            res.extend(&encoded.bytes);
+            return;
+        }
+        let mut encode = |res: &mut Vec<u8>, row: &[RowVal], cell: &RowVal| match cell {
+            RowVal::Bytes(bytes) if overflow::spills(row) && overflow::is_spilled(cell) => {
+                res.extend(spill(bytes))
+            }
+            cell => res.extend(cell.to_bytes()),
+        };
+        let mut prev = self.keys().next().copied();
+        let mut write_id = |res: &mut Vec<u8>, id: NonZeroU32| {
+            write_varint(id.get() - prev.unwrap_or(id).get(), res);
+            prev = Some(id);
+        };
+        match layout {
+            Layout::Rows => {
+                for (id, row) in &**self {
+                    write_id(res, *id);
+                    for cell in row {
+                        encode(res, row, cell);
+                    }
+                }
+            }
+            Layout::Columns => {
+                for id in self.keys() {
+                    write_id(res, *id);
+                }
+                let columns = self.values().next().map_or(0, Vec::len);
+                for column in 0..columns {
+                    for row in self.values() {
+                        encode(res, row, &row[column]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every row's id and its `column`th value, read straight from the
+    /// encoded rows if they're laid out by column, without decoding the
+    /// rest.
+    fn column(&self, column: usize) -> Vec<(NonZeroU32, RowVal)> {
+        if let Some(encoded) = self
+            .encoded
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|encoded| encoded.layout == Layout::Columns)
+        {
+            let EncodedRows {
+                bytes,
+                count,
+                start,
+                schema,
+                ..
+            } = encoded;
+            let (ids, mut offset) = read_ids(bytes, *count, *start);
+            for row_type in &schema[1..column + 1] {
+                for _ in 0..*count {
+                    offset += encoded_row_len(&bytes[offset..], slice::from_ref(row_type)).0;
+                }
+            }
+            return ids
+                .into_iter()
+                .map(|id| {
+                    let (mut vals, len) =
+                        bytes_to_values(&bytes[offset..], slice::from_ref(&schema[column + 1]));
+                    offset += len;
+                    (id, vals.pop().unwrap())
+                })
+                .collect();
+        }
+        self.iter()
+            .map(|(id, row)| (*id, row[column].clone()))
+            .collect()
+    }
+
+    /// Every row's id, read from the encoded rows if they haven't been
+    /// decoded, without decoding them.
+    fn ids(&self) -> Vec<NonZeroU32> {
+        if let Some(encoded) = self.encoded.lock().unwrap().as_ref() {
+            let EncodedRows {
+                bytes,
+                count,
+                start,
+                schema,
+                layout,
+            } = encoded;
+            return match layout {
+                Layout::Columns => read_ids(bytes, *count, *start).0,
+                Layout::Rows => {
+                    let mut offset = 0;
+                    let mut prev = start.map_or(0, NonZeroU32::get);
+                    (0..*count)
+                        .map(|_| {
+                            let (delta, len) = read_varint(&bytes[offset..]);
+                            offset += len;
+                            offset += encoded_row_len(&bytes[offset..], &schema[1..]).0;
+                            prev += delta;
+                            NonZeroU32::new(prev).expect("ids start at 1")
+                        })
+                        .collect()
+                }
+            };
+        }
+        self.keys().copied().collect()
+    }
+}
+
+impl From<BTreeMap<NonZeroU32, Vec<RowVal>>> for Rows {
+    fn from(rows: BTreeMap<NonZeroU32, Vec<RowVal>>) -> Self {
+        Self {
+            encoded: Mutex::new(None),
+            decoded: OnceLock::from(rows),
+        }
+    }
+}
+
+impl Deref for Rows {
+    type Target = BTreeMap<NonZeroU32, Vec<RowVal>>;
+
+    fn deref(&self) -> &Self::Target {
+        self.decode()
+    }
+}
+
+impl DerefMut for Rows {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.decode();
+        self.decoded.get_mut().unwrap()
+    }
+}
+
+impl Clone for Rows {
+    fn clone(&self) -> Self {
+        // another thread may be decoding the rows, in which case they're
+        // cloned once it's done
+        let encoded = self.encoded.lock().unwrap().clone();
+        match encoded {
+            Some(encoded) => Self {
+                encoded: Mutex::new(Some(encoded)),
+                decoded: OnceLock::new(),
+            },
+            None => Self::from((**self).clone()),
+        }
+    }
+}
+
+impl IntoIterator for Rows {
+    type Item = (NonZeroU32, Vec<RowVal>);
+    type IntoIter = btree_map::IntoIter<NonZeroU32, Vec<RowVal>>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        std::mem::take(&mut *self).into_iter()
+    }
+}
+
+impl fmt::Debug for Rows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl PartialEq for Rows {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Rows {}
+
+impl PartialOrd for Rows {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rows {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for Rows {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state);
+    }
+}
+
+#[cfg(test)]
+impl Serialize for Rows {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+impl<'de> Deserialize<'de> for Rows {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        BTreeMap::deserialize(deserializer).map(Self::from)
+    }
+}
+
 impl Page {
     pub fn new(data: &[Vec<RowVal>], schema: &[RowType]) -> Self {
-        let size = data
-            .iter()
-            .flat_map(|r| r.iter().map(|c| c.size()))
-            .sum::<u16>() as usize;
         let data = BTreeMap::from_iter(data.iter().map(|row| {
             let (id, vals) = split_row(row);
             (id, vals.to_vec())
         }));
+        let mut prev = None;
+        let size = PageHeader::size()
+            + data
+                .iter()
+                .map(|(&id, vals)| {
+                    let len = row_len(id, prev, vals);
+                    prev = Some(id);
+                    len
+                })
+                .sum::<usize>();
 
-        let start = *data
-            .first_key_value()
-            .unwrap_or((&1.try_into().unwrap(), &vec![]))
-            .0;
-        let end = *data
-            .last_key_value()
-            .unwrap_or((&1.try_into().unwrap(), &vec![]))
-            .0;
+        let start = data.first_key_value().map(|(id, _)| *id);
+        let end = data.last_key_value().map(|(id, _)| *id);
 
         let header = PageHeader {
             count: data.len() as u32,
             start,
             end,
+            version: 0,
+            lsn: 0,
+            next: None,
+            bloom: false,
         };
 
         Page {
             header,
-            data,
+            data: data.into(),
             dirty: false,
             size,
             schema: schema.to_vec(),
@@ -89,74 +484,200 @@ impl Page {
 
     pub fn new_dirty(data: &[Vec<RowVal>], schema: &[RowType]) -> Self {
         let mut page = Page::new(data, schema);
-        let page_size = data
-            .iter()
-            .flat_map(|r| r.iter().map(|c| c.size()))
-            .sum::<u16>() as usize;
         page.dirty = true;
-        page.size = page_size;
         page
     }
 
+    /// The encoded page, with any spilled values left pointing at page 0,
+    /// since only `to_leaf_pages` writes them out.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut res = self.header.to_bytes();
-        for (id, row) in &self.data {
-            res.extend(id.get().to_le_bytes());
-            for cell in row {
-                res.extend(cell.clone().to_bytes());
-            }
-        }
+        self.data.encode_into(&mut res, Layout::Rows, |bytes| {
+            overflow::spill(bytes, || 0).0
+        });
         res
     }
 
     pub fn to_page_bytes(&self) -> Vec<u8> {
-        let mut res = self.header.to_bytes();
-        for (id, row) in &self.data {
-            res.extend(id.get().to_le_bytes());
-            for cell in row {
-                res.extend(cell.clone().to_bytes());
-            }
-        }
+        self.to_leaf_bytes(self.header.next)
+    }
+
+    /// Like `to_page_bytes`, but pointing the page at `next` as the leaf
+    /// that follows it. The page can't have spilled values.
+    pub fn to_leaf_bytes(&self, next: Option<u32>) -> Vec<u8> {
+        let (res, spilled) = self.to_leaf_pages(next, Layout::Rows, false, || {
+            panic!("The page has spilled values")
+        });
+        debug_assert!(spilled.is_empty());
+        res
+    }
+
+    /// Like `to_leaf_bytes`, but with the rows laid out as `layout` says,
+    /// with a bloom filter over the ids if `bloom_filter` is set and it fits,
+    /// and spilling values too large for the page to overflow pages, each
+    /// put at the index `slot` hands out. Returns the page along with its
+    /// overflow pages.
+    pub fn to_leaf_pages(
+        &self,
+        next: Option<u32>,
+        layout: Layout,
+        bloom_filter: bool,
+        mut slot: impl FnMut() -> u32,
+    ) -> (Vec<u8>, Vec<(u32, Vec<u8>)>) {
+        let mut header = PageHeader {
+            next,
+            bloom: false,
+            ..self.header
+        };
+        let mut res = header.to_bytes();
+        let mut spilled = vec![];
+        self.data.encode_into(&mut res, layout, |bytes| {
+            let (reference, pages) = overflow::spill(bytes, &mut slot);
+            spilled.extend(pages);
+            reference
+        });
         if res.len() > PAGE_SIZE {
             panic!("The page is larger than the page boundary");
         }
-        let bytes_to_pad = PAGE_SIZE - res.len();
-        res.extend(vec![0; bytes_to_pad]);
-        res
+        if bloom_filter && bloom::append(&mut res, &self.data.ids(), PAGE_SIZE) {
+            header.bloom = true;
+            res[..PageHeader::size()].copy_from_slice(&header.to_bytes());
+        }
+        res.resize(PAGE_SIZE, 0);
+        (res, spilled)
     }
 
+    /// Reads a page, leaving its rows encoded until they're first used. A
+    /// page with spilled values has to be read with `read` instead.
     pub fn from_bytes(bytes: &[u8], schema: &[RowType]) -> Self {
-        let header_bytes: &[u8; 12] = bytes[0..12].try_into().unwrap();
+        Self::decode(bytes, schema, Layout::Rows, None)
+    }
 
-        let header = PageHeader::from_bytes(header_bytes);
-        let mut data = vec![];
+    /// Reads the leaf at index `i` of a whole `.db` file, header included.
+    /// Its rows are left encoded as with `from_bytes`, unless some of their
+    /// values were spilled, in which case they're decoded right away, while
+    /// the overflow pages are at hand. They're laid out as the file header
+    /// says.
+    pub fn read(file: &[u8], i: usize, schema: &[RowType]) -> Self {
+        let start = HEADER_SIZE + i * PAGE_SIZE;
+        let layout = header::layout(file);
+        Self::decode(&file[start..start + PAGE_SIZE], schema, layout, Some(file))
+    }
 
-        let mut offset = PageHeader::size();
+    fn decode(bytes: &[u8], schema: &[RowType], layout: Layout, file: Option<&[u8]>) -> Self {
+        let header_bytes: &[u8; 28] = bytes[0..PageHeader::size()].try_into().unwrap();
 
-        for _ in 0..header.count {
-            let (row_val, incr) = bytes_to_values(&bytes[offset..], schema);
-            data.push(row_val);
-            offset += incr;
+        let header = PageHeader::from_bytes(header_bytes);
+        let rows = &bytes[PageHeader::size()..];
+        let mut len = 0;
+        let mut spilled = false;
+        match layout {
+            Layout::Rows => {
+                for _ in 0..header.count {
+                    let (_, id_len) = read_varint(&rows[len..]);
+                    let (row_len, row_spilled) =
+                        encoded_row_len(&rows[len + id_len..], &schema[1..]);
+                    len += id_len + row_len;
+                    spilled |= row_spilled;
+                }
+            }
+            Layout::Columns => {
+                len = read_ids(rows, header.count, header.start).1;
+                for row_type in &schema[1..] {
+                    for _ in 0..header.count {
+                        let (cell_len, cell_spilled) =
+                            encoded_row_len(&rows[len..], slice::from_ref(row_type));
+                        len += cell_len;
+                        spilled |= cell_spilled;
+                    }
+                }
+            }
+        }
+
+        let data = match file.filter(|_| spilled) {
+            // values on overflow pages are read back now, while the file is at
+            // hand
+            Some(file) => Rows::from(decode_rows(
+                &rows[..len],
+                header.count,
+                header.start,
+                schema,
+                layout,
+                |reference| overflow::read(file, reference),
+            )),
+            None => Rows::encoded(
+                rows[..len].to_vec(),
+                header.count,
+                header.start,
+                schema,
+                layout,
+            ),
+        };
+        Page {
+            header,
+            data,
+            dirty: false,
+            size: PageHeader::size() + len,
+            schema: schema.to_vec(),
         }
+    }
 
-        Page::new(&data, schema)
+    /// Every row's id and its `column`th value, counting from the first
+    /// after the id. A page laid out by column that hasn't been decoded
+    /// yet has only that column decoded.
+    pub fn column(&self, column: usize) -> Vec<(NonZeroU32, RowVal)> {
+        self.data.column(column)
     }
 
+    /// How many overflow pages the page's spilled values take.
+    pub fn overflow_count(&self) -> usize {
+        // a page with spilled values is decoded as it's read
+        if !self.data.is_decoded() {
+            return 0;
+        }
+        self.data
+            .values()
+            .map(|vals| overflow::page_count(vals))
+            .sum()
+    }
+
+    /// The encoded length of the page, header included. This is kept up to
+    /// date by every mutation so it always matches `to_bytes().len()`.
     pub fn size(&self) -> usize {
         self.size
     }
 
     pub fn len(&self) -> usize {
-        self.data.len()
+        self.header.count as usize
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
+    /// Splits the page into two halves of roughly equal encoded size. Both
+    /// halves keep at least one row if the page has two or more.
     pub fn split(&self) -> (Self, Self) {
+        self.split_at(0.5)
+    }
+
+    /// Like `split`, but with `point` of the rows, by encoded size, on the
+    /// left.
+    pub fn split_at(&self, point: f64) -> (Self, Self) {
         let len = self.len();
-        let mid = len / 2;
+        let total: usize = self.data.values().map(|vals| stored_row_size(vals)).sum();
+        let mut acc = 0;
+        let mut mid = len / 2;
+        for (i, vals) in self.data.values().enumerate() {
+            acc += stored_row_size(vals);
+            if acc as f64 >= total as f64 * point {
+                mid = i + 1;
+                break;
+            }
+        }
+        if len >= 2 {
+            mid = mid.clamp(1, len - 1);
+        }
         let vec_data: Vec<Vec<RowVal>> = self
             .data
             .clone()
@@ -169,10 +690,13 @@ impl Page {
             .collect();
         let (head, tail) = vec_data.split_at(mid);
 
-        (
-            Self::new_dirty(head, &self.schema),
-            Self::new_dirty(tail, &self.schema),
-        )
+        let mut head = Self::new_dirty(head, &self.schema);
+        let mut tail = Self::new_dirty(tail, &self.schema);
+        head.header.version = self.header.version;
+        tail.header.version = self.header.version;
+        head.header.lsn = self.header.lsn;
+        tail.header.lsn = self.header.lsn;
+        (head, tail)
     }
 
     pub fn merge(&mut self, other: Page) {
@@ -186,7 +710,81 @@ impl Page {
                 res
             })
             .collect();
-        *self = Self::new_dirty(&vec_data, &self.schema)
+        let version = self.header.version;
+        let lsn = self.header.lsn.max(other.header.lsn);
+        *self = Self::new_dirty(&vec_data, &self.schema);
+        self.header.version = version;
+        self.header.lsn = lsn;
+    }
+
+    /// Keeps splitting the page until every piece fits within `PAGE_SIZE`.
+    pub fn split_to_fit(self) -> Vec<Self> {
+        self.split_with(&SplitPolicy::default())
+    }
+
+    /// Keeps splitting the page where `policy` says until every piece is
+    /// within its threshold, or down to a single row.
+    pub fn split_with(self, policy: &SplitPolicy) -> Vec<Self> {
+        if self.size() <= policy.threshold() || self.len() <= 1 {
+            return vec![self];
+        }
+        let (head, tail) = self.split_at(policy.split_point);
+        let mut res = head.split_with(policy);
+        res.extend(tail.split_with(policy));
+        res
+    }
+
+    /// Splits a page that's grown from rows added past `end`, its last id
+    /// before, keeping the rows up to `end` together and packing the rest
+    /// onto as few pages as `policy` lets them fit, as suits ids that only
+    /// go up.
+    pub fn split_after(self, end: NonZeroU32, policy: &SplitPolicy) -> Vec<Self> {
+        if self.size() <= policy.threshold() {
+            return vec![self];
+        }
+        let (version, lsn) = (self.header.version, self.header.lsn);
+        let schema = self.schema.clone();
+        let page = |rows: &[Vec<RowVal>]| {
+            let mut page = Self::new_dirty(rows, &schema);
+            page.header.version = version;
+            page.header.lsn = lsn;
+            page
+        };
+
+        let mut kept = vec![];
+        let mut packed = vec![vec![]];
+        let mut size = PageHeader::size();
+        let mut prev = None;
+        for (id, vals) in self.data.clone() {
+            let mut len = row_len(id, prev, &vals);
+            let mut row = vec![RowVal::Id(id)];
+            row.extend(vals);
+            if id <= end {
+                kept.push(row);
+                continue;
+            }
+            if size + len > policy.threshold() && prev.is_some() {
+                packed.push(vec![]);
+                size = PageHeader::size();
+                len = len - id_len(id, prev) + id_len(id, None);
+            }
+            size += len;
+            prev = Some(id);
+            packed.last_mut().unwrap().push(row);
+        }
+
+        let mut res = if kept.is_empty() {
+            vec![]
+        } else {
+            page(&kept).split_with(policy)
+        };
+        res.extend(
+            packed
+                .iter()
+                .filter(|rows| !rows.is_empty())
+                .map(|rows| page(rows)),
+        );
+        res
     }
 
     pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
@@ -195,25 +793,34 @@ impl Page {
 
     pub fn insert(&mut self, row: &[RowVal]) {
         let (id, values) = split_row(row);
-        self.header.start = self.header.start.min(id);
-        self.header.end = self.header.end.max(id);
+        self.header.start = Some(self.header.start.map_or(id, |start| start.min(id)));
+        self.header.end = Some(self.header.end.map_or(id, |end| end.max(id)));
         self.dirty = true;
-        self.data.insert(id, values.to_vec());
+        match self.data.insert(id, values.to_vec()) {
+            Some(old) => self.size = self.size + stored_row_size(values) - stored_row_size(&old),
+            None => {
+                let prev = self.data.range(..id).next_back().map(|(id, _)| *id);
+                self.size += row_len(id, prev, values);
+                // the row after it is now stored relative to it
+                if let Some((&next, _)) = self.data.range(id..).nth(1) {
+                    self.size = self.size + id_len(next, Some(id)) - id_len(next, prev);
+                }
+            }
+        }
         self.header.count = self.data.len() as u32;
     }
 
     pub fn remove(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
         match self.data.remove(&id) {
             Some(val) => {
-                self.header.start = match self.data.first_key_value() {
-                    Some((id, _)) => *id,
-                    None => NonZeroU32::MIN,
-                };
-                self.header.end = match self.data.last_key_value() {
-                    Some((id, _)) => *id,
-                    None => NonZeroU32::MIN,
-                };
+                self.header.start = self.data.first_key_value().map(|(id, _)| *id);
+                self.header.end = self.data.last_key_value().map(|(id, _)| *id);
                 self.header.count = self.data.len() as u32;
+                let prev = self.data.range(..id).next_back().map(|(id, _)| *id);
+                self.size -= row_len(id, prev, &val);
+                if let Some((&next, _)) = self.data.range(id..).next() {
+                    self.size = self.size + id_len(next, prev) - id_len(next, Some(id));
+                }
                 self.dirty = true;
                 Some(val)
             }
@@ -222,6 +829,79 @@ impl Page {
     }
 }
 
+/// The bytes the id of a row takes on a page, given the id before it, if
+/// there is one.
+fn id_len(id: NonZeroU32, prev: Option<NonZeroU32>) -> usize {
+    varint_len(id.get() - prev.unwrap_or(id).get())
+}
+
+/// The size of a row on a page, given the id before it, if there is one.
+fn row_len(id: NonZeroU32, prev: Option<NonZeroU32>, vals: &[RowVal]) -> usize {
+    // `stored_row_size` counts the id as 4 bytes
+    id_len(id, prev) + stored_row_size(vals) - 4
+}
+
+/// Reads the ids of `count` rows written by `Rows::encode_into`, the first of
+/// which is `start`, from the start of `bytes`, one after another. Returns
+/// them and the length they took.
+fn read_ids(bytes: &[u8], count: u32, start: Option<NonZeroU32>) -> (Vec<NonZeroU32>, usize) {
+    let mut offset = 0;
+    let mut prev = start.map_or(0, NonZeroU32::get);
+    let ids = (0..count)
+        .map(|_| {
+            let (delta, len) = read_varint(&bytes[offset..]);
+            offset += len;
+            prev += delta;
+            NonZeroU32::new(prev).expect("ids start at 1")
+        })
+        .collect();
+    (ids, offset)
+}
+
+/// Decodes `count` rows written by `Rows::encode_into` with `layout`, the
+/// first of which has the id `start`, reading back any spilled values with
+/// `spilled`.
+fn decode_rows(
+    bytes: &[u8],
+    count: u32,
+    start: Option<NonZeroU32>,
+    schema: &[RowType],
+    layout: Layout,
+    spilled: impl Fn(&[u8]) -> Vec<u8>,
+) -> BTreeMap<NonZeroU32, Vec<RowVal>> {
+    match layout {
+        Layout::Rows => {
+            let mut offset = 0;
+            let mut prev = start.map_or(0, NonZeroU32::get);
+            (0..count)
+                .map(|_| {
+                    let (delta, len) = read_varint(&bytes[offset..]);
+                    offset += len;
+                    let id = NonZeroU32::new(prev + delta).expect("ids start at 1");
+                    prev = id.get();
+                    let (vals, len) =
+                        bytes_to_values_with(&bytes[offset..], &schema[1..], &spilled);
+                    offset += len;
+                    (id, vals)
+                })
+                .collect()
+        }
+        Layout::Columns => {
+            let (ids, mut offset) = read_ids(bytes, count, start);
+            let mut rows = vec![Vec::with_capacity(schema.len() - 1); count as usize];
+            for row_type in &schema[1..] {
+                for row in &mut rows {
+                    let (mut vals, len) =
+                        bytes_to_values_with(&bytes[offset..], slice::from_ref(row_type), &spilled);
+                    offset += len;
+                    row.push(vals.pop().unwrap());
+                }
+            }
+            ids.into_iter().zip(rows).collect()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::num::NonZero;
@@ -244,6 +924,20 @@ mod tests {
         let page = Page::new(data, DEFAULT_SCHEMA);
         let (head, tail) = page.split();
         snapshot!((head, tail));
+
+        let (head, tail) = page.split_at(0.75);
+        assert_eq!((head.len(), tail.len()), (3, 1));
+        let policy = SplitPolicy {
+            fill_factor: head.size() as f64 / PAGE_SIZE as f64,
+            split_point: 0.75,
+        };
+        assert!(policy.is_valid());
+        let pieces = page.clone().split_with(&policy);
+        assert_eq!(pieces.iter().map(Page::len).collect::<Vec<_>>(), [3, 1]);
+
+        // rows added past the old end are packed onto pages of their own
+        let pieces = page.split_after(NonZeroU32::new(1).unwrap(), &policy);
+        assert_eq!(pieces.iter().map(Page::len).collect::<Vec<_>>(), [1, 3]);
     }
 
     #[test]
@@ -337,6 +1031,62 @@ mod tests {
         Page::from_bytes(&page.to_bytes(), DEFAULT_SCHEMA) == page
     }
 
+    #[quickcheck]
+    fn fuzz_page_size(records: Vec<(NonZeroU32, u32, bool)>) -> bool {
+        let mut page = Page::new(&[], DEFAULT_SCHEMA);
+        for (id, val, remove) in records {
+            if remove {
+                page.remove(id);
+            } else {
+                page.insert(&[RowVal::Id(id), RowVal::U32(val)]);
+            }
+            if page.size() != page.to_bytes().len() {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn empty_page() {
+        let id = NonZeroU32::new(5).unwrap();
+        let mut page = Page::new(&[], DEFAULT_SCHEMA);
+        assert_eq!((page.header.start, page.header.end), (None, None));
+
+        page.insert(&[RowVal::Id(id), RowVal::U32(50)]);
+        assert_eq!((page.header.start, page.header.end), (Some(id), Some(id)));
+
+        page.remove(id);
+        assert_eq!((page.header.start, page.header.end), (None, None));
+        assert_eq!(
+            Page::from_bytes(&page.to_bytes(), DEFAULT_SCHEMA),
+            Page::new(&[], DEFAULT_SCHEMA)
+        );
+    }
+
+    #[quickcheck]
+    fn fuzz_page_split_to_fit(records: Vec<(NonZeroU32, u16)>) -> bool {
+        let schema = &[RowType::Id, RowType::Bytes];
+        let records: Vec<_> = records
+            .iter()
+            .map(|(id, len)| {
+                let len = *len as usize % (MAX_ROW_SIZE - 6);
+                vec![RowVal::Id(*id), RowVal::Bytes(vec![0; len])]
+            })
+            .collect();
+        let page = Page::new_dirty(&records, schema);
+        let pages = page.clone().split_to_fit();
+
+        let mut merged = Page::new_dirty(&[], schema);
+        for piece in &pages {
+            if piece.size() > PAGE_SIZE || piece.is_empty() != page.is_empty() {
+                return false;
+            }
+            merged.merge(piece.clone());
+        }
+        merged == page
+    }
+
     #[quickcheck]
     fn fuzz_page_split_merge(records: Vec<(NonZeroU32, u32)>) -> bool {
         if records.len() >= u32::MAX as usize {
@@ -351,4 +1101,52 @@ mod tests {
         head.merge(tail);
         head == page
     }
+
+    #[test]
+    fn delta_ids() {
+        let row = |id| vec![RowVal::Id(NonZero::new(id).unwrap()), RowVal::U32(id)];
+        let mut page = Page::new(&[row(1000), row(1001)], DEFAULT_SCHEMA);
+        // the first id is in the header, so it and the next take a byte each
+        assert_eq!(page.size(), PageHeader::size() + 2 * 5);
+        // 1000 is now 298 past the id before it, which takes two bytes
+        page.insert(&row(702));
+        assert_eq!(page.size(), PageHeader::size() + 5 + 6 + 5);
+        page.remove(NonZero::new(1000).unwrap());
+        assert_eq!(page.size(), PageHeader::size() + 5 + 6);
+        assert_eq!(page.size(), page.to_bytes().len());
+        let read = Page::from_bytes(&page.to_page_bytes(), DEFAULT_SCHEMA);
+        assert_eq!((read.data, read.size), (page.data, page.size));
+    }
+
+    #[test]
+    fn decodes_lazily() {
+        let schema = &[RowType::Id, RowType::Bytes, RowType::Bool];
+        let rows: Vec<_> = (1..=3)
+            .map(|i| {
+                vec![
+                    RowVal::Id(NonZeroU32::new(i).unwrap()),
+                    RowVal::Bytes(vec![i as u8; i as usize]),
+                    RowVal::Bool(i % 2 == 0),
+                ]
+            })
+            .collect();
+        let bytes = Page::new(&rows, schema).to_page_bytes();
+
+        let page = Page::from_bytes(&bytes, schema);
+        assert_eq!(page.len(), 3);
+        assert_eq!(page.size(), Page::new(&rows, schema).size());
+        // writing the page back copies its bytes as they were
+        assert_eq!(page.to_page_bytes(), bytes);
+        let copy = page.clone();
+        assert!(!page.data.is_decoded());
+
+        assert_eq!(
+            page.get(NonZeroU32::new(2).unwrap()),
+            Some(vec![RowVal::Bytes(vec![2; 2]), RowVal::Bool(true)])
+        );
+        assert!(page.data.is_decoded());
+        assert!(!copy.data.is_decoded());
+        assert_eq!(page, copy);
+        assert_eq!(page.to_page_bytes(), bytes);
+    }
 }
@@ -0,0 +1,221 @@
+//! The pages of a database held in memory. Each page is kept under a
+//! `PageId` that stays the same however its rows change, and a directory
+//! from the last row id on each page to its `PageId` keeps them in key
+//! order, so a page is found and changed where it is instead of being
+//! cloned out of an ordered set and put back.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    num::NonZeroU32,
+};
+
+#[cfg(test)]
+use serde::{Serialize, Serializer};
+
+use crate::page::Page;
+
+/// Names a page for as long as it's in `Pages`. Ids aren't reused, so one
+/// kept after its page is gone finds nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageId(u64);
+
+/// Every page, each with its position in the `.db` file if it has been
+/// written, in key order. Pages never overlap, and empty pages aren't kept.
+#[derive(Clone, Default)]
+pub struct Pages {
+    pages: HashMap<PageId, (Page, Option<usize>)>,
+    /// Each page's id by the last row id on it, so the page an id falls on,
+    /// if any, is the first one ending at or after it.
+    directory: BTreeMap<NonZeroU32, PageId>,
+    next_id: u64,
+}
+
+impl Pages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Adds a page, returning its new id, or `None` if it's empty, in which
+    /// case it's dropped. It can't overlap any page already here.
+    pub fn insert(&mut self, page: Page, position: Option<usize>) -> Option<PageId> {
+        let end = page.header.end?;
+        let id = PageId(self.next_id);
+        self.next_id += 1;
+        self.directory.insert(end, id);
+        self.pages.insert(id, (page, position));
+        Some(id)
+    }
+
+    pub fn remove(&mut self, id: PageId) -> Option<(Page, Option<usize>)> {
+        let (page, position) = self.pages.remove(&id)?;
+        if let Some(end) = page.header.end {
+            self.directory.remove(&end);
+        }
+        Some((page, position))
+    }
+
+    pub fn get(&self, id: PageId) -> Option<&(Page, Option<usize>)> {
+        self.pages.get(&id)
+    }
+
+    /// The page a row with `id` is on or would go on: the first one that
+    /// ends at or after it. `None` if `id` is past the last page.
+    pub fn find(&self, id: NonZeroU32) -> Option<PageId> {
+        self.directory.range(id..).next().map(|(_, id)| *id)
+    }
+
+    /// Changes a page in place, which is dropped if it's left empty. The
+    /// page's rows can't be made to overlap another page's.
+    pub fn update<T>(&mut self, id: PageId, f: impl FnOnce(&mut Page) -> T) -> T {
+        let (page, _) = self.pages.get_mut(&id).expect("no such page");
+        let end = page.header.end;
+        let res = f(page);
+        let new_end = page.header.end;
+        if new_end != end {
+            if let Some(end) = end {
+                self.directory.remove(&end);
+            }
+            match new_end {
+                Some(new_end) => {
+                    self.directory.insert(new_end, id);
+                }
+                None => {
+                    self.pages.remove(&id);
+                }
+            }
+        }
+        res
+    }
+
+    pub fn set_position(&mut self, id: PageId, position: Option<usize>) {
+        if let Some(entry) = self.pages.get_mut(&id) {
+            entry.1 = position;
+        }
+    }
+
+    /// Every page's id, in key order.
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item = PageId> + '_ {
+        self.directory.values().copied()
+    }
+
+    /// Every page with its position, in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &(Page, Option<usize>)> + '_ {
+        self.directory.values().map(|id| &self.pages[id])
+    }
+
+    pub fn first(&self) -> Option<&(Page, Option<usize>)> {
+        self.iter().next()
+    }
+
+    pub fn last(&self) -> Option<&(Page, Option<usize>)> {
+        self.iter().next_back()
+    }
+
+    /// Keeps only the pages `f` returns `true` for.
+    pub fn retain(&mut self, mut f: impl FnMut(&Page) -> bool) {
+        let removed: Vec<_> = self
+            .pages
+            .iter()
+            .filter(|(_, (page, _))| !f(page))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in removed {
+            self.remove(id);
+        }
+    }
+}
+
+impl FromIterator<(Page, Option<usize>)> for Pages {
+    fn from_iter<I: IntoIterator<Item = (Page, Option<usize>)>>(iter: I) -> Self {
+        let mut pages = Self::new();
+        for (page, position) in iter {
+            pages.insert(page, position);
+        }
+        pages
+    }
+}
+
+impl IntoIterator for Pages {
+    type Item = (Page, Option<usize>);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    /// Every page with its position, in key order.
+    fn into_iter(mut self) -> Self::IntoIter {
+        let ids: Vec<_> = self.ids().collect();
+        ids.into_iter()
+            .map(|id| self.pages.remove(&id).unwrap())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+impl fmt::Debug for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+impl Serialize for Pages {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::row::{RowType, RowVal};
+
+    use super::*;
+
+    fn page(ids: &[u32]) -> Page {
+        let rows: Vec<_> = ids
+            .iter()
+            .map(|&id| vec![RowVal::Id(NonZeroU32::new(id).unwrap())])
+            .collect();
+        Page::new(&rows, &[RowType::Id])
+    }
+
+    #[test]
+    fn directory() {
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut pages: Pages = [(page(&[5, 6]), Some(1)), (page(&[1, 2]), Some(0))]
+            .into_iter()
+            .collect();
+        let ends: Vec<_> = pages.iter().map(|(page, _)| page.header.end).collect();
+        assert_eq!(ends, [Some(id(2)), Some(id(6))]);
+
+        let first = pages.find(id(1)).unwrap();
+        let second = pages.find(id(3)).unwrap();
+        assert_eq!(pages.find(id(6)), Some(second));
+        assert_eq!(pages.find(id(7)), None);
+
+        // growing a page past its end moves it in the directory, without
+        // changing its id
+        pages.update(first, |page| page.insert(&[RowVal::Id(id(3))]));
+        assert_eq!(pages.find(id(3)), Some(first));
+        assert!(pages.get(first).unwrap().0.dirty);
+
+        // a page left empty is dropped
+        pages.update(second, |page| {
+            page.remove(id(5));
+            page.remove(id(6));
+        });
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages.find(id(4)), None);
+        assert!(pages.get(second).is_none());
+
+        assert_eq!(pages.insert(page(&[]), None), None);
+        pages.retain(|page| page.len() > 3);
+        assert!(pages.is_empty());
+    }
+}
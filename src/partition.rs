@@ -0,0 +1,291 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    num::NonZeroU32,
+    ops::{Bound, RangeBounds},
+    path::Path,
+};
+
+use crate::{
+    db::DB,
+    error::DbError,
+    row::{RowType, RowVal},
+};
+
+/// A table split into key ranges, each stored in its own directory. A
+/// partition covers every id from its start up to the next partition's start.
+/// Each partition's directory is named `$name.p$start`, which is what keeps
+/// the bounds between opens.
+#[derive(Debug)]
+pub struct PartitionedDB {
+    pub name: String,
+    pub schema: Vec<RowType>,
+    pub partitions: BTreeMap<NonZeroU32, DB>,
+}
+
+impl PartitionedDB {
+    pub fn new(name: &str, schema: &[RowType]) -> Self {
+        Self {
+            name: name.to_string(),
+            schema: schema.to_vec(),
+            partitions: BTreeMap::new(),
+        }
+    }
+
+    /// Opens the partitioned table saved under `name`, with a partition for
+    /// each directory named after it. Returns `None` if it has none, and
+    /// `DbError::SchemaMismatch` if its partitions' schemas differ.
+    pub fn open(name: &str) -> Result<Option<Self>, DbError> {
+        let path = Path::new(name);
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let prefix = match path.file_name() {
+            Some(file_name) => format!("{}.p", file_name.to_string_lossy()),
+            None => return Ok(None),
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut res = Self::new(name, &[]);
+        for entry in entries {
+            let entry = entry?;
+            let Some(start) = entry
+                .file_name()
+                .to_str()
+                .and_then(|file_name| file_name.strip_prefix(&prefix))
+                .and_then(|start| start.parse::<NonZeroU32>().ok())
+            else {
+                continue;
+            };
+            let Some(db) = DB::open(&res.partition_name(start))? else {
+                continue;
+            };
+            if res.partitions.is_empty() {
+                res.schema = db.schema.schema.clone();
+            } else if db.schema.schema != res.schema {
+                return Err(DbError::SchemaMismatch);
+            }
+            res.partitions.insert(start, db);
+        }
+        Ok((!res.partitions.is_empty()).then_some(res))
+    }
+
+    /// Creates a partitioned table under `name` with a partition starting at
+    /// each of `starts`, replacing any partitions already saved under it.
+    pub fn create(name: &str, schema: &[RowType], starts: &[NonZeroU32]) -> Result<Self, DbError> {
+        if let Some(mut old) = Self::open(name)? {
+            let old_starts: Vec<_> = old.partitions.keys().copied().collect();
+            for start in old_starts {
+                old.drop_partition(start);
+            }
+        }
+        let mut db = Self::new(name, schema);
+        for &start in starts {
+            db.add_partition(start)?;
+        }
+        Ok(db)
+    }
+
+    fn partition_name(&self, start: NonZeroU32) -> String {
+        format!("{}.p{start}", self.name)
+    }
+
+    /// Adds a partition whose range starts at `start`. Rows from `start` on
+    /// in the partition that covered it move to the new one, so none are
+    /// hidden behind it. Returns false if one already starts there, and an
+    /// error if its database can't be created or the rows can't be moved.
+    pub fn add_partition(&mut self, start: NonZeroU32) -> Result<bool, DbError> {
+        if self.partitions.contains_key(&start) {
+            return Ok(false);
+        }
+        let name = self.partition_name(start);
+        let mut db = DB::new(&name, &self.schema)?;
+        if let Some(covering) = self.partition_mut(start) {
+            let moved = covering.scan(start..);
+            if !moved.is_empty() {
+                // the rows are synced to the new partition before they're
+                // removed from the old one, so they're never only in memory
+                if let Err(e) = db.bulk_insert(&moved) {
+                    drop(db);
+                    let _ = fs::remove_dir_all(&name);
+                    return Err(e);
+                }
+                db.sync();
+                for (id, _) in &moved {
+                    covering.remove(*id);
+                }
+                covering.sync();
+            }
+        }
+        self.partitions.insert(start, db);
+        Ok(true)
+    }
+    /// Drops the partition starting at `start` along with all of its rows by
+    /// deleting its files, without touching any other partition.
+    pub fn drop_partition(&mut self, start: NonZeroU32) -> bool {
+        let Some(db) = self.partitions.remove(&start) else {
+            return false;
+        };
+        drop(db);
+
+        let _ = fs::remove_dir_all(self.partition_name(start));
+        true
+    }
+
+    fn partition(&self, id: NonZeroU32) -> Option<&DB> {
+        self.partitions.range(..=id).next_back().map(|(_, db)| db)
+    }
+
+    fn partition_mut(&mut self, id: NonZeroU32) -> Option<&mut DB> {
+        self.partitions
+            .range_mut(..=id)
+            .next_back()
+            .map(|(_, db)| db)
+    }
+
+    pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.partition(id)?.get(id)
+    }
+
+    pub fn insert(&mut self, id: NonZeroU32, val: &[RowVal]) -> Result<(), DbError> {
+        match self.partition_mut(id) {
+            Some(db) => db.insert(id, val),
+            None => Err(DbError::NoPartition(id)),
+        }
+    }
+
+    pub fn remove(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.partition_mut(id)?.remove(id)
+    }
+
+    /// Every row with an id in `range`, in order, from the partitions it
+    /// overlaps.
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<NonZeroU32> + Clone,
+    ) -> Vec<(NonZeroU32, Vec<RowVal>)> {
+        let mut res = vec![];
+        let mut ends = self.partitions.keys().skip(1).map(Some).chain([None]);
+        for (start, db) in &self.partitions {
+            let end = ends.next().flatten();
+            let before = match range.end_bound() {
+                Bound::Included(last) => last < start,
+                Bound::Excluded(last) => last <= start,
+                Bound::Unbounded => false,
+            };
+            let after = match (range.start_bound(), end) {
+                (Bound::Included(first), Some(end)) => first >= end,
+                (Bound::Excluded(first), Some(end)) => first.get() >= end.get() - 1,
+                _ => false,
+            };
+            if !before && !after {
+                res.extend(db.scan(range.clone()));
+            }
+        }
+        res
+    }
+
+    /// Syncs every partition, returning whether they all synced.
+    pub fn sync(&mut self) -> bool {
+        let mut synced = true;
+        for db in self.partitions.values_mut() {
+            synced &= db.sync();
+        }
+        synced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const DEFAULT_SCHEMA: &[RowType] = &[RowType::Id, RowType::U32];
+
+    #[test]
+    fn route_and_drop() {
+        let mut db = PartitionedDB::new("tests/partitions", DEFAULT_SCHEMA);
+        let low = NonZeroU32::new(1).unwrap();
+        let high = NonZeroU32::new(100).unwrap();
+
+        assert!(db.add_partition(high).unwrap());
+        assert_eq!(
+            db.insert(low, &[RowVal::U32(1)]),
+            Err(DbError::NoPartition(low))
+        );
+
+        assert!(db.add_partition(low).unwrap());
+        assert!(!db.add_partition(low).unwrap());
+
+        db.insert(NonZeroU32::new(50).unwrap(), &[RowVal::U32(50)])
+            .unwrap();
+        db.insert(NonZeroU32::new(150).unwrap(), &[RowVal::U32(150)])
+            .unwrap();
+        db.sync();
+
+        assert_eq!(db.partitions[&low].pages.len(), 1);
+        assert_eq!(db.partitions[&high].pages.len(), 1);
+
+        assert!(db.drop_partition(low));
+        assert!(!Path::new("tests/partitions.p1").exists());
+        assert_eq!(db.get(NonZeroU32::new(50).unwrap()), None);
+        assert_eq!(
+            db.get(NonZeroU32::new(150).unwrap()),
+            Some(vec![RowVal::U32(150)])
+        );
+    }
+
+    #[test]
+    fn split_and_reopen() {
+        let name = "tests/split_partitions";
+        for start in [1, 10, 20] {
+            let _ = fs::remove_dir_all(format!("{name}.p{start}"));
+        }
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut db = PartitionedDB::new(name, DEFAULT_SCHEMA);
+        db.add_partition(id(1)).unwrap();
+        for i in 1..=30 {
+            db.insert(id(i), &[RowVal::U32(i)]).unwrap();
+        }
+        db.sync();
+
+        // the rows a new partition covers move into it
+        assert!(db.add_partition(id(10)).unwrap());
+        assert!(db.add_partition(id(20)).unwrap());
+        let counts: Vec<_> = db.partitions.values().map(|db| db.scan(..).len()).collect();
+        assert_eq!(counts, [9, 10, 11]);
+        assert_eq!(db.get(id(15)), Some(vec![RowVal::U32(15)]));
+
+        // scans cover every partition they overlap, in order
+        let ids = |rows: Vec<(NonZeroU32, Vec<RowVal>)>| -> Vec<u32> {
+            rows.into_iter().map(|(id, _)| id.get()).collect()
+        };
+        assert_eq!(ids(db.scan(id(5)..=id(25))), (5..=25).collect::<Vec<_>>());
+        assert_eq!(ids(db.scan(id(10)..id(20))), (10..20).collect::<Vec<_>>());
+        assert_eq!(db.scan(..).len(), 30);
+
+        // the bounds come back with the partitions' directories
+        drop(db);
+        let mut db = PartitionedDB::open(name).unwrap().unwrap();
+        assert_eq!(db.schema, DEFAULT_SCHEMA);
+        assert_eq!(
+            db.partitions.keys().copied().collect::<Vec<_>>(),
+            [id(1), id(10), id(20)]
+        );
+        assert_eq!(db.get(id(25)), Some(vec![RowVal::U32(25)]));
+
+        assert!(db.drop_partition(id(10)));
+        drop(db);
+        let db = PartitionedDB::open(name).unwrap().unwrap();
+        assert_eq!(db.get(id(15)), None);
+        assert_eq!(db.scan(..).len(), 20);
+        assert!(PartitionedDB::open("tests/no_partitions")
+            .unwrap()
+            .is_none());
+    }
+}
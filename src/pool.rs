@@ -0,0 +1,155 @@
+//! The buffer pool of a database opened with `DB::open_mapped`, which holds
+//! the pages read from the mapped `.db` file, decoded, up to a fixed number
+//! of them. When it's full, the clean page used least recently makes way
+//! for the next one read. Dirty pages stay until a `sync` writes them back.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::page::Page;
+
+/// How many pages a pool holds unless `DB::set_pool_capacity` says
+/// otherwise.
+pub const DEFAULT_POOL_CAPACITY: usize = 1024;
+
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    capacity: usize,
+    /// Every page held, by its index in the file, with when it was last
+    /// used.
+    pages: HashMap<usize, (Page, u64)>,
+    /// The indexes of the clean pages by when they were last used, so the
+    /// first is the next to be evicted.
+    lru: BTreeMap<u64, usize>,
+    clock: u64,
+}
+
+impl BufferPool {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.pages.len() > capacity && self.evict() {}
+    }
+
+    /// The encoded size of every page held.
+    pub(crate) fn size(&self) -> usize {
+        self.pages.values().map(|(page, _)| page.size()).sum()
+    }
+
+    /// The page at index `i`, read with `read` if it isn't held already.
+    pub(crate) fn get(&mut self, i: usize, read: impl FnOnce() -> Page) -> &Page {
+        self.entry(i, read, false)
+    }
+
+    /// Like `get`, but marks the page dirty, which keeps it held until it's
+    /// taken by `take_dirty`.
+    pub(crate) fn get_mut(&mut self, i: usize, read: impl FnOnce() -> Page) -> &mut Page {
+        self.entry(i, read, true)
+    }
+
+    /// The page at index `i` if it's held and dirty.
+    pub(crate) fn dirty(&self, i: usize) -> Option<&Page> {
+        self.pages
+            .get(&i)
+            .map(|(page, _)| page)
+            .filter(|page| page.dirty)
+    }
+
+    /// Removes every dirty page, returning them in index order.
+    pub(crate) fn take_dirty(&mut self) -> Vec<(usize, Page)> {
+        let indexes: Vec<_> = self
+            .pages
+            .iter()
+            .filter(|(_, (page, _))| page.dirty)
+            .map(|(i, _)| *i)
+            .collect();
+        let mut dirty: Vec<_> = indexes
+            .into_iter()
+            .map(|i| (i, self.pages.remove(&i).unwrap().0))
+            .collect();
+        dirty.sort_by_key(|(i, _)| *i);
+        dirty
+    }
+
+    fn entry(&mut self, i: usize, read: impl FnOnce() -> Page, dirty: bool) -> &mut Page {
+        self.clock += 1;
+        if !self.pages.contains_key(&i) {
+            while self.pages.len() >= self.capacity && self.evict() {}
+            self.pages.insert(i, (read(), 0));
+        }
+        let (page, used) = self.pages.get_mut(&i).unwrap();
+        page.dirty |= dirty;
+        self.lru.remove(used);
+        *used = self.clock;
+        // a page read in dirty, such as one migrated to a schema that
+        // dropped a column, can't be evicted either
+        if !page.dirty {
+            self.lru.insert(self.clock, i);
+        }
+        page
+    }
+
+    /// Evicts the clean page used least recently, returning whether there
+    /// was one.
+    fn evict(&mut self) -> bool {
+        let Some((_, i)) = self.lru.pop_first() else {
+            return false;
+        };
+        self.pages.remove(&i);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use crate::row::{RowType, RowVal};
+
+    use super::*;
+
+    fn page(id: u32) -> Page {
+        Page::new(
+            &[vec![RowVal::Id(NonZeroU32::new(id).unwrap())]],
+            &[RowType::Id],
+        )
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut pool = BufferPool::new(2);
+        pool.get(0, || page(1));
+        pool.get(1, || page(2));
+        // using page 0 again leaves page 1 as the one to evict
+        pool.get(0, || unreachable!());
+        pool.get(2, || page(3));
+        assert_eq!(pool.pages.len(), 2);
+        assert!(pool.pages.contains_key(&0));
+        assert!(!pool.pages.contains_key(&1));
+
+        // dirty pages are never evicted, even past the capacity
+        pool.get_mut(0, || unreachable!());
+        pool.get_mut(2, || unreachable!());
+        pool.get(3, || page(4));
+        assert_eq!(pool.pages.len(), 3);
+        assert!(pool.dirty(0).is_some());
+        assert!(pool.dirty(3).is_none());
+
+        let dirty = pool.take_dirty();
+        assert_eq!(dirty.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(pool.pages.len(), 1);
+        pool.set_capacity(0);
+        assert_eq!(pool.pages.len(), 0);
+    }
+}
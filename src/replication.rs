@@ -0,0 +1,386 @@
+//! Leader-follower replication by shipping the changes a primary commits to
+//! its WAL to followers, which apply them to their own `DB`s.
+//!
+//! A follower connects by sending the primary's LSN it's caught up to. If
+//! the primary still has every change since then in its backlog, it sends
+//! just those. Otherwise, it sends a copy of every row, which replaces
+//! whatever the follower had. Either way it goes on to stream each change
+//! as it's committed, followed by the LSN the follower has caught up to
+//! whenever it has sent everything so far.
+//!
+//! Followers need the primary's schema, and are meant to be read from only:
+//! anything written to one directly is lost on its next full copy.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    checksum::Checksum,
+    db::{ChangeEvent, DB},
+    error::DbError,
+    row::RowType,
+    shared::SharedDB,
+    wal::{WALRecord, FRAME_HEADER_SIZE},
+};
+
+const RESET: u8 = 0;
+const CHANGE: u8 = 1;
+const SYNCED: u8 = 2;
+
+/// What the primary sends its followers.
+#[derive(Debug, Clone, PartialEq)]
+enum Message {
+    /// Every row is about to be sent, so the follower should drop its own.
+    Reset,
+    Change(ChangeEvent),
+    /// The follower has every change up to this LSN.
+    Synced(u64),
+}
+
+impl Message {
+    fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Message::Reset => out.write_all(&[RESET]),
+            Message::Change(ChangeEvent { lsn, record }) => {
+                out.write_all(&[CHANGE])?;
+                out.write_all(&record.to_frame(*lsn, Checksum::Crc32))
+            }
+            Message::Synced(lsn) => {
+                out.write_all(&[SYNCED])?;
+                out.write_all(&lsn.to_le_bytes())
+            }
+        }
+    }
+
+    /// Reads the next message, or `None` once the primary hangs up.
+    fn read_from(reader: &mut impl Read, schema: &[RowType]) -> io::Result<Option<Self>> {
+        let mut tag = [0];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let message = match tag[0] {
+            RESET => Message::Reset,
+            CHANGE => {
+                let mut frame = vec![0; FRAME_HEADER_SIZE];
+                reader.read_exact(&mut frame)?;
+                let len = u32::from_le_bytes(frame[0..4].try_into().unwrap()) as usize;
+                frame.resize(FRAME_HEADER_SIZE + len, 0);
+                reader.read_exact(&mut frame[FRAME_HEADER_SIZE..])?;
+                let (lsn, record, _) = WALRecord::from_frame(&frame, schema, Checksum::Crc32)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "corrupted change")
+                    })?;
+                Message::Change(ChangeEvent { lsn, record })
+            }
+            SYNCED => {
+                let mut lsn = [0; 8];
+                reader.read_exact(&mut lsn)?;
+                Message::Synced(u64::from_le_bytes(lsn))
+            }
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message {tag}"),
+                ))
+            }
+        };
+        Ok(Some(message))
+    }
+}
+
+/// The last changes made to the primary, for followers that reconnect to
+/// catch up from.
+#[derive(Debug)]
+struct Backlog {
+    changes: Receiver<ChangeEvent>,
+    events: VecDeque<ChangeEvent>,
+    /// The LSN after which every change is in `events`.
+    start: u64,
+    capacity: usize,
+}
+
+impl Backlog {
+    fn push(&mut self, event: ChangeEvent) {
+        self.events.push_back(event);
+        if self.events.len() > self.capacity {
+            self.start = self.events.pop_front().unwrap().lsn;
+        }
+    }
+
+    /// Takes in every change that's been published but not received yet.
+    fn drain(&mut self) {
+        while let Ok(event) = self.changes.try_recv() {
+            self.push(event);
+        }
+    }
+}
+
+/// Serves a database's changes to followers.
+#[derive(Debug)]
+pub struct Primary {
+    db: SharedDB,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+impl Primary {
+    /// Starts keeping the last `capacity` changes made to `db`, so followers
+    /// that fall behind by less than that catch up without a full copy.
+    pub fn new(db: SharedDB, capacity: usize) -> Self {
+        let backlog = {
+            let mut db = db.write();
+            Backlog {
+                changes: db.subscribe(),
+                events: VecDeque::new(),
+                start: db.wal.lsn,
+                capacity,
+            }
+        };
+        let backlog = Arc::new(Mutex::new(backlog));
+        let weak = Arc::downgrade(&backlog);
+        // changes are received with the backlog locked, so none is ever
+        // between the channel and the backlog while a follower catches up
+        thread::spawn(move || {
+            while let Some(backlog) = weak.upgrade() {
+                let mut backlog = backlog.lock().unwrap();
+                match backlog.changes.recv_timeout(Duration::from_millis(100)) {
+                    Ok(event) => backlog.push(event),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        });
+        Self { db, backlog }
+    }
+
+    /// What a follower that has every change up to `from` needs to catch
+    /// up, along with a subscription to the changes after that.
+    fn catch_up(&self, from: u64) -> (Vec<Message>, Receiver<ChangeEvent>) {
+        // nothing can be written while the backlog is brought up to date
+        let mut db = self.db.write();
+        let mut backlog = self.backlog.lock().unwrap();
+        backlog.drain();
+        let lsn = db.wal.lsn;
+        let mut messages = if from == lsn {
+            vec![]
+        } else if backlog.start <= from && from < lsn {
+            backlog
+                .events
+                .iter()
+                .filter(|event| event.lsn > from)
+                .cloned()
+                .map(Message::Change)
+                .collect()
+        } else {
+            let rows = db.scan_with_deleted(..).into_iter();
+            let tombstones = db.wal.tombstones.iter();
+            let records = rows
+                .map(|(id, vals)| WALRecord::Insert(id, vals))
+                .chain(tombstones.map(|id| WALRecord::Tombstone(*id)));
+            [Message::Reset]
+                .into_iter()
+                .chain(records.map(|record| Message::Change(ChangeEvent { lsn, record })))
+                .collect()
+        };
+        messages.push(Message::Synced(lsn));
+        (messages, db.subscribe())
+    }
+
+    /// Serves one follower, catching it up and then streaming changes to it
+    /// until it hangs up.
+    pub fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut from = [0; 8];
+        stream.read_exact(&mut from)?;
+        let (messages, changes) = self.catch_up(u64::from_le_bytes(from));
+        let mut out = BufWriter::new(stream);
+        for message in messages {
+            message.write_to(&mut out)?;
+        }
+        out.flush()?;
+        while let Ok(event) = changes.recv() {
+            Message::Change(event).write_to(&mut out)?;
+            // once every change so far has been sent, which is only certain
+            // while nothing can be written, the follower is caught up
+            loop {
+                let db = self.db.read();
+                match changes.try_recv() {
+                    Ok(event) => {
+                        drop(db);
+                        Message::Change(event).write_to(&mut out)?;
+                    }
+                    Err(_) => {
+                        let lsn = db.wal.lsn;
+                        drop(db);
+                        Message::Synced(lsn).write_to(&mut out)?;
+                        break;
+                    }
+                }
+            }
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps a database up to date with a primary's.
+#[derive(Debug)]
+pub struct Follower {
+    pub db: SharedDB,
+    /// The primary's LSN this has every change up to, or `None` until it's
+    /// been sent a full copy.
+    pub lsn: Option<u64>,
+}
+
+impl Follower {
+    pub fn new(db: SharedDB) -> Self {
+        Self { db, lsn: None }
+    }
+
+    /// Follows the primary at `addr` until it hangs up, catching up on what
+    /// was missed first. Call it again to reconnect.
+    pub fn follow(&mut self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&self.lsn.unwrap_or(u64::MAX).to_le_bytes())?;
+        let mut reader = BufReader::new(stream);
+        let schema = self.db.read().schema.schema.clone();
+        while let Some(message) = Message::read_from(&mut reader, &schema)? {
+            match message {
+                Message::Reset => {
+                    // a copy cut off partway through has to start over
+                    self.lsn = None;
+                    let mut db = self.db.write();
+                    for (id, _) in db.scan_with_deleted(..) {
+                        db.try_remove(id).map_err(io::Error::other)?;
+                    }
+                }
+                Message::Change(event) => {
+                    apply(&mut self.db.write(), event.record).map_err(io::Error::other)?;
+                }
+                Message::Synced(lsn) => self.lsn = Some(lsn),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Makes a change on a follower. Changes sent again after a reconnect land
+/// the same way twice.
+fn apply(db: &mut DB, record: WALRecord) -> Result<(), DbError> {
+    match record {
+        WALRecord::Insert(id, vals) => db.insert(id, &vals)?,
+        WALRecord::Delete(id) => {
+            db.try_remove(id)?;
+        }
+        WALRecord::Tombstone(id) => {
+            db.soft_delete(id)?;
+        }
+        WALRecord::Restore(id) => {
+            db.restore(id)?;
+        }
+        // only the changes above are published, merges as the inserts of
+        // what they gave
+        WALRecord::Checkpoint(_)
+        | WALRecord::Begin(_)
+        | WALRecord::Commit(_)
+        | WALRecord::Rollback(_)
+        | WALRecord::Merge(..)
+        | WALRecord::Request(_) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Shutdown, TcpListener},
+        num::NonZeroU32,
+        sync::mpsc::channel,
+        thread::JoinHandle,
+        time::Instant,
+    };
+
+    use crate::row::RowVal;
+
+    use super::*;
+
+    fn id(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    fn wait_for(f: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn follows_and_catches_up() {
+        let schema = [RowType::Id, RowType::U32];
+        let primary_db = SharedDB::new(DB::new("tests/replication_primary", &schema).unwrap());
+        let primary = Arc::new(Primary::new(primary_db.clone(), 2));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // hands back each connection, so it can be cut off
+        let (connections, accepted) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                connections.send(stream.try_clone().unwrap()).unwrap();
+                let primary = Arc::clone(&primary);
+                thread::spawn(move || primary.serve(stream));
+            }
+        });
+        let follow = |mut follower: Follower| -> JoinHandle<Follower> {
+            thread::spawn(move || {
+                follower.follow(addr).unwrap();
+                follower
+            })
+        };
+        let follower_db = SharedDB::new(DB::new("tests/replication_follower", &schema).unwrap());
+        let caught_up = || follower_db.scan(..) == primary_db.scan(..);
+
+        // a new follower gets a full copy, replacing what it had
+        primary_db.insert(id(1), &[RowVal::U32(1)]).unwrap();
+        primary_db.insert(id(2), &[RowVal::U32(2)]).unwrap();
+        primary_db.write().soft_delete(id(2)).unwrap();
+        follower_db.insert(id(9), &[RowVal::U32(9)]).unwrap();
+        let handle = follow(Follower::new(follower_db.clone()));
+        let connection = accepted.recv().unwrap();
+        wait_for(caught_up);
+        primary_db.write().restore(id(2)).unwrap();
+        primary_db.insert(id(3), &[RowVal::U32(3)]).unwrap();
+        primary_db.remove(id(1)).unwrap();
+        wait_for(caught_up);
+        connection.shutdown(Shutdown::Write).unwrap();
+        let follower = handle.join().unwrap();
+        assert_eq!(follower.lsn, Some(primary_db.read().wal.lsn));
+
+        // one that's behind by less than the backlog gets what it missed
+        primary_db.insert(id(4), &[RowVal::U32(4)]).unwrap();
+        follower_db.insert(id(9), &[RowVal::U32(9)]).unwrap();
+        let handle = follow(follower);
+        let connection = accepted.recv().unwrap();
+        wait_for(|| follower_db.get(id(4)).is_some());
+        assert!(follower_db.get(id(9)).is_some());
+        connection.shutdown(Shutdown::Write).unwrap();
+        let follower = handle.join().unwrap();
+
+        // and one that's fallen out of it starts over
+        for n in 5..=7 {
+            primary_db.insert(id(n), &[RowVal::U32(n)]).unwrap();
+        }
+        follow(follower);
+        wait_for(caught_up);
+        assert_eq!(follower_db.scan(..).len(), 6);
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::{HashSet, VecDeque};
+
+/// How many idempotency keys a database remembers before forgetting the
+/// oldest.
+pub const REQUEST_KEYS: usize = 10_000;
+
+/// The idempotency keys of the last `REQUEST_KEYS` writes made with
+/// `DB::apply_once`, so a client retrying one after a timeout can't apply
+/// it twice. They're logged in the WAL with the writes they name, and
+/// logged again whenever the WAL is cleared, so they survive a restart.
+#[derive(Debug, Default)]
+pub struct Requests {
+    /// Every remembered key, oldest first.
+    keys: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl Requests {
+    pub fn contains(&self, key: u64) -> bool {
+        self.seen.contains(&key)
+    }
+
+    /// Remembers `key`, forgetting the oldest key if there are too many.
+    pub fn record(&mut self, key: u64) {
+        if !self.seen.insert(key) {
+            return;
+        }
+        self.keys.push_back(key);
+        if self.keys.len() > REQUEST_KEYS {
+            let oldest = self.keys.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+    }
+
+    /// Every remembered key, oldest first.
+    pub fn keys(&self) -> Vec<u64> {
+        self.keys.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_oldest() {
+        let mut requests = Requests::default();
+        for key in 0..REQUEST_KEYS as u64 + 2 {
+            requests.record(key);
+        }
+        requests.record(5);
+        assert!(!requests.contains(0) && !requests.contains(1));
+        assert!(requests.contains(2) && requests.contains(REQUEST_KEYS as u64 + 1));
+        assert_eq!(requests.keys().len(), REQUEST_KEYS);
+        assert_eq!(requests.keys()[0], 2);
+    }
+}
@@ -0,0 +1,294 @@
+//! The key/value subset of the Redis protocol (RESP), served from a `Kv`
+//! store, so Redis clients can read and write it. Keys are the store's u32
+//! keys written in decimal, and `GET`, `SET`, `DEL`, `EXISTS`, `SCAN` and
+//! `PING` are understood.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    sync::{Mutex, PoisonError},
+};
+
+use crate::kv::Kv;
+
+/// The longest bulk string a client can send, the same as Redis's default.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// A reply sent back to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string, which is what a missing key reads as.
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn bulk(bytes: impl Into<Vec<u8>>) -> Self {
+        Value::Bulk(Some(bytes.into()))
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Value::Simple(s) => write!(out, "+{s}\r\n"),
+            Value::Error(e) => write!(out, "-{e}\r\n"),
+            Value::Integer(n) => write!(out, ":{n}\r\n"),
+            Value::Bulk(None) => write!(out, "$-1\r\n"),
+            Value::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                write!(out, "\r\n")
+            }
+            Value::Array(values) => {
+                write!(out, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a line, without its `\r\n`, or `None` at the end of the stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8]) -> io::Result<usize> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .filter(|&len| len <= MAX_BULK_LEN)
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+/// Reads one command, either as an array of bulk strings, the way clients
+/// send them, or inline as words on a line, the way they're typed into
+/// telnet. Returns `None` once the client hangs up.
+pub fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        let words = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(words));
+    };
+    let count = parse_len(count)?;
+    let mut args = vec![];
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| invalid("unexpected end of stream"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| invalid("expected a bulk string"))?;
+        let mut arg = vec![0; parse_len(len)? + 2];
+        reader.read_exact(&mut arg)?;
+        if arg.split_off(arg.len() - 2) != b"\r\n" {
+            return Err(invalid("bulk string isn't terminated by CRLF"));
+        }
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Parses a key, which has to be a u32 above 0.
+fn key(bytes: &[u8]) -> Result<u32, String> {
+    parse(bytes)
+        .filter(|&k| k > 0)
+        .ok_or_else(|| "ERR keys must be integers from 1 to 4294967295".to_string())
+}
+
+fn parse(bytes: &[u8]) -> Option<u32> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Counts the keys `f` returns true for, once every key has parsed.
+fn count(keys: &[Vec<u8>], mut f: impl FnMut(u32) -> bool) -> Result<Value, String> {
+    let keys = keys.iter().map(|k| key(k)).collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::Integer(
+        keys.into_iter().filter(|&k| f(k)).count() as i64
+    ))
+}
+
+/// `SCAN $cursor [COUNT $n]`. The cursor is the key to carry on from, and
+/// the cursor sent back is 0 once every key has been seen.
+fn scan(kv: &Kv, cursor: &[u8], options: &[Vec<u8>]) -> Result<Value, String> {
+    let cursor = parse(cursor).ok_or("ERR invalid cursor")?;
+    let count = match options {
+        [] => 10,
+        [option, n] if option.eq_ignore_ascii_case(b"COUNT") => parse(n)
+            .filter(|&n| n > 0)
+            .ok_or("ERR value is not an integer or out of range")?
+            as usize,
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    let mut keys: Vec<_> = kv
+        .scan(cursor..)
+        .into_iter()
+        .map(|(k, _)| k)
+        .take(count + 1)
+        .collect();
+    let next = if keys.len() > count {
+        keys.pop().unwrap()
+    } else {
+        0
+    };
+    Ok(Value::Array(vec![
+        Value::bulk(next.to_string()),
+        Value::Array(
+            keys.into_iter()
+                .map(|k| Value::bulk(k.to_string()))
+                .collect(),
+        ),
+    ]))
+}
+
+/// Runs a command against `kv`, returning the reply to send back.
+pub fn execute(kv: &mut Kv, args: &[Vec<u8>]) -> Value {
+    let Some((name, args)) = args.split_first() else {
+        return Value::Error("ERR empty command".to_string());
+    };
+    let name = String::from_utf8_lossy(name).to_ascii_uppercase();
+    let reply = match (name.as_str(), args) {
+        ("PING", []) => Ok(Value::Simple("PONG".to_string())),
+        ("PING", [message]) => Ok(Value::bulk(message.clone())),
+        ("GET", [k]) => key(k).map(|k| Value::Bulk(kv.get(k))),
+        ("SET", [k, v]) => key(k).and_then(|k| {
+            kv.put(k, v)
+                .map(|()| Value::Simple("OK".to_string()))
+                .map_err(|e| format!("ERR {e}"))
+        }),
+        ("DEL", keys) if !keys.is_empty() => count(keys, |k| kv.delete(k).is_some()),
+        ("EXISTS", keys) if !keys.is_empty() => count(keys, |k| kv.get(k).is_some()),
+        ("SCAN", [cursor, options @ ..]) => scan(kv, cursor, options),
+        ("PING" | "GET" | "SET" | "DEL" | "EXISTS" | "SCAN", _) => Err(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        )),
+        _ => Err(format!("ERR unknown command '{name}'")),
+    };
+    reply.unwrap_or_else(Value::Error)
+}
+
+/// Answers commands from one client until it hangs up. Replies to
+/// pipelined commands are flushed together once the client has nothing
+/// more buffered.
+pub fn handle(
+    kv: &Mutex<Kv>,
+    mut reader: BufReader<impl Read>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return out.flush(),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Value::Error(format!("ERR Protocol error: {e}")).write_to(&mut out)?;
+                return out.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        // blank inline lines are skipped, as Redis does
+        if args.is_empty() {
+            continue;
+        }
+        let reply = execute(
+            &mut kv.lock().unwrap_or_else(PoisonError::into_inner),
+            &args,
+        );
+        reply.write_to(&mut out)?;
+        if reader.buffer().is_empty() {
+            out.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn open(path: &str) -> Kv {
+        let _ = fs::remove_dir_all(path);
+        Kv::open(path).unwrap()
+    }
+
+    fn run(kv: &mut Kv, command: &str) -> Value {
+        let args: Vec<_> = command
+            .split(' ')
+            .map(|arg| arg.as_bytes().to_vec())
+            .collect();
+        execute(kv, &args)
+    }
+
+    #[test]
+    fn commands() {
+        let mut kv = open("tests/resp_commands");
+        let ok = Value::Simple("OK".to_string());
+        assert_eq!(run(&mut kv, "ping"), Value::Simple("PONG".to_string()));
+        for k in 1..=5 {
+            assert_eq!(run(&mut kv, &format!("SET {k} v{k}")), ok);
+        }
+        assert_eq!(run(&mut kv, "GET 2"), Value::bulk("v2"));
+        assert_eq!(run(&mut kv, "GET 6"), Value::Bulk(None));
+        assert_eq!(run(&mut kv, "DEL 2 2 3 6"), Value::Integer(2));
+        assert_eq!(run(&mut kv, "EXISTS 1 1 2 4"), Value::Integer(3));
+        assert_eq!(
+            run(&mut kv, "SCAN 0 COUNT 2"),
+            Value::Array(vec![
+                Value::bulk("5"),
+                Value::Array(vec![Value::bulk("1"), Value::bulk("4")])
+            ])
+        );
+        assert_eq!(
+            run(&mut kv, "SCAN 5"),
+            Value::Array(vec![Value::bulk("0"), Value::Array(vec![Value::bulk("5")])])
+        );
+
+        assert!(matches!(run(&mut kv, "GET 0"), Value::Error(_)));
+        assert!(matches!(run(&mut kv, "DEL 1 x"), Value::Error(_)));
+        assert_eq!(run(&mut kv, "EXISTS 1"), Value::Integer(1));
+        assert!(matches!(run(&mut kv, "SET 1"), Value::Error(_)));
+        assert!(matches!(run(&mut kv, "INCR 1"), Value::Error(_)));
+        assert!(matches!(run(&mut kv, "SCAN 0 MATCH *"), Value::Error(_)));
+    }
+
+    #[test]
+    fn protocol() {
+        let kv = Mutex::new(open("tests/resp_protocol"));
+        let input = b"*3\r\n$3\r\nSET\r\n$1\r\n1\r\n$5\r\na\r\nb!\r\n\r\nGET 1\r\nEXISTS 1 2\n*1\r\n$3\r\nGET\r\n";
+        let mut out = vec![];
+        handle(&kv, BufReader::new(&input[..]), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "+OK\r\n$5\r\na\r\nb!\r\n:1\r\n-ERR wrong number of arguments for 'get' command\r\n"
+        );
+
+        let mut out = vec![];
+        handle(&kv, BufReader::new(&b"*1\r\n$3\r\nGETX\r\n"[..]), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "-ERR Protocol error: bulk string isn't terminated by CRLF\r\n"
+        );
+    }
+}
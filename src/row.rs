@@ -1,9 +1,15 @@
-use std::{fmt::Display, fs::File, io::Write as _, num::NonZeroU32};
+use std::{fmt::Display, num::NonZeroU32, time::Duration};
 
 #[cfg(test)]
 use serde::{Deserialize, Serialize};
 
-use crate::wal::WALRecord;
+use crate::{
+    db::Retention,
+    error::DbError,
+    header::MAX_SCHEMA_SIZE,
+    overflow::{self, SPILLED, SPILLED_SIZE},
+    wal::WALRecord,
+};
 
 pub fn to_bytes_bool(b: bool) -> [u8; 1] {
     match b {
@@ -39,15 +45,27 @@ pub enum RowType {
     U32,
     Bytes,
     Bool,
+    /// A `U32` holding the unix second the row expires at, or 0 if it never
+    /// does. Expired rows are hidden from reads and dropped by the next `sync`.
+    Ttl,
 }
 
 impl RowType {
+    /// Whether `val` can be stored in a column of this type.
+    pub fn accepts(self, val: &RowVal) -> bool {
+        match (val, self) {
+            (RowVal::U32(_), RowType::Ttl) => true,
+            (val, row_type) => val.row_type() == row_type,
+        }
+    }
+
     pub fn to_bytes(self) -> [u8; 1] {
         match self {
             RowType::Id => [0],
             RowType::U32 => [1],
             RowType::Bytes => [2],
             RowType::Bool => [3],
+            RowType::Ttl => [4],
         }
     }
 
@@ -57,11 +75,16 @@ impl RowType {
             [1] => RowType::U32,
             [2] => RowType::Bytes,
             [3] => RowType::Bool,
+            [4] => RowType::Ttl,
             _ => unreachable!(),
         }
     }
 }
 
+/// The longest `Bytes` value. Its length is encoded in a u16, and
+/// `overflow::SPILLED` is kept back to mark a spilled value.
+pub const MAX_BYTES_LEN: usize = SPILLED as usize - 1;
+
 #[cfg_attr(test, derive(Serialize, Deserialize))]
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum RowVal {
@@ -99,7 +122,9 @@ impl RowVal {
 
     pub fn from_bytes(bytes: &[u8], row_type: RowType) -> Self {
         match row_type {
-            RowType::U32 => RowVal::U32(u32::from_le_bytes(bytes.try_into().unwrap())),
+            RowType::U32 | RowType::Ttl => {
+                RowVal::U32(u32::from_le_bytes(bytes.try_into().unwrap()))
+            }
             RowType::Bytes => {
                 let len = u16::from_le_bytes(bytes[..2].try_into().unwrap()) as usize;
                 RowVal::Bytes(bytes[2..2 + len].to_vec())
@@ -113,32 +138,147 @@ impl RowVal {
         }
     }
 
-    pub fn size(&self) -> u16 {
+    pub fn row_type(&self) -> RowType {
+        match self {
+            RowVal::Id(_) => RowType::Id,
+            RowVal::U32(_) => RowType::U32,
+            RowVal::Bytes(_) => RowType::Bytes,
+            RowVal::Bool(_) => RowType::Bool,
+        }
+    }
+
+    /// The encoded size of the value. A `Bytes` value longer than
+    /// `MAX_BYTES_LEN` can't be encoded, though its size is still counted.
+    pub fn size(&self) -> usize {
         match self {
             RowVal::Id(_) | RowVal::U32(_) => 4,
-            RowVal::Bytes(b) => b.len() as u16 + 2,
+            RowVal::Bytes(b) => b.len() + 2,
             RowVal::Bool(_) => 1,
         }
     }
 }
 
-pub fn schema_to_bytes(schema: &[RowType]) -> Vec<u8> {
-    let mut res = vec![];
-    for row_type in schema {
-        res.extend(row_type.to_bytes());
+/// A limit on a column's values, checked on every insert. For `U32` columns
+/// it bounds the value, for `Bytes` columns the length.
+#[cfg_attr(test, derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constraint {
+    Min(u32),
+    Max(u32),
+}
+
+impl Constraint {
+    pub fn to_bytes(&self) -> [u8; 5] {
+        let (tag, n) = match self {
+            Constraint::Min(n) => (0, n),
+            Constraint::Max(n) => (1, n),
+        };
+        let mut res = [tag, 0, 0, 0, 0];
+        res[1..].copy_from_slice(&n.to_le_bytes());
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let n = u32::from_le_bytes(bytes[1..5].try_into().unwrap());
+        match bytes[0] {
+            0 => Constraint::Min(n),
+            1 => Constraint::Max(n),
+            _ => panic!("invalid constraint"),
+        }
+    }
+
+    /// Whether the constraint can be put on a column of this type.
+    pub fn applies_to(row_type: RowType) -> bool {
+        matches!(row_type, RowType::U32 | RowType::Bytes)
+    }
+
+    pub fn check(&self, val: &RowVal) -> bool {
+        let n = match val {
+            RowVal::U32(n) => *n,
+            RowVal::Bytes(b) => b.len() as u32,
+            RowVal::Id(_) | RowVal::Bool(_) => return true,
+        };
+        match self {
+            Constraint::Min(min) => n >= *min,
+            Constraint::Max(max) => n <= *max,
+        }
     }
-    res
 }
 
-pub fn schema_from_bytes(bytes: &[u8]) -> Vec<RowType> {
-    let mut res = vec![];
-    for byte in bytes.iter().copied() {
-        res.push(RowType::from_bytes(&[byte]));
+impl Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Min(n) => f.write_str(&format!("min {n}")),
+            Constraint::Max(n) => f.write_str(&format!("max {n}")),
+        }
+    }
+}
+
+type Column = (RowType, String, Option<RowVal>, Vec<Constraint>);
+
+fn column_to_bytes(
+    row_type: RowType,
+    name: &str,
+    default: Option<&RowVal>,
+    constraints: &[Constraint],
+) -> Vec<u8> {
+    let mut res = row_type.to_bytes().to_vec();
+    res.extend(to_bytes_string(name));
+    match default {
+        Some(default) => {
+            res.push(1);
+            res.extend(default.to_bytes());
+        }
+        None => res.push(0),
+    }
+    res.push(constraints.len() as u8);
+    for constraint in constraints {
+        res.extend(constraint.to_bytes());
     }
     res
 }
 
+fn column_from_bytes(bytes: &[u8]) -> (Column, usize) {
+    let row_type = RowType::from_bytes(&[bytes[0]]);
+    let mut i = 1;
+
+    let name_len = u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap()) as usize;
+    let name = from_bytes_string(&bytes[i..i + 2 + name_len]);
+    i += 2 + name_len;
+
+    let has_default = bytes[i] == 1;
+    i += 1;
+    let default = if has_default {
+        let (mut vals, incr) = bytes_to_values(&bytes[i..], &[row_type]);
+        i += incr;
+        vals.pop()
+    } else {
+        None
+    };
+
+    let constraint_count = bytes[i] as usize;
+    i += 1;
+    let constraints = (0..constraint_count)
+        .map(|j| Constraint::from_bytes(&bytes[i + j * 5..]))
+        .collect();
+    i += constraint_count * 5;
+
+    ((row_type, name, default, constraints), i)
+}
+
 pub fn bytes_to_values(bytes: &[u8], schema: &[RowType]) -> (Vec<RowVal>, usize) {
+    bytes_to_values_with(bytes, schema, |_| {
+        panic!("a spilled value can only be read with the file it was spilled to")
+    })
+}
+
+/// Like `bytes_to_values`, but reading back values spilled to overflow pages
+/// with `spilled`, which is given the reference left in their place.
+pub fn bytes_to_values_with(
+    bytes: &[u8],
+    schema: &[RowType],
+    spilled: impl Fn(&[u8]) -> Vec<u8>,
+) -> (Vec<RowVal>, usize) {
     let mut res = vec![];
     let mut i = 0;
 
@@ -148,14 +288,19 @@ pub fn bytes_to_values(bytes: &[u8], schema: &[RowType]) -> (Vec<RowVal>, usize)
                 res.push(RowVal::from_bytes(&bytes[i..i + 4], RowType::Id));
                 i += 4;
             }
-            RowType::U32 => {
+            RowType::U32 | RowType::Ttl => {
                 res.push(RowVal::from_bytes(&bytes[i..i + 4], RowType::U32));
                 i += 4;
             }
             RowType::Bytes => {
-                let len = u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap()) as usize;
-                res.push(RowVal::from_bytes(&bytes[i..], RowType::Bytes));
-                i += 2 + len;
+                let len = u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap());
+                if len == SPILLED {
+                    res.push(RowVal::Bytes(spilled(&bytes[i..i + SPILLED_SIZE])));
+                    i += SPILLED_SIZE;
+                } else {
+                    res.push(RowVal::from_bytes(&bytes[i..], RowType::Bytes));
+                    i += 2 + len as usize;
+                }
             }
             RowType::Bool => {
                 res.push(RowVal::from_bytes(&bytes[i..i + 1], RowType::Bool));
@@ -167,6 +312,28 @@ pub fn bytes_to_values(bytes: &[u8], schema: &[RowType]) -> (Vec<RowVal>, usize)
     (res, i)
 }
 
+/// The length of the row encoded at the start of `bytes`, as `bytes_to_values`
+/// would find it, and whether any of its values were spilled to overflow
+/// pages, without decoding it.
+pub fn encoded_row_len(bytes: &[u8], schema: &[RowType]) -> (usize, bool) {
+    let mut i = 0;
+    let mut spilled = false;
+    for row_type in schema {
+        i += match row_type {
+            RowType::Id | RowType::U32 | RowType::Ttl => 4,
+            RowType::Bytes => match u16::from_le_bytes(bytes[i..i + 2].try_into().unwrap()) {
+                SPILLED => {
+                    spilled = true;
+                    SPILLED_SIZE
+                }
+                len => 2 + len as usize,
+            },
+            RowType::Bool => 1,
+        };
+    }
+    (i, spilled)
+}
+
 pub fn bytes_to_actions(bytes: &[u8], schema: &[RowType]) -> Vec<WALRecord> {
     let mut res = vec![];
     let mut i = 0;
@@ -191,18 +358,277 @@ pub fn bytes_to_actions(bytes: &[u8], schema: &[RowType]) -> Vec<WALRecord> {
     res
 }
 
-#[derive(Debug)]
+/// A change made to the schema after the table was created. The schema's
+/// version is the number of changes applied, and every page records the
+/// version it was written with, so older pages can be read and upgraded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    /// A column appended to the end of every row. Rows written before the
+    /// change get `default`.
+    AddColumn { row_type: RowType, default: RowVal },
+    /// The column at `index` (counting the id) removed from every row.
+    DropColumn { index: u16, row_type: RowType },
+}
+
+impl SchemaChange {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            SchemaChange::AddColumn { row_type, default } => {
+                let mut res = vec![0];
+                res.extend(row_type.to_bytes());
+                res.extend(default.to_bytes());
+                res
+            }
+            SchemaChange::DropColumn { index, row_type } => {
+                let mut res = vec![1];
+                res.extend(index.to_le_bytes());
+                res.extend(row_type.to_bytes());
+                res
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> (Self, usize) {
+        match bytes[0] {
+            0 => {
+                let row_type = RowType::from_bytes(&[bytes[1]]);
+                let (mut vals, incr) = bytes_to_values(&bytes[2..], &[row_type]);
+                let default = vals.pop().unwrap();
+                (SchemaChange::AddColumn { row_type, default }, 2 + incr)
+            }
+            1 => {
+                let index = u16::from_le_bytes(bytes[1..3].try_into().unwrap());
+                let row_type = RowType::from_bytes(&[bytes[3]]);
+                (SchemaChange::DropColumn { index, row_type }, 4)
+            }
+            _ => panic!("invalid schema change"),
+        }
+    }
+}
+
+/// A step from one schema version to the next. Pages remember the version
+/// they were written with and run every later step over their rows as they're
+/// read, so old pages never have to be rewritten up front.
+pub trait Migration {
+    /// Upgrades a row (without its id) written before this step.
+    fn migrate(&self, vals: &mut Vec<RowVal>);
+    /// Turns the column types after this step back into the ones before it.
+    fn revert(&self, schema: &mut Vec<RowType>);
+}
+
+impl Migration for SchemaChange {
+    fn migrate(&self, vals: &mut Vec<RowVal>) {
+        match self {
+            SchemaChange::AddColumn { default, .. } => vals.push(default.clone()),
+            SchemaChange::DropColumn { index, .. } => {
+                vals.remove(*index as usize - 1);
+            }
+        }
+    }
+
+    fn revert(&self, schema: &mut Vec<RowType>) {
+        match self {
+            SchemaChange::AddColumn { .. } => {
+                schema.pop();
+            }
+            SchemaChange::DropColumn { index, row_type } => {
+                schema.insert(*index as usize, *row_type);
+            }
+        }
+    }
+}
+
+/// What a check's description starts with in a serialized schema, after the
+/// tags of the schema changes.
+const CHECK_TAG: u8 = 2;
+
+/// What the retention policy starts with in a serialized schema, followed
+/// by its column and its maximum age in seconds.
+const RETENTION_TAG: u8 = 3;
+
+#[derive(Debug, Clone)]
 pub struct Schema {
     pub schema: Vec<RowType>,
-    pub file: File,
+    /// The name of each column, indexed like `schema`.
+    pub names: Vec<String>,
+    /// The default value for each column, indexed like `schema`.
+    pub defaults: Vec<Option<RowVal>>,
+    /// The constraints on each column, indexed like `schema`.
+    pub constraints: Vec<Vec<Constraint>>,
+    pub changes: Vec<SchemaChange>,
+    /// The description of each check registered with `DB::add_check`. The
+    /// checks themselves are closures, which have to be registered again
+    /// whenever the database is opened, but their descriptions are saved so
+    /// the schema can be shown with them.
+    pub checks: Vec<String>,
+    /// The policy set with `DB::set_retention`, saved so it keeps being
+    /// applied after the database is reopened.
+    pub retention: Option<Retention>,
 }
 
-impl Drop for Schema {
-    fn drop(&mut self) {
-        let schema_bytes = schema_to_bytes(&self.schema);
-        let _ = self.file.write_all(&schema_bytes);
-        let _ = self.file.set_len(schema_bytes.len() as u64);
+impl Schema {
+    pub fn new(schema: &[RowType]) -> Self {
+        Self {
+            schema: schema.to_vec(),
+            names: vec![String::new(); schema.len()],
+            defaults: vec![None; schema.len()],
+            constraints: vec![vec![]; schema.len()],
+            changes: vec![],
+            checks: vec![],
+            retention: None,
+        }
+    }
+
+    /// Serializes the column count, then each column's type, name, optional
+    /// default and constraints, then every schema change in order, then each
+    /// check's description and the retention policy, tagged so they can't be
+    /// read as changes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = (self.schema.len() as u16).to_le_bytes().to_vec();
+        for (i, row_type) in self.schema.iter().enumerate() {
+            res.extend(column_to_bytes(
+                *row_type,
+                &self.names[i],
+                self.defaults[i].as_ref(),
+                &self.constraints[i],
+            ));
+        }
+        for change in &self.changes {
+            res.extend(change.to_bytes());
+        }
+        for check in &self.checks {
+            res.push(CHECK_TAG);
+            res.extend(to_bytes_string(check));
+        }
+        if let Some(retention) = self.retention {
+            res.push(RETENTION_TAG);
+            res.extend((retention.column as u16).to_le_bytes());
+            res.extend(retention.max_age.as_secs().to_le_bytes());
+        }
+        res
     }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut schema = Self::new(&[]);
+        let len = u16::from_le_bytes(bytes[0..2].try_into().unwrap());
+        let mut i = 2;
+        for _ in 0..len {
+            let ((row_type, name, default, constraints), incr) = column_from_bytes(&bytes[i..]);
+            schema.schema.push(row_type);
+            schema.names.push(name);
+            schema.defaults.push(default);
+            schema.constraints.push(constraints);
+            i += incr;
+        }
+        while i < bytes.len() {
+            if bytes[i] == CHECK_TAG {
+                let len = u16::from_le_bytes(bytes[i + 1..i + 3].try_into().unwrap()) as usize;
+                schema
+                    .checks
+                    .push(from_bytes_string(&bytes[i + 1..i + 3 + len]));
+                i += 3 + len;
+                continue;
+            }
+            if bytes[i] == RETENTION_TAG {
+                let column = u16::from_le_bytes(bytes[i + 1..i + 3].try_into().unwrap());
+                let max_age = u64::from_le_bytes(bytes[i + 3..i + 11].try_into().unwrap());
+                schema.retention = Some(Retention {
+                    column: column as usize,
+                    max_age: Duration::from_secs(max_age),
+                });
+                i += 11;
+                continue;
+            }
+            let (change, incr) = SchemaChange::from_bytes(&bytes[i..]);
+            schema.changes.push(change);
+            i += incr;
+        }
+        schema
+    }
+
+    pub fn version(&self) -> u32 {
+        self.changes.len() as u32
+    }
+
+    /// Fails if the schema is too large to save in the file header.
+    pub fn check_size(&self) -> Result<(), DbError> {
+        let size = self.to_bytes().len();
+        if size > MAX_SCHEMA_SIZE {
+            return Err(DbError::SchemaTooLarge {
+                size,
+                max: MAX_SCHEMA_SIZE,
+            });
+        }
+        Ok(())
+    }
+
+    /// The steps that take a row written at `version` to the current schema,
+    /// oldest first.
+    pub fn migrations(&self, version: u32) -> impl DoubleEndedIterator<Item = &dyn Migration> {
+        self.changes[version as usize..]
+            .iter()
+            .map(|change| change as &dyn Migration)
+    }
+
+    /// Whether `vals` (a row without its leading id) has a `Ttl` column set
+    /// to a time before `now`, in unix seconds.
+    pub fn expired(&self, vals: &[RowVal], now: u64) -> bool {
+        self.schema[1..].iter().zip(vals).any(|(row_type, val)| {
+            *row_type == RowType::Ttl
+                && matches!(val, RowVal::U32(expiry) if *expiry != 0 && (*expiry as u64) < now)
+        })
+    }
+
+    /// The column types rows had at `version`.
+    pub fn schema_at(&self, version: u32) -> Vec<RowType> {
+        let mut schema = self.schema.clone();
+        for migration in self.migrations(version).rev() {
+            migration.revert(&mut schema);
+        }
+        schema
+    }
+
+    /// Upgrades the values of a row written at `version` to the current
+    /// schema.
+    pub fn migrate(&self, vals: &mut Vec<RowVal>, version: u32) {
+        for migration in self.migrations(version) {
+            migration.migrate(vals);
+        }
+    }
+
+    /// Fills in any missing trailing columns of `vals` (a row without its id)
+    /// from the column defaults. Returns `None` if a missing column has no
+    /// default.
+    pub fn with_defaults(&self, vals: &[RowVal]) -> Option<Vec<RowVal>> {
+        let mut res = vals.to_vec();
+        for i in vals.len() + 1..self.schema.len() {
+            res.push(self.defaults.get(i).cloned().flatten()?);
+        }
+        Some(res)
+    }
+}
+
+/// Checks that `vals` (a row without its leading id) matches the column types
+/// of `schema`, which always starts with the id column.
+pub fn verify_row(vals: &[RowVal], schema: &[RowType]) -> bool {
+    if vals.len() != schema.len() - 1 {
+        return false;
+    }
+    vals.iter()
+        .zip(&schema[1..])
+        .all(|(val, row_type)| row_type.accepts(val))
+}
+
+/// The encoded size of a row with the given values, including its 4 byte id.
+pub fn row_size(vals: &[RowVal]) -> usize {
+    4 + vals.iter().map(RowVal::size).sum::<usize>()
+}
+
+/// The size a row with the given values takes on a page, which is
+/// `row_size` unless it's too large for one, when its values are spilled to
+/// overflow pages.
+pub fn stored_row_size(vals: &[RowVal]) -> usize {
+    overflow::stored_size(vals)
 }
 
 pub fn values_to_bytes(values: &[RowVal]) -> Vec<u8> {
@@ -271,9 +697,50 @@ mod tests {
 
     #[test]
     fn serde_schema() {
-        let schema = vec![RowType::Id, RowType::U32, RowType::Bytes, RowType::Bool];
-
-        assert_eq!(schema, schema_from_bytes(&schema_to_bytes(&schema)));
+        let mut schema = Schema::new(&[RowType::Id, RowType::U32, RowType::Bytes]);
+        schema.names = ["id", "count", "name"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        schema.defaults[1] = Some(RowVal::U32(7));
+        schema.defaults[2] = Some(RowVal::Bytes(b"default".to_vec()));
+        schema.constraints[2] = vec![Constraint::Min(1), Constraint::Max(20)];
+        schema.schema.push(RowType::Bool);
+        schema.names.push("active".to_string());
+        schema.defaults.push(None);
+        schema.constraints.push(vec![]);
+        schema.changes.push(SchemaChange::AddColumn {
+            row_type: RowType::Bool,
+            default: RowVal::Bool(true),
+        });
+        schema.schema.remove(1);
+        schema.names.remove(1);
+        schema.defaults.remove(1);
+        schema.constraints.remove(1);
+        schema.changes.push(SchemaChange::DropColumn {
+            index: 1,
+            row_type: RowType::U32,
+        });
+
+        let deserialized = Schema::from_bytes(&schema.to_bytes());
+
+        assert_eq!(schema.schema, deserialized.schema);
+        assert_eq!(schema.names, deserialized.names);
+        assert_eq!(schema.defaults, deserialized.defaults);
+        assert_eq!(schema.constraints, deserialized.constraints);
+        assert_eq!(schema.changes, deserialized.changes);
+        assert_eq!(
+            deserialized.schema_at(0),
+            vec![RowType::Id, RowType::U32, RowType::Bytes]
+        );
+        assert_eq!(
+            deserialized.schema_at(1),
+            vec![RowType::Id, RowType::U32, RowType::Bytes, RowType::Bool]
+        );
+
+        let mut vals = vec![RowVal::U32(1), RowVal::Bytes(vec![])];
+        deserialized.migrate(&mut vals, 0);
+        assert_eq!(vals, vec![RowVal::Bytes(vec![]), RowVal::Bool(true)]);
     }
 
     #[test]
@@ -0,0 +1,341 @@
+use crate::{
+    error::DbError,
+    row::{Constraint, RowType, RowVal},
+};
+
+/// A column parsed from the schema DSL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub row_type: RowType,
+    pub default: Option<RowVal>,
+    pub constraints: Vec<Constraint>,
+}
+
+/// Parses a comma separated list of columns, like
+/// `id, count:u32 = 0 min 1, name:text = "none" max 20, active:bool`.
+///
+/// Each column is `$name[:$type] [= $default] [min $n] [max $n]`. The type is
+/// one of `id`, `u32`, `bytes` (or `text`/`string`) and `bool`, and defaults to
+/// `id` when left out. It can also follow the name after a space, as in
+/// `id id, count u32 = 0`, the way `create table` first took it. `min` and `max` bound the value of a `u32` column and
+/// the length of a `bytes` column. The first column has to be the id.
+///
+/// Columns can't be nullable, since rows have no way to store a missing value.
+pub fn parse(s: &str) -> Result<Vec<Column>, DbError> {
+    let columns = split_columns(s)
+        .into_iter()
+        .map(parse_column)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match columns.first() {
+        Some(column) if column.row_type == RowType::Id => {}
+        _ => return Err(invalid("the first column has to be the id")),
+    }
+    for (i, column) in columns.iter().enumerate() {
+        if columns[..i].iter().any(|c| c.name == column.name) {
+            return Err(invalid(&format!("duplicate column {}", column.name)));
+        }
+    }
+
+    Ok(columns)
+}
+
+/// Parses a single column, see `parse`.
+pub fn parse_column(s: &str) -> Result<Column, DbError> {
+    let s = s.trim();
+    let (column, rest) = match s.find([' ', '=']) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let (name, row_type, rest) = match column.split_once(':') {
+        Some((name, t)) => (name, parse_type(t)?, rest),
+        None => {
+            let after = rest.trim_start();
+            let end = after.find([' ', '=']).unwrap_or(after.len());
+            match &after[..end] {
+                "" | "min" | "max" => (column, RowType::Id, rest),
+                t => (column, parse_type(t)?, &after[end..]),
+            }
+        }
+    };
+    if name.is_empty() {
+        return Err(invalid("a column is missing its name"));
+    }
+
+    let mut rest = rest.trim_start();
+    let mut default = None;
+    if let Some(after) = rest.strip_prefix('=') {
+        let (val, after) = split_value(after.trim_start())?;
+        let val = parse_value(val)
+            .filter(|val| row_type.accepts(val))
+            .ok_or_else(|| invalid(&format!("default for {name} does not match its type")))?;
+        default = Some(val);
+        rest = after;
+    }
+
+    let mut constraints = vec![];
+    let mut tokens = rest.split_whitespace();
+    while let Some(token) = tokens.next() {
+        let n = tokens
+            .next()
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| invalid(&format!("{token} on {name} needs a number")))?;
+        let constraint = match token {
+            "min" => Constraint::Min(n),
+            "max" => Constraint::Max(n),
+            _ => return Err(invalid(&format!("unknown constraint {token} on {name}"))),
+        };
+        if !Constraint::applies_to(row_type) {
+            return Err(invalid(&format!("{name} can't have {constraint}")));
+        }
+        constraints.push(constraint);
+    }
+
+    Ok(Column {
+        name: name.to_string(),
+        row_type,
+        default,
+        constraints,
+    })
+}
+
+/// Parses a single value: a quoted string, `true`/`false`, or a u32. A
+/// string can escape a quote or backslash with a backslash, and any byte as
+/// `\xNN`.
+pub fn parse_value(s: &str) -> Option<RowVal> {
+    let s = s.trim();
+    if let Some(quoted) = s.strip_prefix('"') {
+        return unescape(quoted.strip_suffix('"')?).map(RowVal::Bytes);
+    }
+    match s {
+        "true" => Some(RowVal::Bool(true)),
+        "false" => Some(RowVal::Bool(false)),
+        _ => s.parse().ok().map(RowVal::U32),
+    }
+}
+
+/// Writes a value the way `parse_value` reads it, quoting and escaping
+/// `Bytes` so any bytes read back the same.
+pub fn format_value(val: &RowVal) -> String {
+    let RowVal::Bytes(bytes) = val else {
+        return val.to_string();
+    };
+    let mut res = String::from('"');
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' | '\\' => {
+                    res.push('\\');
+                    res.push(c);
+                }
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for byte in c.encode_utf8(&mut buf).bytes() {
+                        res.push_str(&format!("\\x{byte:02x}"));
+                    }
+                }
+                c => res.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            res.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// The bytes a quoted string holds, undoing the escapes `format_value`
+/// writes. `None` if an escape is cut short.
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'x' => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    res.push(
+                        u8::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == 2)?,
+                    );
+                }
+                c => res.extend(c.to_string().bytes()),
+            },
+            c => res.extend(c.to_string().bytes()),
+        }
+    }
+    Some(res)
+}
+
+/// The name a column type goes by in a column definition.
+pub fn type_name(row_type: RowType) -> &'static str {
+    match row_type {
+        RowType::Id => "id",
+        RowType::U32 => "u32",
+        RowType::Bytes => "text",
+        RowType::Bool => "bool",
+        RowType::Ttl => "ttl",
+    }
+}
+
+fn parse_type(s: &str) -> Result<RowType, DbError> {
+    match s.trim() {
+        "id" => Ok(RowType::Id),
+        "u32" => Ok(RowType::U32),
+        "bytes" | "text" | "string" => Ok(RowType::Bytes),
+        "bool" => Ok(RowType::Bool),
+        "ttl" => Ok(RowType::Ttl),
+        "" => Err(invalid("a column is missing its type")),
+        t if t.ends_with('?') => Err(invalid("nullable columns aren't supported")),
+        t => Err(invalid(&format!("unknown type {t}"))),
+    }
+}
+
+/// Splits off the value at the start of `s`, which runs to the closing quote
+/// for strings and to the next space otherwise.
+fn split_value(s: &str) -> Result<(&str, &str), DbError> {
+    let end = if s.starts_with('"') {
+        let mut escaped = false;
+        s.char_indices()
+            .skip(1)
+            .find(|&(_, c)| {
+                let closes = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closes
+            })
+            .map(|(i, _)| i + 1)
+            .ok_or_else(|| invalid("unterminated string"))?
+    } else {
+        s.find(' ').unwrap_or(s.len())
+    };
+    Ok(s.split_at(end))
+}
+
+/// Splits on commas that aren't inside a quoted string, like the columns
+/// of a table or the values of an insert.
+pub fn split_columns(s: &str) -> Vec<&str> {
+    let mut res = vec![];
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' if !escaped => in_string = !in_string,
+            ',' if !in_string => {
+                res.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        escaped = c == '\\' && in_string && !escaped;
+    }
+    res.push(&s[start..]);
+    res
+}
+
+fn invalid(reason: &str) -> DbError {
+    DbError::InvalidSchema(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_columns() {
+        let columns =
+            parse(r#"id, count:u32 = 0 min 1 max 10, name:text = "a, b" max 20, active:bool"#)
+                .unwrap();
+        assert_eq!(
+            columns,
+            vec![
+                Column {
+                    name: "id".to_string(),
+                    row_type: RowType::Id,
+                    default: None,
+                    constraints: vec![],
+                },
+                Column {
+                    name: "count".to_string(),
+                    row_type: RowType::U32,
+                    default: Some(RowVal::U32(0)),
+                    constraints: vec![Constraint::Min(1), Constraint::Max(10)],
+                },
+                Column {
+                    name: "name".to_string(),
+                    row_type: RowType::Bytes,
+                    default: Some(RowVal::Bytes(b"a, b".to_vec())),
+                    constraints: vec![Constraint::Max(20)],
+                },
+                Column {
+                    name: "active".to_string(),
+                    row_type: RowType::Bool,
+                    default: None,
+                    constraints: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_spaced_types() {
+        assert_eq!(
+            parse("id id, count u32 = 0 min 1, name bytes, active bool").unwrap(),
+            parse("id, count:u32 = 0 min 1, name:bytes, active:bool").unwrap()
+        );
+        assert_eq!(
+            parse_column("count u32=1").unwrap().default,
+            Some(RowVal::U32(1))
+        );
+        // a constraint after the name isn't taken for a type
+        assert_eq!(
+            parse_column("id max 5"),
+            Err(invalid("id can't have max 5"))
+        );
+    }
+
+    #[test]
+    fn escaped_values() {
+        for bytes in [
+            &b"plain"[..],
+            b"a \"quote\", a \\ and \n",
+            b"caf\xc3\xa9 \xff\x00",
+        ] {
+            let val = RowVal::Bytes(bytes.to_vec());
+            let formatted = format_value(&val);
+            assert_eq!(parse_value(&formatted), Some(val.clone()), "{formatted}");
+            let column = parse_column(&format!("name:text = {formatted} max 30")).unwrap();
+            assert_eq!(column.default, Some(val));
+        }
+        assert_eq!(format_value(&RowVal::Bytes(b"a\"b".to_vec())), r#""a\"b""#);
+        assert_eq!(
+            split_columns(r#"1, "a, \"b, c", 2"#),
+            ["1", r#" "a, \"b, c""#, " 2"]
+        );
+        assert_eq!(parse_value(r#""\x4""#), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for s in [
+            "count:u32",
+            "id, count:u32, count:u32",
+            "id, count:u64",
+            "id, count:u32?",
+            "id, count u32?",
+            "id, count u64",
+            "id, count:u32 = true",
+            "id, active:bool max 1",
+            "id, count:u32 min",
+            r#"id, name:text = "none"#,
+        ] {
+            assert!(
+                matches!(parse(s), Err(DbError::InvalidSchema(_))),
+                "{s} should not parse"
+            );
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+
+use crate::error::DbError;
+
+/// Variables set in the shell with `\set`, substituted into later commands
+/// wherever `:name` appears outside a quoted string.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Session {
+    /// Handles `\set $name $value` and `\unset $name`, returning false if the
+    /// line is neither. The value is everything after the name, so it can be
+    /// a quoted string with spaces in it.
+    pub fn command(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("\\set ") {
+            let rest = rest.trim();
+            let (name, value) = rest.split_once(' ').unwrap_or((rest, ""));
+            self.vars.insert(name.to_string(), value.trim().to_string());
+            return true;
+        }
+        if let Some(name) = line.strip_prefix("\\unset ") {
+            self.vars.remove(name.trim());
+            return true;
+        }
+        false
+    }
+
+    /// Replaces every `:name` outside a quoted string with the variable's
+    /// value. A colon straight after a word, like in `count:u32`, is left
+    /// alone.
+    pub fn substitute(&self, line: &str) -> Result<String, DbError> {
+        let mut res = String::new();
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut chars = line.char_indices();
+        while let Some((i, c)) = chars.next() {
+            let after_word = res
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+            let was_escaped = std::mem::replace(&mut escaped, c == '\\' && in_string);
+            match c {
+                '"' if !was_escaped => in_string = !in_string,
+                '\\' if was_escaped => escaped = false,
+                ':' if !in_string && !after_word => {
+                    let name_len = line[i + 1..]
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(line.len() - i - 1);
+                    if name_len > 0 {
+                        let name = &line[i + 1..i + 1 + name_len];
+                        let value = self
+                            .vars
+                            .get(name)
+                            .ok_or_else(|| DbError::UnknownVariable(name.to_string()))?;
+                        res.push_str(value);
+                        for _ in 0..name_len {
+                            chars.next();
+                        }
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            res.push(c);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_substitute() {
+        let mut session = Session::default();
+        assert!(session.command(r#"\set name "ada lovelace""#));
+        assert!(session.command(r"\set id 7"));
+        assert!(!session.command("insert 1, 2"));
+
+        assert_eq!(
+            session.substitute(r#"insert :id, :name, ":id""#).unwrap(),
+            r#"insert 7, "ada lovelace", ":id""#
+        );
+        assert_eq!(
+            session.substitute("get :missing"),
+            Err(DbError::UnknownVariable("missing".to_string()))
+        );
+
+        session.command(r"\unset id");
+        assert!(session.substitute("get :id").is_err());
+        // colons that don't start a name are left alone
+        assert_eq!(
+            session
+                .substitute("create table t (id, count:u32)")
+                .unwrap(),
+            "create table t (id, count:u32)"
+        );
+        assert_eq!(session.substitute("a : b").unwrap(), "a : b");
+        // nor do escaped quotes end a string
+        let escaped = r#"insert 1, "a \" :id", "\\""#;
+        assert_eq!(session.substitute(escaped).unwrap(), escaped);
+    }
+}
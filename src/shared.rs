@@ -0,0 +1,242 @@
+use std::{
+    io,
+    num::NonZeroU32,
+    ops::RangeBounds,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::{
+    db::DB,
+    error::DbError,
+    row::RowVal,
+    transaction::{Txn, WriteBatch},
+};
+
+/// A handle to a `DB` that can be cloned and shared between threads. Reads
+/// share the lock, so any number of them run at once, while writes take it
+/// for themselves and run one at a time.
+#[derive(Debug, Clone)]
+pub struct SharedDB(Arc<RwLock<DB>>);
+
+impl SharedDB {
+    pub fn new(db: DB) -> Self {
+        Self(Arc::new(RwLock::new(db)))
+    }
+
+    /// Locks the database for reading, for anything the methods here don't
+    /// cover.
+    pub fn read(&self) -> RwLockReadGuard<'_, DB> {
+        self.0.read().unwrap()
+    }
+
+    /// Locks the database for writing, blocking every other reader and
+    /// writer until the guard is dropped.
+    pub fn write(&self) -> RwLockWriteGuard<'_, DB> {
+        self.0.write().unwrap()
+    }
+
+    /// Runs a write with the database locked, then waits for its group
+    /// commits, if it made any, with it unlocked, so other writers can join
+    /// the same fsync. Returns the write's result and whether the fsyncs
+    /// succeeded.
+    fn write_durably<T>(&self, f: impl FnOnce(&mut DB) -> T) -> (T, io::Result<()>) {
+        let (res, commits) = self.write().without_waiting(f);
+        let synced = commits.into_iter().try_for_each(|commit| commit.wait());
+        (res, synced)
+    }
+
+    pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.read().get(id)
+    }
+
+    pub fn with_row<T>(&self, id: NonZeroU32, f: impl FnOnce(&[RowVal]) -> T) -> Option<T> {
+        self.read().with_row(id, f)
+    }
+
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<NonZeroU32> + Clone,
+    ) -> Vec<(NonZeroU32, Vec<RowVal>)> {
+        self.read().scan(range)
+    }
+
+    pub fn insert(&self, id: NonZeroU32, val: &[RowVal]) -> Result<(), DbError> {
+        let (res, synced) = self.write_durably(|db| db.insert(id, val));
+        synced?;
+        res
+    }
+
+    pub fn remove(&self, id: NonZeroU32) -> Result<Option<Vec<RowVal>>, DbError> {
+        let (res, synced) = self.write_durably(|db| db.try_remove(id));
+        synced?;
+        res
+    }
+
+    pub fn begin(&self) -> Txn {
+        self.write().begin()
+    }
+
+    pub fn commit(&self, txn: Txn) -> Result<(), DbError> {
+        let (res, synced) = self.write_durably(|db| db.commit(txn));
+        synced?;
+        res
+    }
+
+    pub fn apply(&self, batch: WriteBatch) -> Result<(), DbError> {
+        let (res, synced) = self.write_durably(|db| db.apply(batch));
+        synced?;
+        res
+    }
+
+    pub fn compare_and_swap(
+        &self,
+        id: NonZeroU32,
+        expected: Option<&[RowVal]>,
+        new: Option<&[RowVal]>,
+    ) -> Result<bool, DbError> {
+        let (res, synced) = self.write_durably(|db| db.compare_and_swap(id, expected, new));
+        synced?;
+        res
+    }
+
+    pub fn merge(&self, id: NonZeroU32, operand: &[RowVal]) -> Result<(), DbError> {
+        let (res, synced) = self.write_durably(|db| db.merge(id, operand));
+        synced?;
+        res
+    }
+
+    pub fn sync(&self) -> bool {
+        self.write().sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::atomic::Ordering, thread, time::Duration};
+
+    use crate::{db::Durability, row::RowType, wal::GroupCommit};
+
+    use super::*;
+
+    #[test]
+    fn concurrent_readers_and_writers() {
+        let db = SharedDB::new(
+            DB::new(
+                "tests/concurrent_readers_and_writers",
+                &[RowType::Id, RowType::U32],
+            )
+            .unwrap(),
+        );
+        let writers: Vec<_> = (0..4u32)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 1..=250 {
+                        let id = t * 250 + i;
+                        db.insert(NonZeroU32::new(id).unwrap(), &[RowVal::U32(id)])
+                            .unwrap();
+                        if i % 50 == 0 {
+                            db.sync();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        // every row is whole, whether it's in the WAL or on
+                        // a page mid sync
+                        for (id, vals) in db.scan(..) {
+                            assert_eq!(vals, [RowVal::U32(id.get())]);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
+
+        // the optimistic increments of every thread land, one at a time
+        let counter = NonZeroU32::new(1001).unwrap();
+        db.insert(counter, &[RowVal::U32(0)]).unwrap();
+        let incrementers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        loop {
+                            let old = db.get(counter).unwrap();
+                            let RowVal::U32(n) = old[0] else {
+                                unreachable!()
+                            };
+                            let new = [RowVal::U32(n + 1)];
+                            if db
+                                .compare_and_swap(counter, Some(&old), Some(&new))
+                                .unwrap()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in incrementers {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(db.scan(..).len(), 1001);
+        assert_eq!(db.get(counter), Some(vec![RowVal::U32(400)]));
+    }
+
+    #[test]
+    fn group_commit() {
+        let file_name = "tests/shared_group_commit";
+        let _ = fs::remove_dir_all(file_name);
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let db = DB::options()
+            .create(true)
+            .schema(&[RowType::Id, RowType::U32])
+            .durability(Durability::WalPerCommit)
+            .group_commit(GroupCommit {
+                max_records: 64,
+                max_delay: Duration::from_millis(5),
+            })
+            .open(file_name)
+            .unwrap();
+        let fsyncs = Arc::clone(&db.group_commit.as_ref().unwrap().fsyncs);
+        let fsynced = || fsyncs.load(Ordering::Relaxed);
+
+        // a write straight to the DB waits for its own fsync
+        let db = SharedDB::new(db);
+        db.write().insert(id(1000), &[RowVal::U32(0)]).unwrap();
+        assert_eq!(fsynced(), 1);
+
+        // while writers through the handle share theirs
+        let writers: Vec<_> = (0..8u32)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for i in 1..=25 {
+                        let n = t * 25 + i;
+                        db.insert(id(n), &[RowVal::U32(n)]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in writers {
+            thread.join().unwrap();
+        }
+        assert!(fsynced() > 1);
+        assert!(fsynced() < 201, "{} fsyncs for 200 writes", fsynced());
+
+        drop(db);
+        let db = DB::open(file_name).unwrap().unwrap();
+        assert_eq!(db.scan(..).len(), 201);
+        assert_eq!(db.get(id(200)), Some(vec![RowVal::U32(200)]));
+    }
+}
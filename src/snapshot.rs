@@ -0,0 +1,233 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io,
+    iter::Peekable,
+    num::NonZeroU32,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{
+    btree::Tree,
+    db::migrate_page,
+    header::{FileHeader, HEADER_SIZE},
+    mmap::Mmap,
+    page::{Page, PageHeader, PAGE_SIZE},
+    row::{RowVal, Schema},
+    utils::unix_now,
+};
+
+/// A read-only view of a database at one point in time. It can be sent to
+/// other threads for long scans while the `DB` it came from keeps taking
+/// writes.
+///
+/// Pages that were on disk when it was taken are read straight from the
+/// `.db` file mapped into memory, and the rest, along with the rows still in
+/// the WAL, are copied. While any snapshot is alive the `DB` replaces its
+/// file by renaming a new one over it instead of writing pages in place, so
+/// the mapped file never changes underneath a snapshot.
+#[derive(Debug)]
+pub struct Snapshot {
+    map: Mmap,
+    schema: Schema,
+    /// Every page in key order.
+    pages: Vec<SnapshotPage>,
+    /// Rows that hadn't been synced to the pages, which take precedence over
+    /// their copies on pages.
+    wal: BTreeMap<NonZeroU32, Vec<RowVal>>,
+    /// Rows that had been soft deleted.
+    tombstones: BTreeSet<NonZeroU32>,
+    /// A clone of `DB::snapshots`, which is how the `DB` knows this is alive.
+    _guard: Arc<()>,
+}
+
+#[derive(Debug)]
+pub(crate) enum SnapshotPage {
+    /// The page at this index in the file.
+    File(usize),
+    /// A page that changed after it was last written, copied out of memory.
+    Memory(Page),
+}
+
+impl Snapshot {
+    /// A snapshot of every page in `file`, found by following its leaves
+    /// in key order.
+    pub(crate) fn new(file: &File, guard: Arc<()>) -> io::Result<Self> {
+        let map = Mmap::new(file)?;
+        let FileHeader { schema, root, .. } =
+            FileHeader::from_bytes(map.bytes()).map_err(io::Error::other)?;
+        let pages = Tree::new(map.bytes(), root)
+            .leaves()
+            .map(|(_, i)| SnapshotPage::File(i))
+            .collect();
+        Ok(Self {
+            map,
+            schema,
+            pages,
+            wal: BTreeMap::new(),
+            tombstones: BTreeSet::new(),
+            _guard: guard,
+        })
+    }
+
+    /// A snapshot of `pages`, reading the ones on file from `file`, with
+    /// `wal` and `tombstones` laid over them.
+    pub(crate) fn pinned(
+        file: &File,
+        schema: Schema,
+        pages: Vec<SnapshotPage>,
+        wal: BTreeMap<NonZeroU32, Vec<RowVal>>,
+        tombstones: BTreeSet<NonZeroU32>,
+        guard: Arc<()>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            map: Mmap::new(file)?,
+            schema,
+            pages,
+            wal,
+            tombstones,
+            _guard: guard,
+        })
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    fn page_bytes(&self, i: usize) -> &[u8] {
+        let start = HEADER_SIZE + i * PAGE_SIZE;
+        &self.map.bytes()[start..start + PAGE_SIZE]
+    }
+
+    fn file_page_header(&self, i: usize) -> PageHeader {
+        PageHeader::from_bytes(self.page_bytes(i)[..PageHeader::size()].try_into().unwrap())
+    }
+
+    fn page_header(&self, i: usize) -> PageHeader {
+        match &self.pages[i] {
+            SnapshotPage::File(i) => self.file_page_header(*i),
+            SnapshotPage::Memory(page) => page.header,
+        }
+    }
+
+    fn page(&self, i: usize) -> Page {
+        match &self.pages[i] {
+            SnapshotPage::File(i) => {
+                let version = self.file_page_header(*i).version;
+                let page = Page::read(self.map.bytes(), *i, &self.schema.schema_at(version));
+                migrate_page(page, &self.schema)
+            }
+            SnapshotPage::Memory(page) => page.clone(),
+        }
+    }
+
+    /// The pages that could hold ids in `range`, going by their headers
+    /// alone.
+    fn pages_in<'a>(
+        &'a self,
+        range: &'a impl RangeBounds<NonZeroU32>,
+    ) -> impl Iterator<Item = usize> + 'a {
+        (0..self.page_count()).filter(move |&i| {
+            let header = self.page_header(i);
+            match (header.start, header.end) {
+                (Some(start), Some(end)) => header.count > 0 && overlaps(range, start, end),
+                _ => false,
+            }
+        })
+    }
+
+    /// Whether a row is left out of reads, for having been soft deleted or
+    /// having expired by the time it's read, as it is by `DB::get`.
+    fn hidden(&self, id: NonZeroU32, row: &[RowVal], now: u64) -> bool {
+        self.tombstones.contains(&id) || self.schema.expired(row, now)
+    }
+
+    pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.wal
+            .get(&id)
+            .cloned()
+            .or_else(|| self.pages_in(&(id..=id)).find_map(|i| self.page(i).get(id)))
+            .filter(|row| !self.hidden(id, row, unix_now()))
+    }
+
+    /// Every row with an id in `range`, in id order.
+    pub fn scan(
+        &self,
+        range: impl RangeBounds<NonZeroU32> + Clone,
+    ) -> Vec<(NonZeroU32, Vec<RowVal>)> {
+        let mut rows = BTreeMap::new();
+        for i in self.pages_in(&range) {
+            let page = self.page(i);
+            rows.extend(
+                page.data
+                    .range(range.clone())
+                    .map(|(id, vals)| (*id, vals.clone())),
+            );
+        }
+        rows.extend(self.wal.range(range).map(|(id, vals)| (*id, vals.clone())));
+        let now = unix_now();
+        rows.into_iter()
+            .filter(|(id, row)| !self.hidden(*id, row, now))
+            .collect()
+    }
+
+    /// Every row in id order, read a page at a time, so a scan of the whole
+    /// snapshot doesn't hold all of it in memory at once.
+    pub fn iter(&self) -> impl Iterator<Item = (NonZeroU32, Vec<RowVal>)> + '_ {
+        let now = unix_now();
+        let pages = (0..self.page_count()).flat_map(|i| self.page(i).data);
+        let wal = self.wal.iter().map(|(id, vals)| (*id, vals.clone()));
+        Merge {
+            left: pages.peekable(),
+            right: wal.peekable(),
+        }
+        .filter(move |(id, row)| !self.hidden(*id, row, now))
+    }
+}
+
+/// Merges two iterators of rows in id order, taking the row from `right`
+/// when both have the same id.
+struct Merge<L: Iterator, R: Iterator> {
+    left: Peekable<L>,
+    right: Peekable<R>,
+}
+
+impl<L, R> Iterator for Merge<L, R>
+where
+    L: Iterator<Item = (NonZeroU32, Vec<RowVal>)>,
+    R: Iterator<Item = (NonZeroU32, Vec<RowVal>)>,
+{
+    type Item = (NonZeroU32, Vec<RowVal>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match (self.left.peek(), self.right.peek()) {
+            (Some((left, _)), Some((right, _))) if left < right => self.left.next(),
+            (Some((left, _)), Some((right, _))) if left == right => {
+                self.left.next();
+                self.right.next()
+            }
+            (Some(_), None) => self.left.next(),
+            _ => self.right.next(),
+        }
+    }
+}
+
+/// Whether `range` shares any ids with `start..=end`.
+pub(crate) fn overlaps(
+    range: &impl RangeBounds<NonZeroU32>,
+    start: NonZeroU32,
+    end: NonZeroU32,
+) -> bool {
+    let after_start = match range.start_bound() {
+        Bound::Included(range_start) => *range_start <= end,
+        Bound::Excluded(range_start) => *range_start < end,
+        Bound::Unbounded => true,
+    };
+    let before_end = match range.end_bound() {
+        Bound::Included(range_end) => start <= *range_end,
+        Bound::Excluded(range_end) => start < *range_end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
@@ -0,0 +1,312 @@
+//! Importing the tables of a SQLite database, for `db import-sqlite`, with
+//! `--features sqlite`. Each table becomes a database named after it, with
+//! its `INTEGER PRIMARY KEY` (or its rowid, if it has none) as the id.
+//! Columns declared `BOOL`-something become `bool` columns, ones SQLite
+//! gives integer affinity `u32` columns, and the rest `text`. A row that
+//! doesn't fit, like one with a `NULL` or a negative number, is reported by
+//! its rowid and skipped, while the rest are inserted a batch at a time.
+
+use std::{num::NonZeroU32, path::Path};
+
+use rusqlite::{types::ValueRef, Connection, OpenFlags};
+
+use crate::{
+    csv::BATCH_ROWS,
+    db::DB,
+    error::DbError,
+    manifest,
+    row::{RowType, RowVal},
+    schema::{type_name, Column},
+};
+
+/// What importing one table did.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SqliteImport {
+    /// The table, and the name of the database made of it.
+    pub table: String,
+    /// How many rows were inserted.
+    pub inserted: u64,
+    /// The rowid of each rejected row, and why it was rejected.
+    pub rejected: Vec<(i64, String)>,
+}
+
+/// Imports every table of the SQLite database `path` into a database of the
+/// same name under the directory `dir`, see the module docs, and syncs them.
+/// Fails without importing anything if one of them is there already.
+pub fn import(path: &str, dir: &str) -> Result<Vec<SqliteImport>, DbError> {
+    if !Path::new(path).exists() {
+        return Err(DbError::NotFound(path.to_string()));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(sqlite_error)?;
+    let tables: Vec<String> = conn
+        .prepare(
+            "SELECT name FROM sqlite_master \
+             WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+        .map_err(sqlite_error)?;
+    let file_name = |table: &str| Path::new(dir).join(table).to_string_lossy().into_owned();
+    if let Some(table) = tables
+        .iter()
+        .find(|table| manifest::read(&file_name(table)).is_some())
+    {
+        return Err(DbError::Io(format!(
+            "{} already holds a database",
+            file_name(table)
+        )));
+    }
+    tables
+        .iter()
+        .map(|table| import_table(&conn, table, &file_name(table)))
+        .collect()
+}
+
+fn import_table(conn: &Connection, table: &str, file_name: &str) -> Result<SqliteImport, DbError> {
+    // the name, declared type and primary key position of each column
+    let info: Vec<(String, String, i64)> = conn
+        .prepare(&format!("PRAGMA table_info({})", quote(table)))
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get(1)?, row.get(2)?, row.get(5)?)))?
+                .collect()
+        })
+        .map_err(sqlite_error)?;
+    // an INTEGER PRIMARY KEY is the rowid under another name
+    let key = info
+        .iter()
+        .position(|(_, declared, pk)| *pk == 1 && declared.eq_ignore_ascii_case("integer"))
+        .filter(|_| info.iter().filter(|(_, _, pk)| *pk > 0).count() == 1);
+    let key_name = match key {
+        Some(i) => info[i].0.clone(),
+        None if info.iter().any(|(name, _, _)| name == "id") => "rowid".to_string(),
+        None => "id".to_string(),
+    };
+    let mut columns = vec![Column {
+        name: key_name,
+        row_type: RowType::Id,
+        default: None,
+        constraints: vec![],
+    }];
+    let mut selected = vec![key.map_or("rowid".to_string(), |i| quote(&info[i].0))];
+    for (i, (name, declared, _)) in info.iter().enumerate() {
+        if Some(i) != key {
+            columns.push(Column {
+                name: name.clone(),
+                row_type: row_type(declared),
+                default: None,
+                constraints: vec![],
+            });
+            selected.push(quote(name));
+        }
+    }
+    let mut db = DB::from_columns(file_name, &columns)?;
+
+    let mut res = SqliteImport {
+        table: table.to_string(),
+        ..Default::default()
+    };
+    let mut stmt = conn
+        .prepare(&format!(
+            "SELECT {} FROM {} ORDER BY 1",
+            selected.join(", "),
+            quote(table)
+        ))
+        .map_err(sqlite_error)?;
+    let mut rows = stmt.query([]).map_err(sqlite_error)?;
+    let mut batch = vec![];
+    let mut rowids = vec![];
+    while let Some(sql_row) = rows.next().map_err(sqlite_error)? {
+        let rowid = sql_row.get(0).map_err(sqlite_error)?;
+        match row(&db, &columns, sql_row) {
+            Ok(row) => {
+                batch.push(row);
+                rowids.push(rowid);
+            }
+            Err(reason) => res.rejected.push((rowid, reason)),
+        }
+        if batch.len() == BATCH_ROWS {
+            insert(&mut db, &mut batch, &mut rowids, &mut res);
+        }
+    }
+    insert(&mut db, &mut batch, &mut rowids, &mut res);
+    db.sync();
+    Ok(res)
+}
+
+/// Inserts a batch of rows at once, or one at a time if the batch is
+/// rejected, so only the rows that are get reported.
+fn insert(
+    db: &mut DB,
+    batch: &mut Vec<(NonZeroU32, Vec<RowVal>)>,
+    rowids: &mut Vec<i64>,
+    res: &mut SqliteImport,
+) {
+    if batch.is_empty() {
+        return;
+    }
+    if db.bulk_insert(batch).is_ok() {
+        res.inserted += batch.len() as u64;
+    } else {
+        for ((id, vals), rowid) in batch.iter().zip(rowids.iter()) {
+            match db.insert(*id, vals) {
+                Ok(()) => res.inserted += 1,
+                Err(e) => res.rejected.push((*rowid, e.to_string())),
+            }
+        }
+    }
+    batch.clear();
+    rowids.clear();
+}
+
+/// The row a SQLite row holds, or why it can't be inserted.
+fn row(
+    db: &DB,
+    columns: &[Column],
+    sql_row: &rusqlite::Row,
+) -> Result<(NonZeroU32, Vec<RowVal>), String> {
+    let mut vals = vec![];
+    for (i, column) in columns.iter().enumerate() {
+        let val = sql_row.get_ref(i).map_err(|e| e.to_string())?;
+        vals.push(value(val, column.row_type).ok_or_else(|| {
+            format!(
+                "{} isn't a {} for {}",
+                describe(val),
+                type_name(column.row_type),
+                column.name
+            )
+        })?);
+    }
+    let RowVal::Id(id) = vals.remove(0) else {
+        unreachable!("the first column is the id")
+    };
+    db.validate(id, &vals).map_err(|e| e.to_string())?;
+    Ok((id, vals))
+}
+
+/// The type a column declared as `declared` is imported as, going by
+/// SQLite's affinity rules.
+fn row_type(declared: &str) -> RowType {
+    let declared = declared.to_ascii_uppercase();
+    if declared.contains("BOOL") {
+        RowType::Bool
+    } else if declared.contains("INT") {
+        RowType::U32
+    } else {
+        RowType::Bytes
+    }
+}
+
+/// Reads a SQLite value as `row_type`. Numbers in a `text` column are
+/// written out in decimal.
+fn value(val: ValueRef, row_type: RowType) -> Option<RowVal> {
+    match (row_type, val) {
+        (RowType::Id, ValueRef::Integer(n)) => u32::try_from(n)
+            .ok()
+            .and_then(NonZeroU32::new)
+            .map(RowVal::Id),
+        (RowType::U32, ValueRef::Integer(n)) => u32::try_from(n).ok().map(RowVal::U32),
+        (RowType::Bool, ValueRef::Integer(n @ (0 | 1))) => Some(RowVal::Bool(n == 1)),
+        (RowType::Bytes, ValueRef::Text(bytes) | ValueRef::Blob(bytes)) => {
+            Some(RowVal::Bytes(bytes.to_vec()))
+        }
+        (RowType::Bytes, ValueRef::Integer(n)) => Some(RowVal::Bytes(n.to_string().into())),
+        (RowType::Bytes, ValueRef::Real(n)) => Some(RowVal::Bytes(n.to_string().into())),
+        _ => None,
+    }
+}
+
+fn describe(val: ValueRef) -> String {
+    match val {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(n) => n.to_string(),
+        ValueRef::Real(n) => n.to_string(),
+        ValueRef::Text(bytes) => format!("{:?}", String::from_utf8_lossy(bytes)),
+        ValueRef::Blob(bytes) => format!("a {} byte blob", bytes.len()),
+    }
+}
+
+/// An identifier quoted for SQLite.
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn sqlite_error(e: rusqlite::Error) -> DbError {
+    DbError::Io(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn import_sqlite() {
+        let dir = "tests/import_sqlite";
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let path = format!("{dir}/source.sqlite");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (user_id INTEGER PRIMARY KEY, name TEXT, age INT, admin BOOLEAN);
+             INSERT INTO users VALUES (1, 'ann', 30, 1), (2, 'bob', NULL, 0), (3, 'cy', -1, 0);
+             CREATE TABLE notes (id TEXT, body BLOB, score REAL);
+             INSERT INTO notes VALUES ('a', x'00ff', 1.5), ('b', 'text', 2);",
+        )
+        .unwrap();
+        let mut bulk = conn
+            .prepare("INSERT INTO users VALUES (?, 'x', ?, 0)")
+            .unwrap();
+        for i in 4..=2500 {
+            bulk.execute([i, i % 100]).unwrap();
+        }
+        drop(bulk);
+        drop(conn);
+
+        let report = import(&path, dir).unwrap();
+        assert_eq!(report[0].table, "notes");
+        assert_eq!((report[0].inserted, report[0].rejected.len()), (2, 0));
+        assert_eq!(report[1].inserted, 2498);
+        assert_eq!(
+            report[1].rejected,
+            [
+                (2, "NULL isn't a u32 for age".to_string()),
+                (3, "-1 isn't a u32 for age".to_string())
+            ]
+        );
+
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let users = DB::open(&format!("{dir}/users")).unwrap().unwrap();
+        assert_eq!(users.schema.names, ["user_id", "name", "age", "admin"]);
+        assert_eq!(
+            users.get(id(1)),
+            Some(vec![
+                RowVal::Bytes(b"ann".to_vec()),
+                RowVal::U32(30),
+                RowVal::Bool(true)
+            ])
+        );
+        // without an integer key, the rowid is the id, and a column already
+        // named id keeps its name
+        let notes = DB::open(&format!("{dir}/notes")).unwrap().unwrap();
+        assert_eq!(notes.schema.names, ["rowid", "id", "body", "score"]);
+        assert_eq!(
+            notes.get(id(1)),
+            Some(vec![
+                RowVal::Bytes(b"a".to_vec()),
+                RowVal::Bytes(vec![0, 0xff]),
+                RowVal::Bytes(b"1.5".to_vec())
+            ])
+        );
+        drop((users, notes));
+
+        assert_eq!(
+            import(&path, dir),
+            Err(DbError::Io(format!("{dir}/notes already holds a database")))
+        );
+        assert_eq!(
+            import("tests/no_such.sqlite", dir),
+            Err(DbError::NotFound("tests/no_such.sqlite".to_string()))
+        );
+    }
+}
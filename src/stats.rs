@@ -0,0 +1,251 @@
+//! Statistics about the values in each column, for picking how to run a
+//! query once there's more than one way to. They're gathered by
+//! `DB::analyze`, and gathered again on every `sync` after that, and saved
+//! to the database's `STATS` file.
+
+use std::{
+    fs::{self, File},
+    io,
+    num::NonZeroU32,
+};
+
+use crate::{
+    manifest,
+    row::{bytes_to_values, RowVal, Schema},
+    utils::bytes_to_u32,
+};
+
+/// How many buckets a column's histogram splits its values into.
+pub const BUCKETS: usize = 16;
+
+/// What's known about the values of one column.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnStats {
+    /// The smallest and largest values, or `None` if there are no rows.
+    pub min: Option<RowVal>,
+    pub max: Option<RowVal>,
+    /// How many different values there are.
+    pub distinct: u64,
+    /// The values split into up to `BUCKETS` buckets of about as many values
+    /// each, in order. Each holds the largest value in it and how many values
+    /// it holds, which are all larger than the bucket before's.
+    pub histogram: Vec<(RowVal, u64)>,
+}
+
+impl ColumnStats {
+    /// The statistics of a column holding `vals`.
+    pub fn new(mut vals: Vec<RowVal>) -> Self {
+        vals.sort();
+        let mut histogram: Vec<(RowVal, u64)> = vec![];
+        let depth = vals.len().div_ceil(BUCKETS).max(1);
+        let mut distinct = 0;
+        for (i, val) in vals.iter().enumerate() {
+            let new = i == 0 || vals[i - 1] != *val;
+            distinct += u64::from(new);
+            // a value is never split across buckets, so a bucket can go over
+            // its depth
+            match histogram.last_mut() {
+                Some((last, count)) if !new || (*count as usize) < depth => {
+                    *last = val.clone();
+                    *count += 1;
+                }
+                _ => histogram.push((val.clone(), 1)),
+            }
+        }
+        Self {
+            min: vals.first().cloned(),
+            max: vals.last().cloned(),
+            distinct,
+            histogram,
+        }
+    }
+
+    /// Roughly how many values are at most `val`, from the histogram,
+    /// assuming a bucket's values are spread evenly through it.
+    pub fn at_most(&self, val: &RowVal) -> u64 {
+        let i = self.histogram.partition_point(|(last, _)| last <= val);
+        let below: u64 = self.histogram[..i].iter().map(|(_, count)| count).sum();
+        match self.histogram.get(i) {
+            Some((_, count)) => below + count / 2,
+            None => below,
+        }
+    }
+}
+
+/// The statistics of every column of a table, the id included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub rows: u64,
+    pub columns: Vec<ColumnStats>,
+    /// The schema version the statistics were gathered at. Saved statistics
+    /// for another one are ignored.
+    pub version: u32,
+}
+
+impl TableStats {
+    /// Gathers the statistics of `rows`, as `DB::scan` returns them.
+    pub fn collect(rows: Vec<(NonZeroU32, Vec<RowVal>)>, schema: &Schema) -> Self {
+        let mut columns = vec![Vec::with_capacity(rows.len()); schema.schema.len()];
+        let count = rows.len() as u64;
+        for (id, vals) in rows {
+            columns[0].push(RowVal::Id(id));
+            for (column, val) in columns[1..].iter_mut().zip(vals) {
+                column.push(val);
+            }
+        }
+        Self {
+            rows: count,
+            columns: columns.into_iter().map(ColumnStats::new).collect(),
+            version: schema.version(),
+        }
+    }
+
+    /// Serializes the schema version and row count, then each column's
+    /// distinct count, whether it has a min and max and then them, and its
+    /// histogram's length and buckets.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = self.version.to_le_bytes().to_vec();
+        res.extend(self.rows.to_le_bytes());
+        for column in &self.columns {
+            res.extend(column.distinct.to_le_bytes());
+            match (&column.min, &column.max) {
+                (Some(min), Some(max)) => {
+                    res.push(1);
+                    res.extend(min.to_bytes());
+                    res.extend(max.to_bytes());
+                }
+                _ => res.push(0),
+            }
+            res.extend((column.histogram.len() as u32).to_le_bytes());
+            for (last, count) in &column.histogram {
+                res.extend(last.to_bytes());
+                res.extend(count.to_le_bytes());
+            }
+        }
+        res
+    }
+
+    /// Reads back statistics written for `schema`, or `None` if they were
+    /// gathered at another version of it.
+    pub fn from_bytes(bytes: &[u8], schema: &Schema) -> Option<Self> {
+        let version = bytes_to_u32(bytes.get(0..4)?);
+        if version != schema.version() {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let rows = u64_at(4);
+        let mut i = 12;
+        let mut columns = vec![];
+        for &row_type in &schema.schema {
+            let val = |i: &mut usize| {
+                let (mut vals, len) = bytes_to_values(&bytes[*i..], &[row_type]);
+                *i += len;
+                vals.pop().unwrap()
+            };
+            let distinct = u64_at(i);
+            let has_bounds = bytes[i + 8] == 1;
+            i += 9;
+            let (min, max) = match has_bounds {
+                true => (Some(val(&mut i)), Some(val(&mut i))),
+                false => (None, None),
+            };
+            let len = bytes_to_u32(&bytes[i..i + 4]);
+            i += 4;
+            let histogram = (0..len)
+                .map(|_| {
+                    let last = val(&mut i);
+                    let count = u64_at(i);
+                    i += 8;
+                    (last, count)
+                })
+                .collect();
+            columns.push(ColumnStats {
+                min,
+                max,
+                distinct,
+                histogram,
+            });
+        }
+        Some(Self {
+            rows,
+            columns,
+            version,
+        })
+    }
+}
+
+/// Saves the statistics of the database under `file_name`, writing them to
+/// a temporary file and renaming it into place so a crash leaves the old
+/// ones whole.
+pub fn write(file_name: &str, stats: &TableStats) -> io::Result<()> {
+    let path = manifest::stats_file(file_name);
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, stats.to_bytes())?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, &path)
+}
+
+/// The statistics saved for the database under `file_name`, if there are
+/// any for its current schema.
+pub fn read(file_name: &str, schema: &Schema) -> Option<TableStats> {
+    let bytes = fs::read(manifest::stats_file(file_name)).ok()?;
+    TableStats::from_bytes(&bytes, schema)
+}
+
+pub fn remove(file_name: &str) {
+    let _ = fs::remove_file(manifest::stats_file(file_name));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::row::{RowType, SchemaChange};
+
+    use super::*;
+
+    #[test]
+    fn histograms() {
+        let schema = Schema::new(&[RowType::Id, RowType::U32, RowType::Bytes]);
+        let rows: Vec<_> = (1..=100)
+            .map(|i| {
+                (
+                    NonZeroU32::new(i).unwrap(),
+                    vec![
+                        RowVal::U32(i % 10),
+                        RowVal::Bytes(vec![b'a'; i as usize % 3]),
+                    ],
+                )
+            })
+            .collect();
+        let stats = TableStats::collect(rows, &schema);
+        assert_eq!(stats.rows, 100);
+        let [id, count, bytes] = &stats.columns[..] else {
+            panic!("expected three columns")
+        };
+        assert_eq!(id.distinct, 100);
+        assert_eq!(id.histogram.len(), 15);
+        assert_eq!(id.at_most(&RowVal::Id(NonZeroU32::new(50).unwrap())), 52);
+        assert_eq!(count.min, Some(RowVal::U32(0)));
+        assert_eq!(count.max, Some(RowVal::U32(9)));
+        assert_eq!(count.distinct, 10);
+        // every value of 10 rows is too many for a bucket of 7 rows
+        assert_eq!(count.histogram.len(), 10);
+        assert_eq!(count.histogram[0], (RowVal::U32(0), 10));
+        assert_eq!(bytes.distinct, 3);
+
+        let read = TableStats::from_bytes(&stats.to_bytes(), &schema).unwrap();
+        assert_eq!(read, stats);
+        let mut altered = schema.clone();
+        altered.changes.push(SchemaChange::DropColumn {
+            index: 2,
+            row_type: RowType::Bytes,
+        });
+        assert_eq!(TableStats::from_bytes(&stats.to_bytes(), &altered), None);
+
+        let empty = TableStats::collect(vec![], &schema);
+        assert_eq!(empty.columns[1], ColumnStats::default());
+        assert_eq!(
+            TableStats::from_bytes(&empty.to_bytes(), &schema),
+            Some(empty)
+        );
+    }
+}
@@ -1,8 +1,19 @@
-use std::fs::File;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    num::NonZeroU32,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use crate::{
+    db::DB,
+    error::DbError,
     row::{bytes_to_id, RowType, RowVal},
     utils::{bytes_to_u16, bytes_to_u32},
+    wal::WALRecord,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -12,7 +23,240 @@ pub enum TransactionItem {
     Commit(u32),         // commit transaction $num
     Checkpoint,          // there are no active transactions at this point
     Insert(Vec<RowVal>), // an update that inserts these items (id + values needs to be set)
-    Delete(Vec<RowVal>), // an update that deletes these items (id + values needs to be set)
+    Delete(Vec<RowVal>), // an update that deletes these items (id needs to be set)
+    Savepoint(String),   // a named point in the transaction to roll back to
+    RollbackTo(String),  // undo every update since the named savepoint
+}
+
+/// Inserts and deletes that `DB::apply` makes all at once, for when the
+/// writes are known up front and don't need to read their own results.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    pub records: Vec<WALRecord>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: NonZeroU32, vals: &[RowVal]) {
+        self.records.push(WALRecord::Insert(id, vals.to_vec()));
+    }
+
+    pub fn remove(&mut self, id: NonZeroU32) {
+        self.records.push(WALRecord::Delete(id));
+    }
+}
+
+/// How many bytes of encoded items a `Txn` buffers in memory before it
+/// spills them to a temporary file.
+pub const SPILL_BYTES: usize = 16 << 20;
+
+/// Numbers the spill files of the process, so no two share a name.
+static SPILLS: AtomicU64 = AtomicU64::new(0);
+
+/// A transaction started with `DB::begin`. Its writes are buffered here and
+/// only logged and made visible by `DB::commit`, all at once. Dropping it
+/// without committing rolls it back.
+///
+/// Once the buffered items take more than `spill_bytes`, they're appended
+/// to a temporary file and dropped from memory, so a huge transaction only
+/// needs its writes in memory all at once when it commits. If the file
+/// can't be written, they stay in memory instead.
+#[derive(Debug)]
+pub struct Txn {
+    pub id: u32,
+    /// `Start`, then every insert and delete in the order they were made,
+    /// less those spilled to the temporary file before them.
+    pub items: Vec<TransactionItem>,
+    /// How many bytes `items` can take before they're spilled,
+    /// `SPILL_BYTES` unless changed.
+    pub spill_bytes: usize,
+    /// How many bytes `items` take, encoded.
+    buffered: usize,
+    spill: Option<Spill>,
+}
+
+/// The temporary file a `Txn`'s items are spilled to, removed with it.
+#[derive(Debug)]
+struct Spill {
+    path: PathBuf,
+    file: File,
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Txn {
+    pub fn new(id: u32) -> Self {
+        let start = TransactionItem::Start(id);
+        Self {
+            id,
+            buffered: start.to_bytes().len(),
+            items: vec![start],
+            spill_bytes: SPILL_BYTES,
+            spill: None,
+        }
+    }
+
+    pub fn insert(&mut self, id: NonZeroU32, vals: &[RowVal]) {
+        let mut row = vec![RowVal::Id(id)];
+        row.extend_from_slice(vals);
+        self.push(TransactionItem::Insert(row));
+    }
+
+    pub fn remove(&mut self, id: NonZeroU32) {
+        self.push(TransactionItem::Delete(vec![RowVal::Id(id)]));
+    }
+
+    /// Marks the point `rollback_to` can undo the transaction's writes back
+    /// to. A name can be reused; rolling back goes to its latest use.
+    pub fn savepoint(&mut self, name: &str) {
+        self.push(TransactionItem::Savepoint(name.to_string()));
+    }
+
+    /// Undoes every write made since the savepoint `name`, which stays set
+    /// so it can be rolled back to again. Savepoints set after it are gone.
+    pub fn rollback_to(&mut self, name: &str) -> Result<(), DbError> {
+        let items = self.all_items()?;
+        if !writes(&items)
+            .1
+            .iter()
+            .any(|(savepoint, _)| *savepoint == name)
+        {
+            return Err(DbError::UnknownSavepoint(name.to_string()));
+        }
+        self.push(TransactionItem::RollbackTo(name.to_string()));
+        Ok(())
+    }
+
+    /// Reads `id` as the transaction sees it: its own latest write to the
+    /// row if it made one, or else the row as committed in `db`.
+    pub fn get(&self, db: &DB, id: NonZeroU32) -> Result<Option<Vec<RowVal>>, DbError> {
+        for record in self.records()?.into_iter().rev() {
+            match record {
+                WALRecord::Insert(write, vals) if write == id => {
+                    return Ok(Some(db.schema.with_defaults(&vals).unwrap_or(vals)));
+                }
+                WALRecord::Delete(write) if write == id => return Ok(None),
+                _ => {}
+            }
+        }
+        Ok(db.get(id))
+    }
+
+    /// The transaction's writes as WAL records, in order, leaving out those
+    /// undone by rolling back to a savepoint. Fails if the spilled ones
+    /// can't be read back.
+    pub fn records(&self) -> Result<Vec<WALRecord>, DbError> {
+        Ok(writes(&self.all_items()?).0)
+    }
+
+    /// Whether some of the items have been spilled to a temporary file.
+    pub fn spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    fn push(&mut self, item: TransactionItem) {
+        self.buffered += item.to_bytes().len();
+        self.items.push(item);
+        if self.buffered > self.spill_bytes && self.spill_items().is_ok() {
+            self.items.clear();
+            self.buffered = 0;
+        }
+    }
+
+    /// Appends the buffered items to the spill file, creating it first if
+    /// this is the first spill.
+    fn spill_items(&mut self) -> std::io::Result<()> {
+        if self.spill.is_none() {
+            let n = SPILLS.fetch_add(1, Ordering::Relaxed);
+            let path = env::temp_dir().join(format!("db-txn-{}-{}-{n}", process::id(), self.id));
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            self.spill = Some(Spill { path, file });
+        }
+        let spill = self.spill.as_mut().unwrap();
+        let len = spill.file.metadata()?.len();
+        let res = spill.file.write_all(&encode_items(&self.items));
+        if res.is_err() {
+            // don't leave half an item for reading back
+            spill.file.set_len(len)?;
+        }
+        res
+    }
+
+    /// The spilled items followed by the buffered ones.
+    fn all_items(&self) -> Result<Vec<TransactionItem>, DbError> {
+        let mut items = match &self.spill {
+            Some(spill) => decode_items(&fs::read(&spill.path)?),
+            None => vec![],
+        };
+        items.extend(self.items.iter().cloned());
+        Ok(items)
+    }
+}
+
+/// Encodes items for a spill file, each as its length, in 4 bytes, and
+/// then its `to_bytes`.
+fn encode_items(items: &[TransactionItem]) -> Vec<u8> {
+    let mut res = vec![];
+    for item in items {
+        let bytes = item.to_bytes();
+        res.extend((bytes.len() as u32).to_le_bytes());
+        res.extend(bytes);
+    }
+    res
+}
+
+fn decode_items(mut bytes: &[u8]) -> Vec<TransactionItem> {
+    let mut items = vec![];
+    while bytes.len() >= 4 {
+        let len = bytes_to_u32(&bytes[..4]) as usize;
+        items.push(TransactionItem::from_bytes(&bytes[4..4 + len]));
+        bytes = &bytes[4 + len..];
+    }
+    items
+}
+
+/// Works through the items in order, returning the writes left and the
+/// savepoints still set, each with how many of the writes came before it.
+fn writes(items: &[TransactionItem]) -> (Vec<WALRecord>, Vec<(&str, usize)>) {
+    let mut writes = vec![];
+    let mut savepoints: Vec<(&str, usize)> = vec![];
+    for item in items {
+        match item {
+            TransactionItem::Insert(row) => {
+                writes.push(WALRecord::Insert(row_id(row), row[1..].to_vec()))
+            }
+            TransactionItem::Delete(row) => writes.push(WALRecord::Delete(row_id(row))),
+            TransactionItem::Savepoint(name) => savepoints.push((name, writes.len())),
+            TransactionItem::RollbackTo(name) => {
+                if let Some(i) = savepoints
+                    .iter()
+                    .rposition(|(savepoint, _)| savepoint == name)
+                {
+                    writes.truncate(savepoints[i].1);
+                    savepoints.truncate(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    (writes, savepoints)
+}
+
+fn row_id(row: &[RowVal]) -> NonZeroU32 {
+    match row.first() {
+        Some(RowVal::Id(id)) => *id,
+        _ => panic!("the first value must be an id"),
+    }
 }
 
 pub struct Transactions {
@@ -76,6 +320,16 @@ impl TransactionItem {
                 res.extend(serialize_rows(row_vals));
                 res
             }
+            TransactionItem::Savepoint(name) => {
+                let mut res = vec![6];
+                res.extend(serialize_name(name));
+                res
+            }
+            TransactionItem::RollbackTo(name) => {
+                let mut res = vec![7];
+                res.extend(serialize_name(name));
+                res
+            }
         }
     }
 
@@ -91,11 +345,24 @@ impl TransactionItem {
             3 => Self::Checkpoint,
             4 => Self::Insert(deserialize_bytes(bytes)),
             5 => Self::Delete(deserialize_bytes(bytes)),
+            6 => Self::Savepoint(deserialize_name(bytes)),
+            7 => Self::RollbackTo(deserialize_name(bytes)),
             _ => panic!("invalid transaction"),
         }
     }
 }
 
+fn serialize_name(name: &str) -> Vec<u8> {
+    let mut res = (name.len() as u16).to_le_bytes().to_vec();
+    res.extend(name.as_bytes());
+    res
+}
+
+fn deserialize_name(bytes: &[u8]) -> String {
+    let len = bytes_to_u16(&bytes[0..2]) as usize;
+    String::from_utf8_lossy(&bytes[2..2 + len]).into_owned()
+}
+
 fn deserialize_bytes(bytes: &[u8]) -> Vec<RowVal> {
     let len = bytes_to_u16(&bytes[0..2]);
     let mut items = vec![];
@@ -109,7 +376,7 @@ fn deserialize_bytes(bytes: &[u8]) -> Vec<RowVal> {
                 items.push(RowVal::Id(id));
                 i += 4
             }
-            RowType::U32 => {
+            RowType::U32 | RowType::Ttl => {
                 let num = bytes_to_u32(&bytes[i..i + 4]);
                 items.push(RowVal::U32(num));
                 i += 4
@@ -138,7 +405,9 @@ mod tests {
 
     use crate::row::{RowType, RowVal};
 
-    use super::TransactionItem;
+    use crate::{error::DbError, wal::WALRecord};
+
+    use super::{decode_items, encode_items, TransactionItem, Txn};
 
     impl Arbitrary for RowType {
         fn arbitrary(g: &mut quickcheck::Gen) -> Self {
@@ -158,7 +427,7 @@ mod tests {
             let row_type = RowType::arbitrary(g);
             match row_type {
                 RowType::Id => RowVal::Id(NonZeroU32::arbitrary(g)),
-                RowType::U32 => RowVal::U32(u32::arbitrary(g)),
+                RowType::U32 | RowType::Ttl => RowVal::U32(u32::arbitrary(g)),
                 RowType::Bytes => RowVal::Bytes(Vec::arbitrary(g)),
                 RowType::Bool => RowVal::Bool(bool::arbitrary(g)),
             }
@@ -167,7 +436,7 @@ mod tests {
 
     impl Arbitrary for TransactionItem {
         fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-            let choice = g.choose(&[0u8, 1, 2, 3, 4, 5]).unwrap();
+            let choice = g.choose(&[0u8, 1, 2, 3, 4, 5, 6, 7]).unwrap();
             match choice {
                 0 => Self::Start(u32::arbitrary(g)),
                 1 => Self::Rollback(u32::arbitrary(g)),
@@ -175,11 +444,82 @@ mod tests {
                 3 => Self::Checkpoint,
                 4 => Self::Insert(Vec::arbitrary(g)),
                 5 => Self::Delete(Vec::arbitrary(g)),
+                6 => Self::Savepoint(String::arbitrary(g)),
+                7 => Self::RollbackTo(String::arbitrary(g)),
                 _ => unreachable!(),
             }
         }
     }
 
+    #[test]
+    fn savepoints() {
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut txn = Txn::new(1);
+        txn.insert(id(1), &[RowVal::U32(1)]);
+        txn.savepoint("a");
+        txn.remove(id(1));
+        txn.savepoint("b");
+        txn.insert(id(2), &[RowVal::U32(2)]);
+        txn.rollback_to("b").unwrap();
+        assert_eq!(
+            txn.records().unwrap(),
+            [
+                WALRecord::Insert(id(1), vec![RowVal::U32(1)]),
+                WALRecord::Delete(id(1))
+            ]
+        );
+
+        // rolling back past a savepoint forgets it
+        txn.rollback_to("a").unwrap();
+        assert_eq!(
+            txn.rollback_to("b"),
+            Err(DbError::UnknownSavepoint("b".to_string()))
+        );
+        txn.insert(id(3), &[RowVal::U32(3)]);
+        txn.rollback_to("a").unwrap();
+        assert_eq!(
+            txn.records().unwrap(),
+            [WALRecord::Insert(id(1), vec![RowVal::U32(1)])]
+        );
+    }
+
+    #[test]
+    fn spills() {
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut txn = Txn::new(1);
+        txn.spill_bytes = 64;
+        for i in 1..=20 {
+            txn.insert(id(i), &[RowVal::U32(i), RowVal::Bytes(b"spilled".to_vec())]);
+        }
+        txn.savepoint("a");
+        txn.remove(id(3));
+        txn.rollback_to("a").unwrap();
+        txn.remove(id(4));
+        assert!(txn.spilled() && txn.items.len() < 20);
+
+        let mut records: Vec<_> = (1..=20)
+            .map(|i| {
+                WALRecord::Insert(
+                    id(i),
+                    vec![RowVal::U32(i), RowVal::Bytes(b"spilled".to_vec())],
+                )
+            })
+            .collect();
+        records.push(WALRecord::Delete(id(4)));
+        assert_eq!(txn.records().unwrap(), records);
+
+        // the spill file goes with the transaction
+        let path = txn.spill.as_ref().unwrap().path.clone();
+        assert!(path.exists());
+        drop(txn);
+        assert!(!path.exists());
+    }
+
+    #[quickcheck]
+    fn spill_serde(items: Vec<TransactionItem>) -> bool {
+        decode_items(&encode_items(&items)) == items
+    }
+
     #[quickcheck]
     fn serde(transaction: TransactionItem) -> bool {
         TransactionItem::from_bytes(&transaction.to_bytes()) == transaction
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use crate::{db::DB, error::DbError, transaction::WriteBatch, wal::WALRecord};
+
+/// The writes made in the shell, each with the writes that undo it, so they
+/// can be undone and redone in turn. Undoing and redoing go through
+/// `DB::apply`, so a write made by a transaction comes back as a whole.
+#[derive(Debug, Default)]
+pub struct UndoLog {
+    /// Each write as `(undo, redo)`, latest last.
+    undo: Vec<(Vec<WALRecord>, Vec<WALRecord>)>,
+    /// The writes undone since the last new write, latest last.
+    redo: Vec<(Vec<WALRecord>, Vec<WALRecord>)>,
+}
+
+impl UndoLog {
+    /// The writes that put every row `writes` touches back the way it is in
+    /// `db` now, so it has to be called before they're made.
+    pub fn inverse(db: &DB, writes: &[WALRecord]) -> Vec<WALRecord> {
+        let mut before = BTreeMap::new();
+        for write in writes {
+            if let WALRecord::Insert(id, _) | WALRecord::Delete(id) = write {
+                before.entry(*id).or_insert_with(|| db.get(*id));
+            }
+        }
+        before
+            .into_iter()
+            .map(|(id, val)| match val {
+                Some(val) => WALRecord::Insert(id, val),
+                None => WALRecord::Delete(id),
+            })
+            .collect()
+    }
+
+    /// Records writes that have been made, along with their `inverse`. A new
+    /// write can't be redone past, so it forgets everything undone.
+    pub fn push(&mut self, inverse: Vec<WALRecord>, writes: Vec<WALRecord>) {
+        self.undo.push((inverse, writes));
+        self.redo.clear();
+    }
+
+    /// Undoes the latest write, returning false if there's none left.
+    pub fn undo(&mut self, db: &mut DB) -> Result<bool, DbError> {
+        let Some((inverse, writes)) = self.undo.pop() else {
+            return Ok(false);
+        };
+        if let Err(e) = db.apply(WriteBatch {
+            records: inverse.clone(),
+        }) {
+            self.undo.push((inverse, writes));
+            return Err(e);
+        }
+        self.redo.push((inverse, writes));
+        Ok(true)
+    }
+
+    /// Makes the latest undone write again, returning false if there's none.
+    pub fn redo(&mut self, db: &mut DB) -> Result<bool, DbError> {
+        let Some((inverse, writes)) = self.redo.pop() else {
+            return Ok(false);
+        };
+        if let Err(e) = db.apply(WriteBatch {
+            records: writes.clone(),
+        }) {
+            self.redo.push((inverse, writes));
+            return Err(e);
+        }
+        self.undo.push((inverse, writes));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use crate::row::{RowType, RowVal};
+
+    use super::*;
+
+    #[test]
+    fn undo_and_redo() {
+        let mut db = DB::new("tests/undo_and_redo", &[RowType::Id, RowType::U32]).unwrap();
+        let mut log = UndoLog::default();
+        let (one, two) = (NonZeroU32::new(1).unwrap(), NonZeroU32::new(2).unwrap());
+        let write = |db: &mut DB, log: &mut UndoLog, writes: Vec<WALRecord>| {
+            let inverse = UndoLog::inverse(db, &writes);
+            db.apply(WriteBatch {
+                records: writes.clone(),
+            })
+            .unwrap();
+            log.push(inverse, writes);
+        };
+
+        write(
+            &mut db,
+            &mut log,
+            vec![WALRecord::Insert(one, vec![RowVal::U32(1)])],
+        );
+        write(
+            &mut db,
+            &mut log,
+            vec![
+                WALRecord::Insert(one, vec![RowVal::U32(2)]),
+                WALRecord::Insert(two, vec![RowVal::U32(2)]),
+                WALRecord::Delete(one),
+            ],
+        );
+        assert_eq!(db.get(one), None);
+
+        assert_eq!(log.undo(&mut db), Ok(true));
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        assert_eq!(db.get(two), None);
+        assert_eq!(log.undo(&mut db), Ok(true));
+        assert_eq!(db.get(one), None);
+        assert_eq!(log.undo(&mut db), Ok(false));
+
+        assert_eq!(log.redo(&mut db), Ok(true));
+        assert_eq!(db.get(one), Some(vec![RowVal::U32(1)]));
+        // a new write can't be redone past
+        write(
+            &mut db,
+            &mut log,
+            vec![WALRecord::Insert(two, vec![RowVal::U32(3)])],
+        );
+        assert_eq!(log.redo(&mut db), Ok(false));
+        assert_eq!(log.undo(&mut db), Ok(true));
+        assert_eq!(db.get(two), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! Writes submitted through io_uring, behind the `io_uring` feature on Linux.
+//! Every write of a batch is queued before any is waited on, so the kernel
+//! has all of them in flight at once and the whole batch takes one system
+//! call to submit and reap. Where io_uring isn't available, such as under a
+//! seccomp profile that blocks it, writes fall back to `pwrite`.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::double_write::PageWrite;
+
+/// How many entries each ring has, which is how many writes are in flight
+/// at once. Larger batches are submitted in turns.
+const ENTRIES: u32 = 64;
+
+thread_local! {
+    /// Each thread sets up its own ring on its first write. It holds `None`
+    /// if that failed, or if a write through it did, in which case the
+    /// thread goes back to plain writes.
+    static RING: RefCell<Option<Option<IoUring>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with this thread's ring, or returns `None` if it doesn't have
+/// one.
+fn with_ring<T>(f: impl FnOnce(&mut IoUring) -> io::Result<T>) -> Option<io::Result<T>> {
+    RING.with_borrow_mut(|slot| {
+        let ring = slot.get_or_insert_with(|| IoUring::new(ENTRIES).ok());
+        let res = ring.as_mut().map(f);
+        // the ring can be left with entries that were never submitted, or
+        // are still in flight, so it isn't reused
+        if matches!(res, Some(Err(_))) {
+            *slot = Some(None);
+        }
+        res
+    })
+}
+
+/// Writes every `(offset, bytes)` in `writes` to `file`, returning once all
+/// of them are written.
+pub fn write_all_at(file: &File, writes: &[PageWrite]) -> io::Result<()> {
+    if let Some(res) = with_ring(|ring| submit(ring, file, writes)) {
+        return res;
+    }
+    for (offset, bytes) in writes {
+        file.write_all_at(bytes, *offset)?;
+    }
+    Ok(())
+}
+
+fn submit(ring: &mut IoUring, file: &File, writes: &[PageWrite]) -> io::Result<()> {
+    let fd = types::Fd(file.as_raw_fd());
+    // what's left of each write, which is all of it unless it came back short
+    let mut pending: Vec<(u64, &[u8])> = writes
+        .iter()
+        .filter(|(_, bytes)| !bytes.is_empty())
+        .map(|(offset, bytes)| (*offset, &bytes[..]))
+        .collect();
+    while !pending.is_empty() {
+        let batch: Vec<_> = pending
+            .drain(..pending.len().min(ENTRIES as usize))
+            .collect();
+        for (i, (offset, bytes)) in batch.iter().enumerate() {
+            let len = bytes.len().min(u32::MAX as usize) as u32;
+            let entry = opcode::Write::new(fd, bytes.as_ptr(), len)
+                .offset(*offset)
+                .build()
+                .user_data(i as u64);
+            // the batch is no larger than the ring, which is empty between
+            // batches, and every buffer outlives the wait below
+            unsafe { ring.submission().push(&entry) }.expect("the submission queue is full");
+        }
+        for (i, res) in wait(ring, batch.len())? {
+            let (offset, bytes) = batch[i];
+            match res {
+                res if res < 0 => return Err(io::Error::from_raw_os_error(-res)),
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                res if (res as usize) < bytes.len() => {
+                    pending.push((offset + res as u64, &bytes[res as usize..]));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Submits what's queued and waits for `count` entries to complete,
+/// returning each one's user data and result.
+fn wait(ring: &mut IoUring, count: usize) -> io::Result<Vec<(usize, i32)>> {
+    let mut done = vec![];
+    while done.len() < count {
+        match ring.submit_and_wait(count - done.len()) {
+            Err(e) if e.kind() != io::ErrorKind::Interrupted => return Err(e),
+            _ => {}
+        }
+        done.extend(
+            ring.completion()
+                .map(|entry| (entry.user_data() as usize, entry.result())),
+        );
+    }
+    Ok(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn batched_writes() {
+        let path = "tests/uring_writes";
+        let file = File::create(path).unwrap();
+        // more writes than the ring has entries, out of order
+        let writes: Vec<_> = (0..200u64)
+            .rev()
+            .map(|i| (i * 8, i.to_le_bytes().to_vec()))
+            .collect();
+        write_all_at(&file, &writes).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(bytes.len(), 200 * 8);
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            assert_eq!(u64::from_le_bytes(chunk.try_into().unwrap()), i as u64);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,3 +1,13 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time in seconds since the unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
     u32::from_le_bytes(bytes.try_into().unwrap())
 }
@@ -9,3 +19,31 @@ pub fn bytes_to_u16(bytes: &[u8]) -> u16 {
 pub fn bytes_to_u8(bytes: &[u8]) -> u8 {
     u8::from_le_bytes(bytes.try_into().unwrap())
 }
+
+/// Appends `n` as a varint: 7 bits a byte, low bits first, with the high bit
+/// set on every byte but the last.
+pub fn write_varint(mut n: u32, res: &mut Vec<u8>) {
+    while n >= 0x80 {
+        res.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    res.push(n as u8);
+}
+
+/// Reads a varint written by `write_varint`, returning it and how many bytes
+/// it took.
+pub fn read_varint(bytes: &[u8]) -> (u32, usize) {
+    let mut n = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        n |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return (n, i + 1);
+        }
+    }
+    panic!("varint runs past the end of its bytes")
+}
+
+/// How many bytes `write_varint` takes for `n`.
+pub fn varint_len(n: u32) -> usize {
+    (32 - n.leading_zeros() as usize).max(1).div_ceil(7)
+}
@@ -1,15 +1,68 @@
-use std::{collections::BTreeMap, fs::File, io::Write, num::NonZeroU32};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
 #[cfg(test)]
 use serde::{Deserialize, Serialize};
 
-use crate::row::{bytes_to_id, bytes_to_values, RowType, RowVal};
+use crate::{
+    checksum::Checksum,
+    failpoint::{self, DbFile},
+    row::{bytes_to_id, bytes_to_values, row_size, RowType, RowVal},
+};
 
 #[cfg_attr(test, derive(Serialize, Deserialize))]
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum WALRecord {
     Insert(NonZeroU32, Vec<RowVal>),
     Delete(NonZeroU32),
+    /// Starts the log of a new epoch, once its pages are safely on disk.
+    Checkpoint(u64),
+    /// Hides a row until it's restored or purged by the next sync.
+    Tombstone(NonZeroU32),
+    /// Brings back a row hidden by a tombstone.
+    Restore(NonZeroU32),
+    /// Starts the writes of a transaction, which are only replayed if its
+    /// `Commit` made it to the log too.
+    Begin(u32),
+    Commit(u32),
+    /// Ends a transaction whose commit never made it to the log, so the
+    /// records logged after it aren't taken as part of it.
+    Rollback(u32),
+    /// Merges an operand into a row with the database's merge operator, see
+    /// `DB::merge`.
+    Merge(NonZeroU32, Vec<RowVal>),
+    /// The idempotency key a client gave the writes logged with it, see
+    /// `DB::apply_once`.
+    Request(u64),
+}
+
+/// Set in the epoch of a checkpoint record to mark it as one of the records
+/// below instead, which no real epoch gets near. The kind of record is in the
+/// bits above the low 32, which hold its id or transaction number.
+const MARKER: u64 = 1 << 63;
+const TOMBSTONE: u64 = 0;
+const RESTORE: u64 = 1;
+const BEGIN: u64 = 2;
+const COMMIT: u64 = 3;
+const ROLLBACK: u64 = 4;
+/// Followed by the operand's values, unlike the other markers.
+const MERGE: u64 = 5;
+/// Followed by the key, as it doesn't fit in the low 32 bits.
+const REQUEST: u64 = 6;
+
+fn marker(kind: u64, n: u32) -> Vec<u8> {
+    WALRecord::Checkpoint(MARKER | kind << 32 | n as u64).to_bytes()
 }
 
 impl WALRecord {
@@ -27,11 +80,60 @@ impl WALRecord {
                 res.extend(id.get().to_le_bytes());
                 res
             }
+            WALRecord::Checkpoint(epoch) => {
+                let mut res = vec![0; 8];
+                res.extend(epoch.to_le_bytes());
+                res
+            }
+            WALRecord::Tombstone(id) => marker(TOMBSTONE, id.get()),
+            WALRecord::Restore(id) => marker(RESTORE, id.get()),
+            WALRecord::Begin(txn) => marker(BEGIN, *txn),
+            WALRecord::Commit(txn) => marker(COMMIT, *txn),
+            WALRecord::Rollback(txn) => marker(ROLLBACK, *txn),
+            WALRecord::Merge(id, operand) => {
+                let mut res = marker(MERGE, id.get());
+                res.extend(operand.iter().flat_map(RowVal::to_bytes));
+                res
+            }
+            WALRecord::Request(key) => {
+                let mut res = marker(REQUEST, 0);
+                res.extend(key.to_le_bytes());
+                res
+            }
         }
     }
 
     pub fn from_bytes(bytes: &[u8], schema: &[RowType]) -> (Self, usize) {
         match bytes[0..4] {
+            // ids start at 1, so a zero id marks a checkpoint, or another
+            // marker if its epoch has the marker bit set
+            [0, 0, 0, 0] if bytes[4..8] == [0, 0, 0, 0] => {
+                let epoch = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+                if epoch & MARKER == 0 {
+                    return (WALRecord::Checkpoint(epoch), 16);
+                }
+                let n = epoch as u32;
+                let record = match (epoch & !MARKER) >> 32 {
+                    TOMBSTONE => WALRecord::Tombstone(bytes_to_id(&bytes[8..12])),
+                    RESTORE => WALRecord::Restore(bytes_to_id(&bytes[8..12])),
+                    BEGIN => WALRecord::Begin(n),
+                    COMMIT => WALRecord::Commit(n),
+                    ROLLBACK => WALRecord::Rollback(n),
+                    MERGE => {
+                        let (operand, len) = bytes_to_values(&bytes[16..], &schema[1..]);
+                        return (
+                            WALRecord::Merge(bytes_to_id(&bytes[8..12]), operand),
+                            16 + len,
+                        );
+                    }
+                    REQUEST => {
+                        let key = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+                        return (WALRecord::Request(key), 24);
+                    }
+                    _ => panic!("unknown marker record"),
+                };
+                (record, 16)
+            }
             [0, 0, 0, 0] => {
                 let id = bytes_to_id(&bytes[4..8]);
                 (WALRecord::Delete(id), 8)
@@ -39,7 +141,7 @@ impl WALRecord {
             _ => {
                 let (rows, incr) = bytes_to_values(bytes, schema);
                 if let RowVal::Id(id) = rows[0] {
-                    return (WALRecord::Insert(id, rows[1..].to_vec()), incr + 4);
+                    return (WALRecord::Insert(id, rows[1..].to_vec()), incr);
                 }
                 panic!("Id must be the first row in the byte array")
             }
@@ -47,43 +149,656 @@ impl WALRecord {
     }
 }
 
-pub fn deserialize_wal(bytes: &[u8], schema: &[RowType]) -> Vec<WALRecord> {
-    let mut records = vec![];
-    let mut i = 0;
+/// Bytes before each record's payload: its length, a checksum of the LSN and
+/// payload, and its LSN.
+pub const FRAME_HEADER_SIZE: usize = 16;
+
+impl WALRecord {
+    /// Encodes the record with its log sequence number, prefixed with its
+    /// length and a checksum, so a torn or corrupted record can be told apart
+    /// from a valid one.
+    pub fn to_frame(&self, lsn: u64, checksum: Checksum) -> Vec<u8> {
+        let mut body = lsn.to_le_bytes().to_vec();
+        body.extend(self.to_bytes());
+        let mut res = ((body.len() - 8) as u32).to_le_bytes().to_vec();
+        res.extend(checksum.hash(&body).to_le_bytes());
+        res.extend(body);
+        res
+    }
 
-    if bytes.len() < 4 {
-        return records;
+    /// Decodes a framed record, returning its LSN, the record and its framed
+    /// length, or `None` if the frame is cut short or its checksum doesn't
+    /// match.
+    pub fn from_frame(
+        bytes: &[u8],
+        schema: &[RowType],
+        checksum: Checksum,
+    ) -> Option<(u64, Self, usize)> {
+        if bytes.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+        let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let body = bytes.get(8..FRAME_HEADER_SIZE + len)?;
+        if len == 0 || checksum.hash(body) != crc {
+            return None;
+        }
+        let lsn = u64::from_le_bytes(body[0..8].try_into().unwrap());
+        let (record, incr) = WALRecord::from_bytes(&body[8..], schema);
+        if incr != len {
+            return None;
+        }
+        Some((lsn, record, FRAME_HEADER_SIZE + len))
     }
+}
 
-    while i < bytes.len() - 4 {
-        let (wal_record, incr) = WALRecord::from_bytes(&bytes[i..], schema);
-        records.push(wal_record);
-        i += incr;
+/// Reads framed records until the end of the log or the first record that's
+/// torn or corrupted. Returns the records along with the offset of the
+/// invalid record, if there was one; nothing after it can be trusted.
+pub fn deserialize_wal(
+    bytes: &[u8],
+    schema: &[RowType],
+    checksum: Checksum,
+) -> (Vec<(u64, WALRecord)>, Option<usize>) {
+    let mut records = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match WALRecord::from_frame(&bytes[i..], schema, checksum) {
+            Some((lsn, wal_record, incr)) => {
+                records.push((lsn, wal_record));
+                i += incr;
+            }
+            None => return (records, Some(i)),
+        }
     }
 
-    records
+    (records, None)
 }
 
+/// How large a WAL segment can grow before appends move on to the next one.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The log is split across segment files named `{path}.0001`, `{path}.0002`
+/// and so on, replayed in order. A record never spans two segments.
 #[derive(Debug)]
 pub struct WAL {
-    pub file: File,
+    /// The segment being appended to.
+    pub file: DbFile,
     pub records: BTreeMap<NonZeroU32, Vec<RowVal>>,
+    /// Rows deleted with a tombstone since the last `clear`, which are
+    /// hidden from reads but still in `records` or on a page.
+    pub tombstones: BTreeSet<NonZeroU32>,
+    /// The encoded size of every row in `records`, as a stand in for how much
+    /// memory they take.
+    pub size: usize,
+    pub path: String,
+    /// The number of the segment being appended to, starting at 1.
+    pub segment: u32,
+    pub segment_size: u64,
+    /// How many bytes are in the current segment.
+    written: u64,
+    /// The LSN of the last record appended. LSNs keep counting up across
+    /// `clear` and checkpoints, since pages record the last one they reflect.
+    pub lsn: u64,
+    /// What every frame's checksum is computed with.
+    pub checksum: Checksum,
+}
+
+fn segment_path(path: &str, segment: u32) -> String {
+    format!("{path}.{segment:04}")
+}
+
+fn open_segment(path: &str, segment: u32) -> io::Result<DbFile> {
+    failpoint::open(
+        OpenOptions::new().create(true).read(true).append(true),
+        segment_path(path, segment),
+    )
 }
 
 impl WAL {
-    pub fn insert(&mut self, id: NonZeroU32, values: &[RowVal]) -> bool {
-        self.records.insert(id, values.to_vec());
-        let _ = self
-            .file
-            .write_all(&WALRecord::Insert(id, values.to_vec()).to_bytes());
-        true
+    /// Starts an empty log at `path`, removing any segments already there.
+    pub fn create(path: &str, segment_size: u64, checksum: Checksum) -> io::Result<Self> {
+        Self::remove_segments(path);
+        Ok(Self {
+            file: open_segment(path, 1)?,
+            records: BTreeMap::new(),
+            tombstones: BTreeSet::new(),
+            size: 0,
+            path: path.to_string(),
+            segment: 1,
+            segment_size,
+            written: 0,
+            lsn: 0,
+            checksum,
+        })
+    }
+
+    /// Opens the log at `path` and reads back its records from every segment
+    /// in order. A torn or corrupted record ends the log: its segment is cut
+    /// back to the last valid record and any later segments are removed, so
+    /// new appends aren't stranded behind it. Each record comes with its LSN.
+    pub fn open(
+        path: &str,
+        segment_size: u64,
+        schema: &[RowType],
+        checksum: Checksum,
+    ) -> io::Result<(Self, Vec<(u64, WALRecord)>)> {
+        Self::open_with(path, segment_size, schema, checksum, false)
     }
-    pub fn remove(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+
+    /// Like `open`, but a torn record at the end is left in place instead of
+    /// cut off, and the last segment is opened for reading only, so nothing
+    /// under `path` changes.
+    pub fn open_read_only(
+        path: &str,
+        segment_size: u64,
+        schema: &[RowType],
+        checksum: Checksum,
+    ) -> io::Result<(Self, Vec<(u64, WALRecord)>)> {
+        Self::open_with(path, segment_size, schema, checksum, true)
+    }
+
+    fn open_with(
+        path: &str,
+        segment_size: u64,
+        schema: &[RowType],
+        checksum: Checksum,
+        read_only: bool,
+    ) -> io::Result<(Self, Vec<(u64, WALRecord)>)> {
+        let mut records = vec![];
+        // the last segment read, which is where appends carry on
+        let mut segment = 1;
+        let mut written = 0;
+        while let Ok(bytes) = fs::read(segment_path(path, segment)) {
+            let (segment_records, invalid) = deserialize_wal(&bytes, schema, checksum);
+            records.extend(segment_records);
+            written = bytes.len() as u64;
+            if let Some(offset) = invalid {
+                written = offset as u64;
+                if !read_only {
+                    open_segment(path, segment)?.set_len(written)?;
+                    let mut later = segment + 1;
+                    while fs::remove_file(segment_path(path, later)).is_ok() {
+                        later += 1;
+                    }
+                }
+                break;
+            }
+            if !fs::exists(segment_path(path, segment + 1)).unwrap_or(false) {
+                break;
+            }
+            segment += 1;
+        }
+
+        let file = if read_only {
+            failpoint::open(OpenOptions::new().read(true), segment_path(path, segment))?
+        } else {
+            open_segment(path, segment)?
+        };
+        let wal = Self {
+            file,
+            records: BTreeMap::new(),
+            tombstones: BTreeSet::new(),
+            size: 0,
+            path: path.to_string(),
+            segment,
+            segment_size,
+            written,
+            lsn: records.last().map_or(0, |(lsn, _)| *lsn),
+            checksum,
+        };
+        Ok((wal, records))
+    }
+
+    /// Reads back every record in the log, from every segment in order, with
+    /// its LSN.
+    pub fn read_records(&self, schema: &[RowType]) -> io::Result<Vec<(u64, WALRecord)>> {
+        let mut records = vec![];
+        for segment in 1..=self.segment {
+            let bytes = fs::read(segment_path(&self.path, segment))?;
+            records.extend(deserialize_wal(&bytes, schema, self.checksum).0);
+        }
+        Ok(records)
+    }
+
+    /// Removes every segment of the log at `path`.
+    pub fn remove_segments(path: &str) {
+        let mut segment = 1;
+        while fs::remove_file(segment_path(path, segment)).is_ok() {
+            segment += 1;
+        }
+    }
+
+    /// Frames `record` with the next LSN.
+    fn frame(&mut self, record: WALRecord) -> Vec<u8> {
+        self.lsn += 1;
+        record.to_frame(self.lsn, self.checksum)
+    }
+
+    /// Appends to the current segment, moving on to a new one first if the
+    /// bytes would take it over `segment_size`. A write that fails part way
+    /// is cut back off, if it can be, so later appends aren't stranded behind
+    /// a torn record. Writes are appended before they change the in-memory
+    /// records, so one that fails leaves them as they were.
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.written > 0 && self.written + bytes.len() as u64 > self.segment_size {
+            self.file = open_segment(&self.path, self.segment + 1)?;
+            self.segment += 1;
+            self.written = 0;
+        }
+        if let Err(e) = self.file.write_all(bytes) {
+            let _ = self.file.set_len(self.written);
+            return Err(e);
+        }
+        self.written += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Adds a row to the in-memory records without logging it. A row written
+    /// over a tombstone replaces it.
+    pub fn cache(&mut self, id: NonZeroU32, values: Vec<RowVal>) {
+        self.tombstones.remove(&id);
+        self.size += row_size(&values);
+        if let Some(old) = self.records.insert(id, values) {
+            self.size -= row_size(&old);
+        }
+    }
+
+    /// Removes a row from the in-memory records without logging it.
+    pub fn uncache(&mut self, id: NonZeroU32) -> Option<Vec<RowVal>> {
+        self.tombstones.remove(&id);
         let res = self.records.remove(&id);
-        let _ = self.file.write_all(&WALRecord::Delete(id).to_bytes());
+        if let Some(old) = &res {
+            self.size -= row_size(old);
+        }
         res
     }
+
+    /// Forgets every record and starts the log over with one empty segment.
+    /// Returns `false`, with the records kept, if the first segment can't be
+    /// opened or emptied.
+    pub fn clear(&mut self) -> bool {
+        let Ok(file) = open_segment(&self.path, 1) else {
+            return false;
+        };
+        if file.set_len(0).is_err() {
+            return false;
+        }
+        self.records.clear();
+        self.tombstones.clear();
+        self.size = 0;
+        for segment in 2..=self.segment {
+            let _ = fs::remove_file(segment_path(&self.path, segment));
+        }
+        self.segment = 1;
+        self.written = 0;
+        self.file = file;
+        true
+    }
+
+    pub fn insert(&mut self, id: NonZeroU32, values: &[RowVal]) -> io::Result<bool> {
+        let frame = self.frame(WALRecord::Insert(id, values.to_vec()));
+        self.append(&frame)?;
+        self.cache(id, values.to_vec());
+        Ok(true)
+    }
+    /// Appends every row with a single write, so a bulk load costs one
+    /// syscall instead of one per row.
+    pub fn insert_many(&mut self, rows: &[(NonZeroU32, Vec<RowVal>)]) -> io::Result<bool> {
+        let mut bytes = vec![];
+        for (id, values) in rows {
+            bytes.extend(self.frame(WALRecord::Insert(*id, values.clone())));
+        }
+        self.append(&bytes)?;
+        for (id, values) in rows {
+            self.cache(*id, values.clone());
+        }
+        Ok(true)
+    }
+    pub fn remove(&mut self, id: NonZeroU32) -> io::Result<Option<Vec<RowVal>>> {
+        let frame = self.frame(WALRecord::Delete(id));
+        self.append(&frame)?;
+        Ok(self.uncache(id))
+    }
+    /// Logs the writes of transaction `txn` between its begin and commit
+    /// records with a single write, and applies them to the in-memory
+    /// records. Only inserts and deletes can be part of a transaction. A
+    /// `key` is logged after the begin record, naming the writes.
+    pub fn commit(&mut self, txn: u32, key: Option<u64>, records: &[WALRecord]) -> io::Result<()> {
+        let mut bytes = self.frame(WALRecord::Begin(txn));
+        if let Some(key) = key {
+            bytes.extend(self.frame(WALRecord::Request(key)));
+        }
+        for record in records {
+            assert!(
+                matches!(record, WALRecord::Insert(..) | WALRecord::Delete(_)),
+                "only inserts and deletes can be in a transaction"
+            );
+            bytes.extend(self.frame(record.clone()));
+        }
+        bytes.extend(self.frame(WALRecord::Commit(txn)));
+        self.append(&bytes)?;
+        for record in records {
+            match record {
+                WALRecord::Insert(id, values) => self.cache(*id, values.clone()),
+                WALRecord::Delete(id) => {
+                    self.uncache(*id);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    /// Logs `operand` merged into `id`, and caches `merged`, the value the
+    /// merge gave it.
+    pub fn merge(
+        &mut self,
+        id: NonZeroU32,
+        operand: &[RowVal],
+        merged: Vec<RowVal>,
+    ) -> io::Result<()> {
+        let frame = self.frame(WALRecord::Merge(id, operand.to_vec()));
+        self.append(&frame)?;
+        self.cache(id, merged);
+        Ok(())
+    }
+    /// Logs that transaction `txn` will never commit.
+    pub fn rollback(&mut self, txn: u32) -> io::Result<()> {
+        let frame = self.frame(WALRecord::Rollback(txn));
+        self.append(&frame)
+    }
+    /// Logs a tombstone for `id`, hiding it until it's restored.
+    pub fn tombstone(&mut self, id: NonZeroU32) -> io::Result<()> {
+        let frame = self.frame(WALRecord::Tombstone(id));
+        self.append(&frame)?;
+        self.tombstones.insert(id);
+        Ok(())
+    }
+    /// Logs that the tombstone for `id` is lifted.
+    pub fn restore(&mut self, id: NonZeroU32) -> io::Result<()> {
+        let frame = self.frame(WALRecord::Restore(id));
+        self.append(&frame)?;
+        self.tombstones.remove(&id);
+        Ok(())
+    }
+    /// Marks the start of `epoch` in the log.
+    pub fn checkpoint(&mut self, epoch: u64) -> io::Result<()> {
+        let frame = self.frame(WALRecord::Checkpoint(epoch));
+        self.append(&frame)
+    }
+    /// Logs the idempotency keys of writes the log no longer holds, with a
+    /// single write, so they're still known after a restart.
+    pub fn requests(&mut self, keys: &[u64]) -> io::Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = vec![];
+        for key in keys {
+            bytes.extend(self.frame(WALRecord::Request(*key)));
+        }
+        self.append(&bytes)
+    }
     pub fn get(&self, id: NonZeroU32) -> Option<Vec<RowVal>> {
         self.records.get(&id).cloned()
     }
+
+    /// Asks `committer` to fsync everything appended so far.
+    pub fn request_sync(&self, committer: &GroupCommitter) -> io::Result<Commit> {
+        Ok(committer.request(self.segment, self.file.try_clone()?))
+    }
+}
+
+/// A request to fsync the segment numbered by the `u32`, through its own
+/// handle to the file, and where to say when it's done.
+type SyncRequest = (u32, File, Sender<io::Result<()>>);
+
+/// Bounds on how many writes are grouped under one fsync. The committer waits
+/// at most `max_delay` after the first write of a group for more to arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupCommit {
+    pub max_records: usize,
+    pub max_delay: Duration,
+}
+
+/// Fsyncs the WAL for writes that have to be durable before they return, on
+/// a thread of its own, so writes made at about the same time by different
+/// sessions share one fsync instead of taking one each. See
+/// `DB::set_group_commit`.
+#[derive(Debug)]
+pub struct GroupCommitter {
+    sender: Option<Sender<SyncRequest>>,
+    syncer: Option<JoinHandle<()>>,
+    /// How many fsyncs the committer has done.
+    pub fsyncs: Arc<AtomicU64>,
+}
+
+/// A write waiting on the fsync that makes it durable. The receiver is in a
+/// mutex only so a `DB` holding commits can still be shared between threads.
+#[derive(Debug)]
+pub struct Commit(Mutex<Receiver<io::Result<()>>>);
+
+impl Commit {
+    /// Waits until the write is on disk, or the fsync failed.
+    pub fn wait(self) -> io::Result<()> {
+        self.0
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("the group committer has stopped")))
+    }
+}
+
+impl GroupCommitter {
+    pub fn new(group_commit: GroupCommit) -> Self {
+        let (sender, receiver) = channel::<SyncRequest>();
+        let fsyncs = Arc::new(AtomicU64::new(0));
+        let syncer_fsyncs = Arc::clone(&fsyncs);
+        let syncer = thread::spawn(move || {
+            while let Ok(first) = receiver.recv() {
+                let deadline = Instant::now() + group_commit.max_delay;
+                let mut group = vec![first];
+                while group.len() < group_commit.max_records {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match receiver.recv_timeout(timeout) {
+                        Ok(request) => group.push(request),
+                        Err(_) => break,
+                    }
+                }
+
+                // requests come in the order their writes were made, so the
+                // last one for each segment covers the ones before it
+                let mut res = Ok(());
+                for (i, (segment, file, _)) in group.iter().enumerate() {
+                    if group.get(i + 1).is_some_and(|(next, ..)| next == segment) {
+                        continue;
+                    }
+                    res = res.and_then(|()| file.sync_data());
+                    syncer_fsyncs.fetch_add(1, Ordering::Relaxed);
+                }
+                for (_, _, done) in group {
+                    let res = match &res {
+                        Ok(()) => Ok(()),
+                        Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                    };
+                    let _ = done.send(res);
+                }
+            }
+        });
+        Self {
+            sender: Some(sender),
+            syncer: Some(syncer),
+            fsyncs,
+        }
+    }
+
+    /// Asks for everything written so far to the segment numbered `segment`,
+    /// which `file` is a handle to, to be fsynced.
+    pub fn request(&self, segment: u32, file: File) -> Commit {
+        let (done, result) = channel();
+        let sender = self.sender.as_ref().unwrap();
+        if let Err(e) = sender.send((segment, file, done)) {
+            let (_, _, done) = e.0;
+            let _ = done.send(Err(io::Error::other("the group committer has stopped")));
+        }
+        Commit(Mutex::new(result))
+    }
+}
+
+impl Drop for GroupCommitter {
+    fn drop(&mut self) {
+        // closing the channel lets the syncer finish what's queued and exit
+        drop(self.sender.take());
+        if let Some(syncer) = self.syncer.take() {
+            let _ = syncer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_invalid_record() {
+        for checksum in [Checksum::Crc32, Checksum::Crc32c, Checksum::XxHash64] {
+            stops_at_invalid_record_with(checksum);
+        }
+    }
+
+    fn stops_at_invalid_record_with(checksum: Checksum) {
+        let schema = &[RowType::Id, RowType::U32];
+        let records: Vec<_> = (1..=3)
+            .map(|i| WALRecord::Insert(NonZeroU32::new(i).unwrap(), vec![RowVal::U32(i)]))
+            .chain([
+                WALRecord::Delete(NonZeroU32::new(2).unwrap()),
+                WALRecord::Checkpoint(7),
+                WALRecord::Tombstone(NonZeroU32::MAX),
+                WALRecord::Restore(NonZeroU32::new(1).unwrap()),
+                WALRecord::Begin(0),
+                WALRecord::Commit(u32::MAX),
+                WALRecord::Rollback(3),
+                WALRecord::Merge(NonZeroU32::new(3).unwrap(), vec![RowVal::U32(5)]),
+                WALRecord::Request(u64::MAX),
+            ])
+            .collect();
+        let records: Vec<_> = (1..).zip(records).collect();
+        let bytes: Vec<_> = records
+            .iter()
+            .flat_map(|(lsn, r)| r.to_frame(*lsn, checksum))
+            .collect();
+        assert_eq!(
+            deserialize_wal(&bytes, schema, checksum),
+            (records.clone(), None)
+        );
+
+        // a torn write leaves the last record cut short
+        let torn = &bytes[..bytes.len() - 3];
+        let (lsn, record) = records.last().unwrap();
+        let last = bytes.len() - record.to_frame(*lsn, checksum).len();
+        assert_eq!(
+            deserialize_wal(torn, schema, checksum),
+            (records[..records.len() - 1].to_vec(), Some(last))
+        );
+
+        // a flipped bit in the second record's payload stops replay there
+        let mut corrupted = bytes.clone();
+        let second = records[0].1.to_frame(1, checksum).len();
+        corrupted[second + FRAME_HEADER_SIZE + 5] ^= 1;
+        assert_eq!(
+            deserialize_wal(&corrupted, schema, checksum),
+            (records[..1].to_vec(), Some(second))
+        );
+    }
+
+    #[test]
+    fn rotation_errors() {
+        let path = "tests/rotation_errors.wal";
+        let _ = fs::remove_dir(segment_path(path, 2));
+        let schema = &[RowType::Id, RowType::U32];
+        let id = |i| NonZeroU32::new(i).unwrap();
+        let mut wal = WAL::create(path, 1, Checksum::Crc32).unwrap();
+        wal.insert(id(1), &[RowVal::U32(1)]).unwrap();
+
+        // the next segment can't be opened, so the write fails and isn't
+        // made, and the log carries on in the current one
+        fs::create_dir(segment_path(path, 2)).unwrap();
+        assert!(wal.insert(id(2), &[RowVal::U32(2)]).is_err());
+        assert_eq!(wal.get(id(2)), None);
+        fs::remove_dir(segment_path(path, 2)).unwrap();
+        wal.insert(id(3), &[RowVal::U32(3)]).unwrap();
+        assert_eq!(wal.segment, 2);
+
+        let (_, records) = WAL::open(path, 1, schema, Checksum::Crc32).unwrap();
+        let ids: Vec<_> = records
+            .into_iter()
+            .map(|(_, record)| match record {
+                WALRecord::Insert(id, _) => id.get(),
+                record => panic!("unexpected {record:?}"),
+            })
+            .collect();
+        assert_eq!(ids, [1, 3]);
+    }
+
+    #[test]
+    fn group_commit() {
+        let file = Arc::new(Mutex::new(File::create("tests/group_commit.wal").unwrap()));
+        let committer = Arc::new(GroupCommitter::new(GroupCommit {
+            max_records: 64,
+            max_delay: Duration::from_millis(5),
+        }));
+
+        let threads: Vec<_> = (0..16u32)
+            .map(|t| {
+                let file = Arc::clone(&file);
+                let committer = Arc::clone(&committer);
+                thread::spawn(move || {
+                    for i in 1..=20 {
+                        let id = NonZeroU32::new(t * 20 + i).unwrap();
+                        let record = WALRecord::Insert(id, vec![RowVal::U32(t)]);
+                        // each writer waits for its fsync with the file
+                        // unlocked, as `SharedDB` does
+                        let commit = {
+                            let mut file = file.lock().unwrap();
+                            file.write_all(&record.to_frame(id.get().into(), Checksum::Crc32))
+                                .unwrap();
+                            committer.request(1, file.try_clone().unwrap())
+                        };
+                        commit.wait().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let fsyncs = committer.fsyncs.load(Ordering::Relaxed);
+        drop(committer);
+
+        let bytes = fs::read("tests/group_commit.wal").unwrap();
+        let (records, invalid) =
+            deserialize_wal(&bytes, &[RowType::Id, RowType::U32], Checksum::Crc32);
+        assert_eq!(invalid, None);
+        assert_eq!(records.len(), 320);
+        // with sixteen writers at once, writes share fsyncs
+        assert!(fsyncs < 320, "{fsyncs} fsyncs for 320 writes");
+    }
+
+    // fsyncing /dev/null fails on Linux
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn group_commit_errors() {
+        let committer = GroupCommitter::new(GroupCommit {
+            max_records: 8,
+            max_delay: Duration::from_millis(50),
+        });
+        let commits: Vec<_> = (0..8)
+            .map(|_| committer.request(1, File::options().write(true).open("/dev/null").unwrap()))
+            .collect();
+        // every write in the group hears of the failed fsync, not just the
+        // first
+        for commit in commits {
+            assert!(commit.wait().is_err());
+        }
+    }
 }